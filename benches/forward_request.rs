@@ -0,0 +1,19 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use icx_proxy::bench::Proxy;
+
+/// Forwarding a GET request for an asset of a mebibyte, whose response is verified, and
+/// whose response is not, as its certification policy is skip.
+fn forward(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let body = "a".repeat(1024 * 1024);
+    let mut group = c.benchmark_group("forward_request");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+    for policy in &["required", "skip"] {
+        let proxy = Proxy::new(&["--default-certification-policy", policy], &body);
+        group.bench_function(*policy, |b| b.iter(|| runtime.block_on(proxy.get())));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, forward);
+criterion_main!(benches);
@@ -0,0 +1,66 @@
+use crate::{
+    forward_request,
+    mock_replica::{canned, MockReplica},
+    validate::unsigned::certified_asset,
+    Opts, ProxyConfig,
+};
+use candid::Encode;
+use clap::Parser;
+use hyper::{body::Bytes, Body, Request};
+use ic_agent::{export::Principal, Agent};
+use ic_utils::interfaces::http_request::HeaderField;
+use std::sync::Arc;
+
+// The canister the requests of benchmarks are forwarded to.
+const CANISTER_ID: &str = "rrkah-fqaaa-aaaaa-aaaaq-cai";
+
+/// A proxy in front of a replica without a network, whose canister answers every query
+/// with the same certified asset, for benchmarks to measure forwarding requests.
+pub struct Proxy {
+    config: ProxyConfig,
+    agent: Arc<Agent>,
+    host: String,
+}
+
+impl Proxy {
+    /// A proxy configured with the command-line `args`, in front of a canister answering
+    /// with `body`.
+    pub fn new(args: &[&str], body: &str) -> Proxy {
+        let canister_id = Principal::from_text(CANISTER_ID).unwrap();
+        let (certificate_sha, certificate_header) =
+            certified_asset(&canister_id, "/", body.as_bytes());
+        let mut response = canned(body);
+        response.headers.push(HeaderField(
+            "IC-Certificate".to_string(),
+            certificate_header,
+        ));
+        let answer = Encode!(&response).unwrap();
+        let replica = MockReplica::new(move |_, _| Ok(answer.clone()));
+
+        let opts = Opts::parse_from(std::iter::once("icx-proxy").chain(args.iter().copied()));
+        let config = ProxyConfig::new(&opts).unwrap();
+        // The certificate has an empty signature.
+        if let Some(cache) = &config.verification_cache {
+            cache.insert_certificate(certificate_sha);
+        }
+        Proxy {
+            config,
+            agent: Arc::new(replica.agent()),
+            host: format!("{}.localhost", CANISTER_ID),
+        }
+    }
+
+    /// Forward a GET request for the asset, and read the body of the response.
+    pub async fn get(&self) -> Bytes {
+        let request = Request::get("/")
+            .header("Host", &self.host)
+            .body(Body::empty())
+            .unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let response = forward_request(request, self.agent.clone(), &self.config, logger)
+            .await
+            .unwrap();
+        assert!(response.status().is_success(), "{:?}", response);
+        hyper::body::to_bytes(response.into_body()).await.unwrap()
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// The bytes of bodies buffered across all requests, and how many may be at once.
+#[derive(Clone)]
+pub(crate) struct BufferBudget {
+    max_bytes: Option<u64>,
+    buffered: Arc<AtomicU64>,
+}
+
+impl BufferBudget {
+    /// A budget of `max_bytes`, unlimited if [None], counting buffered bytes in `buffered`.
+    pub fn new(max_bytes: Option<u64>, buffered: Arc<AtomicU64>) -> BufferBudget {
+        BufferBudget {
+            max_bytes,
+            buffered,
+        }
+    }
+
+    /// Reserve `bytes` for the bodies of a request. Returns [None] if they would not fit in
+    /// the budget.
+    pub fn reserve(&self, bytes: u64) -> Option<BufferReservation> {
+        let mut reservation = BufferReservation {
+            bytes: 0,
+            max_bytes: self.max_bytes,
+            buffered: self.buffered.clone(),
+        };
+        if reservation.grow(bytes) {
+            Some(reservation)
+        } else {
+            None
+        }
+    }
+}
+
+/// Bytes counted in the budget for as long as the reservation is held, including when the
+/// request it is held for panics.
+pub(crate) struct BufferReservation {
+    bytes: u64,
+    max_bytes: Option<u64>,
+    buffered: Arc<AtomicU64>,
+}
+
+impl BufferReservation {
+    /// Reserve `bytes` more. Returns false, leaving the reservation unchanged, if they would
+    /// not fit in the budget.
+    pub fn grow(&mut self, bytes: u64) -> bool {
+        let max_bytes = self.max_bytes;
+        let reserved = self
+            .buffered
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |buffered| {
+                let buffered = buffered.checked_add(bytes)?;
+                match max_bytes {
+                    Some(max_bytes) if buffered > max_bytes => None,
+                    _ => Some(buffered),
+                }
+            })
+            .is_ok();
+        if reserved {
+            self.bytes += bytes;
+        }
+        reserved
+    }
+
+    /// Release all but `bytes` of the reservation.
+    pub fn shrink_to(&mut self, bytes: u64) {
+        let released = self.bytes.saturating_sub(bytes);
+        self.buffered.fetch_sub(released, Ordering::Relaxed);
+        self.bytes -= released;
+    }
+}
+
+impl Drop for BufferReservation {
+    fn drop(&mut self) {
+        self.buffered.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::buffer_budget::BufferBudget;
+    use std::sync::{atomic::Ordering, Arc};
+
+    #[test]
+    fn reservations_are_limited_by_the_budget() {
+        let buffered = Arc::default();
+        let budget = BufferBudget::new(Some(100), Arc::clone(&buffered));
+
+        let mut first = budget.reserve(60).unwrap();
+        assert!(budget.reserve(50).is_none());
+        assert!(!first.grow(41));
+        assert!(first.grow(40));
+        assert_eq!(buffered.load(Ordering::Relaxed), 100);
+        assert!(budget.reserve(1).is_none());
+
+        drop(first);
+        assert_eq!(buffered.load(Ordering::Relaxed), 0);
+        assert!(budget.reserve(100).is_some());
+
+        let mut shrunk = budget.reserve(100).unwrap();
+        shrunk.shrink_to(30);
+        assert_eq!(buffered.load(Ordering::Relaxed), 30);
+        assert!(budget.reserve(70).is_some());
+        shrunk.shrink_to(50);
+        drop(shrunk);
+        assert_eq!(buffered.load(Ordering::Relaxed), 0);
+
+        let unlimited = BufferBudget::new(None, Arc::clone(&buffered));
+        let _reservation = unlimited.reserve(u64::MAX / 2).unwrap();
+        assert_eq!(buffered.load(Ordering::Relaxed), u64::MAX / 2);
+    }
+
+    #[test]
+    fn reservations_are_released_on_panics() {
+        let buffered = Arc::default();
+        let budget = BufferBudget::new(Some(100), Arc::clone(&buffered));
+
+        let result = std::panic::catch_unwind(|| {
+            let _reservation = budget.reserve(80).unwrap();
+            panic!("while buffering");
+        });
+
+        assert!(result.is_err());
+        assert_eq!(buffered.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,167 @@
+use hyper::body::Bytes;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+
+/// A pool of buffers reused across requests, so that decoding bodies does not allocate and
+/// grow a buffer every time.
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+    max_capacity: usize,
+}
+
+impl BufferPool {
+    /// A pool keeping up to `max_buffers` buffers. Buffers which grew beyond `max_capacity`
+    /// are dropped rather than kept, so that a burst of large bodies does not pin memory.
+    pub fn new(max_buffers: usize, max_capacity: usize) -> Arc<BufferPool> {
+        Arc::new(BufferPool {
+            buffers: Mutex::new(Vec::new()),
+            max_buffers,
+            max_capacity,
+        })
+    }
+
+    /// An empty buffer, returned to the pool when dropped.
+    pub fn get(self: &Arc<Self>) -> PooledBuffer {
+        let buffer = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        PooledBuffer {
+            pool: self.clone(),
+            buffer,
+        }
+    }
+
+    fn put(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() > self.max_capacity {
+            return;
+        }
+        buffer.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buffer);
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn pooled(&self) -> Vec<usize> {
+        let buffers = self.buffers.lock().unwrap();
+        buffers.iter().map(Vec::capacity).collect()
+    }
+}
+
+/// A buffer of a [BufferPool], returned to it when dropped.
+pub(crate) struct PooledBuffer {
+    pool: Arc<BufferPool>,
+    buffer: Vec<u8>,
+}
+
+impl PooledBuffer {
+    /// Hand the buffer over as [Bytes] without copying it. It goes back to the pool once the
+    /// last clone of the [Bytes] is dropped, typically once hyper wrote it out.
+    pub fn into_bytes(self) -> Bytes {
+        Bytes::from_owner(self)
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::buffer_pool::BufferPool;
+
+    #[test]
+    fn buffers_are_reused_empty() {
+        let pool = BufferPool::new(4, 1024);
+        {
+            let mut buffer = pool.get();
+            buffer.extend_from_slice(b"hello world");
+        }
+        assert_eq!(pool.pooled().len(), 1);
+
+        let buffer = pool.get();
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= 11);
+        assert!(pool.pooled().is_empty());
+    }
+
+    #[test]
+    fn large_buffers_are_dropped() {
+        let pool = BufferPool::new(4, 1024);
+        pool.get().resize(2048, 0);
+        assert!(pool.pooled().is_empty());
+
+        pool.get().resize(1024, 0);
+        assert_eq!(pool.pooled().len(), 1);
+    }
+
+    #[test]
+    fn bytes_return_their_buffer_once_dropped() {
+        let pool = BufferPool::new(4, 1024);
+        let mut buffer = pool.get();
+        buffer.extend_from_slice(b"hello world");
+        let address = buffer.as_ptr();
+
+        let bytes = buffer.into_bytes();
+        let clone = bytes.slice(6..);
+        assert_eq!(bytes.as_ptr(), address);
+        assert_eq!(&clone[..], b"world");
+        drop(bytes);
+        assert!(pool.pooled().is_empty());
+
+        drop(clone);
+        assert_eq!(pool.pooled().len(), 1);
+        assert_eq!(pool.get().as_ptr(), address);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn buffers_are_shared_between_tasks() {
+        let pool = BufferPool::new(8, 64 * 1024);
+        let tasks = (0..64u8)
+            .map(|task| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    for round in 0..100usize {
+                        let mut buffer = pool.get();
+                        assert!(buffer.is_empty());
+                        buffer.resize(round * 100 + 1, task);
+                        let bytes = buffer.into_bytes();
+                        tokio::task::yield_now().await;
+                        assert!(bytes.iter().all(|byte| *byte == task));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let pooled = pool.pooled();
+        assert!(!pooled.is_empty() && pooled.len() <= 8);
+        assert!(pooled.iter().all(|capacity| *capacity <= 64 * 1024));
+    }
+}
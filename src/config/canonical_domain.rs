@@ -0,0 +1,185 @@
+use anyhow::anyhow;
+use hyper::Uri;
+use ic_agent::ic_types::Principal;
+use std::collections::HashMap;
+
+const CANONICAL_DOMAIN_FORMAT_HELP: &str = "Format is principal-id=host";
+
+/// Configuration of the domain the requests to each canister are redirected to, when they
+/// arrive on another host.
+#[derive(Clone, Debug, Default)]
+pub struct CanonicalDomainConfig {
+    domains: HashMap<Principal, CanonicalDomain>,
+}
+
+#[derive(Clone, Debug)]
+struct CanonicalDomain {
+    /// The host requests are redirected to, with its port if it was given one.
+    host: String,
+    /// The host as compared with those of requests, see [normalize_host].
+    normalized: String,
+}
+
+impl CanonicalDomainConfig {
+    /// Create a CanonicalDomainConfig instance from command-line configuration.
+    /// domains: 0 or more entries of the form of canister-id=host
+    pub fn new(domains: &[String]) -> anyhow::Result<CanonicalDomainConfig> {
+        let mut config = CanonicalDomainConfig::default();
+        for domain in domains {
+            config.insert(domain)?;
+        }
+        Ok(config)
+    }
+
+    fn insert(&mut self, entry: &str) -> anyhow::Result<()> {
+        let (principal, host) = entry.split_once('=').ok_or_else(|| {
+            anyhow!(
+                r#"Unrecognized canonical domain "{}".  {}"#,
+                entry,
+                CANONICAL_DOMAIN_FORMAT_HELP
+            )
+        })?;
+        let principal = Principal::from_text(principal.trim())?;
+        let host = host.trim();
+        if host.is_empty() || host.contains('/') {
+            return Err(anyhow!(r#"Invalid canonical domain "{}""#, host));
+        }
+        self.domains.insert(
+            principal,
+            CanonicalDomain {
+                host: host.to_ascii_lowercase(),
+                normalized: normalize_host(host),
+            },
+        );
+        Ok(())
+    }
+
+    /// Return where to redirect a request for `uri` to a canister arriving on `host` with
+    /// `scheme`, or [None] if the canister has no canonical domain or `host` is the
+    /// canonical one.
+    pub fn redirect_target(
+        &self,
+        canister_id: &Principal,
+        scheme: &str,
+        host: &str,
+        uri: &Uri,
+    ) -> Option<String> {
+        let canonical = self.domains.get(canister_id)?;
+        if normalize_host(host) == canonical.normalized {
+            return None;
+        }
+        let path_and_query = uri.path_and_query().map_or("/", |path| path.as_str());
+        Some(format!("{}://{}{}", scheme, canonical.host, path_and_query))
+    }
+}
+
+/// A host without its port, lowercased and without a trailing dot, so that hosts naming
+/// the same domain compare equal.
+fn normalize_host(host: &str) -> String {
+    let host = match host.rsplit_once(':') {
+        // Keep the colons of bracketed IPv6 addresses.
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::canonical_domain::CanonicalDomainConfig;
+    use hyper::Uri;
+    use ic_agent::ic_types::Principal;
+
+    fn canister() -> Principal {
+        Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+    }
+
+    #[test]
+    fn redirects_to_the_canonical_domain_preserving_path_and_query() {
+        let config = parse_domains(vec!["rrkah-fqaaa-aaaaa-aaaaq-cai=Example.COM"]).unwrap();
+        let target = |host: &str, uri: &str| {
+            config.redirect_target(&canister(), "https", host, &uri.parse::<Uri>().unwrap())
+        };
+
+        assert_eq!(
+            target(
+                "rrkah-fqaaa-aaaaa-aaaaq-cai.ic0.app",
+                "/docs/index.html?lang=en&x=%20"
+            ),
+            Some("https://example.com/docs/index.html?lang=en&x=%20".to_string())
+        );
+        assert_eq!(
+            target(
+                "rrkah-fqaaa-aaaaa-aaaaq-cai.ic0.app:443",
+                "http://ic0.app/a?b"
+            ),
+            Some("https://example.com/a?b".to_string())
+        );
+        assert_eq!(
+            config.redirect_target(
+                &canister(),
+                "http",
+                "localhost:8000",
+                &Uri::from_static("/")
+            ),
+            Some("http://example.com/".to_string())
+        );
+    }
+
+    #[test]
+    fn canonical_hosts_are_not_redirected() {
+        let config = parse_domains(vec!["rrkah-fqaaa-aaaaa-aaaaq-cai=example.com"]).unwrap();
+        let target =
+            |host: &str| config.redirect_target(&canister(), "https", host, &Uri::from_static("/"));
+
+        assert_eq!(target("example.com"), None);
+        assert_eq!(target("EXAMPLE.com."), None);
+        assert_eq!(target("example.com:8443"), None);
+        assert!(target("www.example.com").is_some());
+        assert_eq!(
+            parse_domains(vec![]).unwrap().redirect_target(
+                &canister(),
+                "https",
+                "ic0.app",
+                &Uri::from_static("/")
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn ports_of_canonical_domains_are_kept() {
+        let config = parse_domains(vec!["rrkah-fqaaa-aaaaa-aaaaq-cai=Example.com:8443"]).unwrap();
+        let target = |host: &str, uri: &str| {
+            config.redirect_target(&canister(), "https", host, &uri.parse::<Uri>().unwrap())
+        };
+
+        assert_eq!(
+            target("rrkah-fqaaa-aaaaa-aaaaq-cai.ic0.app", "/a?b"),
+            Some("https://example.com:8443/a?b".to_string())
+        );
+        assert_eq!(target("example.com:8443", "/a?b"), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let e = parse_domains(vec!["rrkah-fqaaa-aaaaa-aaaaq-cai:example.com"])
+            .expect_err("expected failure due to missing equals sign");
+        assert_eq!(
+            e.to_string(),
+            r#"Unrecognized canonical domain "rrkah-fqaaa-aaaaa-aaaaq-cai:example.com".  Format is principal-id=host"#
+        );
+
+        let e = parse_domains(vec!["rrkah-fqaaa-aaaaa-aaaaq-cai=https://example.com"])
+            .expect_err("expected failure due to a URL rather than a host");
+        assert_eq!(
+            e.to_string(),
+            r#"Invalid canonical domain "https://example.com""#
+        );
+    }
+
+    fn parse_domains(domains: Vec<&str>) -> anyhow::Result<CanonicalDomainConfig> {
+        let domains: Vec<String> = domains.iter().map(|&s| String::from(s)).collect();
+        CanonicalDomainConfig::new(&domains)
+    }
+}
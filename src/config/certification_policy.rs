@@ -0,0 +1,216 @@
+use anyhow::{anyhow, Context};
+use ic_agent::ic_types::Principal;
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+const CERTIFICATION_POLICY_FORMAT_HELP: &str =
+    "Format is principal-id=policy, where policy is one of required, optional or skip";
+
+/// How responses of a canister are verified against their certificate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CertificationPolicy {
+    /// Responses must be certified; uncertified responses are rejected.
+    Required,
+
+    /// Responses carrying a certificate are verified, uncertified responses are let through.
+    Optional,
+
+    /// Responses are never verified.
+    Skip,
+}
+
+impl FromStr for CertificationPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "required" => Ok(CertificationPolicy::Required),
+            "optional" => Ok(CertificationPolicy::Optional),
+            "skip" => Ok(CertificationPolicy::Skip),
+            _ => Err(anyhow!(
+                r#"Unknown certification policy "{}". Expected one of required, optional or skip"#,
+                s
+            )),
+        }
+    }
+}
+
+/// Configuration of the certification policy of each canister.
+#[derive(Clone, Debug)]
+pub struct CertificationPolicyConfig {
+    policies: HashMap<Principal, CertificationPolicy>,
+    default_policy: CertificationPolicy,
+}
+
+impl CertificationPolicyConfig {
+    /// Create a CertificationPolicyConfig instance from command-line configuration.
+    /// policies: 0 or more entries of the form of canister-id=policy
+    /// policy_file: a file of entries of the same form, one per line. Empty lines and lines
+    /// starting with '#' are ignored. Entries on the command line win over the file.
+    /// default_policy: the policy of canisters without an entry
+    pub fn new(
+        policies: &[String],
+        policy_file: Option<&Path>,
+        default_policy: CertificationPolicy,
+    ) -> anyhow::Result<CertificationPolicyConfig> {
+        let mut config = CertificationPolicyConfig {
+            policies: HashMap::new(),
+            default_policy,
+        };
+
+        if let Some(policy_file) = policy_file {
+            let content = fs::read_to_string(policy_file).with_context(|| {
+                format!(
+                    "Unable to read certification policy file {}",
+                    policy_file.display()
+                )
+            })?;
+            for line in content.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                config.insert(line)?;
+            }
+        }
+        for policy in policies {
+            config.insert(policy)?;
+        }
+
+        Ok(config)
+    }
+
+    fn insert(&mut self, entry: &str) -> anyhow::Result<()> {
+        let (principal, policy) = entry.split_once('=').ok_or_else(|| {
+            anyhow!(
+                r#"Unrecognized certification policy "{}".  {}"#,
+                entry,
+                CERTIFICATION_POLICY_FORMAT_HELP
+            )
+        })?;
+        let principal = Principal::from_text(principal.trim())?;
+        let policy = policy.trim().parse()?;
+        self.policies.insert(principal, policy);
+        Ok(())
+    }
+
+    /// Return the certification policy of a canister.
+    pub fn policy(&self, canister_id: &Principal) -> CertificationPolicy {
+        self.policies
+            .get(canister_id)
+            .copied()
+            .unwrap_or(self.default_policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::certification_policy::{CertificationPolicy, CertificationPolicyConfig};
+    use ic_agent::ic_types::Principal;
+    use std::io::Write;
+
+    #[test]
+    fn unknown_canister_uses_default() {
+        let config = parse_policies(vec![], CertificationPolicy::Required).unwrap();
+
+        assert_eq!(
+            config.policy(&Principal::from_text("r7inp-6aaaa-aaaaa-aaabq-cai").unwrap()),
+            CertificationPolicy::Required
+        );
+    }
+
+    #[test]
+    fn known_canisters_use_their_policy() {
+        let config = parse_policies(
+            vec![
+                "r7inp-6aaaa-aaaaa-aaabq-cai=skip",
+                "rrkah-fqaaa-aaaaa-aaaaq-cai=required",
+            ],
+            CertificationPolicy::Optional,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.policy(&Principal::from_text("r7inp-6aaaa-aaaaa-aaabq-cai").unwrap()),
+            CertificationPolicy::Skip
+        );
+        assert_eq!(
+            config.policy(&Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()),
+            CertificationPolicy::Required
+        );
+        assert_eq!(
+            config.policy(&Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()),
+            CertificationPolicy::Optional
+        );
+    }
+
+    #[test]
+    fn parse_error_no_equals() {
+        let e = parse_policies(
+            vec!["r7inp-6aaaa-aaaaa-aaabq-cai:skip"],
+            CertificationPolicy::Optional,
+        )
+        .expect_err("expected failure due to missing equals sign");
+        assert_eq!(
+            e.to_string(),
+            r#"Unrecognized certification policy "r7inp-6aaaa-aaaaa-aaabq-cai:skip".  Format is principal-id=policy, where policy is one of required, optional or skip"#
+        )
+    }
+
+    #[test]
+    fn parse_error_unknown_policy() {
+        let e = parse_policies(
+            vec!["r7inp-6aaaa-aaaaa-aaabq-cai=sometimes"],
+            CertificationPolicy::Optional,
+        )
+        .expect_err("expected failure due to unknown policy");
+        assert_eq!(
+            e.to_string(),
+            r#"Unknown certification policy "sometimes". Expected one of required, optional or skip"#
+        )
+    }
+
+    #[test]
+    fn parse_error_invalid_principal() {
+        parse_policies(vec!["not-a-principal=skip"], CertificationPolicy::Optional)
+            .expect_err("expected failure due to invalid principal");
+    }
+
+    #[test]
+    fn policy_file_is_overridden_by_command_line() {
+        let path = std::env::temp_dir().join(format!(
+            "icx-proxy-certification-policy-{}.txt",
+            std::process::id()
+        ));
+        {
+            let mut file = std::fs::File::create(&path).unwrap();
+            writeln!(file, "# legacy canisters").unwrap();
+            writeln!(file).unwrap();
+            writeln!(file, "r7inp-6aaaa-aaaaa-aaabq-cai=skip").unwrap();
+            writeln!(file, "rrkah-fqaaa-aaaaa-aaaaq-cai = optional").unwrap();
+        }
+
+        let config = CertificationPolicyConfig::new(
+            &["rrkah-fqaaa-aaaaa-aaaaq-cai=required".to_string()],
+            Some(&path),
+            CertificationPolicy::Optional,
+        );
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        assert_eq!(
+            config.policy(&Principal::from_text("r7inp-6aaaa-aaaaa-aaabq-cai").unwrap()),
+            CertificationPolicy::Skip
+        );
+        assert_eq!(
+            config.policy(&Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()),
+            CertificationPolicy::Required
+        );
+    }
+
+    fn parse_policies(
+        policies: Vec<&str>,
+        default_policy: CertificationPolicy,
+    ) -> anyhow::Result<CertificationPolicyConfig> {
+        let policies: Vec<String> = policies.iter().map(|&s| String::from(s)).collect();
+        CertificationPolicyConfig::new(&policies, None, default_policy)
+    }
+}
@@ -0,0 +1,338 @@
+use anyhow::anyhow;
+use hyper::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+        ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+        ORIGIN, VARY,
+    },
+    Method, Uri,
+};
+use std::{collections::HashSet, str::FromStr};
+
+/// How long browsers may cache the answers of the proxy to preflight requests, in seconds.
+const PREFLIGHT_MAX_AGE: &str = "600";
+
+/// How the proxy handles cross-origin requests to canisters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CorsMode {
+    /// The CORS headers of canisters are removed, so that browsers refuse cross-origin
+    /// requests.
+    Off,
+
+    /// Preflights go to canisters, and their CORS headers to clients, as they are.
+    Passthrough,
+
+    /// The proxy answers preflights itself and allows every origin, without credentials.
+    Permissive,
+
+    /// The proxy answers preflights itself and allows the configured origins, with
+    /// credentials.
+    Allowlist,
+}
+
+impl FromStr for CorsMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(CorsMode::Off),
+            "passthrough" => Ok(CorsMode::Passthrough),
+            "permissive" => Ok(CorsMode::Permissive),
+            "allowlist" => Ok(CorsMode::Allowlist),
+            _ => Err(anyhow!(
+                r#"Unknown CORS mode "{}". Expected one of off, passthrough, permissive or allowlist"#,
+                s
+            )),
+        }
+    }
+}
+
+/// Configuration of the handling of cross-origin requests to canisters.
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    mode: CorsMode,
+
+    /// The origins allowed in the allowlist mode, lowercased and without a trailing slash.
+    allowed_origins: HashSet<String>,
+}
+
+impl CorsConfig {
+    /// Create a CorsConfig instance from command-line configuration.
+    /// allowed_origins: the origins allowed in the allowlist mode, such as
+    /// https://example.com, at least one of which is required in that mode
+    pub fn new(mode: CorsMode, allowed_origins: &[String]) -> anyhow::Result<CorsConfig> {
+        if mode != CorsMode::Allowlist && !allowed_origins.is_empty() {
+            return Err(anyhow!(
+                "Allowed CORS origins require the allowlist CORS mode"
+            ));
+        }
+        if mode == CorsMode::Allowlist && allowed_origins.is_empty() {
+            return Err(anyhow!(
+                "The allowlist CORS mode requires at least one allowed origin"
+            ));
+        }
+        let allowed_origins = allowed_origins
+            .iter()
+            .map(|origin| {
+                let is_origin = Uri::from_str(origin.trim()).map_or(false, |uri| {
+                    uri.scheme().is_some()
+                        && uri.authority().is_some()
+                        && uri.path_and_query().map_or(true, |path| path.as_str() == "/")
+                });
+                if is_origin {
+                    Ok(normalize_origin(origin))
+                } else {
+                    Err(anyhow!(
+                        r#"Invalid CORS origin "{}". Expected a scheme and a host, e.g. https://example.com"#,
+                        origin
+                    ))
+                }
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(CorsConfig {
+            mode,
+            allowed_origins,
+        })
+    }
+
+    /// Whether the proxy answers preflight requests itself, rather than canisters.
+    pub fn answers_preflights(&self) -> bool {
+        matches!(self.mode, CorsMode::Permissive | CorsMode::Allowlist)
+    }
+
+    /// The headers of the answer of the proxy to a preflight request with `request_headers`,
+    /// or [None] if its origin is not allowed. The requested method is allowed unless
+    /// `allowed_methods` are configured, and the requested headers are all allowed.
+    pub fn preflight_headers(
+        &self,
+        request_headers: &HeaderMap,
+        allowed_methods: &[Method],
+    ) -> Option<HeaderMap> {
+        let mut headers = self.allow_origin_headers(request_headers.get(ORIGIN)?)?;
+        let methods = if allowed_methods.is_empty() {
+            request_headers.get(ACCESS_CONTROL_REQUEST_METHOD)?.clone()
+        } else {
+            let methods = allowed_methods
+                .iter()
+                .map(Method::as_str)
+                .collect::<Vec<_>>()
+                .join(", ");
+            HeaderValue::from_str(&methods).ok()?
+        };
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        if let Some(requested_headers) = request_headers.get(ACCESS_CONTROL_REQUEST_HEADERS) {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, requested_headers.clone());
+        }
+        headers.insert(
+            ACCESS_CONTROL_MAX_AGE,
+            HeaderValue::from_static(PREFLIGHT_MAX_AGE),
+        );
+        Some(headers)
+    }
+
+    /// Adjust the CORS `headers` of a response of a canister to a request from `origin`. The
+    /// CORS headers of canisters win over those of the proxy.
+    pub fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        match self.mode {
+            CorsMode::Off => {
+                let cors_headers = headers
+                    .keys()
+                    .filter(|name| name.as_str().starts_with("access-control-"))
+                    .cloned()
+                    .collect::<Vec<HeaderName>>();
+                for name in cors_headers {
+                    headers.remove(name);
+                }
+            }
+            CorsMode::Passthrough => {}
+            CorsMode::Permissive | CorsMode::Allowlist => {
+                if headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) {
+                    return;
+                }
+                if let Some(origin_headers) =
+                    origin.and_then(|origin| self.allow_origin_headers(origin))
+                {
+                    for (name, value) in origin_headers {
+                        if let Some(name) = name {
+                            headers.append(name, value);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The headers allowing requests from `origin`, or [None] if it is not allowed. Only
+    /// origins of the allowlist are allowed to send credentials, as browsers refuse
+    /// credentials when every origin is.
+    fn allow_origin_headers(&self, origin: &HeaderValue) -> Option<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        match self.mode {
+            CorsMode::Permissive => {
+                headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+            }
+            CorsMode::Allowlist => {
+                if !self
+                    .allowed_origins
+                    .contains(&normalize_origin(origin.to_str().ok()?))
+                {
+                    return None;
+                }
+                headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+                headers.insert(
+                    ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                    HeaderValue::from_static("true"),
+                );
+                headers.insert(VARY, HeaderValue::from_static("Origin"));
+            }
+            CorsMode::Off | CorsMode::Passthrough => return None,
+        }
+        Some(headers)
+    }
+}
+
+fn normalize_origin(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::cors::{CorsConfig, CorsMode};
+    use hyper::{header::HeaderMap, Method};
+
+    fn cors(mode: CorsMode, allowed_origins: &[&str]) -> anyhow::Result<CorsConfig> {
+        CorsConfig::new(
+            mode,
+            &allowed_origins
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    fn preflight(origin: &'static str) -> HeaderMap {
+        headers(&[
+            ("origin", origin),
+            ("access-control-request-method", "PUT"),
+            ("access-control-request-headers", "content-type, x-custom"),
+        ])
+    }
+
+    #[test]
+    fn permissive_preflights_allow_any_origin_without_credentials() {
+        let config = cors(CorsMode::Permissive, &[]).unwrap();
+        assert!(config.answers_preflights());
+
+        let headers = config
+            .preflight_headers(&preflight("https://app.example.com"), &[])
+            .unwrap();
+        assert_eq!(headers["access-control-allow-origin"], "*");
+        assert_eq!(headers["access-control-allow-methods"], "PUT");
+        assert_eq!(
+            headers["access-control-allow-headers"],
+            "content-type, x-custom"
+        );
+        assert_eq!(headers["access-control-max-age"], "600");
+        assert!(headers.get("access-control-allow-credentials").is_none());
+
+        let headers = config
+            .preflight_headers(
+                &preflight("https://app.example.com"),
+                &[Method::GET, Method::PUT],
+            )
+            .unwrap();
+        assert_eq!(headers["access-control-allow-methods"], "GET, PUT");
+    }
+
+    #[test]
+    fn allowlist_preflights_allow_listed_origins_with_credentials() {
+        let config = cors(CorsMode::Allowlist, &["https://App.example.com/"]).unwrap();
+
+        let headers = config
+            .preflight_headers(&preflight("https://app.example.com"), &[])
+            .unwrap();
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+        assert_eq!(headers["access-control-allow-credentials"], "true");
+        assert_eq!(headers["vary"], "Origin");
+
+        assert!(config
+            .preflight_headers(&preflight("https://evil.example.com"), &[])
+            .is_none());
+    }
+
+    #[test]
+    fn canister_cors_headers_win() {
+        let config = cors(CorsMode::Permissive, &[]).unwrap();
+        let origin = "https://app.example.com".parse().unwrap();
+
+        let mut response = headers(&[("content-type", "text/plain")]);
+        config.apply(Some(&origin), &mut response);
+        assert_eq!(response["access-control-allow-origin"], "*");
+
+        let mut response = headers(&[("access-control-allow-origin", "https://example.com")]);
+        config.apply(Some(&origin), &mut response);
+        assert_eq!(
+            response["access-control-allow-origin"],
+            "https://example.com"
+        );
+
+        // Requests which are not cross-origin are left alone.
+        let mut response = HeaderMap::new();
+        config.apply(None, &mut response);
+        assert!(response.is_empty());
+
+        let config = cors(CorsMode::Allowlist, &["https://app.example.com"]).unwrap();
+        let mut response = headers(&[("vary", "Accept-Encoding")]);
+        config.apply(Some(&origin), &mut response);
+        assert_eq!(
+            response["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+        assert_eq!(response["access-control-allow-credentials"], "true");
+        assert_eq!(
+            response.get_all("vary").iter().collect::<Vec<_>>(),
+            vec!["Accept-Encoding", "Origin"]
+        );
+    }
+
+    #[test]
+    fn cors_headers_are_removed_when_off() {
+        let config = cors(CorsMode::Off, &[]).unwrap();
+        assert!(!config.answers_preflights());
+        let mut response = headers(&[
+            ("access-control-allow-origin", "*"),
+            ("access-control-expose-headers", "x-custom"),
+            ("content-type", "text/plain"),
+        ]);
+
+        config.apply(None, &mut response);
+
+        assert_eq!(response, headers(&[("content-type", "text/plain")]));
+
+        let config = cors(CorsMode::Passthrough, &[]).unwrap();
+        let mut response = headers(&[("access-control-allow-origin", "*")]);
+        config.apply(None, &mut response);
+        assert_eq!(response["access-control-allow-origin"], "*");
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!("permissive".parse::<CorsMode>().is_ok());
+        assert!("everything".parse::<CorsMode>().is_err());
+        assert!(cors(CorsMode::Allowlist, &[]).is_err());
+        assert!(cors(CorsMode::Permissive, &["https://example.com"]).is_err());
+        assert!(cors(CorsMode::Allowlist, &["example.com"]).is_err());
+        assert!(cors(CorsMode::Allowlist, &["https://example.com/app"]).is_err());
+    }
+}
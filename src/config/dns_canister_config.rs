@@ -18,7 +18,7 @@ impl DnsCanisterConfig {
     ) -> anyhow::Result<DnsCanisterConfig> {
         let mut rules = vec![];
         for suffix in dns_suffixes {
-            rules.push(DnsCanisterRule::new_suffix(suffix));
+            rules.push(DnsCanisterRule::new_suffix(suffix)?);
         }
         for alias in dns_aliases {
             rules.push(DnsCanisterRule::new_alias(alias)?);
@@ -29,6 +29,48 @@ impl DnsCanisterConfig {
         Ok(DnsCanisterConfig { rules })
     }
 
+    /// The DNS aliases, in the order they are checked.
+    pub fn aliases(&self) -> impl Iterator<Item = (&str, Principal)> {
+        self.rules
+            .iter()
+            .filter_map(|rule| Some((rule.domain_name(), rule.alias_principal()?)))
+    }
+
+    /// The DNS suffixes, in the order they are checked.
+    pub fn suffixes(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.alias_principal().is_none())
+            .map(|rule| rule.domain_name())
+    }
+
+    /// Return warnings about rules which will never be used.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        for (index, rule) in self.rules.iter().enumerate() {
+            let shadowed_by = self.rules[..index].iter().find(|previous| {
+                previous.dns_suffix == rule.dns_suffix
+                    && previous.alias_principal().is_some() == rule.alias_principal().is_some()
+            });
+            if let Some(previous) = shadowed_by {
+                warnings.push(match (rule.alias_principal(), previous.alias_principal()) {
+                    (Some(principal), Some(previous_principal)) => format!(
+                        "DNS alias {}:{} is shadowed by DNS alias {}:{}",
+                        rule.domain_name(),
+                        principal,
+                        previous.domain_name(),
+                        previous_principal
+                    ),
+                    _ => format!(
+                        "DNS suffix {} is defined more than once",
+                        rule.domain_name()
+                    ),
+                });
+            }
+        }
+        warnings
+    }
+
     /// Return the Principal of the canister that matches the host name.
     ///
     /// split_hostname is expected to be the hostname split by '.',
@@ -353,6 +395,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn dns_suffix_wildcard_matches_any_single_label() {
+        let config = parse_config(vec![], vec!["*.example.com"]).unwrap();
+        let canister_id = Some(Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap());
+
+        assert_eq!(
+            config.resolve_canister_id_from_split_hostname(&[
+                "rrkah-fqaaa-aaaaa-aaaaq-cai",
+                "eu",
+                "example",
+                "com"
+            ]),
+            canister_id
+        );
+        assert_eq!(
+            config.resolve_canister_id_from_split_hostname(&[
+                "rrkah-fqaaa-aaaaa-aaaaq-cai",
+                "example",
+                "com"
+            ]),
+            None
+        );
+        assert_eq!(
+            config.resolve_canister_id_from_split_hostname(&[
+                "rrkah-fqaaa-aaaaa-aaaaq-cai",
+                "eu",
+                "west",
+                "example",
+                "com"
+            ]),
+            None
+        );
+    }
+
     #[test]
     fn aliases_and_suffixes() {
         let config = parse_config(
@@ -404,6 +480,57 @@ mod tests {
         );
     }
 
+    #[test]
+    fn lists_aliases_and_suffixes_in_lookup_order() {
+        let config = parse_config(
+            vec![
+                "d.e:rrkah-fqaaa-aaaaa-aaaaq-cai",
+                "a.b.c:r7inp-6aaaa-aaaaa-aaabq-cai",
+            ],
+            vec!["localhost", "g.h.i"],
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.aliases().collect::<Vec<_>>(),
+            vec![
+                (
+                    "a.b.c",
+                    Principal::from_text("r7inp-6aaaa-aaaaa-aaabq-cai").unwrap()
+                ),
+                (
+                    "d.e",
+                    Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+                ),
+            ]
+        );
+        assert_eq!(
+            config.suffixes().collect::<Vec<_>>(),
+            vec!["g.h.i", "localhost"]
+        );
+        assert!(config.warnings().is_empty());
+    }
+
+    #[test]
+    fn warns_about_shadowed_rules() {
+        let config = parse_config(
+            vec![
+                "a.b.c:r7inp-6aaaa-aaaaa-aaabq-cai",
+                "A.b.c:rrkah-fqaaa-aaaaa-aaaaq-cai",
+            ],
+            vec!["localhost", "localhost"],
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.warnings(),
+            vec![
+                "DNS alias A.b.c:rrkah-fqaaa-aaaaa-aaaaq-cai is shadowed by DNS alias a.b.c:r7inp-6aaaa-aaaaa-aaabq-cai".to_string(),
+                "DNS suffix localhost is defined more than once".to_string(),
+            ]
+        );
+    }
+
     fn parse_dns_aliases(aliases: Vec<&str>) -> anyhow::Result<DnsCanisterConfig> {
         let aliases: Vec<String> = aliases.iter().map(|&s| String::from(s)).collect();
         DnsCanisterConfig::new(&aliases, &[])
@@ -4,6 +4,9 @@ use anyhow::anyhow;
 
 const DNS_ALIAS_FORMAT_HELP: &str = "Format is dns.alias:principal-id";
 
+const DNS_SUFFIX_FORMAT_HELP: &str =
+    "Format is a domain name, whose labels may be * to match any single label";
+
 #[derive(Clone, Debug)]
 enum PrincipalDeterminationStrategy {
     // A domain name which matches the suffix is an alias for this specific Principal.
@@ -40,18 +43,43 @@ impl DnsCanisterRule {
 
     /// Create a rule which for domain names that match the specified suffix,
     /// if the preceding subdomain parses as a principal, return that principal.
-    pub fn new_suffix(suffix: &str) -> DnsCanisterRule {
+    /// A label of the suffix may be `*`, matching any single label.
+    pub fn new_suffix(suffix: &str) -> anyhow::Result<DnsCanisterRule> {
         let dns_suffix: Vec<String> = split_hostname_lowercase(suffix);
-        DnsCanisterRule {
+        if let Some(label) = dns_suffix
+            .iter()
+            .find(|label| label.as_str() != "*" && !is_valid_label(label))
+        {
+            return Err(anyhow!(
+                r#"Invalid label "{}" in DNS suffix "{}".  {}"#,
+                label,
+                suffix,
+                DNS_SUFFIX_FORMAT_HELP
+            ));
+        }
+        Ok(DnsCanisterRule {
             domain_name: suffix.to_string(),
             dns_suffix,
             strategy: PrincipalDeterminationStrategy::PrecedingDomainName,
+        })
+    }
+
+    /// The domain name this rule matches, as configured.
+    pub fn domain_name(&self) -> &str {
+        &self.domain_name
+    }
+
+    /// The principal of this rule if it is an alias, or [None] if it is a suffix.
+    pub fn alias_principal(&self) -> Option<Principal> {
+        match &self.strategy {
+            PrincipalDeterminationStrategy::Alias(principal) => Some(*principal),
+            PrincipalDeterminationStrategy::PrecedingDomainName => None,
         }
     }
 
     /// Return the associated principal if this rule applies to the domain name.
     pub fn lookup(&self, split_hostname_lowercase: &[String]) -> Option<Principal> {
-        if split_hostname_lowercase.ends_with(&self.dns_suffix) {
+        if self.matches(split_hostname_lowercase) {
             match &self.strategy {
                 PrincipalDeterminationStrategy::Alias(principal) => Some(*principal),
                 PrincipalDeterminationStrategy::PrecedingDomainName => {
@@ -68,6 +96,29 @@ impl DnsCanisterRule {
             None
         }
     }
+
+    /// Whether the domain name ends with the suffix of this rule. Wildcard labels only
+    /// match in suffixes, aliases being matched literally.
+    fn matches(&self, split_hostname_lowercase: &[String]) -> bool {
+        let is_suffix = matches!(
+            self.strategy,
+            PrincipalDeterminationStrategy::PrecedingDomainName
+        );
+        split_hostname_lowercase.len() >= self.dns_suffix.len()
+            && split_hostname_lowercase[split_hostname_lowercase.len() - self.dns_suffix.len()..]
+                .iter()
+                .zip(&self.dns_suffix)
+                .all(|(label, pattern)| label == pattern || (is_suffix && pattern == "*"))
+    }
+}
+
+/// Whether a label of a domain name is made of 1 to 63 letters, digits and hyphens, and
+/// neither starts nor ends with a hyphen.
+fn is_valid_label(label: &str) -> bool {
+    (1..=63).contains(&label.len())
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !label.starts_with('-')
+        && !label.ends_with('-')
 }
 
 fn split_hostname_lowercase(hostname: &str) -> Vec<String> {
@@ -137,6 +188,29 @@ mod tests {
         )
     }
 
+    #[test]
+    fn parse_error_invalid_suffix() {
+        for (suffix, label) in &[
+            ("", ""),
+            ("example..com", ""),
+            ("example.com.", ""),
+            ("-example.com", "-example"),
+            ("exa mple.com", "exa mple"),
+            ("example.c*m", "c*m"),
+        ] {
+            let e = DnsCanisterRule::new_suffix(suffix).expect_err("expected an invalid suffix");
+            assert_eq!(
+                e.to_string(),
+                format!(
+                    r#"Invalid label "{}" in DNS suffix "{}".  Format is a domain name, whose labels may be * to match any single label"#,
+                    label, suffix
+                )
+            );
+        }
+        assert!(DnsCanisterRule::new_suffix("app.Example-1.com").is_ok());
+        assert!(DnsCanisterRule::new_suffix("*.example.com").is_ok());
+    }
+
     fn parse_dns_alias(alias: &str) -> anyhow::Result<DnsCanisterRule> {
         DnsCanisterRule::new_alias(alias)
     }
@@ -1,2 +1,9 @@
+pub mod canonical_domain;
+pub mod certification_policy;
+pub mod cors;
 pub mod dns_canister_config;
 mod dns_canister_rule;
+pub mod outbound_proxy;
+pub mod response_headers;
+pub mod stream_rate_limit;
+pub mod trusted_proxy;
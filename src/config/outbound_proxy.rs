@@ -0,0 +1,184 @@
+use anyhow::{anyhow, Context};
+use std::env;
+use url::Url;
+
+/// The environment variables holding the outbound proxy, in order of precedence.
+const PROXY_ENV_VARS: &[&str] = &["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"];
+
+/// The environment variables holding the hosts not to proxy, in order of precedence.
+const NO_PROXY_ENV_VARS: &[&str] = &["NO_PROXY", "no_proxy"];
+
+/// Configuration of the proxy outbound connections to replicas are made through.
+#[derive(Clone, Debug)]
+pub struct OutboundProxyConfig {
+    proxy: Option<Url>,
+    no_proxy: Vec<String>,
+}
+
+impl OutboundProxyConfig {
+    /// Create an OutboundProxyConfig instance from command-line configuration.
+    /// proxy: the URL of an http, https or socks5 proxy. If absent, the HTTPS_PROXY or
+    /// ALL_PROXY environment variables are used.
+    /// no_proxy: 0 or more hosts or domains to connect to directly. If empty, the
+    /// comma-separated list in the NO_PROXY environment variable is used.
+    pub fn new(proxy: Option<&str>, no_proxy: &[String]) -> anyhow::Result<OutboundProxyConfig> {
+        let proxy = proxy.map(String::from).or_else(|| env_var(PROXY_ENV_VARS));
+        let no_proxy = if no_proxy.is_empty() {
+            env_var(NO_PROXY_ENV_VARS)
+                .map(|no_proxy| no_proxy.split(',').map(String::from).collect())
+                .unwrap_or_default()
+        } else {
+            no_proxy.to_vec()
+        };
+        Self::from_values(proxy.as_deref(), &no_proxy)
+    }
+
+    fn from_values(proxy: Option<&str>, no_proxy: &[String]) -> anyhow::Result<Self> {
+        let proxy = proxy
+            .map(|proxy| {
+                let url = Url::parse(proxy)
+                    .with_context(|| format!(r#"Invalid outbound proxy "{}""#, proxy))?;
+                match url.scheme() {
+                    "http" | "https" | "socks5" | "socks5h" => Ok(url),
+                    scheme => Err(anyhow!(
+                        r#"Unsupported outbound proxy scheme "{}". Expected one of http, https, socks5 or socks5h"#,
+                        scheme
+                    )),
+                }
+            })
+            .transpose()?;
+        let no_proxy = no_proxy
+            .iter()
+            .map(|host| {
+                normalize_host(host.trim().trim_start_matches("*.").trim_start_matches('.'))
+            })
+            .filter(|host| !host.is_empty())
+            .collect();
+        Ok(OutboundProxyConfig { proxy, no_proxy })
+    }
+
+    /// The configured outbound proxy, if any.
+    pub fn proxy(&self) -> Option<&Url> {
+        self.proxy.as_ref()
+    }
+
+    /// Return the proxy to connect to `url` through, or [None] to connect directly.
+    pub fn proxy_for(&self, url: &Url) -> Option<Url> {
+        let proxy = self.proxy.as_ref()?;
+        match url.host_str() {
+            Some(host) if self.is_excluded(host) => None,
+            _ => Some(proxy.clone()),
+        }
+    }
+
+    /// Whether connections to `host` bypass the proxy. An entry matches the host itself
+    /// and its subdomains, and "*" matches every host.
+    fn is_excluded(&self, host: &str) -> bool {
+        let host = normalize_host(host);
+        self.no_proxy.iter().any(|entry| {
+            entry == "*"
+                || host == *entry
+                || (host.ends_with(entry.as_str())
+                    && host[..host.len() - entry.len()].ends_with('.'))
+        })
+    }
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase()
+}
+
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::outbound_proxy::OutboundProxyConfig;
+    use url::Url;
+
+    fn proxy_for(config: &OutboundProxyConfig, url: &str) -> Option<String> {
+        config
+            .proxy_for(&Url::parse(url).unwrap())
+            .map(|proxy| proxy.to_string())
+    }
+
+    #[test]
+    fn no_proxy_connects_directly() {
+        let config = OutboundProxyConfig::from_values(None, &[]).unwrap();
+
+        assert_eq!(proxy_for(&config, "https://ic0.app/"), None);
+    }
+
+    #[test]
+    fn proxies_every_host() {
+        let config =
+            OutboundProxyConfig::from_values(Some("socks5://127.0.0.1:1080"), &[]).unwrap();
+
+        assert_eq!(
+            proxy_for(&config, "https://ic0.app/"),
+            Some("socks5://127.0.0.1:1080".to_string())
+        );
+        assert_eq!(
+            proxy_for(&config, "http://localhost:8000/"),
+            Some("socks5://127.0.0.1:1080".to_string())
+        );
+    }
+
+    #[test]
+    fn excluded_hosts_connect_directly() {
+        let config = OutboundProxyConfig::from_values(
+            Some("http://proxy.corp:3128"),
+            &[
+                "localhost".to_string(),
+                ".internal.corp".to_string(),
+                "::1".to_string(),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(proxy_for(&config, "http://localhost:8000/"), None);
+        assert_eq!(proxy_for(&config, "http://LOCALHOST:8000/"), None);
+        assert_eq!(proxy_for(&config, "https://replica.internal.corp/"), None);
+        assert_eq!(proxy_for(&config, "https://internal.corp/"), None);
+        assert_eq!(proxy_for(&config, "http://[::1]:8000/"), None);
+        assert_eq!(
+            proxy_for(&config, "https://notinternal.corp/"),
+            Some("http://proxy.corp:3128/".to_string())
+        );
+        assert_eq!(
+            proxy_for(&config, "https://ic0.app/"),
+            Some("http://proxy.corp:3128/".to_string())
+        );
+    }
+
+    #[test]
+    fn wildcard_excludes_every_host() {
+        let config =
+            OutboundProxyConfig::from_values(Some("http://proxy.corp:3128"), &["*".to_string()])
+                .unwrap();
+
+        assert_eq!(proxy_for(&config, "https://ic0.app/"), None);
+    }
+
+    #[test]
+    fn parse_error_unsupported_scheme() {
+        let e = OutboundProxyConfig::from_values(Some("ftp://proxy.corp"), &[])
+            .expect_err("expected failure due to unsupported scheme");
+        assert_eq!(
+            e.to_string(),
+            r#"Unsupported outbound proxy scheme "ftp". Expected one of http, https, socks5 or socks5h"#
+        );
+    }
+
+    #[test]
+    fn parse_error_invalid_url() {
+        OutboundProxyConfig::from_values(Some("not a url"), &[])
+            .expect_err("expected failure due to invalid url");
+    }
+}
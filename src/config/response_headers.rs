@@ -0,0 +1,272 @@
+use crate::config::dns_canister_config::DnsCanisterConfig;
+use anyhow::{anyhow, Context};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+
+const RESPONSE_HEADER_FORMAT_HELP: &str =
+    "Format is Name: value, or !Name: value to replace the header of the canister";
+
+const DOMAIN_RESPONSE_HEADER_FORMAT_HELP: &str =
+    "Format is dns.alias=Name: value, or dns.alias=!Name: value to replace the header of the canister";
+
+/// A header added to the responses of canisters.
+#[derive(Clone, Debug)]
+struct AddedHeader {
+    name: HeaderName,
+    value: HeaderValue,
+
+    /// Whether the header replaces the one of the canister, rather than being added only if
+    /// the canister did not set it.
+    replace: bool,
+}
+
+/// Configuration of the headers added to the responses of canisters, such as security
+/// headers their owners may not set.
+#[derive(Clone, Debug, Default)]
+pub struct ResponseHeaderConfig {
+    headers: Vec<AddedHeader>,
+
+    /// The headers of the responses served on a DNS alias, with the lower case labels of the
+    /// alias.
+    domain_headers: Vec<(Vec<String>, AddedHeader)>,
+}
+
+impl ResponseHeaderConfig {
+    /// Create a ResponseHeaderConfig instance from command-line configuration.
+    /// headers: 0 or more entries of the form Name: value, or !Name: value to replace the
+    /// header of the canister
+    /// domain_headers: 0 or more entries of the form dns.alias=Name: value, the alias being
+    /// one of `dns_canister_config`
+    pub fn new(
+        headers: &[String],
+        domain_headers: &[String],
+        dns_canister_config: &DnsCanisterConfig,
+    ) -> anyhow::Result<ResponseHeaderConfig> {
+        let headers = headers
+            .iter()
+            .map(|header| {
+                parse_header(header).with_context(|| {
+                    format!(
+                        r#"Invalid response header "{}".  {}"#,
+                        header, RESPONSE_HEADER_FORMAT_HELP
+                    )
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let domain_headers = domain_headers
+            .iter()
+            .map(|domain_header| {
+                parse_domain_header(domain_header, dns_canister_config).with_context(|| {
+                    format!(
+                        r#"Invalid DNS alias response header "{}".  {}"#,
+                        domain_header, DOMAIN_RESPONSE_HEADER_FORMAT_HELP
+                    )
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(ResponseHeaderConfig {
+            headers,
+            domain_headers,
+        })
+    }
+
+    /// Whether no header is added to responses.
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty() && self.domain_headers.is_empty()
+    }
+
+    /// Add the configured headers to the headers of a response of a canister, served on
+    /// `hostname` if known. The headers of a DNS alias replace those of all responses with
+    /// the same name.
+    pub fn apply(&self, headers: &mut HeaderMap, hostname: Option<&str>) {
+        let split_hostname_lowercase = hostname
+            .map(|hostname| {
+                hostname
+                    .split('.')
+                    .map(|label| label.to_ascii_lowercase())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let domain_headers = self
+            .domain_headers
+            .iter()
+            .filter(|(domain, _)| split_hostname_lowercase.ends_with(domain))
+            .map(|(_, header)| header)
+            .collect::<Vec<_>>();
+        let added_headers = domain_headers.iter().copied().chain(
+            self.headers
+                .iter()
+                .filter(|header| !domain_headers.iter().any(|added| added.name == header.name)),
+        );
+
+        // Several headers of the same name are all added.
+        let mut added = Vec::new();
+        for header in added_headers {
+            if !added.contains(&&header.name) {
+                if header.replace {
+                    headers.remove(&header.name);
+                } else if headers.contains_key(&header.name) {
+                    continue;
+                }
+                added.push(&header.name);
+            }
+            headers.append(header.name.clone(), header.value.clone());
+        }
+    }
+}
+
+fn parse_header(header: &str) -> anyhow::Result<AddedHeader> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Missing ':' after the header name"))?;
+    let (name, replace) = match name.trim().strip_prefix('!') {
+        Some(name) => (name, true),
+        None => (name.trim(), false),
+    };
+    Ok(AddedHeader {
+        name: HeaderName::from_bytes(name.as_bytes())?,
+        value: HeaderValue::from_str(value.trim())?,
+        replace,
+    })
+}
+
+fn parse_domain_header(
+    domain_header: &str,
+    dns_canister_config: &DnsCanisterConfig,
+) -> anyhow::Result<(Vec<String>, AddedHeader)> {
+    let (domain, header) = domain_header
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Missing '=' after the DNS alias"))?;
+    let domain = domain.trim();
+    if !dns_canister_config
+        .aliases()
+        .any(|(alias, _)| alias.eq_ignore_ascii_case(domain))
+    {
+        return Err(anyhow!("{} is not a DNS alias", domain));
+    }
+    let domain = domain
+        .split('.')
+        .map(|label| label.to_ascii_lowercase())
+        .collect();
+    Ok((domain, parse_header(header)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{
+        dns_canister_config::DnsCanisterConfig, response_headers::ResponseHeaderConfig,
+    };
+    use hyper::header::HeaderMap;
+
+    fn parse_response_headers(
+        headers: &[&str],
+        domain_headers: &[&str],
+    ) -> anyhow::Result<ResponseHeaderConfig> {
+        let dns_canister_config = DnsCanisterConfig::new(
+            &["www.example.com:r7inp-6aaaa-aaaaa-aaabq-cai".to_string()],
+            &[],
+        )
+        .unwrap();
+        ResponseHeaderConfig::new(
+            &headers.iter().map(|s| s.to_string()).collect::<Vec<_>>(),
+            &domain_headers
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>(),
+            &dns_canister_config,
+        )
+    }
+
+    fn canister_headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn canister_headers_win_unless_replaced() {
+        let config = parse_response_headers(
+            &[
+                "X-Content-Type-Options: nosniff",
+                "Strict-Transport-Security: max-age=31536000",
+                "!Content-Security-Policy: default-src 'self'",
+            ],
+            &[],
+        )
+        .unwrap();
+        let mut headers = canister_headers(&[
+            ("strict-transport-security", "max-age=60"),
+            ("content-security-policy", "default-src *"),
+        ]);
+
+        config.apply(&mut headers, Some("www.example.com"));
+
+        assert_eq!(headers["x-content-type-options"], "nosniff");
+        assert_eq!(headers["strict-transport-security"], "max-age=60");
+        assert_eq!(
+            headers
+                .get_all("content-security-policy")
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["default-src 'self'"]
+        );
+    }
+
+    #[test]
+    fn alias_headers_replace_headers_of_all_responses() {
+        let config = parse_response_headers(
+            &[
+                "Content-Security-Policy: default-src 'self'",
+                "X-Frame-Options: DENY",
+            ],
+            &["WWW.example.com=Content-Security-Policy: default-src 'self' cdn.example.com"],
+        )
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        config.apply(&mut headers, Some("www.Example.com"));
+        assert_eq!(
+            headers["content-security-policy"],
+            "default-src 'self' cdn.example.com"
+        );
+        assert_eq!(headers["x-frame-options"], "DENY");
+
+        let mut headers = HeaderMap::new();
+        config.apply(&mut headers, Some("other.example.com"));
+        assert_eq!(headers["content-security-policy"], "default-src 'self'");
+
+        let mut headers = HeaderMap::new();
+        config.apply(&mut headers, None);
+        assert_eq!(headers["content-security-policy"], "default-src 'self'");
+    }
+
+    #[test]
+    fn headers_of_the_same_name_are_all_added() {
+        let config = parse_response_headers(&["Link: </a.css>", "Link: </b.css>"], &[]).unwrap();
+
+        let mut headers = HeaderMap::new();
+        config.apply(&mut headers, None);
+        assert_eq!(
+            headers.get_all("link").iter().collect::<Vec<_>>(),
+            vec!["</a.css>", "</b.css>"]
+        );
+
+        let mut headers = canister_headers(&[("link", "</c.css>")]);
+        config.apply(&mut headers, None);
+        assert_eq!(
+            headers.get_all("link").iter().collect::<Vec<_>>(),
+            vec!["</c.css>"]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse_response_headers(&["X-Frame-Options DENY"], &[]).is_err());
+        assert!(parse_response_headers(&["X Frame Options: DENY"], &[]).is_err());
+        assert!(parse_response_headers(&["X-Frame-Options: DE\nNY"], &[]).is_err());
+        assert!(parse_response_headers(&[], &["www.example.com X-Frame-Options: DENY"]).is_err());
+        assert!(parse_response_headers(&[], &["other.example.com=X-Frame-Options: DENY"]).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Context};
+use ic_agent::ic_types::Principal;
+use std::collections::HashMap;
+
+const STREAM_RATE_LIMIT_FORMAT_HELP: &str =
+    "Format is principal-id=limit, where limit is a number of bytes per second, 0 for unlimited";
+
+/// Configuration of the rate, in bytes per second, responses of each canister are streamed
+/// at.
+#[derive(Clone, Debug)]
+pub struct StreamRateLimitConfig {
+    limits: HashMap<Principal, u64>,
+    default_limit: u64,
+}
+
+impl StreamRateLimitConfig {
+    /// Create a StreamRateLimitConfig instance from command-line configuration.
+    /// limits: 0 or more entries of the form of canister-id=limit
+    /// default_limit: the limit of canisters without an entry
+    /// A limit of 0 means unlimited.
+    pub fn new(limits: &[String], default_limit: u64) -> anyhow::Result<StreamRateLimitConfig> {
+        let mut config = StreamRateLimitConfig {
+            limits: HashMap::new(),
+            default_limit,
+        };
+        for limit in limits {
+            config.insert(limit)?;
+        }
+        Ok(config)
+    }
+
+    fn insert(&mut self, entry: &str) -> anyhow::Result<()> {
+        let (principal, limit) = entry.split_once('=').ok_or_else(|| {
+            anyhow!(
+                r#"Unrecognized stream rate limit "{}".  {}"#,
+                entry,
+                STREAM_RATE_LIMIT_FORMAT_HELP
+            )
+        })?;
+        let principal = Principal::from_text(principal.trim())?;
+        let limit = limit
+            .trim()
+            .parse()
+            .with_context(|| format!(r#"Invalid stream rate limit "{}""#, limit.trim()))?;
+        self.limits.insert(principal, limit);
+        Ok(())
+    }
+
+    /// Return the rate limit of the streamed responses of a canister, or [None] if they
+    /// are not limited.
+    pub fn limit(&self, canister_id: &Principal) -> Option<u64> {
+        Some(
+            self.limits
+                .get(canister_id)
+                .copied()
+                .unwrap_or(self.default_limit),
+        )
+        .filter(|limit| *limit > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::stream_rate_limit::StreamRateLimitConfig;
+    use ic_agent::ic_types::Principal;
+
+    #[test]
+    fn canisters_use_their_limit_or_the_default() {
+        let config = parse_limits(
+            vec![
+                "r7inp-6aaaa-aaaaa-aaabq-cai=0",
+                "rrkah-fqaaa-aaaaa-aaaaq-cai=1000",
+            ],
+            500,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.limit(&Principal::from_text("r7inp-6aaaa-aaaaa-aaabq-cai").unwrap()),
+            None
+        );
+        assert_eq!(
+            config.limit(&Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()),
+            Some(1000)
+        );
+        assert_eq!(
+            config.limit(&Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()),
+            Some(500)
+        );
+    }
+
+    #[test]
+    fn unlimited_by_default() {
+        let config = parse_limits(vec![], 0).unwrap();
+
+        assert_eq!(
+            config.limit(&Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_error_no_equals() {
+        let e = parse_limits(vec!["r7inp-6aaaa-aaaaa-aaabq-cai:1000"], 0)
+            .expect_err("expected failure due to missing equals sign");
+        assert_eq!(
+            e.to_string(),
+            r#"Unrecognized stream rate limit "r7inp-6aaaa-aaaaa-aaabq-cai:1000".  Format is principal-id=limit, where limit is a number of bytes per second, 0 for unlimited"#
+        )
+    }
+
+    #[test]
+    fn parse_error_invalid_limit() {
+        let e = parse_limits(vec!["r7inp-6aaaa-aaaaa-aaabq-cai=fast"], 0)
+            .expect_err("expected failure due to an invalid limit");
+        assert_eq!(e.to_string(), r#"Invalid stream rate limit "fast""#)
+    }
+
+    fn parse_limits(
+        limits: Vec<&str>,
+        default_limit: u64,
+    ) -> anyhow::Result<StreamRateLimitConfig> {
+        let limits: Vec<String> = limits.iter().map(|&s| String::from(s)).collect();
+        StreamRateLimitConfig::new(&limits, default_limit)
+    }
+}
@@ -0,0 +1,130 @@
+use anyhow::{anyhow, Context};
+use std::net::IpAddr;
+
+const TRUSTED_PROXY_FORMAT_HELP: &str =
+    "Format is an IP address, or a network of the form address/prefix-length";
+
+/// Configuration of the peers trusted to tell the address of the client they forward
+/// requests for.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxyConfig {
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxyConfig {
+    /// Create a TrustedProxyConfig instance from command-line configuration.
+    /// proxies: 0 or more IP addresses, or networks of the form address/prefix-length
+    pub fn new(proxies: &[String]) -> anyhow::Result<TrustedProxyConfig> {
+        let networks = proxies
+            .iter()
+            .map(|proxy| parse_network(proxy.trim()))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(TrustedProxyConfig { networks })
+    }
+
+    /// Whether `peer` is a trusted proxy. IPv4-mapped IPv6 addresses are expected to be
+    /// converted back to IPv4.
+    pub fn is_trusted(&self, peer: &IpAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|(network, prefix_length)| match (network, peer) {
+                (IpAddr::V4(network), IpAddr::V4(peer)) => {
+                    let mask = u32::MAX
+                        .checked_shl(32 - *prefix_length as u32)
+                        .unwrap_or(0);
+                    u32::from(*network) & mask == u32::from(*peer) & mask
+                }
+                (IpAddr::V6(network), IpAddr::V6(peer)) => {
+                    let mask = u128::MAX
+                        .checked_shl(128 - *prefix_length as u32)
+                        .unwrap_or(0);
+                    u128::from(*network) & mask == u128::from(*peer) & mask
+                }
+                _ => false,
+            })
+    }
+}
+
+fn parse_network(network: &str) -> anyhow::Result<(IpAddr, u8)> {
+    let (address, prefix_length) = match network.split_once('/') {
+        Some((address, prefix_length)) => (address, Some(prefix_length)),
+        None => (network, None),
+    };
+    let address = address.parse::<IpAddr>().with_context(|| {
+        format!(
+            r#"Invalid trusted proxy "{}".  {}"#,
+            network, TRUSTED_PROXY_FORMAT_HELP
+        )
+    })?;
+    let max_length = if address.is_ipv4() { 32 } else { 128 };
+    let prefix_length = match prefix_length {
+        Some(prefix_length) => prefix_length
+            .parse::<u8>()
+            .ok()
+            .filter(|prefix_length| *prefix_length <= max_length)
+            .ok_or_else(|| {
+                anyhow!(
+                    r#"Invalid prefix length "{}" of trusted proxy "{}""#,
+                    prefix_length,
+                    network
+                )
+            })?,
+        None => max_length,
+    };
+    Ok((address, prefix_length))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::trusted_proxy::TrustedProxyConfig;
+    use std::net::IpAddr;
+
+    fn is_trusted(config: &TrustedProxyConfig, peer: &str) -> bool {
+        config.is_trusted(&peer.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn addresses_and_networks_are_trusted() {
+        let config =
+            parse_proxies(vec!["192.0.2.1", "10.0.0.0/8", "2001:db8::/32", "::1"]).unwrap();
+
+        assert!(is_trusted(&config, "192.0.2.1"));
+        assert!(!is_trusted(&config, "192.0.2.2"));
+        assert!(is_trusted(&config, "10.1.2.3"));
+        assert!(!is_trusted(&config, "11.0.0.1"));
+        assert!(is_trusted(&config, "2001:db8:1::1"));
+        assert!(!is_trusted(&config, "2001:db9::1"));
+        assert!(is_trusted(&config, "::1"));
+        assert!(!is_trusted(&config, "127.0.0.1"));
+    }
+
+    #[test]
+    fn nothing_is_trusted_by_default() {
+        let config = parse_proxies(vec![]).unwrap();
+        assert!(!is_trusted(&config, "127.0.0.1"));
+
+        let config = parse_proxies(vec!["0.0.0.0/0"]).unwrap();
+        assert!(is_trusted(&config, "198.51.100.7"));
+        assert!(!is_trusted(&config, "2001:db8::1"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let e = parse_proxies(vec!["localhost"]).expect_err("expected an invalid address");
+        assert_eq!(
+            e.to_string(),
+            r#"Invalid trusted proxy "localhost".  Format is an IP address, or a network of the form address/prefix-length"#
+        );
+
+        let e = parse_proxies(vec!["10.0.0.0/33"]).expect_err("expected an invalid prefix");
+        assert_eq!(
+            e.to_string(),
+            r#"Invalid prefix length "33" of trusted proxy "10.0.0.0/33""#
+        );
+    }
+
+    fn parse_proxies(proxies: Vec<&str>) -> anyhow::Result<TrustedProxyConfig> {
+        let proxies: Vec<String> = proxies.iter().map(|&s| String::from(s)).collect();
+        TrustedProxyConfig::new(&proxies)
+    }
+}
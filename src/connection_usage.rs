@@ -0,0 +1,258 @@
+use std::{
+    io::{self, IoSlice},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A connection counting the bytes read from and written to it, which fails to read once
+/// `max_read` bytes were read, so that a client cannot send an endless request. It also
+/// fails to read once dropped, as soon as the client sends anything more.
+pub(crate) struct Metered<T> {
+    inner: T,
+    bytes: Arc<AtomicU64>,
+    read: u64,
+    max_read: Option<u64>,
+    dropped: Arc<AtomicBool>,
+}
+
+impl<T> Metered<T> {
+    pub fn new(inner: T, max_read: Option<u64>) -> Metered<T> {
+        Metered {
+            inner,
+            bytes: Arc::default(),
+            read: 0,
+            max_read,
+            dropped: Arc::default(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// The bytes read and written so far, kept up to date as the connection is used.
+    pub fn bytes(&self) -> Arc<AtomicU64> {
+        self.bytes.clone()
+    }
+
+    /// Whether the connection is dropped, which anyone holding it may set.
+    pub fn dropped(&self) -> Arc<AtomicBool> {
+        self.dropped.clone()
+    }
+
+    fn count<R>(
+        &self,
+        poll: Poll<io::Result<R>>,
+        bytes: impl FnOnce(&R) -> usize,
+    ) -> Poll<io::Result<R>> {
+        if let Poll::Ready(Ok(result)) = &poll {
+            self.bytes
+                .fetch_add(bytes(result) as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Metered<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.max_read.map_or(false, |max| self.read >= max) {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the connection reached its byte limit",
+            )));
+        }
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - filled;
+        // Reads are only refused once there is something to read, so that what was sent to
+        // the client before the connection was dropped is still written.
+        if read > 0 && self.dropped.load(Ordering::Relaxed) {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the connection was dropped",
+            )));
+        }
+        self.read += read as u64;
+        self.count(poll, |_| read)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Metered<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.count(poll, |written| *written)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.count(poll, |written| *written)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// How much a single client connection may be used before it is closed.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ConnectionLimits {
+    pub max_requests: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+/// What to do with a request of a client connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Admission {
+    /// Answer it, and keep the connection open.
+    Keep,
+    /// Answer it, and close the connection: the connection reached one of its limits.
+    Close,
+    /// Refuse it: the connection is past its limit of requests, which only HTTP/2
+    /// connections get to as they cannot be told to close. The connection is dropped.
+    Refuse,
+}
+
+/// What a client connection was used for so far.
+pub(crate) struct ConnectionUsage {
+    limits: ConnectionLimits,
+    requests: AtomicU64,
+    bytes: Arc<AtomicU64>,
+    dropped: Arc<AtomicBool>,
+}
+
+impl ConnectionUsage {
+    /// Account for a connection whose bytes are counted in `bytes`, and which is dropped by
+    /// setting `dropped`.
+    pub fn new(
+        limits: ConnectionLimits,
+        bytes: Arc<AtomicU64>,
+        dropped: Arc<AtomicBool>,
+    ) -> ConnectionUsage {
+        ConnectionUsage {
+            limits,
+            requests: AtomicU64::new(0),
+            bytes,
+            dropped,
+        }
+    }
+
+    /// Count a request about to be answered, and tell what to do with it.
+    pub fn answer(&self) -> Admission {
+        let requests = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        if self.limits.max_requests.map_or(false, |max| requests > max) {
+            self.dropped.store(true, Ordering::Relaxed);
+            Admission::Refuse
+        } else if self
+            .limits
+            .max_requests
+            .map_or(false, |max| requests >= max)
+            || self.limits.max_bytes.map_or(false, |max| bytes >= max)
+        {
+            Admission::Close
+        } else {
+            Admission::Keep
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection_usage::{Admission, ConnectionLimits, ConnectionUsage, Metered};
+    use std::sync::{atomic::Ordering, Arc};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn bytes_read_and_written_are_counted() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = Metered::new(server, None);
+        let bytes = server.bytes();
+
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut buf = [0u8; 16];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"HTTP/1.1 200 OK\r\n").await.unwrap();
+
+        assert_eq!(bytes.load(Ordering::Relaxed), 16 + 17);
+    }
+
+    #[tokio::test]
+    async fn reads_fail_past_the_byte_limit() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = Metered::new(server, Some(16));
+
+        client.write_all(b"GET / HTTP/1.1\r\nHost: ").await.unwrap();
+        let mut buf = [0u8; 16];
+        server.read_exact(&mut buf).await.unwrap();
+        assert!(server.read(&mut buf).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_fail_once_dropped() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = Metered::new(server, None);
+
+        server.dropped().store(true, Ordering::Relaxed);
+        // What is left to send is still written.
+        server.write_all(b"HTTP/1.1 503 ").await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut buf = [0u8; 16];
+        assert!(server.read(&mut buf).await.is_err());
+    }
+
+    #[test]
+    fn connections_are_closed_once_a_limit_is_reached() {
+        let limits = ConnectionLimits {
+            max_requests: Some(3),
+            max_bytes: None,
+        };
+        let dropped = Arc::default();
+        let usage = ConnectionUsage::new(limits, Arc::default(), Arc::clone(&dropped));
+        assert_eq!(usage.answer(), Admission::Keep);
+        assert_eq!(usage.answer(), Admission::Keep);
+        assert_eq!(usage.answer(), Admission::Close);
+        assert!(!dropped.load(Ordering::Relaxed));
+        assert_eq!(usage.answer(), Admission::Refuse);
+        assert!(dropped.load(Ordering::Relaxed));
+
+        let limits = ConnectionLimits {
+            max_requests: None,
+            max_bytes: Some(1000),
+        };
+        let bytes = Arc::default();
+        let usage = ConnectionUsage::new(limits, Arc::clone(&bytes), Arc::default());
+        bytes.store(999, Ordering::Relaxed);
+        assert_eq!(usage.answer(), Admission::Keep);
+        bytes.store(1000, Ordering::Relaxed);
+        assert_eq!(usage.answer(), Admission::Close);
+
+        let usage =
+            ConnectionUsage::new(ConnectionLimits::default(), Arc::default(), Arc::default());
+        assert!((0..100).all(|_| usage.answer() == Admission::Keep));
+    }
+}
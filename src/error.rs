@@ -0,0 +1,257 @@
+use hyper::{
+    header::{InvalidHeaderValue, ToStrError, RETRY_AFTER},
+    http::{
+        self,
+        uri::{InvalidUri, InvalidUriParts},
+    },
+    Body, Response, StatusCode,
+};
+use ic_agent::AgentError;
+use std::fmt;
+
+// The header carrying the reject code of a rejected canister call.
+static X_IC_REJECT_CODE_HEADER: &str = "x-ic-reject-code";
+
+/// Why a request could not be answered, which tells the status code to answer it with.
+#[derive(Debug)]
+pub(crate) enum ProxyError {
+    /// No canister to forward the request to could be found.
+    ResolveCanister,
+    /// The request is malformed, or could not be read.
+    BadRequest(String),
+    /// The replica, or the proxy, could not be reached.
+    ReplicaUnreachable(String),
+    /// The replica did not answer in time.
+    Timeout,
+    /// The proxy cannot take the request at the moment, and the client should retry later.
+    Overloaded(String),
+    /// The canister rejected the call.
+    Rejected {
+        reject_code: u64,
+        reject_message: String,
+    },
+    /// The response of the canister did not pass verification.
+    Verification(String),
+    /// The replica, or the proxy, answered with something which cannot be served.
+    Upstream(String),
+    /// The canister answered with a streaming strategy the proxy does not know, which it
+    /// cannot serve the whole body of.
+    UnsupportedStreamingStrategy,
+    /// As many responses as allowed are streamed already.
+    TooManyStreams,
+    /// The response could not be built.
+    Internal(String),
+}
+
+impl ProxyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::ResolveCanister | ProxyError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ProxyError::ReplicaUnreachable(_)
+            | ProxyError::Upstream(_)
+            | ProxyError::UnsupportedStreamingStrategy => StatusCode::BAD_GATEWAY,
+            ProxyError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::Overloaded(_) | ProxyError::TooManyStreams => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            // There is no information leak in rejects because a user could use `dfx` to get
+            // the same reply.
+            ProxyError::Rejected { .. } | ProxyError::Verification(_) | ProxyError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Whether the error is a fault of the proxy or its upstreams, whose details are only
+    /// told to clients in debug mode.
+    pub fn is_internal(&self) -> bool {
+        matches!(
+            self,
+            ProxyError::ReplicaUnreachable(_) | ProxyError::Upstream(_) | ProxyError::Internal(_)
+        )
+    }
+
+    /// The response telling a client about the error, with its details if `debug`.
+    pub fn response(&self, debug: bool) -> Response<Body> {
+        let mut builder = Response::builder().status(self.status());
+        // The reject code is also returned in a header, for clients to tell rejects apart
+        // without parsing the body.
+        if let ProxyError::Rejected { reject_code, .. } = self {
+            builder = builder.header(X_IC_REJECT_CODE_HEADER, *reject_code);
+        }
+        if matches!(self, ProxyError::Overloaded(_) | ProxyError::TooManyStreams) {
+            builder = builder.header(RETRY_AFTER, "1");
+        }
+        builder
+            .body(if !self.is_internal() {
+                self.to_string().into()
+            } else if debug {
+                format!("Internal Error: {}", self).into()
+            } else {
+                "Internal Server Error".into()
+            })
+            .unwrap()
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::ResolveCanister => write!(f, "Could not find a canister id to forward to."),
+            ProxyError::BadRequest(message) => write!(f, "{}", message),
+            ProxyError::ReplicaUnreachable(message) => {
+                write!(f, "Unable to reach the replica: {}", message)
+            }
+            ProxyError::Timeout => write!(f, "Timed out waiting for the canister to answer"),
+            ProxyError::Overloaded(message) => write!(f, "{}", message),
+            ProxyError::Rejected {
+                reject_code,
+                reject_message,
+            } => write!(
+                f,
+                r#"Replica Error ({}): "{}""#,
+                reject_code, reject_message
+            ),
+            ProxyError::Verification(message) => write!(f, "{}", message),
+            ProxyError::Upstream(message) => write!(f, "Invalid upstream response: {}", message),
+            ProxyError::UnsupportedStreamingStrategy => write!(
+                f,
+                "The canister answered with a streaming strategy which is not supported"
+            ),
+            ProxyError::TooManyStreams => {
+                write!(f, "Too many responses are streamed at the moment")
+            }
+            ProxyError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl From<AgentError> for ProxyError {
+    fn from(error: AgentError) -> Self {
+        match error {
+            AgentError::TransportError(e) => ProxyError::ReplicaUnreachable(e.to_string()),
+            AgentError::TimeoutWaitingForResponse() => ProxyError::Timeout,
+            AgentError::ReplicaError {
+                reject_code,
+                reject_message,
+            } => ProxyError::Rejected {
+                reject_code,
+                reject_message,
+            },
+            e => ProxyError::Upstream(e.to_string()),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ProxyError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            ProxyError::Timeout
+        } else if error.is_connect() {
+            ProxyError::ReplicaUnreachable(error.to_string())
+        } else {
+            ProxyError::Upstream(error.to_string())
+        }
+    }
+}
+
+macro_rules! internal_errors {
+    ($($error:ty),*) => {
+        $(
+            impl From<$error> for ProxyError {
+                fn from(error: $error) -> Self {
+                    ProxyError::Internal(error.to_string())
+                }
+            }
+        )*
+    };
+}
+
+internal_errors!(
+    http::Error,
+    InvalidHeaderValue,
+    InvalidUri,
+    InvalidUriParts,
+    ToStrError
+);
+
+#[cfg(test)]
+mod tests {
+    use crate::error::ProxyError;
+    use hyper::StatusCode;
+    use ic_agent::AgentError;
+
+    #[test]
+    fn agent_errors_are_classified() {
+        let rejected = ProxyError::from(AgentError::ReplicaError {
+            reject_code: 5,
+            reject_message: "trapped".to_string(),
+        });
+        assert_eq!(rejected.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(rejected.to_string(), r#"Replica Error (5): "trapped""#);
+
+        let timeout = ProxyError::from(AgentError::TimeoutWaitingForResponse());
+        assert_eq!(timeout.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let unreachable = ProxyError::from(AgentError::TransportError("refused".into()));
+        assert_eq!(unreachable.status(), StatusCode::BAD_GATEWAY);
+
+        let upstream = ProxyError::from(AgentError::InvalidReplicaUrl("x".to_string()));
+        assert_eq!(upstream.status(), StatusCode::BAD_GATEWAY);
+
+        assert_eq!(
+            ProxyError::UnsupportedStreamingStrategy.status(),
+            StatusCode::BAD_GATEWAY
+        );
+    }
+
+    #[test]
+    fn rejects_carry_their_code() {
+        let response = ProxyError::Rejected {
+            reject_code: 4,
+            reject_message: "no such method".to_string(),
+        }
+        .response(false);
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()["x-ic-reject-code"], "4");
+    }
+
+    #[test]
+    fn overloads_are_retried_later() {
+        let response = ProxyError::Overloaded("busy".to_string()).response(false);
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "1");
+
+        let response = ProxyError::TooManyStreams.response(false);
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "1");
+    }
+
+    #[test]
+    fn internal_details_are_only_told_in_debug_mode() {
+        let error = ProxyError::ReplicaUnreachable("connection refused".to_string());
+
+        assert_eq!(error.response(false).status(), StatusCode::BAD_GATEWAY);
+        let body = |debug| {
+            let response = error.response(debug);
+            futures::executor::block_on(hyper::body::to_bytes(response.into_body())).unwrap()
+        };
+        assert_eq!(body(false), "Internal Server Error");
+        assert_eq!(
+            body(true),
+            "Internal Error: Unable to reach the replica: connection refused"
+        );
+        assert_eq!(
+            futures::executor::block_on(hyper::body::to_bytes(
+                ProxyError::ResolveCanister.response(false).into_body()
+            ))
+            .unwrap(),
+            "Could not find a canister id to forward to."
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use ic_agent::export::Principal;
+use lru::LruCache;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The share of the capacity of a [HostnameCache] given to hostnames resolving to no
+/// canister.
+const UNRESOLVED_SHARE: usize = 8;
+
+/// A bounded cache of the canisters Host headers resolve to, so that the few hostnames a
+/// proxy serves are not parsed again on every request. Hostnames resolving to no canister
+/// are remembered as well, but only for a short time, so that garbage Host headers are not
+/// resolved over and over either. They are kept apart, in a smaller cache, so that a flood
+/// of garbage Host headers does not evict the hostnames the proxy serves.
+///
+/// Resolutions only depend on the DNS configuration, so the cache is built along with it:
+/// a new configuration comes with an empty cache.
+pub(crate) struct HostnameCache {
+    resolved: Mutex<LruCache<String, Principal>>,
+    /// The hostnames resolving to no canister, with the time they were resolved at.
+    unresolved: Mutex<LruCache<String, Instant>>,
+    negative_ttl: Duration,
+}
+
+impl HostnameCache {
+    pub fn new(capacity: usize, negative_ttl: Duration) -> HostnameCache {
+        HostnameCache {
+            resolved: Mutex::new(LruCache::new(capacity)),
+            unresolved: Mutex::new(LruCache::new((capacity / UNRESOLVED_SHARE).max(1))),
+            negative_ttl,
+        }
+    }
+
+    /// The canister `hostname` resolves to, resolved with `resolve` unless it was resolved
+    /// already.
+    pub fn resolve(
+        &self,
+        hostname: &str,
+        resolve: impl FnOnce(&str) -> Option<Principal>,
+    ) -> Option<Principal> {
+        if let Some(&canister_id) = self.resolved.lock().unwrap().get(hostname) {
+            return Some(canister_id);
+        }
+        if let Some(&resolved_at) = self.unresolved.lock().unwrap().get(hostname) {
+            if resolved_at.elapsed() < self.negative_ttl {
+                return None;
+            }
+        }
+        // The locks are not held while resolving, at the cost of hostnames missing the cache
+        // at the same time being resolved more than once.
+        let canister_id = resolve(hostname);
+        match canister_id {
+            Some(canister_id) => {
+                self.resolved
+                    .lock()
+                    .unwrap()
+                    .put(hostname.to_string(), canister_id);
+            }
+            None => {
+                self.unresolved
+                    .lock()
+                    .unwrap()
+                    .put(hostname.to_string(), Instant::now());
+            }
+        }
+        canister_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hostname_cache::HostnameCache;
+    use ic_agent::export::Principal;
+    use std::{cell::Cell, time::Duration};
+
+    #[test]
+    fn resolutions_are_cached() {
+        let cache = HostnameCache::new(10, Duration::from_secs(60));
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").ok();
+        let resolutions = Cell::new(0);
+        let resolve = |hostname: &str| {
+            cache.resolve(hostname, |hostname| {
+                resolutions.set(resolutions.get() + 1);
+                canister_id.filter(|_| hostname == "app.localhost")
+            })
+        };
+
+        assert_eq!(resolve("app.localhost"), canister_id);
+        assert_eq!(resolve("app.localhost"), canister_id);
+        assert_eq!(resolutions.get(), 1);
+        assert_eq!(resolve("garbage"), None);
+        assert_eq!(resolve("garbage"), None);
+        assert_eq!(resolutions.get(), 2);
+    }
+
+    #[test]
+    fn negative_resolutions_expire() {
+        let cache = HostnameCache::new(10, Duration::from_secs(0));
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").ok();
+        let resolutions = Cell::new(0);
+        let resolve = |hostname: &str, answer| {
+            cache.resolve(hostname, |_| {
+                resolutions.set(resolutions.get() + 1);
+                answer
+            })
+        };
+
+        assert_eq!(resolve("garbage", None), None);
+        assert_eq!(resolve("garbage", None), None);
+        assert_eq!(resolutions.get(), 2);
+        assert_eq!(resolve("app.localhost", canister_id), canister_id);
+        assert_eq!(resolve("app.localhost", None), canister_id);
+        assert_eq!(resolutions.get(), 3);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = HostnameCache::new(2, Duration::from_secs(60));
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").ok();
+        let resolutions = Cell::new(0);
+        let resolve = |hostname: &str| {
+            cache.resolve(hostname, |_| {
+                resolutions.set(resolutions.get() + 1);
+                canister_id
+            })
+        };
+
+        resolve("a");
+        resolve("b");
+        resolve("a");
+        resolve("c");
+        assert_eq!(resolutions.get(), 3);
+        resolve("a");
+        assert_eq!(resolutions.get(), 3);
+        resolve("b");
+        assert_eq!(resolutions.get(), 4);
+    }
+
+    #[test]
+    fn garbage_does_not_evict_resolved_hostnames() {
+        let cache = HostnameCache::new(16, Duration::from_secs(60));
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").ok();
+        let resolutions = Cell::new(0);
+        let resolve = |hostname: &str| {
+            cache.resolve(hostname, |hostname| {
+                resolutions.set(resolutions.get() + 1);
+                canister_id.filter(|_| hostname == "app.localhost")
+            })
+        };
+
+        resolve("app.localhost");
+        for garbage in 0..100 {
+            resolve(&garbage.to_string());
+        }
+        assert_eq!(resolutions.get(), 101);
+        assert_eq!(resolve("app.localhost"), canister_id);
+        assert_eq!(resolutions.get(), 101);
+        // Only the last garbage hostnames are remembered.
+        resolve("99");
+        resolve("0");
+        assert_eq!(resolutions.get(), 102);
+    }
+}
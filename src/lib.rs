@@ -0,0 +1,7156 @@
+//! An HTTP proxy to the Internet Computer, run by the icx-proxy binary.
+
+use crate::{
+    buffer_budget::{BufferBudget, BufferReservation},
+    buffer_pool::{BufferPool, PooledBuffer},
+    config::{
+        canonical_domain::CanonicalDomainConfig,
+        certification_policy::{CertificationPolicy, CertificationPolicyConfig},
+        cors::{CorsConfig, CorsMode},
+        dns_canister_config::DnsCanisterConfig,
+        outbound_proxy::OutboundProxyConfig,
+        response_headers::ResponseHeaderConfig,
+        stream_rate_limit::StreamRateLimitConfig,
+        trusted_proxy::TrustedProxyConfig,
+    },
+    connection_usage::{Admission, ConnectionLimits, ConnectionUsage, Metered},
+    error::ProxyError,
+    hostname_cache::HostnameCache,
+    idle_timeout::IdleTimeout,
+    metrics::{handle_metrics_request, Metrics},
+    replica_transport::ClientTransport,
+    response_cache::{
+        accepted_encodings, handle_purge_request, AcceptedEncodings, CacheKey, CachedResponse,
+        ResponseCache,
+    },
+    single_flight::SingleFlight,
+    validate::{
+        certificate_time, decodable_accept_encoding, decode_body, extract_headers_data,
+        extract_uncertified_headers_data, validate, Certification, ValidationConfig,
+    },
+    verification_cache::VerificationCache,
+};
+use anyhow::{anyhow, Context};
+use candid::{CandidType, Decode, Deserialize, Encode, Reserved};
+use clap::{crate_authors, crate_version, AppSettings, Parser};
+use futures::{future::poll_fn, StreamExt};
+use hyper::{
+    body,
+    body::{Bytes, HttpBody},
+    header::{
+        HeaderName, HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, ACCESS_CONTROL_REQUEST_METHOD,
+        CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, DATE, ETAG,
+        IF_NONE_MATCH, IF_RANGE, LOCATION, ORIGIN, RANGE, RETRY_AFTER, SERVER, VARY,
+    },
+    http::uri::{Parts, PathAndQuery},
+    server::{
+        accept::{self, Accept},
+        conn::{AddrIncoming, AddrStream},
+    },
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode, Uri,
+};
+use ic_agent::{export::Principal, Agent, AgentError};
+use ic_utils::{
+    call::AsyncCall,
+    interfaces::http_request::{
+        HeaderField, HttpRequestCanister, HttpResponse, StreamingCallbackHttpResponse,
+        StreamingStrategy,
+    },
+};
+use rand::Rng;
+use slog::Drain;
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    convert::Infallible,
+    error::Error,
+    fs,
+    future::Future,
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::{watch, Notify, OwnedSemaphorePermit, Semaphore};
+
+/// What the benchmarks drive the proxy with. It is not part of the interface of the crate.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+mod buffer_budget;
+mod buffer_pool;
+mod config;
+mod connection_usage;
+mod error;
+mod hostname_cache;
+mod idle_timeout;
+mod logging;
+mod metrics;
+// Only the tests use all of the mock replica.
+#[cfg(any(test, feature = "bench"))]
+#[cfg_attr(not(test), allow(dead_code))]
+mod mock_replica;
+mod replica_transport;
+mod response_cache;
+mod single_flight;
+mod stream_spill;
+mod telemetry;
+mod tree_dump;
+mod validate;
+mod verification_cache;
+
+// The maximum length of a body we should log as tracing.
+static MAX_LOG_BODY_SIZE: usize = 100;
+
+// The number of buffers kept to decode bodies into, and the largest one kept.
+static DECODE_BUFFERS: usize = 32;
+static DECODE_BUFFER_MAX_CAPACITY: usize = 1 << 20;
+
+// The domains of the boundary nodes of the Internet Computer mainnet.
+static MAINNET_DOMAINS: &[&str] = &["ic0.app", "icp0.io", "icp-api.io"];
+
+// How often the event loop lag is probed.
+static EVENT_LOOP_LAG_PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+// The headers of requests carrying credentials, which are not forwarded to canisters
+// unless allowed explicitly: queries can be read by any replica node.
+static SENSITIVE_REQUEST_HEADERS: &[&str] = &["cookie", "authorization", "proxy-authorization"];
+
+// How long, in seconds, clients are told to wait before retrying when the maintenance page
+// is served.
+static MAINTENANCE_RETRY_AFTER: u64 = 30;
+
+// The header telling clients whether a response was certified.
+static X_IC_CERTIFIED_HEADER: &str = "x-ic-certified";
+
+// The headers telling canisters the scheme and host requested, with
+// --x-forwarded-proto-host.
+static X_FORWARDED_PROTO_HEADER: &str = "x-forwarded-proto";
+static X_FORWARDED_HOST_HEADER: &str = "x-forwarded-host";
+
+// The headers removed from verified responses, with --strip-certification-headers.
+static CERTIFICATION_HEADERS: &[&str] = &["ic-certificate", "ic-certificateexpression"];
+
+// The body of responses failing verification, without --no-fail-closed.
+static VERIFICATION_FAILED: &str = "Response verification failed";
+// The header telling clients whether a response was served from the response cache.
+static X_CACHE_HEADER: &str = "x-cache";
+
+#[derive(Parser)]
+#[clap(
+    version = crate_version!(),
+    author = crate_authors!(),
+    global_setting = AppSettings::PropagateVersion,
+)]
+pub(crate) struct Opts {
+    /// Verbose level. By default, INFO will be used. Add a single `-v` to upgrade to
+    /// DEBUG, and another `-v` to upgrade to TRACE.
+    #[clap(long, short('v'), parse(from_occurrences))]
+    verbose: u64,
+
+    /// Quiet level. The opposite of verbose. A single `-q` will drop the logging to
+    /// WARN only, then another one to ERR, and finally another one for FATAL. Another
+    /// `-q` will silence ALL logs.
+    #[clap(long, short('q'), parse(from_occurrences))]
+    quiet: u64,
+
+    /// Mode to use the logging. "stderr" will output logs in STDERR, "file" will output
+    /// logs in a file, and "tee" will do both.
+    #[clap(long("log"), default_value("stderr"), possible_values(&["stderr", "tee", "file"]))]
+    logmode: String,
+
+    /// File to output the log to, when using logmode=tee or logmode=file.
+    #[clap(long)]
+    logfile: Option<PathBuf>,
+
+    /// The number of log records queued to be written, e.g. while tracing.
+    #[clap(long, default_value = "1024")]
+    log_channel_size: usize,
+
+    /// What to do with a log record once --log-channel-size records are queued: drop it,
+    /// and report how many were dropped, or block until the queue has room.
+    #[clap(long, default_value("drop"), possible_values(&["drop", "block"]))]
+    log_overflow_strategy: String,
+
+    /// The address to bind to.
+    #[clap(long, default_value = "127.0.0.1:3000")]
+    address: SocketAddr,
+
+    /// A replica to use as backend. Locally, this should be a local instance or the
+    /// boundary node. Multiple replicas can be passed and they'll be used round-robin.
+    #[clap(long, default_value = "http://localhost:8000/")]
+    replica: Vec<String>,
+
+    /// An address to forward any requests from /_/
+    #[clap(long)]
+    proxy: Option<String>,
+
+    /// Whether or not this is run in a debug context (e.g. errors returned in responses
+    /// should show full stack and error details).
+    #[clap(long)]
+    debug: bool,
+
+    /// Whether or not to fetch the root key from the replica back end. Do not use this when
+    /// talking to the Internet Computer blockchain mainnet as it is unsecure.
+    #[clap(long)]
+    fetch_root_key: bool,
+
+    /// Allow --fetch-root-key with replicas on the domains of the mainnet boundary nodes,
+    /// which the proxy otherwise refuses to start with: the root key would then come from
+    /// the very replicas it is meant to verify.
+    #[clap(long)]
+    i_know_this_is_insecure: bool,
+
+    /// A map of domain names to canister IDs.
+    /// Format: domain.name:canister-id
+    #[clap(long)]
+    dns_alias: Vec<String>,
+
+    /// A list of domain name suffixes.  If found, the next (to the left) subdomain
+    /// is used as the Principal, if it parses as a Principal.
+    #[clap(long, default_value = "localhost")]
+    dns_suffix: Vec<String>,
+
+    /// The `Server` header added to responses without one, or "off" to add none. Defaults to
+    /// icx-proxy/<version>.
+    #[clap(long)]
+    server_header: Option<String>,
+
+    /// Do not add a `Server` header to responses, like --server-header off.
+    #[clap(long, conflicts_with = "server-header")]
+    no_server_header: bool,
+
+    /// Add an `X-Ic-Canister-Id` header with the resolved canister id to responses from
+    /// canisters. Useful to debug routing, but reveals which canister serves a domain.
+    #[clap(long)]
+    expose_canister_header: bool,
+
+    /// A header added to responses from canisters which did not set it, such as a security
+    /// header. Format: "Name: value", or "!Name: value" to replace the header of the
+    /// canister.
+    #[clap(long)]
+    add_response_header: Vec<String>,
+
+    /// A header added to responses from canisters served on a DNS alias, replacing the
+    /// --add-response-header of the same name. Format: "dns.alias=Name: value", or
+    /// "dns.alias=!Name: value" to replace the header of the canister.
+    #[clap(long)]
+    dns_alias_response_header: Vec<String>,
+
+    /// The certification policy of a canister, overriding the default policy.
+    /// Format: canister-id=policy, where policy is "required" (uncertified and streamed
+    /// responses are rejected), "optional" (responses are verified if they carry a certificate) or
+    /// "skip" (responses are never verified).
+    #[clap(long)]
+    certification_policy: Vec<String>,
+
+    /// A file of certification policies, one canister-id=policy entry per line. Entries
+    /// passed with --certification-policy take precedence.
+    #[clap(long)]
+    certification_policy_file: Option<PathBuf>,
+
+    /// An HTML page to answer requests for canisters with 503 Service Unavailable when the
+    /// replica cannot be reached, rather than a terse error. Requests to the replica API are
+    /// still answered with the error. The page is read once at startup. It is served per
+    /// replica: each client connection is forwarded to one of the --replica, and requests
+    /// are not retried with the others, so the page is served as soon as the replica of the
+    /// connection cannot be reached, even if others could be.
+    #[clap(long)]
+    maintenance_page: Option<PathBuf>,
+
+    /// The certification policy of canisters without an explicit policy.
+    #[clap(long, default_value = "optional", possible_values(&["required", "optional", "skip"]))]
+    default_certification_policy: CertificationPolicy,
+
+    /// A path prefix followed by a canister ID to route requests to, e.g. with "/canister/"
+    /// a request to /canister/<canister-id>/index.html is forwarded as /index.html to that
+    /// canister. Takes precedence over the canisterId query parameter, but not the Host or
+    /// the --canister-id-header.
+    #[clap(long)]
+    canister_path_prefix: Option<String>,
+
+    /// The path prefix of requests forwarded to the replica API, e.g. "/ic-api/" to serve
+    /// assets under /api/. It is replaced by /api/ when forwarding.
+    #[clap(long, default_value = "/api/")]
+    api_prefix: String,
+
+    /// The path prefix of requests forwarded to the --proxy. It is replaced by /_/ when
+    /// forwarding.
+    #[clap(long, default_value = "/_/")]
+    proxy_prefix: String,
+
+    /// A request header holding the canister ID to route requests to, e.g. X-Ic-Canister-Id,
+    /// for clients which can neither choose the Host nor the URL. Takes precedence over the
+    /// --canister-path-prefix, but not the Host.
+    #[clap(long)]
+    canister_id_header: Option<String>,
+
+    /// A query parameter holding the canister ID to route requests to, in the URL or the
+    /// Referer. Can be repeated, in which case the first parameter present wins.
+    #[clap(long, default_value = "canisterId")]
+    canister_id_param: Vec<String>,
+
+    /// The domain GET and HEAD requests to a canister are permanently redirected to when they
+    /// arrive on another host, of the form canister-id=host, keeping their path and query.
+    #[clap(long)]
+    canonical_domain: Vec<String>,
+
+    /// Do not resolve the canister of requests from the canister ID query parameter of
+    /// their Referer. The Referer tells where a client navigated from, which any site may
+    /// choose by linking to the proxy, so a request could be routed to a canister the
+    /// client did not ask for.
+    #[clap(long)]
+    disable_referer_resolution: bool,
+
+    /// Do not add the `X-IC-Certified: true|false|skipped` header, which tells clients
+    /// whether a response from a canister was verified against its certificate.
+    #[clap(long)]
+    no_certification_header: bool,
+
+    /// The maximum number of headers of a canister response. Responses with more are
+    /// answered 502 Bad Gateway.
+    #[clap(long, default_value = "100")]
+    max_response_headers: usize,
+
+    /// The maximum size in bytes of the value of a header of a canister response.
+    /// Responses with a larger one are answered 502 Bad Gateway.
+    #[clap(long, default_value = "8192")]
+    max_response_header_value_bytes: usize,
+
+    /// The maximum size in bytes of the names and values of all the headers of a canister
+    /// response. Responses with larger headers are answered 502 Bad Gateway.
+    #[clap(long, default_value = "65536")]
+    max_response_header_bytes: usize,
+
+    /// Answer 502 when a canister answers with an invalid header name or value, rather than
+    /// skipping the header.
+    #[clap(long)]
+    strict_canister_headers: bool,
+
+    /// Remove the IC-Certificate and IC-CertificateExpression headers from responses which
+    /// passed verification. They add kilobytes to every response, but clients verifying
+    /// responses themselves, such as service workers, need them.
+    #[clap(long)]
+    strip_certification_headers: bool,
+
+    /// Do not add the client address to the `X-Forwarded-For` header of requests
+    /// forwarded to the replica or the proxy.
+    #[clap(long)]
+    no_x_forwarded_for_header: bool,
+
+    /// Add the client address, and the scheme and host the client requested, to an RFC 7239
+    /// `Forwarded` header of requests forwarded to the replica or the proxy, e.g.
+    /// `Forwarded: for="[2001:db8::1]";proto=http;host=example.com`. Independent of
+    /// `X-Forwarded-For`.
+    #[clap(long)]
+    forwarded_header: bool,
+
+    /// Tell canisters the scheme and host the client requested in `X-Forwarded-Proto` and
+    /// `X-Forwarded-Host` headers, for them to build absolute URLs. The values a
+    /// --trusted-proxy sets are kept, and those of other clients replaced.
+    #[clap(long)]
+    x_forwarded_proto_host: bool,
+
+    /// A header telling the address of the client, e.g. `CF-Connecting-IP`, used in place
+    /// of the address of the peer for logging and `X-Forwarded-For` when the peer is a
+    /// --trusted-proxy. Ignored if it is not a valid IP address.
+    #[clap(long)]
+    client_ip_header: Option<String>,
+
+    /// An IP address, or a network of the form address/prefix-length, of a proxy trusted to
+    /// set the --client-ip-header.
+    #[clap(long)]
+    trusted_proxy: Vec<String>,
+
+    /// Validate the configuration, print the effective DNS alias and suffix tables along
+    /// with any warnings, and exit without starting the server.
+    #[clap(long)]
+    check_config: bool,
+
+    /// The output format of --check-config.
+    #[clap(long, default_value = "text", possible_values(&["text", "json"]))]
+    check_config_format: String,
+
+    /// The address to serve Prometheus metrics on, at /metrics. Not served if absent.
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// The address to purge the response cache on, with
+    /// POST /cache/purge?canister_id=...&path_prefix=... . Not served if absent. Anyone who
+    /// can reach it can empty the cache, so bind it to a private address.
+    #[clap(long)]
+    admin_addr: Option<SocketAddr>,
+
+    /// How often, in seconds, to log a summary of the certification outcomes. 0 disables
+    /// the summary.
+    #[clap(long, default_value = "60")]
+    certification_summary_interval: u64,
+
+    /// Log a warning for every request taking longer than this many milliseconds to be
+    /// answered, with the time spent in each of its phases. 0 disables the warning.
+    #[clap(long, default_value = "0")]
+    slow_request_threshold_ms: u64,
+
+    /// The number of threads serving requests. Defaults to the number of CPUs.
+    #[clap(long)]
+    worker_threads: Option<usize>,
+
+    /// The maximum number of threads running blocking tasks, on top of the worker threads.
+    /// Defaults to the limit of the runtime.
+    #[clap(long)]
+    blocking_threads: Option<usize>,
+
+    /// The URL of an http, https or socks5 proxy to connect to replicas and the --proxy
+    /// through, e.g. socks5://127.0.0.1:1080. Defaults to the HTTPS_PROXY or ALL_PROXY
+    /// environment variables.
+    #[clap(long)]
+    outbound_proxy: Option<String>,
+
+    /// A host or domain to connect to directly rather than through the outbound proxy.
+    /// Defaults to the comma-separated list in the NO_PROXY environment variable.
+    #[clap(long)]
+    no_proxy: Vec<String>,
+
+    /// When the certification of a response does not match its tree, log the tree, the
+    /// certified data and the looked up path at debug level. Trees can be large, so this
+    /// is meant for diagnosing certification issues.
+    #[clap(long)]
+    dump_trees_on_failure: bool,
+
+    /// A header canisters may set to true, e.g. IC-Uncertified, to have their responses
+    /// served without verification even if they carry a certificate. Only honored for
+    /// canisters whose certification policy is optional.
+    #[clap(long)]
+    uncertified_header: Option<String>,
+
+    /// The document single page apps serve for paths which are not assets of theirs, used
+    /// to verify responses for paths missing from the certified tree.
+    #[clap(long, default_value = "/index.html")]
+    spa_fallback: String,
+
+    /// The maximum number of calls to the streaming callback of a canister for a single
+    /// response. Longer streams are cut short.
+    #[clap(long, default_value = "1000")]
+    max_stream_callbacks: usize,
+
+    /// The maximum number of bytes streamed for a single response. Longer streams are cut
+    /// short. Unlimited if absent.
+    #[clap(long)]
+    max_stream_bytes: Option<u64>,
+
+    /// The maximum size in bytes of the body of a response, whether it is streamed or not.
+    /// Larger responses are refused, or cut short once streamed. Unlimited if absent.
+    #[clap(long)]
+    max_response_bytes: Option<u64>,
+
+    /// How long, in seconds, to wait for each call to the streaming callback of a canister
+    /// before cutting the stream short.
+    #[clap(long, default_value = "30")]
+    stream_callback_timeout: u64,
+
+    /// How many times to retry a call to the streaming callback of a canister which failed
+    /// to reach it, with a transport error or a 5xx, spaced as queries are with
+    /// --retry-base-delay, before cutting the stream short. Rejects are never retried.
+    #[clap(long, default_value = "3")]
+    stream_callback_retries: u32,
+
+    /// How often, in seconds, to send a comment on a server-sent events stream
+    /// (text/event-stream) waiting for its next chunk, so that intermediaries do not drop
+    /// the connection. Comments are only sent between events, and other streams are left
+    /// untouched. Disabled if absent.
+    #[clap(long)]
+    stream_keepalive_interval: Option<u64>,
+
+    /// The maximum rate, in bytes per second, a single response is streamed at, so that a
+    /// single download cannot saturate the uplink. 0 means unlimited.
+    #[clap(long, default_value = "0")]
+    stream_rate_limit: u64,
+
+    /// The --stream-rate-limit of the responses of a canister, of the form
+    /// canister-id=limit. 0 means unlimited.
+    #[clap(long)]
+    canister_stream_rate_limit: Vec<String>,
+
+    /// The maximum number of responses streamed at once. Further streamed responses wait or
+    /// are rejected depending on --stream-overflow-policy.
+    #[clap(long, default_value = "256")]
+    max_concurrent_streams: usize,
+
+    /// Keep streaming the chunks of a response from its canister while the client is slow
+    /// to read them, holding up to this many bytes in memory and spilling the rest to a
+    /// temporary file. Streams wait for the client by default.
+    #[clap(long)]
+    stream_spill_threshold: Option<usize>,
+
+    /// The maximum number of bytes spilled to temporary files at once across all streams,
+    /// with --stream-spill-threshold. Streams which would go over it are cut short.
+    /// Unlimited if absent.
+    #[clap(long)]
+    max_spill_bytes: Option<u64>,
+
+    /// What to do with a streamed response once --max-concurrent-streams are streamed:
+    /// wait up to --stream-wait-timeout for another stream to end, or reject it right away.
+    /// Rejected responses are answered 503 Service Unavailable.
+    #[clap(long, default_value = "wait", possible_values(&["wait", "reject"]))]
+    stream_overflow_policy: StreamOverflowPolicy,
+
+    /// How long, in milliseconds, a streamed response waits for another stream to end.
+    #[clap(long, default_value = "1000")]
+    stream_wait_timeout: u64,
+
+    /// The maximum time, in seconds, a single response is streamed for. Longer streams are
+    /// cut short. Unlimited if absent.
+    #[clap(long)]
+    max_stream_duration: Option<u64>,
+
+    /// The maximum number of bytes of request and response bodies buffered at once across
+    /// all requests, as responses which are not streamed are buffered whole to be
+    /// verified. Requests which would go over it are answered 503 Service Unavailable.
+    /// Unlimited if absent.
+    #[clap(long)]
+    max_buffered_bytes: Option<u64>,
+
+    /// How long, in seconds, to wait for the result of an update call made for a request
+    /// upgraded by a canister, before answering 504 Gateway Timeout.
+    #[clap(long, default_value = "15")]
+    update_poll_timeout: u64,
+
+    /// How long, in seconds, a request to a canister may take before being answered 504
+    /// Gateway Timeout. The time left bounds the wait for queries, and the ingress expiry of
+    /// update calls and the wait for their result, so that the replica gives up on them as
+    /// well. Unlimited if absent.
+    #[clap(long)]
+    request_timeout: Option<u64>,
+
+    /// How often, in milliseconds, to poll for the result of an update call.
+    #[clap(long, default_value = "500")]
+    update_poll_interval: u64,
+
+    /// How many times to retry a query to a canister which failed for a transient reason,
+    /// such as a replica restarting or rate limiting. Canister responses, including error
+    /// ones, and permanent rejects are never retried.
+    #[clap(long, default_value = "2")]
+    retry_attempts: u32,
+
+    /// The delay, in milliseconds, before the first retry of a query. It doubles with each
+    /// retry, and a random part of it is taken off so that clients do not retry in step.
+    #[clap(long, default_value = "100")]
+    retry_base_delay: u64,
+
+    /// How long, in seconds, to let the responses being streamed end on shutdown before
+    /// cutting them short.
+    #[clap(long, default_value = "30")]
+    shutdown_timeout: u64,
+
+    /// How long, in seconds, a client connection may be idle before TCP keepalive probes
+    /// are sent on it, so that load balancers do not drop it. Unset to not send probes.
+    #[clap(long)]
+    http_keepalive_timeout: Option<u64>,
+
+    /// Close client connections which neither received nor sent anything for this long,
+    /// in seconds, including while a canister is answering.
+    #[clap(long)]
+    http_idle_timeout: Option<u64>,
+
+    /// Close client connections after each response, instead of keeping them alive for
+    /// further requests.
+    #[clap(long)]
+    disable_http_keepalive: bool,
+
+    /// Only speak HTTP/1 on client connections, rather than also accepting HTTP/2 with prior
+    /// knowledge (h2c).
+    #[clap(long)]
+    http1_only: bool,
+
+    /// Close client connections once they were used for this many requests. HTTP/2 clients
+    /// cannot be told to close a connection: their further requests are refused with 503
+    /// Service Unavailable, and the connection is dropped.
+    #[clap(long)]
+    max_requests_per_connection: Option<u64>,
+
+    /// Close HTTP/1 client connections once this many bytes were received and sent over
+    /// them, counted as each request is answered. Connections receiving this many bytes
+    /// are cut right away, even in the middle of a request.
+    #[clap(long)]
+    max_bytes_per_connection: Option<u64>,
+
+    /// The endpoint of an OpenTelemetry collector to export the spans of requests to over
+    /// OTLP, e.g. http://localhost:4317. The W3C trace context of requests is then honored
+    /// and propagated to the replica and the --proxy. Tracing is disabled if absent.
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Refuse to start if this binary was built with the skip_body_verification feature,
+    /// which lets responses failing verification through.
+    #[clap(long)]
+    require_verification: bool,
+
+    /// Together with --debug, answer responses failing verification with the reason of the
+    /// failure, rather than with a generic 500 whose reason is only logged. Without --debug,
+    /// this has no effect.
+    #[clap(long)]
+    no_fail_closed: bool,
+
+    /// A method canister requests may use, e.g. GET. Requests to canisters with any other
+    /// method are answered 405 Method Not Allowed. All methods but CONNECT and TRACE, which
+    /// are always rejected, are allowed if absent.
+    #[clap(long)]
+    allowed_methods: Vec<String>,
+
+    /// How cross-origin requests to canisters are handled: "off" removes the CORS headers
+    /// of canisters, "passthrough" leaves CORS to canisters, "permissive" answers preflights
+    /// and allows every origin, without credentials, and "allowlist" answers preflights and
+    /// allows the --cors-allowed-origin, with credentials. The CORS headers of canisters
+    /// win over those of the proxy.
+    #[clap(
+        long,
+        default_value("passthrough"),
+        possible_values(&["off", "passthrough", "permissive", "allowlist"])
+    )]
+    cors: CorsMode,
+
+    /// An origin allowed with --cors allowlist, e.g. https://example.com.
+    #[clap(long)]
+    cors_allowed_origin: Vec<String>,
+
+    /// A domain whose responses are served without verification, for canisters serving
+    /// dynamic content which cannot be certified. WARNING: responses served on a raw
+    /// domain can be tampered with by a malicious replica or boundary node without
+    /// clients noticing, so never serve sensitive content on one.
+    #[clap(long)]
+    raw_domain: Vec<String>,
+
+    /// A PEM file of root certificates to trust, in addition to the system ones, when
+    /// connecting to replicas and the --proxy over TLS. Useful for replicas with a
+    /// certificate issued by a private CA.
+    #[clap(long)]
+    replica_ca_cert: Vec<PathBuf>,
+
+    /// Accept any TLS certificate from replicas and the --proxy, including expired and
+    /// self-signed ones. INSECURE: only use this for local testing.
+    #[clap(long)]
+    danger_accept_invalid_replica_certs: bool,
+
+    /// The number of verified certificates, and of verified responses, to remember so that
+    /// identical responses are not verified again. 0 disables the cache.
+    #[clap(long, default_value = "1024")]
+    verification_cache_size: usize,
+
+    /// How long, in seconds, a verified certificate or response is remembered.
+    #[clap(long, default_value = "60")]
+    verification_cache_ttl: u64,
+
+    /// The number of Host headers to remember the canister of, so that they are not
+    /// resolved again on every request, and an eighth as many of those resolving to no
+    /// canister. 0 disables the cache.
+    #[clap(long, default_value = "1024")]
+    hostname_cache_size: usize,
+
+    /// How long, in seconds, a Host header resolving to no canister is remembered.
+    #[clap(long, default_value = "10")]
+    hostname_cache_negative_ttl: u64,
+
+    /// Reject responses whose certificate was issued more than this many seconds ago,
+    /// whether or not it was verified already. Certificates of any age are accepted if
+    /// absent.
+    #[clap(long)]
+    max_certificate_age: Option<u64>,
+
+    /// The maximum number of GET requests waiting for the answer of an identical query in
+    /// flight rather than querying the canister themselves. 0 disables coalescing.
+    #[clap(long, default_value = "128")]
+    max_coalesced_requests: usize,
+
+    /// Do not set an ETag on verified responses, nor answer requests with a matching
+    /// If-None-Match with 304 Not Modified.
+    #[clap(long)]
+    no_etag: bool,
+
+    /// A header of requests not to forward to canisters, in addition to Cookie,
+    /// Authorization and Proxy-Authorization, which are only forwarded if allowed with
+    /// --allow-request-header.
+    #[clap(long, alias = "strip-request-headers")]
+    strip_request_header: Vec<String>,
+
+    /// A header of requests to forward to canisters. If any is given, other headers are not
+    /// forwarded to canisters.
+    #[clap(long, alias = "forward-request-headers")]
+    allow_request_header: Vec<String>,
+
+    /// Forward the Accept-Encoding of clients to canisters as it is, rather than restricted
+    /// to the codings bodies can be decoded from to be verified.
+    #[clap(long)]
+    passthrough_accept_encoding: bool,
+
+    /// Decode request bodies sent with a Content-Encoding (gzip, deflate, br or zstd) before
+    /// forwarding them to canisters, which mostly expect them as they are. Changes the bytes
+    /// canisters receive.
+    #[clap(long)]
+    decompress_request_body: bool,
+
+    /// The bytes of verified GET responses to keep in memory, to serve repeated requests
+    /// without querying the canister. 0 disables the cache.
+    #[clap(long, default_value = "0")]
+    cache_size_bytes: usize,
+
+    /// How long, in seconds, a cached response is served for. Responses are not served
+    /// once their certificate is older than this either.
+    #[clap(long, default_value = "60")]
+    cache_ttl: u64,
+}
+
+/// Whether a Host header is a syntactically valid authority: a name made of letters,
+/// digits, dots and hyphens, or a bracketed IPv6 address, optionally followed by a port.
+/// Anything else, such as userinfo, paths or control characters, could be parsed into
+/// something else than the host the client meant.
+fn is_valid_host(host: &str) -> bool {
+    let (name, port) = if let Some(rest) = host.strip_prefix('[') {
+        let (address, port) = match rest.split_once(']') {
+            Some(split) => split,
+            None => return false,
+        };
+        if address.is_empty() || !address.chars().all(|c| c.is_ascii_hexdigit() || c == ':') {
+            return false;
+        }
+        (None, port)
+    } else {
+        match host.find(':') {
+            Some(index) => (Some(&host[..index]), &host[index..]),
+            None => (Some(host), ""),
+        }
+    };
+    let is_valid_name = name.map_or(true, |name| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+    });
+    let is_valid_port = port.is_empty()
+        || port.strip_prefix(':').map_or(false, |port| {
+            (1..=5).contains(&port.len()) && port.chars().all(|c| c.is_ascii_digit())
+        });
+    is_valid_name && is_valid_port
+}
+
+pub(crate) fn resolve_canister_id_from_hostname(
+    hostname: &str,
+    dns_canister_config: &DnsCanisterConfig,
+) -> Option<Principal> {
+    if !is_valid_host(hostname) {
+        return None;
+    }
+    let url = Uri::from_str(hostname).ok()?;
+
+    let split_hostname = url.host()?.split('.').collect::<Vec<&str>>();
+    let split_hostname = split_hostname.as_slice();
+
+    if let Some(principal) =
+        dns_canister_config.resolve_canister_id_from_split_hostname(split_hostname)
+    {
+        return Some(principal);
+    }
+    // Check if it's localhost or ic0.
+    match split_hostname {
+        [.., maybe_canister_id, "localhost"] => Principal::from_text(maybe_canister_id).ok(),
+        [maybe_canister_id, ..] => Principal::from_text(maybe_canister_id).ok(),
+        _ => None,
+    }
+}
+
+/// Try to resolve a canister ID from the query of a URI, looking for each of `params` in
+/// turn.
+pub(crate) fn resolve_canister_id_from_uri(
+    url: &hyper::Uri,
+    params: &[String],
+) -> Option<Principal> {
+    let query = url::form_urlencoded::parse(url.query()?.as_bytes()).collect::<Vec<_>>();
+    params.iter().find_map(|param| {
+        let (_, canister_id) = query.iter().find(|(name, _)| name == param)?;
+        Principal::from_text(canister_id.as_ref()).ok()
+    })
+}
+
+/// Try to resolve a canister ID from the path of a URI starting with `prefix` followed by
+/// the canister ID, e.g. `/canister/<canister-id>/index.html`. Returns the canister ID and
+/// the URI with the prefix and canister ID stripped from its path.
+fn resolve_canister_id_from_path_prefix(
+    url: &hyper::Uri,
+    prefix: &str,
+) -> Option<(Principal, Uri)> {
+    let rest = url.path().strip_prefix(prefix)?;
+    let (canister_id, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let canister_id = Principal::from_text(canister_id).ok()?;
+
+    let path_and_query = match url.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = url.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::from_str(&path_and_query).ok()?);
+    Some((canister_id, Uri::from_parts(parts).ok()?))
+}
+
+/// Try to resolve a canister ID from an HTTP Request. If it cannot be resolved,
+/// [None] will be returned.
+///
+/// The canister ID is looked for, in order, in the Host, the --canister-id-header, the
+/// --canister-path-prefix, the canisterId query parameter and the Referer, unless
+/// --disable-referer-resolution.
+pub(crate) fn resolve_canister_id(
+    request: &Request<Body>,
+    config: &ProxyConfig,
+) -> Option<Principal> {
+    // Look for subdomains if there's a host header.
+    if let Some(host_header) = request.headers().get("Host") {
+        if let Ok(host) = host_header.to_str() {
+            let resolve =
+                |host: &str| resolve_canister_id_from_hostname(host, &config.dns_canister_config);
+            let canister_id = match &config.hostname_cache {
+                Some(cache) => cache.resolve(host, resolve),
+                None => resolve(host),
+            };
+            if canister_id.is_some() {
+                return canister_id;
+            }
+        }
+    }
+
+    // Look into the canister ID header, if one is configured.
+    if let Some(header_name) = &config.canister_id_header {
+        if let Some(canister_id) = request
+            .headers()
+            .get(header_name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| Principal::from_text(value.trim()).ok())
+        {
+            return Some(canister_id);
+        }
+    }
+
+    // Look into the path, if a canister path prefix is configured.
+    if let Some(prefix) = &config.canister_path_prefix {
+        if let Some((canister_id, _)) = resolve_canister_id_from_path_prefix(request.uri(), prefix)
+        {
+            return Some(canister_id);
+        }
+    }
+
+    // Look into the URI.
+    if let Some(canister_id) =
+        resolve_canister_id_from_uri(request.uri(), &config.canister_id_params)
+    {
+        return Some(canister_id);
+    }
+
+    // Look into the request by header.
+    if let Some(referer_header) = request
+        .headers()
+        .get("referer")
+        .filter(|_| config.referer_resolution)
+    {
+        if let Ok(referer) = referer_header.to_str() {
+            if let Ok(referer_uri) = hyper::Uri::from_str(referer) {
+                if let Some(canister_id) =
+                    resolve_canister_id_from_uri(&referer_uri, &config.canister_id_params)
+                {
+                    return Some(canister_id);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+async fn forward_request(
+    request: Request<Body>,
+    agent: Arc<Agent>,
+    config: &ProxyConfig,
+    logger: slog::Logger,
+) -> Result<Response<Body>, ProxyError> {
+    let mut request = request;
+    if let Some(host) = request.headers().get("host") {
+        if !host.to_str().map_or(false, is_valid_host) {
+            return Err(ProxyError::BadRequest("Invalid Host header".to_string()));
+        }
+    }
+    let canister_id = match telemetry::in_span("resolve_canister_id", None, || {
+        resolve_canister_id(&request, config)
+    }) {
+        None => return Err(ProxyError::ResolveCanister),
+        Some(x) => x,
+    };
+
+    // Canisters resolved from the path don't know about the prefix, so strip it.
+    if let Some(prefix) = &config.canister_path_prefix {
+        if let Some((path_canister_id, uri)) =
+            resolve_canister_id_from_path_prefix(request.uri(), prefix)
+        {
+            if path_canister_id == canister_id {
+                *request.uri_mut() = uri;
+            }
+        }
+    }
+
+    if let Some(redirect) = canonical_redirect(&request, canister_id, config)? {
+        return Ok(redirect);
+    }
+
+    let hostname = request
+        .headers()
+        .get("host")
+        .filter(|_| !config.response_headers.is_empty())
+        .and_then(|host| Uri::from_str(host.to_str().ok()?).ok())
+        .and_then(|host| host.host().map(str::to_string));
+    let mut response =
+        forward_request_to_canister(request, canister_id, agent, config, logger).await?;
+
+    // Added after verification, which covers the headers of the canister only.
+    config
+        .response_headers
+        .apply(response.headers_mut(), hostname.as_deref());
+
+    // Let the request log tell which canister served the request.
+    response.extensions_mut().insert(canister_id);
+
+    if config.expose_canister_header {
+        response.headers_mut().insert(
+            "x-ic-canister-id",
+            HeaderValue::from_str(&canister_id.to_text())?,
+        );
+    }
+
+    Ok(response)
+}
+
+/// The redirect of a request to the canonical domain of its canister, if it arrived on
+/// another host. Only GET and HEAD requests are redirected, as clients may not repeat the
+/// body of others.
+fn canonical_redirect(
+    request: &Request<Body>,
+    canister_id: Principal,
+    config: &ProxyConfig,
+) -> Result<Option<Response<Body>>, ProxyError> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return Ok(None);
+    }
+    let host = match request
+        .headers()
+        .get("host")
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| {
+            request
+                .uri()
+                .authority()
+                .map(|authority| authority.as_str())
+        }) {
+        Some(host) => host,
+        None => return Ok(None),
+    };
+    let target = match config.canonical_domains.redirect_target(
+        &canister_id,
+        &request_scheme(request),
+        host,
+        request.uri(),
+    ) {
+        Some(target) => target,
+        None => return Ok(None),
+    };
+    Ok(Some(
+        Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(LOCATION, target)
+            .body(Body::empty())?,
+    ))
+}
+
+/// The scheme a client sent `request` with: the one of its URI if absolute, or the
+/// X-Forwarded-Proto of a trusted proxy, or http, which the proxy serves.
+fn request_scheme(request: &Request<Body>) -> String {
+    request
+        .uri()
+        .scheme_str()
+        .map(str::to_string)
+        .or_else(|| {
+            request
+                .headers()
+                .get(X_FORWARDED_PROTO_HEADER)
+                .filter(|_| request.extensions().get::<TrustedPeer>().is_some())
+                .and_then(|value| value.to_str().ok())
+                .map(|proto| proto.trim().to_ascii_lowercase())
+                .filter(|proto| proto == "http" || proto == "https")
+        })
+        .unwrap_or_else(|| "http".to_string())
+}
+
+async fn forward_request_to_canister(
+    request: Request<Body>,
+    canister_id: Principal,
+    agent: Arc<Agent>,
+    config: &ProxyConfig,
+    logger: slog::Logger,
+) -> Result<Response<Body>, ProxyError> {
+    slog::trace!(
+        logger,
+        "<< {} {} {:?}",
+        request.method(),
+        request.uri(),
+        &request.version()
+    );
+
+    let is_raw_domain = request
+        .headers()
+        .get("host")
+        .and_then(|host| host.to_str().ok())
+        .map_or(false, |host| config.is_raw_domain(host));
+    let method = request.method().to_string();
+    let uri = request.uri().clone();
+    let deadline = request
+        .extensions()
+        .get::<Deadline>()
+        .map(|deadline| deadline.0);
+    let request_start = request.extensions().get::<RequestStart>().cloned();
+
+    // Only GET responses are stored, but they answer HEAD requests as well.
+    let is_get = request.method() == Method::GET;
+    let is_head = request.method() == Method::HEAD;
+    let cache_key = config
+        .response_cache
+        .as_ref()
+        .filter(|_| is_get || is_head)
+        .map(|_| CacheKey::new(canister_id, &uri, request.headers()));
+    let if_none_match = request
+        .headers()
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let if_none_match = Some(if_none_match)
+        .filter(|if_none_match| !if_none_match.is_empty())
+        .filter(|_| config.etag && (is_get || is_head));
+    // Ranges of verified bodies are served by the proxy, as canisters mostly ignore them.
+    let header = |name: HeaderName| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .filter(|_| is_get || is_head)
+            .map(str::to_string)
+    };
+    let (range, if_range) = (header(RANGE), header(IF_RANGE));
+    let client_encodings = accepted_encodings(request.headers());
+    if let (Some(cache), Some(key)) = (&config.response_cache, &cache_key) {
+        if let Some(cached) = cache.get(key) {
+            config.metrics.response_cache.inc("hit");
+            let body = cached.body;
+            let mut response = build_response(
+                Response::builder().status(cached.status),
+                body.clone(),
+                is_head,
+                false,
+            )?;
+            // The Content-Length of a response to a HEAD request is the one of the cached body.
+            let headers = std::mem::replace(response.headers_mut(), cached.headers);
+            response.headers_mut().extend(headers);
+            response
+                .headers_mut()
+                .insert(X_CACHE_HEADER, HeaderValue::from_static("HIT"));
+            return Ok(not_modified(&response, if_none_match.as_deref())
+                .or_else(|| {
+                    partial_content(
+                        &response,
+                        &body,
+                        range.as_deref(),
+                        if_range.as_deref(),
+                        is_head,
+                    )
+                })
+                .unwrap_or(response));
+        }
+        config.metrics.response_cache.inc("miss");
+    }
+    let mut headers = request
+        .headers()
+        .into_iter()
+        .filter(|(name, _)| config.forwards_request_header(name))
+        .filter_map(|(name, value)| {
+            let value = value.to_str().ok()?;
+            let value = if name == ACCEPT_ENCODING && !config.passthrough_accept_encoding {
+                decodable_accept_encoding(value)
+            } else {
+                value.to_string()
+            };
+            Some(HeaderField(name.to_string(), value))
+        })
+        .inspect(|HeaderField(name, value)| {
+            slog::trace!(logger, "<< {}: {}", name, value);
+        })
+        .collect::<Vec<_>>();
+    if config.x_forwarded_proto_host {
+        headers.retain(|HeaderField(name, _)| {
+            name != X_FORWARDED_PROTO_HEADER && name != X_FORWARDED_HOST_HEADER
+        });
+        headers.extend(x_forwarded_proto_host(
+            request.headers(),
+            request.extensions().get::<TrustedPeer>().is_some(),
+        ));
+    }
+    let query_key = QueryKey::new(canister_id, request.method(), &uri, &headers);
+
+    // The budget is checked against the announced length of the request body before it is
+    // read, and grown with every chunk read beyond it.
+    let announced_length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .unwrap_or(0);
+    let mut buffered = config
+        .buffer_budget
+        .reserve(announced_length)
+        .ok_or_else(over_buffer_budget)?;
+    let entire_body = read_body(request.into_body(), &mut buffered, announced_length).await?;
+    let entire_body = if config.decompress_request_body {
+        let decoded =
+            decode_request_body(entire_body, &mut headers, &config.decode_buffers, &logger)?;
+        if !buffered.grow(decoded.len() as u64) {
+            return Err(over_buffer_budget());
+        }
+        decoded
+    } else {
+        entire_body
+    };
+    trace_body(&logger, "<<", &entire_body, false);
+
+    let canister = HttpRequestCanister::create(agent.as_ref(), canister_id);
+    // The answer of the query is decoded here rather than by the canister interface, so
+    // that answers which cannot be decoded can be told apart without querying again.
+    let url = uri.to_string();
+    let arg = Encode!(&RawHttpRequest {
+        method: &method,
+        url: &url,
+        headers: &headers,
+        body: &entire_body,
+    })
+    .map_err(|e| AgentError::CandidError(e.into()))?;
+    let query = || {
+        telemetry::traced(
+            "query",
+            Some(&canister_id),
+            within_deadline(
+                deadline,
+                call_with_retries(
+                    config.retry_policy,
+                    || {
+                        let agent = agent.clone();
+                        let arg = arg.clone();
+                        async move {
+                            agent
+                                .query(&canister_id, "http_request")
+                                .with_arg(arg)
+                                .call()
+                                .await
+                        }
+                    },
+                    |attempt, reason, e| {
+                        config.metrics.replica_retries.inc((canister_id, reason));
+                        slog::debug!(
+                            logger,
+                            "Retrying the query (attempt {}) after: {}",
+                            attempt,
+                            e
+                        );
+                    },
+                ),
+            ),
+        )
+    };
+    let answer = match (
+        &config.in_flight_queries,
+        query_key.filter(|_| entire_body.is_empty()),
+    ) {
+        (Some(in_flight_queries), Some(key)) => {
+            in_flight_queries
+                .run(key, || async { query().await.map(Bytes::from) })
+                .await?
+        }
+        _ => Bytes::from(query().await?),
+    };
+
+    let http_response =
+        Decode!(&answer, HttpResponse).map_err(|e| AgentError::CandidError(e.into()))?;
+    // Serving the response without its strategy would cut its body short.
+    if is_streaming_strategy_unknown(&http_response, &answer) {
+        slog::warn!(
+            logger,
+            "Canister {} answered with an unsupported streaming strategy",
+            canister_id
+        );
+        return Err(ProxyError::UnsupportedStreamingStrategy);
+    }
+
+    let is_upgraded = http_response.upgrade == Some(true);
+    let http_response = if is_upgraded {
+        // The update call expires when the request does, after which the replica drops it if
+        // it did not run yet. Polling for its result stops then too, but a call already
+        // running completes regardless.
+        let waiter = garcon::Delay::builder()
+            .throttle(config.update_poll_interval)
+            .timeout(time_left(deadline, config.update_poll_timeout))
+            .build();
+        let update_result = telemetry::traced(
+            "update",
+            Some(&canister_id),
+            canister
+                .http_request_update(method, uri.to_string(), headers, &entire_body)
+                .call_and_wait(waiter),
+        )
+        .await;
+        let (http_response,) = update_result?;
+        http_response
+    } else {
+        http_response
+    };
+
+    if let Some(reason) = config
+        .response_header_limits
+        .exceeded_by(&http_response.headers)
+    {
+        config
+            .metrics
+            .rejected_response_headers
+            .inc((canister_id, reason));
+        slog::warn!(
+            logger,
+            "Refusing a response of canister {} with {} headers: {}",
+            canister_id,
+            http_response.headers.len(),
+            reason
+        );
+        return Err(ProxyError::Upstream(format!(
+            "the canister answered with headers over the limits: {}",
+            reason
+        )));
+    }
+
+    let is_streaming = http_response.streaming_strategy.is_some();
+    let policy = if is_raw_domain {
+        CertificationPolicy::Skip
+    } else {
+        config.certification_policies.policy(&canister_id)
+    };
+    // Only the first chunk of a streamed body is answered with the certificate, and the
+    // chunks after it are not certified at all, so canisters whose responses must be
+    // certified cannot stream them.
+    if is_streaming && policy == CertificationPolicy::Required {
+        return Err(ProxyError::Verification(
+            "Streamed responses cannot be verified".to_string(),
+        ));
+    }
+    // The certificate and tree of responses which are not verified are left undecoded, and
+    // their body goes straight to the client.
+    let headers_data = if is_upgraded || is_streaming || policy == CertificationPolicy::Skip {
+        extract_uncertified_headers_data(&http_response.headers)
+    } else {
+        extract_headers_data(
+            &http_response.headers,
+            config.uncertified_header.as_deref(),
+            &logger,
+        )
+    };
+    if let Some(max_response_bytes) = config
+        .max_response_bytes
+        .filter(|max| http_response.body.len() as u64 > *max)
+    {
+        slog::error!(
+            logger,
+            "Refusing a response of {} bytes, larger than {} bytes",
+            http_response.body.len(),
+            max_response_bytes
+        );
+        return Err(ProxyError::Upstream(format!(
+            "The canister answered with a response larger than {} bytes",
+            max_response_bytes
+        )));
+    }
+    if !buffered.grow(http_response.body.len() as u64) {
+        return Err(over_buffer_budget());
+    }
+
+    // Verify the response before anything is copied from it, so that nothing of a response
+    // failing verification reaches the client but the error. Streamed bodies cannot be
+    // verified, and the replies of update calls carry no certificate of their own: the
+    // agent verified the certificate it read them from.
+    let certification = if is_upgraded {
+        Certification::Consensus
+    } else if is_streaming {
+        Certification::Skipped
+    } else {
+        match telemetry::in_span("verify", Some(&canister_id), || {
+            validate(
+                &headers_data,
+                &canister_id,
+                &agent,
+                &uri,
+                http_response.status_code,
+                &http_response.body,
+                policy,
+                &config.metrics,
+                config.verification_cache.as_ref(),
+                &config.validation_config,
+                logger.clone(),
+            )
+        }) {
+            Ok(certification) => certification,
+            Err(e) => return Err(ProxyError::Verification(e)),
+        }
+    };
+
+    let decoded_body = if is_streaming {
+        None
+    } else {
+        decode_for_client(
+            &http_response.body,
+            headers_data.encoding.as_deref(),
+            client_encodings,
+            &config.decode_buffers,
+            &logger,
+        )
+    };
+    let is_decoded = decoded_body.is_some();
+    if !buffered.grow(decoded_body.as_ref().map_or(0, |body| body.len() as u64)) {
+        return Err(over_buffer_budget());
+    }
+    let response_body = Bytes::from(http_response.body);
+
+    let mut builder = Response::builder().status(canister_status(
+        http_response.status_code,
+        &canister_id,
+        &logger,
+    )?);
+    let mut content_length = None;
+    let mut is_event_stream = false;
+    let mut varies_by_encoding = false;
+    let body_length = Some(response_body.len()).filter(|_| !is_streaming);
+    // Only once verified are the certification headers of no use to clients.
+    let strips_certification_headers = config.strip_certification_headers
+        && matches!(certification, Certification::Verified { .. });
+    for HeaderField(name, value) in end_to_end_headers(http_response.headers, body_length) {
+        // One invalid header would make building the whole response fail.
+        let (header_name, header_value) = match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            (Ok(header_name), Ok(header_value)) => (header_name, header_value),
+            _ if config.strict_canister_headers => {
+                slog::warn!(
+                    logger,
+                    "Canister {} answered with an invalid header {:?}: {:?}",
+                    canister_id,
+                    name,
+                    value
+                );
+                return Err(ProxyError::Upstream(format!(
+                    "The canister answered with an invalid header {:?}",
+                    name
+                )));
+            }
+            _ => {
+                slog::warn!(
+                    logger,
+                    "Skipping the invalid header {:?}: {:?} of canister {}",
+                    name,
+                    value,
+                    canister_id
+                );
+                continue;
+            }
+        };
+        if strips_certification_headers
+            && CERTIFICATION_HEADERS
+                .iter()
+                .any(|header| name.eq_ignore_ascii_case(header))
+        {
+            continue;
+        }
+        if name.eq_ignore_ascii_case("content-type") {
+            is_event_stream = value.trim().starts_with("text/event-stream");
+        }
+        if name.eq_ignore_ascii_case("vary") {
+            let value = value.to_ascii_lowercase();
+            varies_by_encoding |= value.contains("accept-encoding") || value.contains('*');
+        }
+        // The headers describing the encoded body do not apply to the decoded one.
+        if is_decoded
+            && ["content-encoding", "content-length", "etag"]
+                .iter()
+                .any(|header| name.eq_ignore_ascii_case(header))
+        {
+            continue;
+        }
+        // A streamed response keeps the length announced by the canister, so that clients
+        // can show progress, and is checked against it as it is streamed. An invalid length
+        // is dropped and the response is sent chunked.
+        if is_streaming && name.eq_ignore_ascii_case("content-length") {
+            match value.trim().parse::<u64>() {
+                Ok(length) => content_length = Some(length),
+                Err(_) => continue,
+            }
+        }
+        builder = builder.header(header_name, header_value);
+    }
+    if config.certification_header {
+        builder = builder.header(X_IC_CERTIFIED_HEADER, certification.header_value());
+    }
+    // Whether the body is decoded depends on the Accept-Encoding of the client.
+    if headers_data.encoding.is_some() && !is_streaming && !varies_by_encoding {
+        builder = builder.header(VARY, "Accept-Encoding");
+    }
+
+    // The body of a response to a HEAD request is not sent, so there is nothing to stream.
+    let streaming_strategy = http_response.streaming_strategy.filter(|_| !is_head);
+    let response = if let Some(streaming_strategy) = streaming_strategy {
+        let permit = match acquire_stream_permit(config).await {
+            Some(permit) => permit,
+            None => {
+                slog::warn!(
+                    logger,
+                    "Rejecting a streamed response as {} responses are streamed already",
+                    config.max_concurrent_streams
+                );
+                return Err(ProxyError::TooManyStreams);
+            }
+        };
+        let (mut sender, body) = body::Body::channel();
+        let agent = agent.as_ref().clone();
+        sender
+            .send_data(response_body.clone())
+            .await
+            .map_err(|e| ProxyError::Internal(e.to_string()))?;
+        if let Some(threshold) = config.stream_spill_threshold {
+            let (chunks_sender, chunks) = body::Body::channel();
+            tokio::spawn(stream_spill::forward(
+                chunks,
+                std::mem::replace(&mut sender, chunks_sender),
+                threshold,
+                std::env::temp_dir(),
+                config.spill_budget.clone(),
+                logger.clone(),
+            ));
+        }
+
+        match streaming_strategy {
+            StreamingStrategy::Callback(callback) => {
+                let streaming_canister_id_id = callback.callback.principal;
+                let method_name = callback.callback.method;
+                // The tokens of ic-utils cannot be cloned, which retrying a callback takes, so
+                // they are kept as the encoded argument of the callback.
+                let callback_arg =
+                    Encode!(&callback.token).map_err(|e| AgentError::CandidError(e.into()))?;
+                let logger = logger.new(slog::o!(
+                    "canister_id" => canister_id.to_text(),
+                    "path" => uri.path().to_string(),
+                ));
+                // Keepalive comments can only be injected in server-sent events, which
+                // ignore them, and only if they are not counted in a Content-Length.
+                let stream_limits = StreamLimits {
+                    keepalive_interval: config
+                        .stream_limits
+                        .keepalive_interval
+                        .filter(|_| is_event_stream && content_length.is_none()),
+                    rate_limit: config.stream_rate_limits.limit(&canister_id),
+                    ..config.stream_limits
+                };
+                let metrics = config.metrics.clone();
+                let streams = config.streams.clone();
+                let sent = response_body.clone();
+                let stream = async move {
+                    let _permit = permit;
+                    let _registration = streams.register();
+                    let started = Instant::now();
+                    let streaming = stream_chunks(
+                        &mut sender,
+                        callback_arg,
+                        |arg| {
+                            let agent = &agent;
+                            let canister_id = &streaming_canister_id_id;
+                            let method_name = &method_name;
+                            async move {
+                                let answer = agent
+                                    .query(canister_id, method_name)
+                                    .with_arg(arg)
+                                    .call()
+                                    .await?;
+                                let StreamingCallbackHttpResponse { body, token } =
+                                    Decode!(&answer, StreamingCallbackHttpResponse)
+                                        .map_err(|e| AgentError::CandidError(e.into()))?;
+                                let arg = token
+                                    .map(|token| Encode!(&token))
+                                    .transpose()
+                                    .map_err(|e| AgentError::CandidError(e.into()))?;
+                                Ok((body, arg))
+                            }
+                        },
+                        stream_limits,
+                        &sent,
+                        content_length,
+                        &logger,
+                    );
+                    let summary = tokio::select! {
+                        summary = streaming => Some(summary),
+                        _ = streams.cut_short() => None,
+                    };
+                    let summary = match summary {
+                        Some(summary) => summary,
+                        None => {
+                            slog::warn!(logger, "Cutting the stream short on shutdown");
+                            metrics.streams.inc((canister_id, "shutdown"));
+                            metrics.aborted_streams.inc((canister_id, "shutdown"));
+                            sender.abort();
+                            return;
+                        }
+                    };
+
+                    let duration = started.elapsed();
+                    metrics.record_stream(
+                        canister_id,
+                        summary.abort_reason,
+                        summary.chunks,
+                        summary.bytes_sent,
+                        duration,
+                    );
+                    // The request was logged when the response started, so the end of the
+                    // stream is logged on its own.
+                    slog::info!(logger, "Stream ended";
+                        "outcome" => summary.abort_reason.unwrap_or("completed"),
+                        "chunks" => summary.chunks,
+                        "bytes" => summary.bytes_sent,
+                        "duration_ms" => duration.as_millis() as u64,
+                    );
+                    if let Some(reason) = summary.abort_reason {
+                        metrics.aborted_streams.inc((canister_id, reason));
+                        sender.abort();
+                    }
+                };
+                // The stream is timed as a phase of the request, which is only logged as slow
+                // once the stream ended.
+                let stream = telemetry::traced("stream", Some(&canister_id), stream);
+                let slow_request_threshold = config.slow_request_threshold;
+                let cx = opentelemetry::Context::current();
+                tokio::spawn(async move {
+                    stream.await;
+                    if let Some(start) = request_start {
+                        let duration = start.at.elapsed();
+                        let logger = start.logger.new(slog::o!(
+                            "duration_ms" => duration.as_millis() as u64,
+                            "canister_id" => canister_id.to_text(),
+                        ));
+                        warn_if_slow(slow_request_threshold, duration, &cx, &logger);
+                    }
+                });
+            }
+        }
+
+        builder.extension(Streamed).body(body)?
+    } else {
+        let body = decoded_body.map_or_else(|| response_body.clone(), PooledBuffer::into_bytes);
+        let mut response = build_response(builder, body.clone(), is_head, is_streaming)?;
+        let is_verified = matches!(certification, Certification::Verified { .. });
+        if let Certification::Verified { body_sha } = certification {
+            if config.etag && !response.headers().contains_key(ETAG) {
+                let encoding = headers_data.encoding.as_deref().filter(|_| !is_decoded);
+                let etag = etag(&body_sha, encoding);
+                response
+                    .headers_mut()
+                    .insert(ETAG, HeaderValue::from_str(&etag)?);
+            }
+            if response.status() == StatusCode::OK {
+                response
+                    .headers_mut()
+                    .entry(ACCEPT_RANGES)
+                    .or_insert_with(|| HeaderValue::from_static("bytes"));
+            }
+        }
+        if let (Some(cache), Some(key)) = (&config.response_cache, cache_key) {
+            // Responses of update calls may differ from call to call, and partial responses
+            // are not stored under the key of the whole body.
+            let is_partial = response.status() == StatusCode::PARTIAL_CONTENT;
+            if is_get && !is_upgraded && is_verified && !is_partial {
+                let certificate_age = match &headers_data.certificate {
+                    Some(Ok(certificate)) => certificate_time(certificate)
+                        .and_then(|time| SystemTime::now().duration_since(time).ok())
+                        .unwrap_or_default(),
+                    _ => Duration::ZERO,
+                };
+                let cached = CachedResponse {
+                    status: response.status(),
+                    headers: response.headers().clone(),
+                    body: body.clone(),
+                };
+                let evicted = cache.insert(key, cached, certificate_age);
+                config.metrics.response_cache.add("evict", evicted as u64);
+            }
+            response
+                .headers_mut()
+                .insert(X_CACHE_HEADER, HeaderValue::from_static("MISS"));
+        }
+        // Only verified content is confirmed to be unchanged, or served in parts.
+        if is_verified {
+            not_modified(&response, if_none_match.as_deref())
+                .or_else(|| {
+                    partial_content(
+                        &response,
+                        &body,
+                        range.as_deref(),
+                        if_range.as_deref(),
+                        is_head,
+                    )
+                })
+                .unwrap_or(response)
+        } else {
+            response
+        }
+    };
+
+    if logger.is_trace_enabled() {
+        slog::trace!(
+            logger,
+            ">> {:?} {} {}",
+            &response.version(),
+            response.status().as_u16(),
+            response.status().to_string()
+        );
+
+        for (name, value) in response.headers() {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            slog::trace!(logger, ">> {}: {}", name, value);
+        }
+
+        trace_body(&logger, ">>", &response_body, is_streaming);
+    }
+
+    Ok(holding_reservation(response, buffered))
+}
+
+/// The response with a body holding `reservation` until it is sent, or the client went
+/// away, as the bytes it counts are held by the body until then. Its length is kept in a
+/// Content-Length header, which the wrapped body no longer tells.
+fn holding_reservation(response: Response<Body>, reservation: BufferReservation) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    if let Some(length) = body.size_hint().exact() {
+        if length == 0 {
+            return Response::from_parts(parts, body);
+        }
+        parts
+            .headers
+            .entry(CONTENT_LENGTH)
+            .or_insert_with(|| HeaderValue::from(length));
+    }
+    let body = Body::wrap_stream(body.map(move |chunk| {
+        let _held = &reservation;
+        chunk
+    }));
+    Response::from_parts(parts, body)
+}
+
+/// The status of a response of a canister, which may answer with any number.
+fn canister_status(
+    status_code: u16,
+    canister_id: &Principal,
+    logger: &slog::Logger,
+) -> Result<StatusCode, ProxyError> {
+    // The body of an interim response would have to be followed by a final response, which
+    // a single answer of a canister cannot provide.
+    let problem = match StatusCode::from_u16(status_code) {
+        Ok(status) if !status.is_informational() => return Ok(status),
+        Ok(_) => "the interim status code",
+        Err(_) => "the invalid status code",
+    };
+    slog::warn!(
+        logger,
+        "Canister {} answered with {} {}",
+        canister_id,
+        problem,
+        status_code
+    );
+    Err(ProxyError::Upstream(format!(
+        "canister {} answered with {} {}",
+        canister_id, problem, status_code
+    )))
+}
+
+/// The error of a request whose bodies would go over --max-buffered-bytes.
+fn over_buffer_budget() -> ProxyError {
+    ProxyError::Overloaded("Too many bodies are buffered at the moment".to_string())
+}
+
+/// Read the whole body of a request, without copying it if it came in a single chunk. The
+/// bytes read are counted in `buffered`, which holds `reserved` of them already, and reading
+/// stops as soon as they would go over the budget.
+async fn read_body(
+    mut body: Body,
+    buffered: &mut BufferReservation,
+    mut reserved: u64,
+) -> Result<Bytes, ProxyError> {
+    let mut chunks = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| {
+            ProxyError::BadRequest(format!("Unable to read the request body: {}", e))
+        })?;
+        let length = chunk.len() as u64;
+        if !buffered.grow(length.saturating_sub(reserved)) {
+            return Err(over_buffer_budget());
+        }
+        reserved = reserved.saturating_sub(length);
+        chunks.push(chunk);
+    }
+    Ok(match chunks.len() {
+        1 => chunks.remove(0),
+        _ => Bytes::from(chunks.concat()),
+    })
+}
+
+/// Log at most [MAX_LOG_BODY_SIZE] bytes of a body at trace level, followed by its length
+/// if it is longer.
+fn trace_body(logger: &slog::Logger, direction: &str, body: &[u8], is_streaming: bool) {
+    if !logger.is_trace_enabled() {
+        return;
+    }
+    slog::trace!(logger, "{}", direction);
+    slog::trace!(
+        logger,
+        "{} \"{}\"{}",
+        direction,
+        String::from_utf8_lossy(&body[..usize::min(MAX_LOG_BODY_SIZE, body.len())])
+            .escape_default(),
+        if is_streaming {
+            "... streaming".to_string()
+        } else if body.len() > MAX_LOG_BODY_SIZE {
+            format!("... {} bytes total", body.len())
+        } else {
+            String::new()
+        }
+    );
+}
+
+/// Decode a `body` encoded with `encoding` for a client which does not accept it, as
+/// canisters may serve encoded bodies whatever clients accept. Returns [None] if the client
+/// accepts the body as it is, or it cannot be decoded, in which case it is served as is.
+fn decode_for_client(
+    body: &[u8],
+    encoding: Option<&str>,
+    client_encodings: AcceptedEncodings,
+    buffers: &Arc<BufferPool>,
+    logger: &slog::Logger,
+) -> Option<PooledBuffer> {
+    match encoding {
+        Some(encoding) if !client_encodings.accepts(encoding) => {
+            decode_body(body, Some(encoding), buffers, logger)
+        }
+        _ => None,
+    }
+}
+
+/// Decode a request body sent with a Content-Encoding, for --decompress-request-body, and
+/// fix the `headers` describing it. Bodies are decoded up to the same size as response
+/// bodies, and those which cannot be are refused.
+fn decode_request_body(
+    body: Bytes,
+    headers: &mut Vec<HeaderField>,
+    buffers: &Arc<BufferPool>,
+    logger: &slog::Logger,
+) -> Result<Bytes, ProxyError> {
+    let encoding = headers
+        .iter()
+        .filter(|HeaderField(name, _)| name.eq_ignore_ascii_case("content-encoding"))
+        .map(|HeaderField(_, value)| value.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+        .to_ascii_lowercase();
+    let is_encoded = encoding
+        .split(',')
+        .map(str::trim)
+        .any(|coding| !coding.is_empty() && coding != "identity");
+    // An identity coding is dropped too, the body being left as it is.
+    headers.retain(|HeaderField(name, _)| !name.eq_ignore_ascii_case("content-encoding"));
+    if !is_encoded {
+        return Ok(body);
+    }
+
+    let decoded =
+        decode_body(&body, Some(encoding.as_str()), buffers, logger).ok_or_else(|| {
+            ProxyError::BadRequest(format!(
+                "Unable to decode the request body encoded with {}",
+                encoding
+            ))
+        })?;
+    for HeaderField(name, value) in headers.iter_mut() {
+        if name.eq_ignore_ascii_case("content-length") {
+            *value = decoded.len().to_string();
+        }
+    }
+    Ok(decoded.into_bytes())
+}
+
+/// Build a response with `body`, or only with its headers for a HEAD request. The length of
+/// the body is kept then, unless it is the first chunk of a streamed body.
+fn build_response(
+    builder: hyper::http::response::Builder,
+    body: Bytes,
+    is_head: bool,
+    is_streaming: bool,
+) -> Result<Response<Body>, hyper::http::Error> {
+    if !is_head {
+        return builder.body(Body::from(body));
+    }
+    let mut response = builder.body(Body::empty())?;
+    if !is_streaming {
+        response
+            .headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+    Ok(response)
+}
+
+// The response headers a 304 Not Modified response keeps.
+const NOT_MODIFIED_HEADERS: &[&str] = &[
+    "cache-control",
+    "content-location",
+    "date",
+    "etag",
+    "expires",
+    "vary",
+    "x-ic-certified",
+];
+
+/// The ETag of a verified body: strong if the body is served as is, but weak if it is
+/// encoded, since the hash is the one of the decoded body, shared by all its encodings.
+fn etag(body_sha: &[u8; 32], encoding: Option<&str>) -> String {
+    let is_encoded = encoding.map_or(false, |encoding| {
+        encoding
+            .split(',')
+            .any(|coding| !coding.trim().is_empty() && coding.trim() != "identity")
+    });
+    format!(
+        "{}\"{}\"",
+        if is_encoded { "W/" } else { "" },
+        hex::encode(body_sha)
+    )
+}
+
+/// Whether an If-None-Match header, a list of entity tags, matches `etag`. Entity tags are
+/// compared weakly, as If-None-Match requires.
+fn if_none_match_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    let mut rest = if_none_match;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        if rest.is_empty() {
+            return false;
+        }
+        if rest.starts_with('*') {
+            return true;
+        }
+        let tag = rest.strip_prefix("W/").unwrap_or(rest);
+        match tag.strip_prefix('"').and_then(|quoted| quoted.find('"')) {
+            // Entity tags may contain commas, so they are delimited by their quotes.
+            Some(end) => {
+                if tag[..end + 2] == etag {
+                    return true;
+                }
+                rest = &tag[end + 2..];
+            }
+            None => rest = rest.find(',').map_or("", |comma| &rest[comma..]),
+        }
+    }
+}
+
+/// Answer 304 Not Modified instead of a successful `response` if `if_none_match` matches
+/// its ETag.
+fn not_modified(response: &Response<Body>, if_none_match: Option<&str>) -> Option<Response<Body>> {
+    let etag = response.headers().get(ETAG)?.to_str().ok()?;
+    if response.status() != StatusCode::OK || !if_none_match_matches(if_none_match?, etag) {
+        return None;
+    }
+    let mut builder = Response::builder().status(StatusCode::NOT_MODIFIED);
+    for name in NOT_MODIFIED_HEADERS {
+        for value in response.headers().get_all(*name) {
+            builder = builder.header(*name, value);
+        }
+    }
+    builder.body(Body::empty()).ok()
+}
+
+/// The bytes of a body of `length` bytes a Range header asks for, as an empty range if none
+/// of them exist. Headers which are not a single range of bytes are ignored.
+fn byte_range(range: &str, length: usize) -> Option<std::ops::Range<usize>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (start, end) = match (first.trim(), last.trim()) {
+        ("", suffix) => (length.saturating_sub(suffix.parse().ok()?), length),
+        (first, "") => (first.parse().ok()?, length),
+        (first, last) => {
+            let (first, last) = (first.parse().ok()?, last.parse::<usize>().ok()?);
+            if last < first {
+                return None;
+            }
+            (first, last.saturating_add(1).min(length))
+        }
+    };
+    Some(start..end.max(start))
+}
+
+/// Answer 206 Partial Content with the part of the `body` of a successful `response` which
+/// `range` asks for, or 416 Range Not Satisfiable. The whole body is served if `if_range`
+/// is not the ETag of the response.
+fn partial_content(
+    response: &Response<Body>,
+    body: &Bytes,
+    range: Option<&str>,
+    if_range: Option<&str>,
+    is_head: bool,
+) -> Option<Response<Body>> {
+    if response.status() != StatusCode::OK {
+        return None;
+    }
+    if let Some(if_range) = if_range {
+        // Only strong entity tags match, and dates never do as there is no Last-Modified.
+        let etag = response.headers().get(ETAG)?.to_str().ok()?;
+        if etag.starts_with("W/") || if_range.trim() != etag {
+            return None;
+        }
+    }
+    let range = byte_range(range?, body.len())?;
+    if range.is_empty() {
+        return Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CONTENT_RANGE, format!("bytes */{}", body.len()))
+            .body(Body::empty())
+            .ok();
+    }
+    let mut builder = Response::builder().status(StatusCode::PARTIAL_CONTENT);
+    for (name, value) in response.headers() {
+        if name != CONTENT_LENGTH {
+            builder = builder.header(name, value);
+        }
+    }
+    let builder = builder.header(
+        CONTENT_RANGE,
+        format!("bytes {}-{}/{}", range.start, range.end - 1, body.len()),
+    );
+    build_response(builder, body.slice(range), is_head, false).ok()
+}
+
+/// What identical queries have in common, for them to be made once.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct QueryKey {
+    canister_id: Principal,
+    uri: String,
+    headers: Vec<(String, String)>,
+}
+
+impl QueryKey {
+    /// Return the key of a query with the `headers` forwarded to the canister, or [None] if
+    /// it must be made on its own: only GET requests without credentials are made once for
+    /// all identical ones. As canisters may answer differently to any header they are sent,
+    /// queries are only identical if all of them are.
+    fn new(
+        canister_id: Principal,
+        method: &Method,
+        uri: &Uri,
+        headers: &[HeaderField],
+    ) -> Option<QueryKey> {
+        let is_credential = |name: &str| {
+            name.eq_ignore_ascii_case(hyper::header::AUTHORIZATION.as_str())
+                || name.eq_ignore_ascii_case(hyper::header::COOKIE.as_str())
+        };
+        if method != Method::GET
+            || headers
+                .iter()
+                .any(|HeaderField(name, _)| is_credential(name))
+        {
+            return None;
+        }
+        Some(QueryKey {
+            canister_id,
+            uri: uri.to_string(),
+            headers: headers
+                .iter()
+                .map(|HeaderField(name, value)| (name.to_ascii_lowercase(), value.clone()))
+                .collect(),
+        })
+    }
+}
+
+/// How calls to replicas failing for a transient reason are retried.
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    attempts: u32,
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// The delay before the `attempt`th retry: the base delay doubled for each previous
+    /// retry, with up to half of it taken off at random.
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(10));
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Why a failed call to a replica may succeed if made again, or [None] if it may not.
+/// Rejects are final, except those the replica marks as transient.
+fn replica_retry_reason(error: &AgentError) -> Option<&'static str> {
+    match error {
+        AgentError::TransportError(_) => Some("transport_error"),
+        AgentError::HttpError(payload) => match payload.status {
+            429 => Some("too_many_requests"),
+            502 | 503 | 504 => Some("unavailable"),
+            _ => None,
+        },
+        // SYS_TRANSIENT
+        AgentError::ReplicaError { reject_code: 2, .. } => Some("transient_reject"),
+        _ => None,
+    }
+}
+
+/// Whether a failed call to a streaming callback may succeed if made again. Only failures
+/// to reach the replica are, as a callback rejected for whatever reason may have moved on
+/// from its token.
+fn callback_is_retryable(error: &AgentError) -> bool {
+    match error {
+        AgentError::TransportError(_) => true,
+        AgentError::HttpError(payload) => (500..600).contains(&payload.status),
+        _ => false,
+    }
+}
+
+/// Make `call` until it succeeds, fails for a reason which is not transient, or was
+/// retried as many times as `policy` allows. `on_retry` is told about each retry.
+async fn call_with_retries<T, F, Fut, R>(
+    policy: RetryPolicy,
+    mut call: F,
+    mut on_retry: R,
+) -> Result<T, AgentError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, AgentError>>,
+    R: FnMut(u32, &'static str, &AgentError),
+{
+    let mut attempt = 0;
+    loop {
+        let error = match call().await {
+            Ok(result) => return Ok(result),
+            Err(e) => e,
+        };
+        match replica_retry_reason(&error) {
+            Some(reason) if attempt < policy.attempts => {
+                attempt += 1;
+                on_retry(attempt, reason, &error);
+                tokio::time::sleep(policy.delay(attempt)).await;
+            }
+            _ => return Err(error),
+        }
+    }
+}
+
+/// What to do with a streamed response when the maximum number of responses are streamed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StreamOverflowPolicy {
+    /// Wait for another stream to end, for a while.
+    Wait,
+    /// Reject the response right away.
+    Reject,
+}
+
+impl FromStr for StreamOverflowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wait" => Ok(StreamOverflowPolicy::Wait),
+            "reject" => Ok(StreamOverflowPolicy::Reject),
+            _ => Err(anyhow!(
+                r#"Unknown stream overflow policy "{}". Expected one of wait or reject"#,
+                s
+            )),
+        }
+    }
+}
+
+/// The streamed responses being sent, so that they can be drained, or cut short, on
+/// shutdown.
+struct StreamRegistry {
+    active: AtomicUsize,
+    /// Notified whenever a stream ends.
+    ended: Notify,
+    shutdown: watch::Sender<bool>,
+    shutdown_receiver: watch::Receiver<bool>,
+}
+
+/// A stream counted in the [StreamRegistry] for as long as it is held.
+struct StreamRegistration(Arc<StreamRegistry>);
+
+impl Drop for StreamRegistration {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+        self.0.ended.notify_waiters();
+    }
+}
+
+impl StreamRegistry {
+    fn new() -> StreamRegistry {
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        StreamRegistry {
+            active: AtomicUsize::new(0),
+            ended: Notify::new(),
+            shutdown,
+            shutdown_receiver,
+        }
+    }
+
+    fn register(self: &Arc<Self>) -> StreamRegistration {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        StreamRegistration(self.clone())
+    }
+
+    /// The number of streams being sent.
+    fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Wait until no stream is sent anymore.
+    async fn drained(&self) {
+        loop {
+            let ended = self.ended.notified();
+            if self.active() == 0 {
+                return;
+            }
+            ended.await;
+        }
+    }
+
+    /// Complete once streams are to be cut short.
+    async fn cut_short(&self) {
+        let mut shutdown = self.shutdown_receiver.clone();
+        while !*shutdown.borrow() {
+            if shutdown.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Let the streams being sent end for up to `drain_timeout`, then cut the others
+    /// short. Returns the number of streams which were cut short.
+    async fn shutdown(&self, drain_timeout: Duration) -> usize {
+        if tokio::time::timeout(drain_timeout, self.drained())
+            .await
+            .is_ok()
+        {
+            return 0;
+        }
+        let remaining = self.active();
+        let _ = self.shutdown.send(true);
+        // Give the streams a moment to be aborted, so that clients see them fail rather
+        // than end.
+        let _ = tokio::time::timeout(Duration::from_secs(1), self.drained()).await;
+        remaining
+    }
+}
+
+/// The right to stream a response, counted in the streams in flight for as long as it is
+/// held.
+struct StreamPermit {
+    _permit: OwnedSemaphorePermit,
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.metrics
+            .streams_in_flight
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Acquire the right to stream a response, according to the stream overflow policy.
+/// Returns [None] if the response should be rejected.
+async fn acquire_stream_permit(config: &ProxyConfig) -> Option<StreamPermit> {
+    let permits = config.stream_permits.clone();
+    let permit = match config.stream_overflow_policy {
+        StreamOverflowPolicy::Reject => permits.try_acquire_owned().ok()?,
+        StreamOverflowPolicy::Wait => {
+            tokio::time::timeout(config.stream_wait_timeout, permits.acquire_owned())
+                .await
+                .ok()?
+                .ok()?
+        }
+    };
+    config
+        .metrics
+        .streams_in_flight
+        .fetch_add(1, Ordering::Relaxed);
+    Some(StreamPermit {
+        _permit: permit,
+        metrics: config.metrics.clone(),
+    })
+}
+
+/// The argument of the http_request method of canisters.
+#[derive(CandidType)]
+struct RawHttpRequest<'a> {
+    method: &'a str,
+    url: &'a str,
+    headers: &'a [HeaderField],
+    body: &'a [u8],
+}
+
+/// The streaming strategy of an [HttpResponse], whatever it is. The other fields of the
+/// response are skipped.
+#[derive(CandidType, Deserialize)]
+struct AnyStreamingHttpResponse {
+    streaming_strategy: Option<Reserved>,
+}
+
+/// Whether the encoded `answer` to http_request, decoded as `http_response`, has a streaming
+/// strategy this proxy does not know. Such a strategy decodes as none, as it is optional.
+fn is_streaming_strategy_unknown(http_response: &HttpResponse, answer: &[u8]) -> bool {
+    http_response.streaming_strategy.is_none()
+        && Decode!(answer, AnyStreamingHttpResponse)
+            .map_or(false, |response| response.streaming_strategy.is_some())
+}
+
+/// The limits of a single streamed response.
+#[derive(Clone, Copy, Debug)]
+struct StreamLimits {
+    max_callbacks: usize,
+    max_bytes: Option<u64>,
+    callback_timeout: Duration,
+    /// How a callback failing for a transient reason is retried.
+    callback_retries: RetryPolicy,
+    max_duration: Option<Duration>,
+    /// How often to send a keepalive comment while waiting for a chunk, for server-sent
+    /// events streams only.
+    keepalive_interval: Option<Duration>,
+    /// The maximum rate chunks are sent at, in bytes per second.
+    rate_limit: Option<u64>,
+}
+
+/// Paces the bytes sent to a rate, in bytes per second, allowing bursts of up to a second
+/// worth of bytes once the sender was idle.
+struct RateLimiter {
+    rate: u64,
+    /// How many bytes may be sent right away, negative when bytes were sent ahead of the
+    /// rate.
+    allowance: f64,
+    last_update: tokio::time::Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            rate,
+            allowance: 0.0,
+            last_update: tokio::time::Instant::now(),
+        }
+    }
+
+    /// Wait until `bytes` may be sent.
+    async fn wait_for(&mut self, bytes: usize) {
+        let now = tokio::time::Instant::now();
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+        self.last_update = now;
+        self.allowance =
+            (self.allowance + elapsed * self.rate as f64).min(self.rate as f64) - bytes as f64;
+        if self.allowance < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-self.allowance / self.rate as f64)).await;
+        }
+    }
+}
+
+/// A server-sent events comment, which clients ignore.
+const SSE_KEEPALIVE: &[u8] = b": keepalive\n\n";
+
+/// Keep in `tail` the last bytes sent of a server-sent events stream, after `bytes`, as
+/// many as [ends_event] looks at.
+fn keep_event_tail(tail: &mut Vec<u8>, bytes: &[u8]) {
+    tail.extend_from_slice(&bytes[bytes.len().saturating_sub(4)..]);
+    let excess = tail.len().saturating_sub(4);
+    tail.drain(..excess);
+}
+
+/// Whether a server-sent events stream whose last bytes sent are `tail` is between two
+/// events, which it is before anything was sent. Events end with an empty line.
+fn ends_event(tail: &[u8]) -> bool {
+    tail.is_empty()
+        || tail.ends_with(b"\n\n")
+        || tail.ends_with(b"\r\r")
+        || tail.ends_with(b"\r\n\r\n")
+}
+
+/// The instant a request must be answered by, set in its extensions with --request-timeout.
+#[derive(Clone, Copy, Debug)]
+struct Deadline(tokio::time::Instant);
+
+/// Set in the extensions of requests coming from a --trusted-proxy.
+#[derive(Clone, Copy, Debug)]
+struct TrustedPeer;
+
+/// When a request for a canister started, set in its extensions with the logger of its
+/// slow-request warning, which is logged once the stream of a streamed response ends.
+#[derive(Clone)]
+struct RequestStart {
+    at: Instant,
+    logger: slog::Logger,
+}
+
+/// Set in the extensions of responses whose body is still streamed once they are returned.
+#[derive(Clone, Copy, Debug)]
+struct Streamed;
+
+// The longest ingress expiry replicas accept.
+const MAX_INGRESS_EXPIRY: Duration = Duration::from_secs(5 * 60);
+
+// The shortest ingress expiry calls can be made with: the agent takes the 60 seconds of
+// clock drift it permits off the expiry, which must not end up in the past.
+const MIN_INGRESS_EXPIRY: Duration = Duration::from_secs(90);
+
+/// The ingress expiry of the calls made for a request which must be answered by
+/// `deadline`, within what replicas accept. Calls may expire well after a short deadline,
+/// which is enforced by [within_deadline] instead.
+fn ingress_expiry(deadline: Option<tokio::time::Instant>) -> Option<Duration> {
+    deadline.map(|_| time_left(deadline, MAX_INGRESS_EXPIRY).max(MIN_INGRESS_EXPIRY))
+}
+
+/// The agent calling canisters through `replica_url` for a request which must be answered
+/// by `deadline`.
+fn canister_agent(
+    replica_url: String,
+    config: &ProxyConfig,
+    deadline: Option<tokio::time::Instant>,
+) -> Agent {
+    Agent::builder()
+        .with_transport(ClientTransport::create(&replica_url, config.http_client.clone()).unwrap())
+        .with_ingress_expiry(ingress_expiry(deadline))
+        .build()
+        .expect("Could not create agent...")
+}
+
+/// Await `call`, failing it as timed out if `deadline` passes first.
+async fn within_deadline<T, F: Future<Output = Result<T, AgentError>>>(
+    deadline: Option<tokio::time::Instant>,
+    call: F,
+) -> Result<T, AgentError> {
+    until_deadline(deadline, call)
+        .await
+        .unwrap_or(Err(AgentError::TimeoutWaitingForResponse()))
+}
+
+/// How long to wait for something which may take up to `limit`, without going past
+/// `deadline`.
+fn time_left(deadline: Option<tokio::time::Instant>, limit: Duration) -> Duration {
+    deadline.map_or(limit, |deadline| {
+        deadline
+            .saturating_duration_since(tokio::time::Instant::now())
+            .min(limit)
+    })
+}
+
+/// Await `future`, unless `deadline` passes first.
+async fn until_deadline<F: Future>(
+    deadline: Option<tokio::time::Instant>,
+    future: F,
+) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// How many of the last tokens of a stream are remembered to detect a canister handing out
+/// a token again.
+const RECENT_TOKENS: usize = 16;
+
+/// The hashes of the Candid encoding of the last tokens of a stream. The streaming
+/// protocol never reuses a token, so a repeated one means the canister loops.
+#[derive(Default)]
+struct RecentTokens(VecDeque<u64>);
+
+impl RecentTokens {
+    /// Remember `token`, and return whether it was seen recently.
+    fn repeats<T: CandidType>(&mut self, token: &T) -> bool {
+        let encoded = match candid::encode_one(token) {
+            Ok(encoded) => encoded,
+            Err(_) => return false,
+        };
+        let mut hasher = DefaultHasher::new();
+        encoded.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        if self.0.contains(&hash) {
+            return true;
+        }
+        if self.0.len() == RECENT_TOKENS {
+            self.0.pop_front();
+        }
+        self.0.push_back(hash);
+        false
+    }
+}
+
+/// How a streamed response ended.
+#[derive(Debug)]
+struct StreamSummary {
+    /// Why the stream was cut short, if it was.
+    abort_reason: Option<&'static str>,
+    /// The number of chunks fetched from the streaming callback.
+    chunks: usize,
+    /// The number of bytes sent, including those sent before streaming.
+    bytes_sent: u64,
+}
+
+/// Send the chunks of a streamed response to `sender`, fetching each chunk with
+/// `next_chunk` from the token of the previous one, until the last chunk. The `sent` bytes
+/// of the response were already sent, out of `content_length` if the length of the
+/// response is known. Returns how the stream ended.
+///
+/// The next chunk is only fetched once the client is ready for it, so that the canister
+/// is not called for a client which went away. The stream is cut short as soon as the
+/// canister hands out a token it handed out recently.
+async fn stream_chunks<T, F, Fut>(
+    sender: &mut body::Sender,
+    mut token: T,
+    mut next_chunk: F,
+    limits: StreamLimits,
+    sent: &[u8],
+    content_length: Option<u64>,
+    logger: &slog::Logger,
+) -> StreamSummary
+where
+    T: Clone + CandidType,
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = Result<(Vec<u8>, Option<T>), AgentError>>,
+{
+    let mut chunks = 0;
+    let mut bytes_sent = sent.len() as u64;
+    let mut sent_tail = Vec::new();
+    keep_event_tail(&mut sent_tail, sent);
+    let abort_reason = async {
+        let deadline = limits
+            .max_duration
+            .map(|max_duration| tokio::time::Instant::now() + max_duration);
+        let mut rate_limiter = limits.rate_limit.map(RateLimiter::new);
+        let mut recent_tokens = RecentTokens::default();
+        recent_tokens.repeats(&token);
+        // We have not yet called http_request_stream_callback.
+        let mut count = 0;
+        loop {
+            count += 1;
+            if count > limits.max_callbacks {
+                slog::warn!(
+                    logger,
+                    "Cutting the stream short after {} callbacks, {} bytes sent",
+                    limits.max_callbacks,
+                    bytes_sent
+                );
+                return Some("callback_limit");
+            }
+
+            let chunk = until_deadline(deadline, async {
+                if poll_fn(|cx| sender.poll_ready(cx)).await.is_err() {
+                    return None;
+                }
+                let fetch = async {
+                    let mut attempt = 0;
+                    loop {
+                        match tokio::time::timeout(limits.callback_timeout, next_chunk(token.clone()))
+                            .await
+                        {
+                            Ok(Err(e))
+                                if attempt < limits.callback_retries.attempts
+                                    && callback_is_retryable(&e) =>
+                            {
+                                attempt += 1;
+                                slog::debug!(
+                                    logger,
+                                    "Retrying the streaming callback (attempt {}) after: {}",
+                                    attempt,
+                                    e
+                                );
+                                tokio::time::sleep(limits.callback_retries.delay(attempt)).await;
+                            }
+                            result => return result,
+                        }
+                    }
+                };
+                tokio::pin!(fetch);
+
+                // A comment in the middle of an event would corrupt it.
+                let keepalive_interval = match limits
+                    .keepalive_interval
+                    .filter(|_| ends_event(&sent_tail))
+                {
+                    Some(keepalive_interval) => keepalive_interval,
+                    None => return Some(fetch.await),
+                };
+                // Keep intermediaries from dropping a connection on which nothing is written for
+                // a while.
+                loop {
+                    tokio::select! {
+                        result = &mut fetch => return Some(result),
+                        _ = tokio::time::sleep(keepalive_interval) => {
+                            if sender.send_data(Bytes::from_static(SSE_KEEPALIVE)).await.is_err() {
+                                return None;
+                            }
+                        }
+                    }
+                }
+            })
+            .await;
+
+            match chunk {
+                None => {
+                    slog::warn!(
+                        logger,
+                        "Cutting the stream short after it streamed for too long, {} bytes sent",
+                        bytes_sent
+                    );
+                    return Some("deadline");
+                }
+                Some(None) => {
+                    slog::debug!(
+                        logger,
+                        "The client went away during streaming, {} bytes sent",
+                        bytes_sent
+                    );
+                    return Some("client_disconnected");
+                }
+                Some(Some(Err(_))) => {
+                    slog::warn!(
+                        logger,
+                        "Cutting the stream short after its callback did not answer within {:?}, {} bytes sent",
+                        limits.callback_timeout,
+                        bytes_sent
+                    );
+                    return Some("callback_timeout");
+                }
+                Some(Some(Ok(Err(e)))) => {
+                    slog::debug!(logger, "Error happened during streaming: {}", e);
+                    return Some("callback_error");
+                }
+                Some(Some(Ok(Ok((body, next_token))))) => {
+                    chunks += 1;
+                    if let Some(max_bytes) = limits
+                        .max_bytes
+                        .filter(|max| bytes_sent + body.len() as u64 > *max)
+                    {
+                        slog::error!(
+                            logger,
+                            "Cutting the stream short at {} bytes, {} bytes sent",
+                            max_bytes,
+                            bytes_sent
+                        );
+                        return Some("byte_limit");
+                    }
+                    if let Some(content_length) =
+                        content_length.filter(|length| bytes_sent + body.len() as u64 > *length)
+                    {
+                        slog::warn!(
+                            logger,
+                            "Cutting the stream short as it is longer than its Content-Length of {} bytes, {} bytes sent",
+                            content_length,
+                            bytes_sent
+                        );
+                        return Some("length_mismatch");
+                    }
+                    bytes_sent += body.len() as u64;
+                    keep_event_tail(&mut sent_tail, &body);
+                    if let Some(rate_limiter) = &mut rate_limiter {
+                        rate_limiter.wait_for(body.len()).await;
+                    }
+                    if sender.send_data(Bytes::from(body)).await.is_err() {
+                        return Some("client_disconnected");
+                    }
+                    match next_token {
+                        Some(next_token) if recent_tokens.repeats(&next_token) => {
+                            slog::warn!(
+                                logger,
+                                "Cutting the stream short as its callback returned a token again after {} callbacks, {} bytes sent",
+                                count,
+                                bytes_sent
+                            );
+                            return Some("token_loop");
+                        }
+                        Some(next_token) => token = next_token,
+                        None => {
+                            return match content_length.filter(|length| *length != bytes_sent) {
+                                Some(content_length) => {
+                                    slog::warn!(
+                                        logger,
+                                        "The stream ended after {} bytes, short of its Content-Length of {} bytes",
+                                        bytes_sent,
+                                        content_length
+                                    );
+                                    Some("length_mismatch")
+                                }
+                                None => None,
+                            };
+                        }
+                    }
+                }
+            }
+        }
+    }
+    .await;
+    StreamSummary {
+        abort_reason,
+        chunks,
+        bytes_sent,
+    }
+}
+
+/// Whether a header is always hop-by-hop. `name` is lowercase, as header names are.
+fn is_hop_header(name: &str) -> bool {
+    matches!(
+        name,
+        "connection"
+            | "keep-alive"
+            | "proxy-authenticate"
+            | "proxy-authorization"
+            | "te"
+            | "trailers"
+            | "transfer-encoding"
+            | "upgrade"
+    )
+}
+
+/// Returns a clone of the headers without the [hop-by-hop headers], including those listed
+/// in the Connection header, as in [RFC 7230 section 6.1].
+///
+/// [hop-by-hop headers]: http://www.w3.org/Protocols/rfc2616/rfc2616-sec13.html
+/// [RFC 7230 section 6.1]: https://datatracker.ietf.org/doc/html/rfc7230#section-6.1
+fn remove_hop_headers(
+    headers: &hyper::header::HeaderMap<hyper::header::HeaderValue>,
+) -> hyper::header::HeaderMap<hyper::header::HeaderValue> {
+    let connection_options = connection_options(
+        headers
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok()),
+    );
+    let mut result = hyper::HeaderMap::new();
+    for (k, v) in headers.iter() {
+        if !is_hop_header(k.as_str())
+            && !connection_options.iter().any(|option| option == k.as_str())
+        {
+            result.insert(k.clone(), v.clone());
+        }
+    }
+    result
+}
+
+/// The lowercase names of the headers listed in Connection header `values`.
+fn connection_options<'a>(values: impl Iterator<Item = &'a str>) -> Vec<String> {
+    values
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .collect()
+}
+
+/// The headers of a canister response which may be sent to the client: the hop-by-hop ones
+/// would desynchronize the connection with the client. A Content-Length disagreeing with
+/// the `body_length` actually sent, if known, is dropped as well.
+fn end_to_end_headers(headers: Vec<HeaderField>, body_length: Option<usize>) -> Vec<HeaderField> {
+    let connection_options = connection_options(
+        headers
+            .iter()
+            .filter(|HeaderField(name, _)| name.eq_ignore_ascii_case("connection"))
+            .map(|HeaderField(_, value)| value.as_str()),
+    );
+    headers
+        .into_iter()
+        .filter(|HeaderField(name, value)| {
+            let name = name.to_ascii_lowercase();
+            !is_hop_header(&name)
+                && !connection_options.contains(&name)
+                && (name != "content-length"
+                    || body_length.map_or(true, |length| value.trim() == length.to_string()))
+        })
+        .collect()
+}
+
+fn forward_uri<B>(forward_url: &str, req: &Request<B>) -> Result<Uri, ProxyError> {
+    let uri = Uri::from_str(forward_url)?;
+    let mut parts = Parts::from(uri);
+    parts.path_and_query = req.uri().path_and_query().cloned();
+
+    Ok(Uri::from_parts(parts)?)
+}
+
+/// Returns the client IP, with IPv4-mapped IPv6 addresses (as accepted on dual-stack
+/// sockets) converted back to IPv4.
+fn canonical_client_ip(client_ip: &IpAddr) -> IpAddr {
+    match client_ip {
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => IpAddr::V4(Ipv4Addr::new(
+                (high >> 8) as u8,
+                high as u8,
+                (low >> 8) as u8,
+                low as u8,
+            )),
+            _ => *client_ip,
+        },
+        IpAddr::V4(_) => *client_ip,
+    }
+}
+
+/// Returns the `for` parameter of a [Forwarded] header element. IPv6 addresses must be
+/// enclosed in brackets, which requires quoting.
+///
+/// [Forwarded]: https://datatracker.ietf.org/doc/html/rfc7239#section-6
+fn forwarded_for(client_ip: &IpAddr) -> String {
+    match client_ip {
+        IpAddr::V4(ip) => format!("for={}", ip),
+        IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+    }
+}
+
+/// Returns a [Forwarded] header element telling the client address, and the scheme and
+/// host it requested.
+///
+/// [Forwarded]: https://datatracker.ietf.org/doc/html/rfc7239#section-4
+fn forwarded_element(client_ip: &IpAddr, proto: &str, host: Option<&str>) -> String {
+    let mut element = format!("{};proto={}", forwarded_for(client_ip), proto);
+    if let Some(host) = host {
+        element.push_str(";host=");
+        element.push_str(&forwarded_value(host));
+    }
+    element
+}
+
+/// Returns a value of a Forwarded header parameter, quoted unless it is a token.
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        value.to_string()
+    } else {
+        let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("\"{}\"", escaped)
+    }
+}
+
+/// The `X-Forwarded-Proto` and `X-Forwarded-Host` headers telling canisters the scheme and
+/// host the client requested. Those set by a trusted peer are kept, as it knows better, e.g.
+/// when it terminates TLS. Otherwise requests are only accepted over plain HTTP.
+fn x_forwarded_proto_host(headers: &hyper::HeaderMap, is_trusted_peer: bool) -> Vec<HeaderField> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .filter(|_| is_trusted_peer)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+    };
+    let proto = header(X_FORWARDED_PROTO_HEADER)
+        .map(str::to_ascii_lowercase)
+        .filter(|proto| proto == "http" || proto == "https")
+        .unwrap_or_else(|| "http".to_string());
+    let host = header(X_FORWARDED_HOST_HEADER)
+        .or_else(|| headers.get("host").and_then(|host| host.to_str().ok()));
+
+    let mut forwarded = vec![HeaderField(X_FORWARDED_PROTO_HEADER.to_string(), proto)];
+    if let Some(host) = host {
+        forwarded.push(HeaderField(
+            X_FORWARDED_HOST_HEADER.to_string(),
+            host.to_string(),
+        ));
+    }
+    forwarded
+}
+
+/// Appends `value` to the list of values of a header, or sets it if absent.
+fn append_to_header(
+    headers: &mut hyper::HeaderMap,
+    name: &'static str,
+    value: &str,
+) -> Result<(), ProxyError> {
+    match headers.entry(name) {
+        hyper::header::Entry::Vacant(entry) => {
+            entry.insert(value.parse()?);
+        }
+
+        hyper::header::Entry::Occupied(mut entry) => {
+            let value = format!("{}, {}", entry.get().to_str()?, value);
+            entry.insert(value.parse()?);
+        }
+    }
+    Ok(())
+}
+
+fn create_proxied_request<B>(
+    client_ip: &IpAddr,
+    forward_url: &str,
+    mut request: Request<B>,
+    config: &ProxyConfig,
+) -> Result<Request<B>, ProxyError> {
+    // The scheme and host the client requested, before the request is pointed upstream.
+    // Requests are only accepted over plain HTTP.
+    let proto = request.uri().scheme_str().unwrap_or("http").to_string();
+    let host = request
+        .headers()
+        .get("host")
+        .and_then(|host| host.to_str().ok())
+        .map(str::to_string);
+    *request.headers_mut() = remove_hop_headers(request.headers());
+    telemetry::inject_context(request.headers_mut());
+    *request.uri_mut() = forward_uri(forward_url, &request)?;
+
+    let client_ip = canonical_client_ip(client_ip);
+
+    // Add forwarding information in the headers. X-Forwarded-For takes bare addresses,
+    // including IPv6 ones.
+    if config.x_forwarded_for_header {
+        append_to_header(
+            request.headers_mut(),
+            "x-forwarded-for",
+            &client_ip.to_string(),
+        )?;
+    }
+    if config.forwarded_header {
+        append_to_header(
+            request.headers_mut(),
+            "forwarded",
+            &forwarded_element(&client_ip, &proto, host.as_deref()),
+        )?;
+    }
+
+    Ok(request)
+}
+
+async fn forward_api(
+    ip_addr: &IpAddr,
+    request: Request<Body>,
+    replica_url: &str,
+    config: &ProxyConfig,
+) -> Result<Response<Body>, ProxyError> {
+    let proxied_request = create_proxied_request(ip_addr, replica_url, request, config)?;
+    let (parts, body) = proxied_request.into_parts();
+
+    let response = match config
+        .http_client
+        .request(parts.method, parts.uri.to_string())
+        .headers(parts.headers)
+        .body(reqwest::Body::wrap_stream(body))
+        .send()
+        .await
+    {
+        Ok(response) => response,
+        // Tell which upstream could not be reached, as both the replica and the proxy are
+        // forwarded to. The cause is shown without the URL of the request.
+        Err(e) if e.is_connect() => {
+            let cause =
+                std::error::Error::source(&e).map_or_else(|| e.to_string(), ToString::to_string);
+            return Err(ProxyError::ReplicaUnreachable(format!(
+                "{}: {}",
+                replica_url, cause
+            )));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    // Error responses are passed through as they are, for agents to tell what went wrong.
+    let status = response.status();
+    let headers = response.headers().clone();
+    let mut proxied_response = Response::new(Body::wrap_stream(response.bytes_stream()));
+    *proxied_response.status_mut() = status;
+    *proxied_response.headers_mut() = headers;
+    Ok(proxied_response)
+}
+
+/// Load every certificate of the PEM files at `paths`.
+fn load_ca_certs(paths: &[PathBuf]) -> anyhow::Result<Vec<reqwest::Certificate>> {
+    const END_CERTIFICATE: &str = "-----END CERTIFICATE-----";
+
+    let mut certs = vec![];
+    for path in paths {
+        let pem = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read CA certificate {}", path.display()))?;
+        let count = certs.len();
+        // A bundle is a concatenation of certificates, each parsed on its own.
+        for block in pem.split_inclusive(END_CERTIFICATE) {
+            if block.contains(END_CERTIFICATE) {
+                certs.push(
+                    reqwest::Certificate::from_pem(block.trim().as_bytes())
+                        .with_context(|| format!("Invalid CA certificate in {}", path.display()))?,
+                );
+            }
+        }
+        if certs.len() == count {
+            return Err(anyhow!("No certificate found in {}", path.display()));
+        }
+    }
+    Ok(certs)
+}
+
+/// Create the client for outbound connections, to replicas and the --proxy.
+fn create_http_client(
+    outbound_proxy: &OutboundProxyConfig,
+    ca_certs: Vec<reqwest::Certificate>,
+    accept_invalid_certs: bool,
+) -> anyhow::Result<reqwest::Client> {
+    // Responses are relayed to clients as they are, redirects included.
+    let mut builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .danger_accept_invalid_certs(accept_invalid_certs);
+    for cert in ca_certs {
+        builder = builder.add_root_certificate(cert);
+    }
+    let builder = if outbound_proxy.proxy().is_some() {
+        let outbound_proxy = outbound_proxy.clone();
+        builder.proxy(reqwest::Proxy::custom(move |url| {
+            outbound_proxy.proxy_for(url)
+        }))
+    } else {
+        // The proxy environment variables were already taken into account.
+        builder.no_proxy()
+    };
+    Ok(builder.build()?)
+}
+
+fn not_found() -> Result<Response<Body>, ProxyError> {
+    Ok(Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body("Not found".into())?)
+}
+
+/// The limits of the headers of canister responses, which are copied into the responses to
+/// clients.
+#[derive(Clone, Copy, Debug)]
+struct HeaderLimits {
+    max_headers: usize,
+    max_value_bytes: usize,
+    /// The limit of the bytes of all the names and values.
+    max_total_bytes: usize,
+}
+
+impl HeaderLimits {
+    /// Why `headers` go over the limits, if they do.
+    fn exceeded_by(&self, headers: &[HeaderField]) -> Option<&'static str> {
+        if headers.len() > self.max_headers {
+            return Some("too_many_headers");
+        }
+        if headers
+            .iter()
+            .any(|HeaderField(_, value)| value.len() > self.max_value_bytes)
+        {
+            return Some("header_value_too_large");
+        }
+        let total_bytes = headers
+            .iter()
+            .map(|HeaderField(name, value)| name.len() + value.len())
+            .sum::<usize>();
+        Some("headers_too_large").filter(|_| total_bytes > self.max_total_bytes)
+    }
+}
+
+/// The response serving the --maintenance-page `page`.
+fn maintenance_response(page: &Bytes) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(RETRY_AFTER, MAINTENANCE_RETRY_AFTER)
+        .header(CACHE_CONTROL, "no-store")
+        .body(Body::from(page.clone()))
+        .unwrap()
+}
+
+fn unable_to_fetch_root_key() -> Result<Response<Body>, ProxyError> {
+    Ok(Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body("Unable to fetch root key".into())?)
+}
+
+/// The configuration shared by every request handler, built once from [Opts] at startup.
+struct ProxyConfig {
+    dns_canister_config: DnsCanisterConfig,
+    /// The headers added to responses from canisters.
+    response_headers: ResponseHeaderConfig,
+    /// The canisters Host headers resolved to with the `dns_canister_config`.
+    hostname_cache: Option<HostnameCache>,
+    proxy_url: Option<String>,
+    fetch_root_key: bool,
+    debug: bool,
+    /// Whether the reason of a verification failure is kept from clients.
+    fail_closed: bool,
+    /// The value of the Server header added to responses, if any.
+    server_header: Option<HeaderValue>,
+    expose_canister_header: bool,
+    certification_policies: CertificationPolicyConfig,
+    /// The page served when the replica cannot be reached, if any.
+    maintenance_page: Option<Bytes>,
+    /// The header canisters opt out of verification with, if any.
+    uncertified_header: Option<String>,
+    /// The path prefix followed by a canister ID, always starting and ending with '/'.
+    canister_path_prefix: Option<String>,
+    canister_id_header: Option<HeaderName>,
+    /// The query parameters holding canister IDs, in the order they are looked for.
+    canister_id_params: Vec<String>,
+    /// Whether to resolve canister IDs from the Referer as a last resort.
+    referer_resolution: bool,
+    canonical_domains: CanonicalDomainConfig,
+    /// The path prefix of requests forwarded to the replica, always starting and ending
+    /// with '/'.
+    api_prefix: String,
+    /// The path prefix of requests forwarded to the proxy, always starting and ending with
+    /// '/'.
+    proxy_prefix: String,
+    certification_header: bool,
+    strict_canister_headers: bool,
+    strip_certification_headers: bool,
+    response_header_limits: HeaderLimits,
+    x_forwarded_for_header: bool,
+    forwarded_header: bool,
+    x_forwarded_proto_host: bool,
+    client_ip_header: Option<HeaderName>,
+    trusted_proxies: TrustedProxyConfig,
+    metrics: Arc<Metrics>,
+    /// The client for outbound connections, going through the outbound proxy if any.
+    http_client: reqwest::Client,
+    validation_config: ValidationConfig,
+    verification_cache: Option<VerificationCache>,
+    response_cache: Option<ResponseCache>,
+    /// Whether to set ETags on verified responses and answer conditional requests.
+    etag: bool,
+    passthrough_accept_encoding: bool,
+    /// The buffers bodies are decoded into for clients not accepting their encoding, and
+    /// request bodies for --decompress-request-body.
+    decode_buffers: Arc<BufferPool>,
+    decompress_request_body: bool,
+    /// The headers of requests not forwarded to canisters.
+    stripped_request_headers: Vec<HeaderName>,
+    /// The only headers of requests forwarded to canisters, if not empty.
+    allowed_request_headers: Vec<HeaderName>,
+    /// The maximum size of the body of a response, the streamed ones being cut short by
+    /// [StreamLimits::max_bytes].
+    max_response_bytes: Option<u64>,
+    stream_limits: StreamLimits,
+    stream_rate_limits: StreamRateLimitConfig,
+    max_concurrent_streams: usize,
+    /// How many bytes of a stream may wait for a slow client in memory before being spilled
+    /// to disk, if streams do not wait for clients.
+    stream_spill_threshold: Option<usize>,
+    /// The bytes spilled to disk across all streams, and how many may be at once.
+    spill_budget: BufferBudget,
+    /// One permit per response which may be streamed at once.
+    stream_permits: Arc<Semaphore>,
+    buffer_budget: BufferBudget,
+    /// The responses being streamed.
+    streams: Arc<StreamRegistry>,
+    /// The GET queries in flight, with their answer candid-encoded for every request
+    /// waiting for it to decode its own copy.
+    in_flight_queries: Option<SingleFlight<QueryKey, Bytes>>,
+    stream_overflow_policy: StreamOverflowPolicy,
+    stream_wait_timeout: Duration,
+    retry_policy: RetryPolicy,
+    update_poll_timeout: Duration,
+    update_poll_interval: Duration,
+    request_timeout: Option<Duration>,
+    /// The domains whose responses are not verified, lowercased.
+    raw_domains: Vec<String>,
+    /// The methods canister requests may use, or empty to allow all of them.
+    allowed_methods: Vec<Method>,
+    cors: CorsConfig,
+    /// How long a request may take before it is logged as slow, if at all.
+    slow_request_threshold: Option<Duration>,
+}
+
+impl ProxyConfig {
+    fn new(opts: &Opts) -> anyhow::Result<ProxyConfig> {
+        let server_header = match opts.server_header.as_deref() {
+            _ if opts.no_server_header => None,
+            Some("off") => None,
+            Some(value) => Some(
+                HeaderValue::from_str(value)
+                    .with_context(|| format!(r#"Invalid --server-header "{}""#, value))?,
+            ),
+            None => Some(HeaderValue::from_str(&format!(
+                "icx-proxy/{}",
+                crate_version!()
+            ))?),
+        };
+
+        let api_prefix = format!("/{}/", opts.api_prefix.trim_matches('/'));
+        let proxy_prefix = format!("/{}/", opts.proxy_prefix.trim_matches('/'));
+        if api_prefix == "//" || proxy_prefix == "//" {
+            return Err(anyhow!("The API and proxy prefixes cannot be empty"));
+        }
+        if api_prefix.starts_with(&proxy_prefix) || proxy_prefix.starts_with(&api_prefix) {
+            return Err(anyhow!(
+                r#"The API prefix "{}" and the proxy prefix "{}" overlap"#,
+                api_prefix,
+                proxy_prefix
+            ));
+        }
+
+        let metrics = Arc::<Metrics>::default();
+        let allowed_request_headers = parse_header_names(&opts.allow_request_header)?;
+        let dns_canister_config = DnsCanisterConfig::new(&opts.dns_alias, &opts.dns_suffix)?;
+        Ok(ProxyConfig {
+            response_headers: ResponseHeaderConfig::new(
+                &opts.add_response_header,
+                &opts.dns_alias_response_header,
+                &dns_canister_config,
+            )?,
+            dns_canister_config,
+            hostname_cache: if opts.hostname_cache_size > 0 {
+                Some(HostnameCache::new(
+                    opts.hostname_cache_size,
+                    Duration::from_secs(opts.hostname_cache_negative_ttl),
+                ))
+            } else {
+                None
+            },
+            proxy_url: opts.proxy.clone(),
+            fetch_root_key: opts.fetch_root_key,
+            debug: opts.debug,
+            fail_closed: !opts.no_fail_closed,
+            server_header,
+            expose_canister_header: opts.expose_canister_header,
+            uncertified_header: opts.uncertified_header.clone(),
+            maintenance_page: opts
+                .maintenance_page
+                .as_ref()
+                .map(|path| {
+                    fs::read(path).map(Bytes::from).with_context(|| {
+                        format!("Unable to read the maintenance page {}", path.display())
+                    })
+                })
+                .transpose()?,
+            certification_policies: CertificationPolicyConfig::new(
+                &opts.certification_policy,
+                opts.certification_policy_file.as_deref(),
+                opts.default_certification_policy,
+            )?,
+            canister_path_prefix: opts
+                .canister_path_prefix
+                .as_ref()
+                .map(|prefix| format!("/{}/", prefix.trim_matches('/'))),
+            api_prefix,
+            proxy_prefix,
+            canister_id_header: opts
+                .canister_id_header
+                .as_deref()
+                .map(|name| {
+                    HeaderName::from_str(name)
+                        .with_context(|| format!(r#"Invalid canister ID header "{}""#, name))
+                })
+                .transpose()?,
+            canister_id_params: opts.canister_id_param.clone(),
+            referer_resolution: !opts.disable_referer_resolution,
+            canonical_domains: CanonicalDomainConfig::new(&opts.canonical_domain)?,
+            certification_header: !opts.no_certification_header,
+            strict_canister_headers: opts.strict_canister_headers,
+            strip_certification_headers: opts.strip_certification_headers,
+            response_header_limits: HeaderLimits {
+                max_headers: opts.max_response_headers,
+                max_value_bytes: opts.max_response_header_value_bytes,
+                max_total_bytes: opts.max_response_header_bytes,
+            },
+            x_forwarded_for_header: !opts.no_x_forwarded_for_header,
+            x_forwarded_proto_host: opts.x_forwarded_proto_host,
+            forwarded_header: opts.forwarded_header,
+            client_ip_header: opts
+                .client_ip_header
+                .as_deref()
+                .map(|name| {
+                    HeaderName::from_str(name)
+                        .with_context(|| format!(r#"Invalid client IP header "{}""#, name))
+                })
+                .transpose()?,
+            trusted_proxies: TrustedProxyConfig::new(&opts.trusted_proxy)?,
+            buffer_budget: BufferBudget::new(
+                opts.max_buffered_bytes,
+                metrics.buffered_bytes.clone(),
+            ),
+            metrics,
+            http_client: create_http_client(
+                &OutboundProxyConfig::new(opts.outbound_proxy.as_deref(), &opts.no_proxy)?,
+                load_ca_certs(&opts.replica_ca_cert)?,
+                opts.danger_accept_invalid_replica_certs,
+            )?,
+            validation_config: ValidationConfig {
+                spa_fallback: format!("/{}", opts.spa_fallback.trim_start_matches('/')),
+                dump_trees_on_failure: opts.dump_trees_on_failure,
+                max_certificate_age: opts.max_certificate_age.map(Duration::from_secs),
+            },
+            verification_cache: if opts.verification_cache_size > 0 {
+                Some(VerificationCache::new(
+                    opts.verification_cache_size,
+                    Duration::from_secs(opts.verification_cache_ttl),
+                ))
+            } else {
+                None
+            },
+            etag: !opts.no_etag,
+            passthrough_accept_encoding: opts.passthrough_accept_encoding,
+            decode_buffers: BufferPool::new(DECODE_BUFFERS, DECODE_BUFFER_MAX_CAPACITY),
+            decompress_request_body: opts.decompress_request_body,
+            stripped_request_headers: stripped_request_headers(
+                &opts.strip_request_header,
+                &allowed_request_headers,
+            )?,
+            allowed_request_headers,
+            response_cache: if opts.cache_size_bytes > 0 {
+                Some(ResponseCache::new(
+                    opts.cache_size_bytes,
+                    Duration::from_secs(opts.cache_ttl),
+                    opts.max_certificate_age.map(Duration::from_secs),
+                ))
+            } else {
+                None
+            },
+            max_response_bytes: opts.max_response_bytes,
+            stream_limits: StreamLimits {
+                max_callbacks: opts.max_stream_callbacks,
+                max_bytes: match (opts.max_stream_bytes, opts.max_response_bytes) {
+                    (Some(max_stream_bytes), Some(max_response_bytes)) => {
+                        Some(max_stream_bytes.min(max_response_bytes))
+                    }
+                    (max_stream_bytes, max_response_bytes) => {
+                        max_stream_bytes.or(max_response_bytes)
+                    }
+                },
+                callback_timeout: Duration::from_secs(opts.stream_callback_timeout),
+                callback_retries: RetryPolicy {
+                    attempts: opts.stream_callback_retries,
+                    base_delay: Duration::from_millis(opts.retry_base_delay),
+                },
+                max_duration: opts.max_stream_duration.map(Duration::from_secs),
+                keepalive_interval: opts.stream_keepalive_interval.map(Duration::from_secs),
+                rate_limit: None,
+            },
+            stream_rate_limits: StreamRateLimitConfig::new(
+                &opts.canister_stream_rate_limit,
+                opts.stream_rate_limit,
+            )?,
+            max_concurrent_streams: opts.max_concurrent_streams,
+            stream_spill_threshold: opts.stream_spill_threshold,
+            spill_budget: BufferBudget::new(opts.max_spill_bytes, Arc::default()),
+            stream_permits: Arc::new(Semaphore::new(opts.max_concurrent_streams)),
+            streams: Arc::new(StreamRegistry::new()),
+            in_flight_queries: if opts.max_coalesced_requests > 0 {
+                Some(SingleFlight::new(opts.max_coalesced_requests))
+            } else {
+                None
+            },
+            stream_overflow_policy: opts.stream_overflow_policy,
+            stream_wait_timeout: Duration::from_millis(opts.stream_wait_timeout),
+            retry_policy: RetryPolicy {
+                attempts: opts.retry_attempts,
+                base_delay: Duration::from_millis(opts.retry_base_delay),
+            },
+            update_poll_timeout: Duration::from_secs(opts.update_poll_timeout),
+            request_timeout: opts.request_timeout.map(Duration::from_secs),
+            update_poll_interval: Duration::from_millis(opts.update_poll_interval),
+            raw_domains: opts
+                .raw_domain
+                .iter()
+                .map(|domain| domain.trim_end_matches('.').to_ascii_lowercase())
+                .collect(),
+            slow_request_threshold: Some(opts.slow_request_threshold_ms)
+                .filter(|threshold| *threshold > 0)
+                .map(Duration::from_millis),
+            cors: CorsConfig::new(opts.cors, &opts.cors_allowed_origin)?,
+            allowed_methods: opts
+                .allowed_methods
+                .iter()
+                .map(|method| {
+                    let method = Method::from_str(&method.to_ascii_uppercase())
+                        .with_context(|| format!(r#"Invalid method "{}""#, method))?;
+                    if is_always_rejected(&method) {
+                        return Err(anyhow!("The {} method cannot be allowed", method));
+                    }
+                    Ok(method)
+                })
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    /// The address of the client of a request from `peer`: the one in the client IP header
+    /// if the peer is a trusted proxy and it is valid, or the address of the peer.
+    fn client_ip(&self, peer: IpAddr, headers: &hyper::HeaderMap) -> IpAddr {
+        let peer = canonical_client_ip(&peer);
+        self.client_ip_header
+            .as_ref()
+            .filter(|_| self.trusted_proxies.is_trusted(&peer))
+            .and_then(|name| headers.get(name))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<IpAddr>().ok())
+            .unwrap_or(peer)
+    }
+
+    /// Whether the request header `name` is forwarded to canisters.
+    fn forwards_request_header(&self, name: &HeaderName) -> bool {
+        !self.stripped_request_headers.contains(name)
+            && (self.allowed_request_headers.is_empty()
+                || self.allowed_request_headers.contains(name))
+    }
+
+    /// Where a request to `path` is routed to.
+    fn route(&self, path: &str) -> Route {
+        if path.starts_with(&self.api_prefix) {
+            Route::Replica
+        } else if path.starts_with(&self.proxy_prefix) {
+            Route::Proxy
+        } else {
+            Route::Canister
+        }
+    }
+
+    /// Whether a request with `method` is served. CONNECT and TRACE requests never are, and
+    /// requests to canisters only with the allowed methods.
+    fn allows_method(&self, method: &Method, is_canister_request: bool) -> bool {
+        !is_always_rejected(method)
+            && (!is_canister_request
+                || self.allowed_methods.is_empty()
+                || self.allowed_methods.contains(method))
+    }
+
+    /// Whether responses served for the `host` of a request are not verified.
+    fn is_raw_domain(&self, host: &str) -> bool {
+        let host = match Uri::from_str(host) {
+            Ok(uri) => uri
+                .host()
+                .map(|host| host.trim_end_matches('.').to_ascii_lowercase()),
+            Err(_) => None,
+        };
+        host.map_or(false, |host| self.raw_domains.contains(&host))
+    }
+}
+
+/// Where a request is routed to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Route {
+    /// The replica API, under the --api-prefix.
+    Replica,
+    /// The --proxy, under the --proxy-prefix.
+    Proxy,
+    /// A canister, for every other path.
+    Canister,
+}
+
+/// Replace the `prefix` of the path of `request` by `replacement`, so that upstreams see the
+/// paths they serve whatever the prefixes requests are routed by. Prefixes are whole path
+/// segments, with or without a trailing slash, and an empty or `/` prefix is the one of
+/// every path.
+fn replace_path_prefix<B>(
+    request: &mut Request<B>,
+    prefix: &str,
+    replacement: &str,
+) -> Result<(), ProxyError> {
+    let prefix = prefix.trim_end_matches('/');
+    let replacement = replacement.trim_end_matches('/');
+    let path_and_query = match request
+        .uri()
+        .path_and_query()
+        .and_then(|path_and_query| path_and_query.as_str().strip_prefix(prefix))
+    {
+        Some(rest) if prefix != replacement && rest.starts_with('/') => {
+            format!("{}{}", replacement, rest)
+        }
+        Some(rest) if prefix != replacement && (rest.is_empty() || rest.starts_with('?')) => {
+            format!("{}/{}", replacement, rest)
+        }
+        _ => return Ok(()),
+    };
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::from_str(&path_and_query)?);
+    *request.uri_mut() = Uri::from_parts(parts)?;
+    Ok(())
+}
+
+/// CONNECT and TRACE have semantics which do not map onto canister calls, and TRACE would
+/// echo headers back, so they are never forwarded.
+fn is_always_rejected(method: &Method) -> bool {
+    method == Method::CONNECT || method == Method::TRACE
+}
+
+/// Whether a request is a CORS preflight, which browsers send before cross-origin requests
+/// which are not simple.
+fn is_cors_preflight<B>(request: &Request<B>) -> bool {
+    request.method() == Method::OPTIONS
+        && request.headers().contains_key(ORIGIN)
+        && request
+            .headers()
+            .contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// The answer of the proxy to a CORS preflight, which spares a round trip to canisters
+/// which mostly do not implement OPTIONS.
+fn cors_preflight<B>(
+    request: &Request<B>,
+    config: &ProxyConfig,
+) -> Result<Response<Body>, ProxyError> {
+    match config
+        .cors
+        .preflight_headers(request.headers(), &config.allowed_methods)
+    {
+        Some(headers) => {
+            let mut response = Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())?;
+            *response.headers_mut() = headers;
+            Ok(response)
+        }
+        None => Ok(Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body("Origin not allowed".into())?),
+    }
+}
+
+fn method_not_allowed(config: &ProxyConfig) -> Result<Response<Body>, ProxyError> {
+    let mut builder = Response::builder().status(StatusCode::METHOD_NOT_ALLOWED);
+    if !config.allowed_methods.is_empty() {
+        let allowed = config
+            .allowed_methods
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        builder = builder.header("allow", allowed);
+    }
+    Ok(builder.body("Method not allowed".into())?)
+}
+
+/// The response telling the client about `err`. Only browsers visiting canisters are shown
+/// the maintenance page: agents calling the replica API are told the error itself.
+fn error_response(
+    err: &ProxyError,
+    is_canister_request: bool,
+    config: &ProxyConfig,
+    logger: &slog::Logger,
+) -> Response<Body> {
+    if err.is_internal() {
+        slog::warn!(logger, "Internal Error during request:\n{:#?}", err);
+    }
+    let maintenance_page = config
+        .maintenance_page
+        .as_ref()
+        .filter(|_| is_canister_request);
+    let mut response = match (err, maintenance_page) {
+        (ProxyError::ReplicaUnreachable(_), Some(page)) => maintenance_response(page),
+        // The reason of a failure is only told to clients with --debug and --no-fail-closed.
+        (ProxyError::Verification(reason), _) if config.fail_closed || !config.debug => {
+            slog::warn!(logger, "Response verification failed: {}", reason);
+            ProxyError::Verification(VERIFICATION_FAILED.to_string()).response(config.debug)
+        }
+        _ => err.response(config.debug),
+    };
+    // Nothing of a response failing verification reaches the client but the error.
+    if matches!(err, ProxyError::Verification(_)) && config.certification_header {
+        response.headers_mut().insert(
+            X_IC_CERTIFIED_HEADER,
+            HeaderValue::from_static(Certification::Unverified.header_value()),
+        );
+    }
+    response
+}
+
+/// Add the headers every response carries, whether it comes from a canister or the proxy,
+/// unless it already has them.
+fn finalize_response(response: &mut Response<Body>, config: &ProxyConfig) {
+    let headers = response.headers_mut();
+    if let Some(server_header) = &config.server_header {
+        headers
+            .entry(SERVER)
+            .or_insert_with(|| server_header.clone());
+    }
+    if !headers.contains_key(DATE) {
+        if let Ok(date) = HeaderValue::from_str(&httpdate::fmt_http_date(SystemTime::now())) {
+            headers.insert(DATE, date);
+        }
+    }
+}
+
+async fn handle_request(
+    ip_addr: IpAddr,
+    mut request: Request<Body>,
+    replica_url: String,
+    config: Arc<ProxyConfig>,
+    logger: slog::Logger,
+) -> Result<Response<Body>, Infallible> {
+    let start = Instant::now();
+    let deadline = config
+        .request_timeout
+        .map(|timeout| tokio::time::Instant::now() + timeout);
+    if config
+        .trusted_proxies
+        .is_trusted(&canonical_client_ip(&ip_addr))
+    {
+        request.extensions_mut().insert(TrustedPeer);
+    }
+    let ip_addr = config.client_ip(ip_addr, request.headers());
+    let path = request.uri().path().to_string();
+    let method = request.method().clone();
+    let replica = replica_url.clone();
+    let route = config.route(request.uri().path());
+    let is_canister_request = route == Route::Canister;
+    let origin = request
+        .headers()
+        .get(ORIGIN)
+        .filter(|_| is_canister_request)
+        .cloned();
+    let cx = telemetry::start_request(request.headers(), request.method(), &path);
+    let handling = async {
+        if is_canister_request && is_cors_preflight(&request) && config.cors.answers_preflights() {
+            slog::debug!(
+                logger,
+                "Answering a CORS preflight to path '{}'",
+                &request.uri().path()
+            );
+            cors_preflight(&request, &config)
+        } else if !config.allows_method(request.method(), is_canister_request) {
+            slog::debug!(
+                logger,
+                "Rejecting a {} request to path '{}'",
+                request.method(),
+                &request.uri().path()
+            );
+            method_not_allowed(&config)
+        } else if route == Route::Replica {
+            slog::debug!(
+                logger,
+                "URI Request to path '{}' being forwarded to Replica",
+                &request.uri().path()
+            );
+            match replace_path_prefix(&mut request, &config.api_prefix, "/api/") {
+                Ok(()) => forward_api(&ip_addr, request, &replica_url, &config).await,
+                Err(e) => Err(e),
+            }
+        } else if route == Route::Proxy {
+            if let Some(proxy_url) = &config.proxy_url {
+                slog::debug!(
+                    logger,
+                    "URI Request to path '{}' being forwarded to proxy",
+                    &request.uri().path(),
+                );
+                match replace_path_prefix(&mut request, &config.proxy_prefix, "/_/") {
+                    Ok(()) => forward_api(&ip_addr, request, proxy_url, &config).await,
+                    Err(e) => Err(e),
+                }
+            } else {
+                slog::warn!(
+                    logger,
+                    "Unable to proxy {} because no --proxy is configured",
+                    &request.uri().path()
+                );
+                not_found()
+            }
+        } else {
+            if let Some(deadline) = deadline {
+                request.extensions_mut().insert(Deadline(deadline));
+            }
+            request.extensions_mut().insert(RequestStart {
+                at: start,
+                logger: logger.new(slog::o!(
+                    "path" => path.clone(),
+                    "client_ip" => ip_addr.to_string(),
+                    "method" => method.to_string(),
+                    "replica" => replica.clone(),
+                )),
+            });
+            let agent = Arc::new(canister_agent(replica_url, &config, deadline));
+            if config.fetch_root_key && agent.fetch_root_key().await.is_err() {
+                unable_to_fetch_root_key()
+            } else {
+                until_deadline(
+                    deadline,
+                    forward_request(request, agent, &config, logger.clone()),
+                )
+                .await
+                .unwrap_or(Err(ProxyError::Timeout))
+            }
+        }
+    };
+    // Through the trait, as anyhow::Context has a with_context method too.
+    let mut response =
+        match opentelemetry::trace::FutureExt::with_context(handling, cx.clone()).await {
+            Err(err) => error_response(&err, is_canister_request, &config, &logger),
+            Ok(x) => x,
+        };
+
+    if is_canister_request {
+        config.cors.apply(origin.as_ref(), response.headers_mut());
+    }
+    finalize_response(&mut response, &config);
+
+    telemetry::end_request(
+        &cx,
+        response.status().as_u16(),
+        response.extensions().get::<Principal>(),
+    );
+
+    // Streamed bodies may still be sent after this point, which is not accounted for.
+    let duration = start.elapsed();
+    let logger = logger.new(slog::o!(
+        "duration_ms" => duration.as_millis() as u64,
+        "status" => response.status().as_u16(),
+        "canister_id" => response.extensions().get::<Principal>().map(Principal::to_text),
+        "path" => path,
+        "client_ip" => ip_addr.to_string(),
+    ));
+    if is_canister_request {
+        slog::info!(logger, "Request completed");
+    } else {
+        slog::debug!(logger, "Request completed");
+    }
+    // The phases of streamed responses are told once their stream ended.
+    if response.extensions().get::<Streamed>().is_none() {
+        let logger = logger.new(slog::o!(
+            "method" => method.to_string(),
+            "replica" => replica,
+        ));
+        warn_if_slow(config.slow_request_threshold, duration, &cx, &logger);
+    }
+
+    Ok(response)
+}
+
+/// Log a warning with the time spent in each phase of a request, timed in `cx`, if its
+/// `duration` is over the --slow-request-threshold-ms `threshold`.
+fn warn_if_slow(
+    threshold: Option<Duration>,
+    duration: Duration,
+    cx: &opentelemetry::Context,
+    logger: &slog::Logger,
+) {
+    if threshold.map_or(false, |threshold| duration >= threshold) {
+        let (phases, slowest_phase) = describe_phases(&telemetry::phase_timings(cx));
+        slog::warn!(logger, "Slow request";
+            "phases" => phases,
+            "slowest_phase" => slowest_phase,
+        );
+    }
+}
+
+/// Describe the time spent in the phases of a request, and tell the one it spent the most
+/// time in.
+fn describe_phases(timings: &[(&'static str, Duration)]) -> (String, &'static str) {
+    let phases = timings
+        .iter()
+        .map(|(phase, duration)| format!("{}={}ms", phase, duration.as_millis()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let slowest_phase = timings
+        .iter()
+        .max_by_key(|(_, duration)| *duration)
+        .map_or("none", |(phase, _)| phase);
+    (phases, slowest_phase)
+}
+
+/// The first of `replicas` on a domain of the mainnet boundary nodes, if any.
+fn mainnet_replica(replicas: &[String]) -> Option<&str> {
+    replicas
+        .iter()
+        .find(|replica| {
+            let host = match Uri::from_str(replica).ok().and_then(|uri| {
+                uri.host()
+                    .map(|host| host.trim_end_matches('.').to_ascii_lowercase())
+            }) {
+                Some(host) => host,
+                None => return false,
+            };
+            MAINNET_DOMAINS.iter().any(|domain| {
+                host == *domain
+                    || host
+                        .strip_suffix(domain)
+                        .map_or(false, |subdomain| subdomain.ends_with('.'))
+            })
+        })
+        .map(String::as_str)
+}
+
+/// The headers of requests not forwarded to canisters: the `stripped` ones, and the
+/// sensitive ones which are not `allowed` explicitly.
+fn stripped_request_headers(
+    stripped: &[String],
+    allowed: &[HeaderName],
+) -> anyhow::Result<Vec<HeaderName>> {
+    let mut headers = parse_header_names(stripped)?;
+    headers.extend(
+        SENSITIVE_REQUEST_HEADERS
+            .iter()
+            .map(|name| HeaderName::from_static(name))
+            .filter(|name| !allowed.contains(name)),
+    );
+    Ok(headers)
+}
+
+fn parse_header_names(names: &[String]) -> anyhow::Result<Vec<HeaderName>> {
+    names
+        .iter()
+        .map(|name| {
+            HeaderName::from_str(name.trim())
+                .with_context(|| format!(r#"Invalid header name "{}""#, name))
+        })
+        .collect()
+}
+
+/// Print the effective routing configuration, for --check-config.
+fn print_config(config: &ProxyConfig, format: &str) {
+    let dns_canister_config = &config.dns_canister_config;
+    let warnings = dns_canister_config.warnings();
+
+    if format == "json" {
+        let aliases = dns_canister_config
+            .aliases()
+            .map(|(domain_name, principal)| {
+                serde_json::json!({ "domain": domain_name, "canister_id": principal.to_text() })
+            })
+            .collect::<Vec<_>>();
+        let output = serde_json::json!({
+            "dns_aliases": aliases,
+            "dns_suffixes": dns_canister_config.suffixes().collect::<Vec<_>>(),
+            "warnings": warnings,
+        });
+        println!("{:#}", output);
+        return;
+    }
+
+    println!("DNS aliases:");
+    for (domain_name, principal) in dns_canister_config.aliases() {
+        println!("  {} => {}", domain_name, principal);
+    }
+    println!("DNS suffixes:");
+    for suffix in dns_canister_config.suffixes() {
+        println!("  {}", suffix);
+    }
+    println!("Warnings:");
+    for warning in &warnings {
+        println!("  {}", warning);
+    }
+}
+
+/// A summary of the effective configuration, one setting per line, for operators to
+/// confirm what the process loaded.
+fn describe_config(opts: &Opts, config: &ProxyConfig) -> String {
+    let verification = if cfg!(feature = "skip_body_verification") {
+        "disabled (built with skip_body_verification)".to_string()
+    } else {
+        format!(
+            "enabled (default policy {})",
+            format!("{:?}", opts.default_certification_policy).to_lowercase()
+        )
+    };
+    let settings = [
+        ("version", crate_version!().to_string()),
+        ("listening on", format!("http://{}/", opts.address)),
+        (
+            "metrics on",
+            opts.metrics_addr
+                .map_or("-".to_string(), |addr| format!("http://{}/metrics", addr)),
+        ),
+        (
+            "admin on",
+            opts.admin_addr
+                .map_or("-".to_string(), |addr| format!("http://{}/", addr)),
+        ),
+        ("replicas", opts.replica.join(", ")),
+        (
+            "proxy",
+            opts.proxy.clone().unwrap_or_else(|| "-".to_string()),
+        ),
+        (
+            "fetch root key",
+            if opts.fetch_root_key { "yes" } else { "no" }.to_string(),
+        ),
+        ("verification", verification),
+        (
+            "DNS aliases",
+            config.dns_canister_config.aliases().count().to_string(),
+        ),
+        (
+            "DNS suffixes",
+            config.dns_canister_config.suffixes().count().to_string(),
+        ),
+    ];
+    settings
+        .iter()
+        .map(|(name, value)| format!("  {:<16}{}", format!("{}:", name), value))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Configure the client-facing server according to the command-line options.
+fn server_builder<I>(incoming: I, opts: &Opts) -> hyper::server::Builder<I> {
+    Server::builder(incoming)
+        .http1_keepalive(!opts.disable_http_keepalive)
+        .http1_only(opts.http1_only)
+}
+
+/// Answer a request from `ip_addr` with `response`, unless its connection is used past its
+/// limits. HTTP/1 connections are closed once the request reaching a limit is answered.
+/// HTTP/2 has no way to tell clients to close a connection, so requests past the limit of
+/// requests are refused and the connection is dropped.
+async fn within_connection_limits(
+    ip_addr: IpAddr,
+    usage: &ConnectionUsage,
+    response: impl Future<Output = Result<Response<Body>, Infallible>>,
+    logger: &slog::Logger,
+) -> Result<Response<Body>, Infallible> {
+    match usage.answer() {
+        Admission::Keep => response.await,
+        Admission::Close => {
+            slog::debug!(
+                logger,
+                "Closing a connection from {} which reached its limits",
+                ip_addr
+            );
+            let mut response = response.await?;
+            response
+                .headers_mut()
+                .insert(CONNECTION, HeaderValue::from_static("close"));
+            Ok(response)
+        }
+        Admission::Refuse => {
+            slog::debug!(
+                logger,
+                "Dropping a connection from {} past its limit of requests",
+                ip_addr
+            );
+            Ok(
+                ProxyError::Overloaded("The connection reached its limit of requests".to_string())
+                    .response(false),
+            )
+        }
+    }
+}
+
+/// Run the proxy with the options of the command line, until it is shut down.
+pub fn run() -> Result<(), Box<dyn Error>> {
+    let opts: Opts = Opts::parse();
+
+    if opts.check_config {
+        let config = ProxyConfig::new(&opts).map_err(|e| {
+            eprintln!("Invalid configuration: {}", e);
+            e
+        })?;
+        print_config(&config, &opts.check_config_format);
+        return Ok(());
+    }
+
+    // The guard flushes the queued log records when main returns.
+    let (logger, _log_guard) = logging::setup_logging(&opts);
+
+    if cfg!(feature = "skip_body_verification") {
+        if opts.require_verification {
+            slog::crit!(
+                logger,
+                "This binary was built with the skip_body_verification feature, which --require-verification forbids."
+            );
+            return Err("verification is disabled in this build".into());
+        }
+        slog::warn!(
+            logger,
+            "This binary was built with the skip_body_verification feature: responses failing verification are served anyway."
+        );
+    }
+
+    if opts.danger_accept_invalid_replica_certs {
+        slog::warn!(
+            logger,
+            "Accepting invalid TLS certificates from replicas. Do not use this in production."
+        );
+    }
+
+    if opts.fetch_root_key && !opts.i_know_this_is_insecure {
+        if let Some(replica) = mainnet_replica(&opts.replica) {
+            slog::crit!(
+                logger,
+                "Refusing to fetch the root key from {}, which looks like a mainnet boundary node: a replica answering with its own root key could forge any response. Pass --i-know-this-is-insecure to do so anyway.",
+                replica
+            );
+            return Err("--fetch-root-key is not safe with mainnet replicas".into());
+        }
+    }
+
+    // The replicas requests are sent to, round-robin. The list never changes, so it is
+    // read without locking.
+    let replicas = opts.replica.clone();
+    if replicas.is_empty() {
+        slog::crit!(logger, "At least one --replica is required.");
+        return Err("no replica to forward requests to".into());
+    }
+
+    let config = Arc::new(ProxyConfig::new(&opts)?);
+
+    // Start at a random replica, so that proxies restarted together do not all send their
+    // first requests to the same one.
+    let counter = AtomicUsize::new(rand::thread_rng().gen_range(0..replicas.len()));
+
+    let connection_limits = ConnectionLimits {
+        max_requests: opts.max_requests_per_connection,
+        max_bytes: opts.max_bytes_per_connection,
+    };
+
+    let service = make_service_fn(|socket: &IdleTimeout<Metered<AddrStream>>| {
+        let ip_addr = socket.get_ref().get_ref().remote_addr();
+        let ip_addr = ip_addr.ip();
+        let config = config.clone();
+        let logger = logger.clone();
+        let usage = Arc::new(ConnectionUsage::new(
+            connection_limits,
+            socket.get_ref().bytes(),
+            socket.get_ref().dropped(),
+        ));
+
+        // Select an agent.
+        let count = counter.fetch_add(1, Ordering::Relaxed);
+        let replica_url = replicas[count % replicas.len()].clone();
+        slog::debug!(logger, "Replica URL: {}", replica_url);
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let logger = logger.clone();
+                let config = config.clone();
+                let usage = usage.clone();
+                let response =
+                    handle_request(ip_addr, req, replica_url.clone(), config, logger.clone());
+                async move { within_connection_limits(ip_addr, &usage, response, &logger).await }
+            }))
+        }
+    });
+
+    slog::info!(
+        logger,
+        "Starting server.\n{}",
+        describe_config(&opts, &config)
+    );
+
+    if opts.worker_threads == Some(0) || opts.blocking_threads == Some(0) {
+        return Err("--worker-threads and --blocking-threads must be at least 1".into());
+    }
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(worker_threads) = opts.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    if let Some(blocking_threads) = opts.blocking_threads {
+        runtime.max_blocking_threads(blocking_threads);
+    }
+    let runtime = runtime.enable_all().build()?;
+    runtime.block_on(async {
+        telemetry::init(opts.otlp_endpoint.as_deref())?;
+
+        let metrics = config.metrics.clone();
+        tokio::spawn(async move {
+            metrics
+                .probe_event_loop_lag(EVENT_LOOP_LAG_PROBE_INTERVAL)
+                .await
+        });
+
+        if let Some(metrics_addr) = opts.metrics_addr {
+            let config = config.clone();
+            let metrics_service = make_service_fn(move |_| {
+                let config = config.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        let config = config.clone();
+                        async move { handle_metrics_request(req, &config.metrics).await }
+                    }))
+                }
+            });
+            let metrics_server = Server::bind(&metrics_addr).serve(metrics_service);
+            slog::info!(logger, "Serving metrics on http://{}/metrics", metrics_addr);
+            let logger = logger.clone();
+            tokio::spawn(async move {
+                if let Err(e) = metrics_server.await {
+                    slog::error!(logger, "Metrics server failed: {}", e);
+                }
+            });
+        }
+
+        if let Some(admin_addr) = opts.admin_addr {
+            let config = config.clone();
+            let admin_service = make_service_fn(move |_| {
+                let config = config.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        let response = if req.uri().path() == "/cache/purge" {
+                            Ok(handle_purge_request(&req, config.response_cache.as_ref()))
+                        } else {
+                            not_found()
+                        };
+                        let response = response.unwrap_or_else(|err| err.response(false));
+                        async move { Ok::<_, Infallible>(response) }
+                    }))
+                }
+            });
+            let admin_server = Server::bind(&admin_addr).serve(admin_service);
+            slog::info!(logger, "Serving the admin API on http://{}/", admin_addr);
+            let logger = logger.clone();
+            tokio::spawn(async move {
+                if let Err(e) = admin_server.await {
+                    slog::error!(logger, "Admin server failed: {}", e);
+                }
+            });
+        }
+
+        if opts.certification_summary_interval > 0 {
+            let config = config.clone();
+            let logger = logger.clone();
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(opts.certification_summary_interval));
+            tokio::spawn(async move {
+                // The first tick completes immediately.
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    config.metrics.log_summary(&logger);
+                }
+            });
+        }
+
+        let mut incoming = AddrIncoming::bind(&opts.address)?;
+        incoming.set_keepalive(opts.http_keepalive_timeout.map(Duration::from_secs));
+        let idle_timeout = opts.http_idle_timeout.map(Duration::from_secs);
+        let max_bytes_per_connection = opts.max_bytes_per_connection;
+        let incoming = accept::from_stream(futures::stream::poll_fn(move |cx| {
+            Pin::new(&mut incoming).poll_accept(cx).map_ok(|stream| {
+                IdleTimeout::new(Metered::new(stream, max_bytes_per_connection), idle_timeout)
+            })
+        }));
+        let shutdown_timeout = Duration::from_secs(opts.shutdown_timeout);
+        let server = server_builder(incoming, &opts)
+            .serve(service)
+            .with_graceful_shutdown({
+                let config = config.clone();
+                let logger = logger.clone();
+                async move {
+                    let _ = tokio::signal::ctrl_c().await;
+                    // The server waits for the connections streaming responses, so the
+                    // streams are drained, then cut short, alongside.
+                    let active = config.streams.active();
+                    if active > 0 {
+                        slog::info!(
+                            logger,
+                            "Shutting down, waiting for {} streams to end",
+                            active
+                        );
+                        tokio::spawn(async move {
+                            let cut_short = config.streams.shutdown(shutdown_timeout).await;
+                            if cut_short > 0 {
+                                slog::warn!(logger, "Cut {} streams short on shutdown", cut_short);
+                            }
+                        });
+                    }
+                }
+            });
+        server.await?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection_usage::{ConnectionLimits, ConnectionUsage, Metered};
+    use crate::mock_replica::{
+        canned, CannedCallbackResponse, CannedResponse, CannedStreamingStrategy, CannedToken,
+        MockReplica,
+    };
+    use crate::telemetry;
+    use crate::validate::unsigned::certified_asset;
+    use crate::{
+        accepted_encodings, acquire_stream_permit, build_response, byte_range, call_with_retries,
+        canister_agent, canister_status, canonical_redirect, create_proxied_request,
+        decode_for_client, decode_request_body, describe_config, describe_phases,
+        end_to_end_headers, error_response, etag, forward_api, forward_request, forwarded_element,
+        if_none_match_matches, ingress_expiry, is_streaming_strategy_unknown, is_valid_host,
+        mainnet_replica, not_modified, partial_content, read_body, remove_hop_headers,
+        replace_path_prefix, resolve_canister_id, resolve_canister_id_from_hostname,
+        resolve_canister_id_from_path_prefix, resolve_canister_id_from_uri, server_builder,
+        stream_chunks, time_left, within_connection_limits, x_forwarded_proto_host, BufferBudget,
+        BufferPool, DnsCanisterConfig, Opts, ProxyConfig, ProxyError, QueryKey, RequestStart,
+        RetryPolicy, Route, StreamLimits, StreamRegistry, TrustedPeer,
+    };
+    use candid::{CandidType, Decode, Encode, Func, Nat};
+    use clap::{crate_version, Parser};
+    use hyper::{
+        body::{Bytes, HttpBody},
+        server::{
+            accept::{self, Accept},
+            conn::{AddrIncoming, AddrStream},
+        },
+        service::{make_service_fn, service_fn},
+        Body, Method, Request, Response, Uri,
+    };
+    use ic_agent::{agent::agent_error::HttpErrorPayload, export::Principal, AgentError};
+    use ic_utils::interfaces::http_request::{HeaderField, HttpResponse};
+    use slog::{Drain, KV};
+    use std::{
+        convert::Infallible,
+        net::IpAddr,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+    use tokio_rustls::{rustls, TlsAcceptor};
+
+    fn config(args: &[&str]) -> ProxyConfig {
+        let opts = Opts::parse_from(std::iter::once("icx-proxy").chain(args.iter().copied()));
+        ProxyConfig::new(&opts).unwrap()
+    }
+
+    #[test]
+    fn startup_configuration_is_summarized() {
+        let opts = Opts::parse_from(&[
+            "icx-proxy",
+            "--replica",
+            "http://a:8000/",
+            "--replica",
+            "http://b:8000/",
+            "--dns-alias",
+            &format!("www.example.com:{}", CANISTER_A),
+            "--dns-suffix",
+            "localhost",
+            "--dns-suffix",
+            "example.com",
+        ]);
+        let summary = describe_config(&opts, &ProxyConfig::new(&opts).unwrap());
+
+        assert!(summary.contains("  listening on:   http://127.0.0.1:3000/\n"));
+        assert!(summary.contains("  metrics on:     -\n"));
+        assert!(summary.contains("  admin on:       -\n"));
+        assert!(summary.contains("  replicas:       http://a:8000/, http://b:8000/\n"));
+        assert!(summary.contains("  fetch root key: no\n"));
+        assert!(summary.contains("  DNS aliases:    1\n"));
+        assert!(summary.ends_with("  DNS suffixes:   2"));
+    }
+
+    #[test]
+    fn server_headers() {
+        let server_header = |args: &[&str]| config(args).server_header;
+
+        assert_eq!(
+            server_header(&[]).unwrap(),
+            format!("icx-proxy/{}", crate_version!())
+        );
+        assert_eq!(server_header(&["--server-header", "edge"]).unwrap(), "edge");
+        assert_eq!(server_header(&["--server-header", "off"]), None);
+        assert_eq!(server_header(&["--no-server-header"]), None);
+        assert!(Opts::try_parse_from(&[
+            "icx-proxy",
+            "--no-server-header",
+            "--server-header",
+            "edge"
+        ])
+        .is_err());
+        assert!(ProxyConfig::new(&Opts::parse_from(&[
+            "icx-proxy",
+            "--server-header",
+            "bad\nvalue"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn mainnet_replicas_are_detected() {
+        let replicas = |replicas: &[&str]| {
+            replicas
+                .iter()
+                .map(|replica| replica.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            mainnet_replica(&replicas(&["http://localhost:8000/", "https://ic0.app"])),
+            Some("https://ic0.app")
+        );
+        for replica in &[
+            "https://icp-api.io/",
+            "https://boundary.ic0.app",
+            "https://IC0.APP.",
+            "https://icp0.io:443",
+        ] {
+            assert!(
+                mainnet_replica(&replicas(&[replica])).is_some(),
+                "{}",
+                replica
+            );
+        }
+        for replica in &[
+            "http://localhost:8000/",
+            "http://127.0.0.1:4943",
+            "https://notic0.app",
+            "https://ic0.app.example.com",
+            "not a url",
+        ] {
+            assert!(
+                mainnet_replica(&replicas(&[replica])).is_none(),
+                "{}",
+                replica
+            );
+        }
+    }
+
+    #[test]
+    fn response_size_limits_streams() {
+        let max_bytes = |args: &[&str]| config(args).stream_limits.max_bytes;
+
+        assert_eq!(max_bytes(&[]), None);
+        assert_eq!(max_bytes(&["--max-stream-bytes", "100"]), Some(100));
+        assert_eq!(max_bytes(&["--max-response-bytes", "50"]), Some(50));
+        assert_eq!(
+            max_bytes(&["--max-stream-bytes", "100", "--max-response-bytes", "50"]),
+            Some(50)
+        );
+        assert_eq!(
+            max_bytes(&["--max-stream-bytes", "10", "--max-response-bytes", "50"]),
+            Some(10)
+        );
+    }
+
+    #[test]
+    fn request_headers_are_stripped_or_allowed() {
+        let forwards = |args: &[&str], name: &'static str| {
+            config(args).forwards_request_header(&hyper::header::HeaderName::from_static(name))
+        };
+
+        assert!(forwards(&[], "accept"));
+        assert!(!forwards(
+            &["--strip-request-header", "X-Session"],
+            "x-session"
+        ));
+        assert!(forwards(&["--strip-request-header", "X-Session"], "accept"));
+        assert!(!forwards(
+            &["--strip-request-headers", "X-Session"],
+            "x-session"
+        ));
+        let allowlist = [
+            "--allow-request-header",
+            "Accept",
+            "--allow-request-header",
+            "range",
+        ];
+        assert!(forwards(&allowlist, "accept"));
+        assert!(forwards(&allowlist, "range"));
+        assert!(!forwards(&allowlist, "authorization"));
+        assert!(!forwards(
+            &[
+                "--allow-request-header",
+                "cookie",
+                "--strip-request-header",
+                "cookie"
+            ],
+            "cookie"
+        ));
+        assert!(ProxyConfig::new(&Opts::parse_from(&[
+            "icx-proxy",
+            "--strip-request-header",
+            "not a header"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn sensitive_request_headers_are_stripped_unless_allowed() {
+        let forwards = |args: &[&str], name: &'static str| {
+            config(args).forwards_request_header(&hyper::header::HeaderName::from_static(name))
+        };
+
+        for name in &["cookie", "authorization", "proxy-authorization"] {
+            assert!(!forwards(&[], name), "{}", name);
+            assert!(
+                !forwards(&["--allow-request-header", "accept"], name),
+                "{}",
+                name
+            );
+        }
+        assert!(forwards(&["--allow-request-header", "Cookie"], "cookie"));
+        assert!(!forwards(
+            &["--allow-request-header", "Cookie"],
+            "authorization"
+        ));
+        assert!(forwards(
+            &[
+                "--forward-request-headers",
+                "cookie",
+                "--forward-request-headers",
+                "accept"
+            ],
+            "cookie"
+        ));
+    }
+
+    #[test]
+    fn phases_of_slow_requests_are_described() {
+        assert_eq!(
+            describe_phases(&[
+                ("resolve_canister_id", Duration::from_micros(10)),
+                ("query", Duration::from_millis(1200)),
+                ("verify", Duration::from_millis(3)),
+            ]),
+            (
+                "resolve_canister_id=0ms query=1200ms verify=3ms".to_string(),
+                "query"
+            )
+        );
+        assert_eq!(describe_phases(&[]), (String::new(), "none"));
+    }
+
+    #[test]
+    fn routes_by_default_prefixes() {
+        let config = config(&[]);
+
+        assert_eq!(config.route("/api/v2/status"), Route::Replica);
+        assert_eq!(config.route("/_/dashboard"), Route::Proxy);
+        assert_eq!(config.route("/index.html"), Route::Canister);
+        assert_eq!(config.route("/api"), Route::Canister);
+    }
+
+    #[test]
+    fn routes_by_custom_prefixes() {
+        let config = config(&["--api-prefix", "ic-api", "--proxy-prefix", "/_proxy/"]);
+
+        assert_eq!(config.route("/ic-api/v2/status"), Route::Replica);
+        assert_eq!(config.route("/_proxy/dashboard"), Route::Proxy);
+        assert_eq!(config.route("/api/v2/status"), Route::Canister);
+        assert_eq!(config.route("/_/dashboard"), Route::Canister);
+    }
+
+    #[test]
+    fn custom_prefixes_are_replaced_when_forwarding() {
+        let replaced = |uri: &str, prefix: &str, replacement: &str| {
+            let mut request = Request::builder().uri(uri).body(()).unwrap();
+            replace_path_prefix(&mut request, prefix, replacement).unwrap();
+            request.uri().to_string()
+        };
+
+        assert_eq!(
+            replaced("/ic-api/v2/status?verbose=true", "/ic-api/", "/api/"),
+            "/api/v2/status?verbose=true"
+        );
+        assert_eq!(replaced("/ic-api?x=1", "/ic-api/", "/api/"), "/api/?x=1");
+        assert_eq!(replaced("/ic-apis/v2", "/ic-api/", "/api/"), "/ic-apis/v2");
+
+        // Empty and `/` prefixes and replacements make no `//` paths.
+        assert_eq!(replaced("/v2/status", "/", "/api/"), "/api/v2/status");
+        assert_eq!(replaced("/v2/status", "", "/api/"), "/api/v2/status");
+        assert_eq!(replaced("/api/v2/status", "/api/", "/"), "/v2/status");
+        assert_eq!(replaced("/api", "/api/", ""), "/");
+    }
+
+    #[test]
+    fn overlapping_prefixes_are_rejected() {
+        let opts = Opts::parse_from(["icx-proxy", "--proxy-prefix", "/api/"]);
+        let e = ProxyConfig::new(&opts)
+            .err()
+            .expect("expected failure due to overlapping prefixes");
+        assert_eq!(
+            e.to_string(),
+            r#"The API prefix "/api/" and the proxy prefix "/api/" overlap"#
+        );
+    }
+
+    #[test]
+    fn connect_and_trace_are_rejected() {
+        let config = config(&[]);
+
+        assert!(config.allows_method(&Method::GET, true));
+        assert!(config.allows_method(&Method::DELETE, true));
+        assert!(config.allows_method(&Method::POST, false));
+        assert!(!config.allows_method(&Method::CONNECT, true));
+        assert!(!config.allows_method(&Method::CONNECT, false));
+        assert!(!config.allows_method(&Method::TRACE, true));
+        assert!(!config.allows_method(&Method::TRACE, false));
+    }
+
+    #[test]
+    fn canister_requests_are_restricted_to_allowed_methods() {
+        let config = config(&["--allowed-methods", "get", "--allowed-methods", "HEAD"]);
+
+        assert!(config.allows_method(&Method::GET, true));
+        assert!(config.allows_method(&Method::HEAD, true));
+        assert!(!config.allows_method(&Method::POST, true));
+        // Calls to the replica API are not canister requests.
+        assert!(config.allows_method(&Method::POST, false));
+    }
+
+    #[test]
+    fn connect_cannot_be_allowed() {
+        let opts = Opts::parse_from(["icx-proxy", "--allowed-methods", "CONNECT"]);
+        let e = ProxyConfig::new(&opts)
+            .err()
+            .expect("expected failure due to an allowed CONNECT method");
+        assert_eq!(e.to_string(), "The CONNECT method cannot be allowed");
+    }
+
+    #[test]
+    fn raw_domains_match_the_host() {
+        let config = config(&["--raw-domain", "Dynamic.Example.com"]);
+
+        assert!(config.is_raw_domain("dynamic.example.com"));
+        assert!(config.is_raw_domain("DYNAMIC.example.com:443"));
+        assert!(config.is_raw_domain("dynamic.example.com."));
+        assert!(!config.is_raw_domain("example.com"));
+        assert!(!config.is_raw_domain("static.dynamic.example.com"));
+    }
+
+    #[test]
+    fn path_prefix_is_stripped() {
+        let (canister_id, uri) = resolve_canister_id_from_path_prefix(
+            &Uri::from_static("/canister/rrkah-fqaaa-aaaaa-aaaaq-cai/assets/app.js?v=1"),
+            "/canister/",
+        )
+        .unwrap();
+
+        assert_eq!(
+            canister_id,
+            Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+        );
+        assert_eq!(uri, Uri::from_static("/assets/app.js?v=1"));
+    }
+
+    #[test]
+    fn path_prefix_without_trailing_path_rewrites_to_root() {
+        let (_, uri) = resolve_canister_id_from_path_prefix(
+            &Uri::from_static("/canister/rrkah-fqaaa-aaaaa-aaaaq-cai"),
+            "/canister/",
+        )
+        .unwrap();
+        assert_eq!(uri, Uri::from_static("/"));
+
+        let (_, uri) = resolve_canister_id_from_path_prefix(
+            &Uri::from_static("/canister/rrkah-fqaaa-aaaaa-aaaaq-cai?a=b"),
+            "/canister/",
+        )
+        .unwrap();
+        assert_eq!(uri, Uri::from_static("/?a=b"));
+    }
+
+    #[test]
+    fn path_prefix_requires_canister_id() {
+        assert_eq!(
+            resolve_canister_id_from_path_prefix(
+                &Uri::from_static("/canister/not-a-canister/index.html"),
+                "/canister/",
+            ),
+            None
+        );
+        assert_eq!(
+            resolve_canister_id_from_path_prefix(
+                &Uri::from_static("/other/rrkah-fqaaa-aaaaa-aaaaq-cai/index.html"),
+                "/canister/",
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn path_prefix_is_normalized() {
+        let config = config(&["--canister-path-prefix", "canister"]);
+        assert_eq!(config.canister_path_prefix.as_deref(), Some("/canister/"));
+    }
+
+    #[test]
+    fn path_prefix_resolution() {
+        let request = Request::builder()
+            .uri("/canister/rrkah-fqaaa-aaaaa-aaaaq-cai/index.html?canisterId=r7inp-6aaaa-aaaaa-aaabq-cai")
+            .header("Host", "localhost:3000")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(
+            resolve_canister_id(&request, &config(&["--canister-path-prefix", "/canister/"])),
+            Some(Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap())
+        );
+        assert_eq!(
+            resolve_canister_id(&request, &config(&[])),
+            Some(Principal::from_text("r7inp-6aaaa-aaaaa-aaabq-cai").unwrap())
+        );
+    }
+
+    #[test]
+    fn canister_id_header_resolution() {
+        let request = |host: &str| {
+            Request::builder()
+                .uri("/index.html?canisterId=r7inp-6aaaa-aaaaa-aaabq-cai")
+                .header("Host", host)
+                .header("X-Ic-Canister-Id", "rrkah-fqaaa-aaaaa-aaaaq-cai")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let header_config = config(&["--canister-id-header", "X-Ic-Canister-Id"]);
+
+        // The header takes precedence over the query parameter.
+        assert_eq!(
+            resolve_canister_id(&request("cdn.example.com"), &header_config),
+            Some(Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap())
+        );
+        // But not over the Host.
+        assert_eq!(
+            resolve_canister_id(
+                &request("ryjl3-tyaaa-aaaaa-aaaba-cai.localhost"),
+                &header_config
+            ),
+            Some(Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap())
+        );
+        // It is ignored unless configured.
+        assert_eq!(
+            resolve_canister_id(&request("cdn.example.com"), &config(&[])),
+            Some(Principal::from_text("r7inp-6aaaa-aaaaa-aaabq-cai").unwrap())
+        );
+    }
+
+    const CANISTER_A: &str = "rrkah-fqaaa-aaaaa-aaaaq-cai";
+    const CANISTER_B: &str = "r7inp-6aaaa-aaaaa-aaabq-cai";
+    const CANISTER_C: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+    fn principal(text: &str) -> Option<Principal> {
+        Some(Principal::from_text(text).unwrap())
+    }
+
+    fn request_with(uri: &str, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut builder = Request::builder().uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn hostname_resolution() {
+        let dns_canister_config = DnsCanisterConfig::new(&[], &["localhost".to_string()]).unwrap();
+        let resolve = |host: &str| resolve_canister_id_from_hostname(host, &dns_canister_config);
+
+        assert_eq!(
+            resolve(&format!("{}.localhost", CANISTER_A)),
+            principal(CANISTER_A)
+        );
+        assert_eq!(
+            resolve(&format!("{}.localhost:8000", CANISTER_A)),
+            principal(CANISTER_A)
+        );
+        assert_eq!(
+            resolve(&format!("assets.{}.localhost", CANISTER_A)),
+            principal(CANISTER_A)
+        );
+        // The first label is tried when no suffix matches.
+        assert_eq!(
+            resolve(&format!("{}.ic0.app", CANISTER_A)),
+            principal(CANISTER_A)
+        );
+        assert_eq!(resolve("localhost:8000"), None);
+        assert_eq!(resolve("www.example.com"), None);
+        assert_eq!(resolve("not a host"), None);
+    }
+
+    #[test]
+    fn injected_hosts_are_invalid() {
+        for host in &[
+            "localhost",
+            "localhost:8000",
+            "rrkah-fqaaa-aaaaa-aaaaq-cai.ic0.app",
+            "127.0.0.1:80",
+            "[::1]",
+            "[::1]:8000",
+        ] {
+            assert!(is_valid_host(host), "{}", host);
+        }
+        for host in &[
+            "",
+            ":8000",
+            "evil.com/@good.localhost",
+            "evil.com@rrkah-fqaaa-aaaaa-aaaaq-cai.localhost",
+            "rrkah-fqaaa-aaaaa-aaaaq-cai.localhost\r\nX-Injected: 1",
+            "good.localhost\tevil.com",
+            "good.localhost?canisterId=rrkah-fqaaa-aaaaa-aaaaq-cai",
+            "good.localhost#fragment",
+            "localhost:80:80",
+            "localhost:123456",
+            "localhost:http",
+            "[::1",
+            "[evil.com]:80",
+            "[::1]evil.com",
+            "http://localhost",
+            "good_host.localhost",
+        ] {
+            assert!(!is_valid_host(host), "{:?}", host);
+        }
+    }
+
+    #[test]
+    fn injected_hosts_do_not_resolve() {
+        let dns_canister_config = DnsCanisterConfig::new(
+            &[format!("good.localhost:{}", CANISTER_B)],
+            &["localhost".to_string()],
+        )
+        .unwrap();
+        let resolve = |host: &str| resolve_canister_id_from_hostname(host, &dns_canister_config);
+
+        assert_eq!(resolve("good.localhost"), principal(CANISTER_B));
+        assert_eq!(
+            resolve(&format!("evil.com/@{}.localhost", CANISTER_A)),
+            None
+        );
+        assert_eq!(resolve(&format!("evil.com@{}.localhost", CANISTER_A)), None);
+        assert_eq!(resolve(&format!("{}.localhost/evil", CANISTER_A)), None);
+        assert_eq!(resolve("evil.com/@good.localhost"), None);
+        assert_eq!(resolve("good.localhost\u{0}"), None);
+    }
+
+    #[tokio::test]
+    async fn requests_with_invalid_hosts_are_bad_requests() {
+        let config = config(&[]);
+        let mut request = request_with("/", &[]);
+        request.headers_mut().insert(
+            "host",
+            hyper::header::HeaderValue::from_static("evil.com/@good.localhost"),
+        );
+        let replica = MockReplica::new(|_, _| Err("Unexpected query".to_string()));
+
+        let error = forward_request(request, Arc::new(replica.agent()), &config, logger())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, ProxyError::BadRequest(_)));
+        assert!(replica.calls().is_empty());
+    }
+
+    /// Forward a GET request for `path` to canister A of `replica`.
+    async fn forward_to(
+        replica: &Arc<MockReplica>,
+        path: &str,
+        args: &[&str],
+    ) -> Result<Response<Body>, ProxyError> {
+        forward_method_to(replica, Method::GET, path, args).await
+    }
+
+    /// Forward a `method` request for `path` to canister A of `replica`.
+    async fn forward_method_to(
+        replica: &Arc<MockReplica>,
+        method: Method,
+        path: &str,
+        args: &[&str],
+    ) -> Result<Response<Body>, ProxyError> {
+        let host = format!("{}.localhost", CANISTER_A);
+        let mut request = request_with(path, &[("Host", &host)]);
+        *request.method_mut() = method;
+        forward_request(request, Arc::new(replica.agent()), &config(args), logger()).await
+    }
+
+    #[tokio::test]
+    async fn queries_are_answered_by_canisters() {
+        let replica = MockReplica::new(|method, _| match method {
+            "http_request" => Ok(Encode!(&canned("hello")).unwrap()),
+            _ => Err(format!("Unexpected method {}", method)),
+        });
+
+        let response = forward_to(&replica, "/index.html", &[]).await.unwrap();
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "hello");
+        assert_eq!(replica.calls(), ["http_request"]);
+    }
+
+    #[tokio::test]
+    async fn only_queries_with_identical_headers_are_coalesced() {
+        let replica =
+            MockReplica::with_latency(Duration::from_millis(50), |method, _| match method {
+                "http_request" => Ok(Encode!(&canned("hello")).unwrap()),
+                _ => Err(format!("Unexpected method {}", method)),
+            });
+        let agent = Arc::new(replica.agent());
+        let config = config(&[]);
+        let host = format!("{}.localhost", CANISTER_A);
+        let forward = |api_key| {
+            let request = request_with("/", &[("Host", &host), ("X-API-Key", api_key)]);
+            forward_request(request, agent.clone(), &config, logger())
+        };
+
+        let (a, b) = tokio::join!(forward("a"), forward("a"));
+        assert_eq!(a.unwrap().status(), 200);
+        assert_eq!(b.unwrap().status(), 200);
+        assert_eq!(replica.calls(), ["http_request"]);
+
+        let (a, b) = tokio::join!(forward("a"), forward("b"));
+        assert_eq!(a.unwrap().status(), 200);
+        assert_eq!(b.unwrap().status(), 200);
+        assert_eq!(
+            replica.calls(),
+            ["http_request", "http_request", "http_request"]
+        );
+    }
+
+    #[tokio::test]
+    async fn upgraded_queries_are_called_as_updates() {
+        let replica = MockReplica::new(|method, _| match method {
+            "http_request" => Ok(Encode!(&CannedResponse {
+                upgrade: Some(true),
+                ..canned("")
+            })
+            .unwrap()),
+            "http_request_update" => Ok(Encode!(&canned("updated")).unwrap()),
+            _ => Err(format!("Unexpected method {}", method)),
+        });
+
+        // The outcome of the update call is read back, but the agent refuses the certificate
+        // it comes with, as the mock replica cannot sign it.
+        let error = forward_to(&replica, "/", &[]).await.unwrap_err();
+
+        assert!(matches!(error, ProxyError::Upstream(_)), "{:?}", error);
+        assert_eq!(replica.calls(), ["http_request", "http_request_update"]);
+
+        // Replies of update calls carry no IC-Certificate, and are not verified against one
+        // even if the canister must be certified: it is the certificate the agent verifies
+        // which decides.
+        let error = forward_to(
+            &replica,
+            "/",
+            &["--default-certification-policy", "required"],
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, ProxyError::Upstream(_)), "{:?}", error);
+    }
+
+    /// A response whose body starts with `body`, and goes on with the chunks of the
+    /// http_request_stream_callback method.
+    fn canned_streamed(body: &str) -> CannedResponse {
+        CannedResponse {
+            streaming_strategy: Some(CannedStreamingStrategy::Callback {
+                callback: Func {
+                    principal: Principal::from_text(CANISTER_A).unwrap(),
+                    method: "http_request_stream_callback".to_string(),
+                },
+                token: CannedToken {
+                    key: "/video.mp4".to_string(),
+                    content_encoding: "identity".to_string(),
+                    index: Nat::from(1),
+                    sha256: None,
+                },
+            }),
+            ..canned(body)
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_responses_are_sent_in_full() {
+        let replica = MockReplica::new(|method, _| match method {
+            "http_request" => Ok(Encode!(&canned_streamed("hello")).unwrap()),
+            "http_request_stream_callback" => Ok(Encode!(&CannedCallbackResponse {
+                body: b" world".to_vec(),
+                token: None,
+            })
+            .unwrap()),
+            _ => Err(format!("Unexpected method {}", method)),
+        });
+
+        let response = forward_to(&replica, "/video.mp4", &[]).await.unwrap();
+
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "hello world");
+        assert_eq!(
+            replica.calls(),
+            ["http_request", "http_request_stream_callback"]
+        );
+    }
+
+    /// A drain keeping the message and the key-values of every record.
+    #[derive(Clone, Default)]
+    struct Records(Arc<Mutex<Vec<(String, Vec<(String, String)>)>>>);
+
+    impl slog::Drain for Records {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(
+            &self,
+            record: &slog::Record,
+            values: &slog::OwnedKVList,
+        ) -> Result<(), slog::Never> {
+            let mut key_values = KeyValues::default();
+            record.kv().serialize(record, &mut key_values).unwrap();
+            values.serialize(record, &mut key_values).unwrap();
+            self.0
+                .lock()
+                .unwrap()
+                .push((record.msg().to_string(), key_values.0));
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct KeyValues(Vec<(String, String)>);
+
+    impl slog::Serializer for KeyValues {
+        fn emit_arguments(&mut self, key: slog::Key, value: &std::fmt::Arguments) -> slog::Result {
+            self.0.push((key.to_string(), value.to_string()));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_streams_are_logged_once_they_end() {
+        let replica = MockReplica::new(|method, _| match method {
+            "http_request" => Ok(Encode!(&canned_streamed("hello")).unwrap()),
+            "http_request_stream_callback" => {
+                std::thread::sleep(Duration::from_millis(50));
+                Ok(Encode!(&CannedCallbackResponse {
+                    body: b" world".to_vec(),
+                    token: None,
+                })
+                .unwrap())
+            }
+            _ => Err(format!("Unexpected method {}", method)),
+        });
+        let records = Records::default();
+        let logger = slog::Logger::root(records.clone().fuse(), slog::o!());
+        let config = config(&["--slow-request-threshold-ms", "20"]);
+        let host = format!("{}.localhost", CANISTER_A);
+        let mut request = request_with("/video.mp4", &[("Host", &host)]);
+        request.extensions_mut().insert(RequestStart {
+            at: Instant::now(),
+            logger: logger.clone(),
+        });
+        let cx = telemetry::start_request(request.headers(), request.method(), "/video.mp4");
+
+        let response = opentelemetry::trace::FutureExt::with_context(
+            forward_request(request, Arc::new(replica.agent()), &config, logger),
+            cx,
+        )
+        .await
+        .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "hello world");
+
+        let slow_request = || {
+            records
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(message, _)| message == "Slow request")
+                .map(|(_, key_values)| key_values.clone())
+        };
+        let key_values = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match slow_request() {
+                    Some(key_values) => return key_values,
+                    None => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let value = |key: &str| {
+            key_values
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.clone())
+        };
+        assert_eq!(value("slowest_phase").as_deref(), Some("stream"));
+        assert!(value("phases").unwrap().contains("stream="));
+    }
+
+    #[tokio::test]
+    async fn streamed_responses_of_canisters_requiring_certification_are_refused() {
+        let replica = MockReplica::new(|method, _| match method {
+            "http_request" => Ok(Encode!(&canned_streamed("hello")).unwrap()),
+            "http_request_stream_callback" => Ok(Encode!(&CannedCallbackResponse {
+                body: b" world".to_vec(),
+                token: None,
+            })
+            .unwrap()),
+            _ => Err(format!("Unexpected method {}", method)),
+        });
+
+        let error = forward_to(
+            &replica,
+            "/video.mp4",
+            &["--default-certification-policy", "required"],
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, ProxyError::Verification(_)), "{:?}", error);
+        // Not a chunk of the body is fetched, let alone sent.
+        assert_eq!(replica.calls(), ["http_request"]);
+    }
+
+    #[tokio::test]
+    async fn streamed_head_responses_have_no_length() {
+        let replica = MockReplica::new(|method, _| match method {
+            "http_request" => Ok(Encode!(&canned_streamed("hello")).unwrap()),
+            _ => Err(format!("Unexpected method {}", method)),
+        });
+
+        let response = forward_method_to(&replica, Method::HEAD, "/video.mp4", &[])
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        // The length of a streamed body is not known from its first chunk.
+        assert!(response.headers().get("content-length").is_none());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn uncertified_responses_fail_required_verification() {
+        let replica = MockReplica::new(|_, _| Ok(Encode!(&canned("forged")).unwrap()));
+
+        let error = forward_to(
+            &replica,
+            "/",
+            &["--default-certification-policy", "required"],
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, ProxyError::Verification(_)), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn verification_failures_fail_closed() {
+        let replica = MockReplica::new(|_, _| Ok(Encode!(&canned("forged")).unwrap()));
+        let required = ["--default-certification-policy", "required", "--debug"];
+        let error = forward_to(&replica, "/", &required).await.unwrap_err();
+
+        let response = error_response(&error, true, &config(&required), &logger());
+        assert_eq!(response.status(), 500);
+        assert_eq!(response.headers()["x-ic-certified"], "false");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Response verification failed");
+
+        let fail_open = [&required[..], &["--no-fail-closed"]].concat();
+        let response = error_response(&error, true, &config(&fail_open), &logger());
+        assert_eq!(response.status(), 500);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Response is not certified");
+
+        // Without --debug, the reason is not told even with --no-fail-closed.
+        let response = error_response(
+            &error,
+            true,
+            &config(&[
+                "--default-certification-policy",
+                "required",
+                "--no-fail-closed",
+            ]),
+            &logger(),
+        );
+        assert_eq!(response.status(), 500);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Response verification failed");
+    }
+
+    #[tokio::test]
+    async fn invalid_canister_headers_are_skipped() {
+        let replica = MockReplica::new(|_, _| {
+            let mut response = canned("hello");
+            response.headers.extend(vec![
+                HeaderField("bad header name".to_string(), "1".to_string()),
+                HeaderField("X-Bad-Value".to_string(), "a\u{1}b".to_string()),
+                HeaderField("X-Good".to_string(), "1".to_string()),
+            ]);
+            Ok(Encode!(&response).unwrap())
+        });
+
+        let response = forward_to(&replica, "/", &[]).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get("x-bad-value").is_none());
+        assert_eq!(response.headers()["x-good"], "1");
+        assert_eq!(response.headers()["content-type"], "text/plain");
+
+        let error = forward_to(&replica, "/", &["--strict-canister-headers"])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, ProxyError::Upstream(message) if message.contains("bad header name")),
+            "{:?}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn headers_are_added_after_verification() {
+        let replica = MockReplica::new(|_, _| {
+            let mut response = canned("hello");
+            response.headers.extend(vec![
+                HeaderField("X-Frame-Options".to_string(), "SAMEORIGIN".to_string()),
+                HeaderField(
+                    "Content-Security-Policy".to_string(),
+                    "default-src *".to_string(),
+                ),
+            ]);
+            Ok(Encode!(&response).unwrap())
+        });
+        let args = [
+            "--add-response-header",
+            "X-Frame-Options: DENY",
+            "--add-response-header",
+            "!Content-Security-Policy: default-src 'self'",
+            "--add-response-header",
+            "X-Content-Type-Options: nosniff",
+        ];
+
+        let response = forward_to(&replica, "/", &args).await.unwrap();
+        assert_eq!(response.headers()["x-frame-options"], "SAMEORIGIN");
+        assert_eq!(
+            response.headers()["content-security-policy"],
+            "default-src 'self'"
+        );
+        assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+        assert_eq!(response.headers()["x-ic-certified"], "false");
+
+        // Added headers do not make a response pass verification it fails.
+        let required = [&args[..], &["--default-certification-policy", "required"]].concat();
+        let error = forward_to(&replica, "/", &required).await.unwrap_err();
+        assert!(matches!(error, ProxyError::Verification(_)), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn certification_headers_of_verified_responses_are_stripped() {
+        let (certificate_sha, certificate_header) =
+            certified_asset(&Principal::from_text(CANISTER_A).unwrap(), "/", b"hello");
+        let replica = MockReplica::new(move |_, _| {
+            let mut response = canned("hello");
+            response.headers.extend(vec![
+                HeaderField("IC-Certificate".to_string(), certificate_header.clone()),
+                HeaderField(
+                    "IC-CertificateExpression".to_string(),
+                    "default_certification(ValidationArgs{no_certification:Empty{}})".to_string(),
+                ),
+            ]);
+            Ok(Encode!(&response).unwrap())
+        });
+        let config = config(&[
+            "--strip-certification-headers",
+            "--default-certification-policy",
+            "required",
+        ]);
+        // The mock replica cannot sign certificates.
+        config
+            .verification_cache
+            .as_ref()
+            .unwrap()
+            .insert_certificate(certificate_sha);
+
+        let host = format!("{}.localhost", CANISTER_A);
+        let request = request_with("/", &[("Host", &host)]);
+        let response = forward_request(request, Arc::new(replica.agent()), &config, logger())
+            .await
+            .unwrap();
+
+        assert_eq!(response.headers()["x-ic-certified"], "true");
+        assert!(response.headers().get("ic-certificate").is_none());
+        assert!(response.headers().get("ic-certificateexpression").is_none());
+    }
+
+    #[tokio::test]
+    async fn certification_headers_of_unverified_responses_are_kept() {
+        let replica = MockReplica::new(|_, _| {
+            let mut response = canned("hello");
+            response.headers.push(HeaderField(
+                "IC-Certificate".to_string(),
+                "certificate=:AAAA:, tree=:AAAA:".to_string(),
+            ));
+            Ok(Encode!(&response).unwrap())
+        });
+
+        let response = forward_to(
+            &replica,
+            "/",
+            &[
+                "--strip-certification-headers",
+                "--default-certification-policy",
+                "skip",
+            ],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            response.headers()["ic-certificate"],
+            "certificate=:AAAA:, tree=:AAAA:"
+        );
+    }
+
+    #[tokio::test]
+    async fn waits_are_bounded_by_deadlines() {
+        let limit = Duration::from_secs(15);
+        assert_eq!(time_left(None, limit), limit);
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(60);
+        assert_eq!(time_left(Some(deadline), limit), limit);
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        assert!(time_left(Some(deadline), limit) <= Duration::from_secs(5));
+        let deadline = tokio::time::Instant::now() - Duration::from_secs(5);
+        assert_eq!(time_left(Some(deadline), limit), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn ingress_expiry_stays_within_what_replicas_accept() {
+        let config = config(&["--request-timeout", "1"]);
+        let deadline = tokio::time::Instant::now() + config.request_timeout.unwrap();
+        // The agent takes 60 seconds of drift off the expiry, which must stay ahead of now.
+        assert!(ingress_expiry(Some(deadline)).unwrap() > Duration::from_secs(60));
+        canister_agent("http://127.0.0.1:1/".to_string(), &config, Some(deadline));
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(3600);
+        assert_eq!(
+            ingress_expiry(Some(deadline)),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(ingress_expiry(None), None);
+    }
+
+    #[test]
+    fn response_header_limits() {
+        let limits = config(&[
+            "--max-response-headers",
+            "3",
+            "--max-response-header-value-bytes",
+            "10",
+            "--max-response-header-bytes",
+            "24",
+        ])
+        .response_header_limits;
+        let headers = |values: &[&str]| {
+            values
+                .iter()
+                .map(|value| HeaderField("x-h".to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(limits.exceeded_by(&headers(&["1234567890", "1"])), None);
+        assert_eq!(
+            limits.exceeded_by(&headers(&["1", "2", "3", "4"])),
+            Some("too_many_headers")
+        );
+        assert_eq!(
+            limits.exceeded_by(&headers(&["12345678901"])),
+            Some("header_value_too_large")
+        );
+        assert_eq!(
+            limits.exceeded_by(&headers(&["1234567890", "1234567890"])),
+            Some("headers_too_large")
+        );
+    }
+
+    #[tokio::test]
+    async fn responses_with_too_many_headers_are_refused() {
+        let replica = MockReplica::new(|_, _| {
+            let mut response = canned("hello");
+            response.headers = (0..101)
+                .map(|i| HeaderField(format!("x-header-{}", i), "1".to_string()))
+                .collect();
+            Ok(Encode!(&response).unwrap())
+        });
+        let config = config(&[]);
+        let host = format!("{}.localhost", CANISTER_A);
+        let request = request_with("/", &[("Host", &host)]);
+
+        let error = forward_request(request, Arc::new(replica.agent()), &config, logger())
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.status(), hyper::StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            config.metrics.rejected_response_headers.values(),
+            [((principal(CANISTER_A).unwrap(), "too_many_headers"), 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_queries_are_errors() {
+        let replica = MockReplica::new(|_, _| Err("No such asset".to_string()));
+
+        let error = forward_to(&replica, "/", &[]).await.unwrap_err();
+
+        assert!(matches!(error, ProxyError::Rejected { .. }), "{:?}", error);
+    }
+
+    #[test]
+    fn multi_label_suffix_resolution() {
+        let config = config(&["--dns-suffix", "app.example.com"]);
+        let resolve =
+            |host: &str| resolve_canister_id(&request_with("/", &[("Host", host)]), &config);
+
+        assert_eq!(
+            resolve(&format!("{}.app.example.com", CANISTER_A)),
+            principal(CANISTER_A)
+        );
+        assert_eq!(
+            resolve(&format!("{}.App.Example.com:443", CANISTER_A)),
+            principal(CANISTER_A)
+        );
+        assert!(ProxyConfig::new(&Opts::parse_from(&["icx-proxy", "--dns-suffix", ""])).is_err());
+    }
+
+    #[test]
+    fn alias_resolution() {
+        let config = config(&["--dns-alias", &format!("www.example.com:{}", CANISTER_B)]);
+
+        for host in &["www.example.com", "WWW.Example.com", "www.example.com:443"] {
+            assert_eq!(
+                resolve_canister_id(&request_with("/", &[("Host", host)]), &config),
+                principal(CANISTER_B),
+                "{}",
+                host
+            );
+        }
+        assert_eq!(
+            resolve_canister_id(&request_with("/", &[("Host", "example.com")]), &config),
+            None
+        );
+    }
+
+    #[test]
+    fn suffix_resolution() {
+        let config = config(&["--dns-suffix", "example.com"]);
+        let resolve =
+            |host: &str| resolve_canister_id(&request_with("/", &[("Host", host)]), &config);
+
+        assert_eq!(
+            resolve(&format!("{}.example.com", CANISTER_A)),
+            principal(CANISTER_A)
+        );
+        assert_eq!(
+            resolve(&format!("assets.{}.example.com", CANISTER_A)),
+            principal(CANISTER_A)
+        );
+        assert_eq!(resolve("assets.example.com"), None);
+    }
+
+    #[test]
+    fn query_parameter_resolution() {
+        let params = ["canisterId".to_string()];
+        let resolve =
+            |uri: &str| resolve_canister_id_from_uri(&uri.parse::<Uri>().unwrap(), &params);
+
+        assert_eq!(
+            resolve(&format!("/index.html?canisterId={}", CANISTER_A)),
+            principal(CANISTER_A)
+        );
+        assert_eq!(
+            resolve(&format!(
+                "http://localhost:8000/?page=2&canisterId={}",
+                CANISTER_A
+            )),
+            principal(CANISTER_A)
+        );
+        assert_eq!(resolve("/index.html?canisterId=not-a-principal"), None);
+        assert_eq!(
+            resolve("/index.html?canister_id=rrkah-fqaaa-aaaaa-aaaaq-cai"),
+            None
+        );
+        assert_eq!(resolve("/index.html"), None);
+    }
+
+    #[test]
+    fn query_parameter_names() {
+        let config = config(&[
+            "--canister-id-param",
+            "canister_id",
+            "--canister-id-param",
+            "canister-id",
+        ]);
+        let resolve =
+            |uri: &str| resolve_canister_id(&request_with(uri, &[("Host", "localhost")]), &config);
+
+        assert_eq!(
+            resolve(&format!("/?canister_id={}", CANISTER_A)),
+            principal(CANISTER_A)
+        );
+        assert_eq!(
+            resolve(&format!("/?canister-id={}", CANISTER_B)),
+            principal(CANISTER_B)
+        );
+        // The default name is replaced.
+        assert_eq!(resolve(&format!("/?canisterId={}", CANISTER_C)), None);
+        // The first name given wins, wherever it is in the query.
+        assert_eq!(
+            resolve(&format!(
+                "/?canister-id={}&canister_id={}",
+                CANISTER_B, CANISTER_A
+            )),
+            principal(CANISTER_A)
+        );
+        // Unless it is not a canister ID.
+        assert_eq!(
+            resolve(&format!("/?canister_id=index&canister-id={}", CANISTER_B)),
+            principal(CANISTER_B)
+        );
+        // Names apply to the Referer as well.
+        let referer = format!("http://localhost/?canister-id={}", CANISTER_C);
+        assert_eq!(
+            resolve_canister_id(
+                &request_with("/logo.png", &[("Referer", &referer)]),
+                &config
+            ),
+            principal(CANISTER_C)
+        );
+    }
+
+    #[test]
+    fn referer_resolution() {
+        let referer = format!("http://localhost:8000/index.html?canisterId={}", CANISTER_A);
+        let request = request_with(
+            "/logo.png",
+            &[("Host", "localhost:8000"), ("Referer", &referer)],
+        );
+
+        assert_eq!(
+            resolve_canister_id(&request, &config(&[])),
+            principal(CANISTER_A)
+        );
+        assert_eq!(
+            resolve_canister_id(
+                &request_with("/logo.png", &[("Referer", "http://localhost:8000/")]),
+                &config(&[])
+            ),
+            None
+        );
+        assert_eq!(
+            resolve_canister_id(&request, &config(&["--disable-referer-resolution"])),
+            None
+        );
+    }
+
+    #[test]
+    fn requests_are_redirected_to_canonical_domains() {
+        let config = config(&["--canonical-domain", &format!("{}=example.com", CANISTER_A)]);
+        let canister_id = principal(CANISTER_A).unwrap();
+        let redirect = |method: Method, host: &str| {
+            let mut request = request_with("/docs?page=2", &[("Host", host)]);
+            *request.method_mut() = method;
+            canonical_redirect(&request, canister_id, &config).unwrap()
+        };
+
+        let response = redirect(Method::GET, &format!("{}.ic0.app", CANISTER_A)).unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.headers()["location"],
+            "http://example.com/docs?page=2"
+        );
+        assert!(redirect(Method::HEAD, "localhost:8000").is_some());
+        assert!(redirect(Method::POST, "localhost:8000").is_none());
+        assert!(redirect(Method::GET, "example.com").is_none());
+        assert!(canonical_redirect(
+            &request_with("/", &[("Host", "localhost")]),
+            principal(CANISTER_B).unwrap(),
+            &config
+        )
+        .unwrap()
+        .is_none());
+
+        // The scheme is the one the client used, as far as trusted proxies tell.
+        let location = |request: Request<Body>| {
+            canonical_redirect(&request, canister_id, &config)
+                .unwrap()
+                .unwrap()
+                .headers()["location"]
+                .clone()
+        };
+        let forwarded = || {
+            request_with(
+                "/docs",
+                &[("Host", "localhost"), ("X-Forwarded-Proto", "https")],
+            )
+        };
+        assert_eq!(location(forwarded()), "http://example.com/docs");
+        let mut request = forwarded();
+        request.extensions_mut().insert(TrustedPeer);
+        assert_eq!(location(request), "https://example.com/docs");
+
+        // Requests in absolute form may come without a Host header.
+        let request = Request::builder()
+            .uri("https://localhost:8443/docs")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(location(request), "https://example.com/docs");
+    }
+
+    #[test]
+    fn resolution_precedence() {
+        let config = config(&[
+            "--dns-alias",
+            &format!("{}.ic0.app:{}", CANISTER_C, CANISTER_B),
+        ]);
+        let query = format!("/?canisterId={}", CANISTER_A);
+        let referer = format!("http://localhost:8000/?canisterId={}", CANISTER_C);
+
+        // Aliases take precedence over canister IDs in the hostname.
+        assert_eq!(
+            resolve_canister_id(
+                &request_with(&query, &[("Host", &format!("{}.ic0.app", CANISTER_C))]),
+                &config
+            ),
+            principal(CANISTER_B)
+        );
+        // The Host takes precedence over the query parameter.
+        assert_eq!(
+            resolve_canister_id(
+                &request_with(&query, &[("Host", &format!("{}.localhost", CANISTER_C))]),
+                &config
+            ),
+            principal(CANISTER_C)
+        );
+        // Which takes precedence over the Referer.
+        assert_eq!(
+            resolve_canister_id(
+                &request_with(&query, &[("Host", "localhost"), ("Referer", &referer)]),
+                &config
+            ),
+            principal(CANISTER_A)
+        );
+        assert_eq!(
+            resolve_canister_id(&request_with("/", &[("Host", "localhost")]), &config),
+            None
+        );
+    }
+
+    fn proxied_headers(client_ip: &str, forwarded: &[&str], args: &[&str]) -> (String, String) {
+        let mut request = Request::builder().uri("/api/v2/status");
+        for value in forwarded {
+            request = request
+                .header("x-forwarded-for", *value)
+                .header("forwarded", format!("for={}", value));
+        }
+        let request = create_proxied_request(
+            &client_ip.parse::<IpAddr>().unwrap(),
+            "http://localhost:8000/",
+            request.body(()).unwrap(),
+            &config(args),
+        )
+        .unwrap();
+
+        let header = |name| {
+            request
+                .headers()
+                .get_all(name)
+                .iter()
+                .map(|value| value.to_str().unwrap())
+                .collect::<Vec<_>>()
+                .join(" | ")
+        };
+        (header("x-forwarded-for"), header("forwarded"))
+    }
+
+    #[test]
+    fn hop_headers_are_removed() {
+        let mut headers = hyper::HeaderMap::new();
+        for (name, value) in &[
+            ("Connection", "keep-alive, X-Custom"),
+            ("connection", "X-Other"),
+            ("Keep-Alive", "timeout=5"),
+            ("TE", "trailers"),
+            ("Upgrade", "websocket"),
+            ("X-Custom", "hop"),
+            ("X-Other", "hop"),
+            ("X-Kept", "end-to-end"),
+            ("Content-Type", "application/cbor"),
+        ] {
+            headers.append(*name, value.parse().unwrap());
+        }
+
+        let headers = remove_hop_headers(&headers);
+
+        let mut names = headers.keys().map(|name| name.as_str()).collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["content-type", "x-kept"]);
+    }
+
+    #[test]
+    fn hop_headers_of_canister_responses_are_removed() {
+        let headers = |headers: &[(&str, &str)]| {
+            headers
+                .iter()
+                .map(|(name, value)| HeaderField(name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let names = |headers: Vec<HeaderField>| {
+            headers
+                .into_iter()
+                .map(|HeaderField(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+
+        for dangerous in &[
+            ("Transfer-Encoding", "chunked"),
+            ("Connection", "close"),
+            ("Keep-Alive", "timeout=5"),
+            ("Upgrade", "h2c"),
+            ("TE", "trailers"),
+            ("Trailers", "X-Checksum"),
+            ("Proxy-Authenticate", "Basic"),
+        ] {
+            assert_eq!(
+                names(end_to_end_headers(
+                    headers(&[*dangerous, ("Content-Type", "text/html")]),
+                    Some(0)
+                )),
+                ["Content-Type"],
+                "{}",
+                dangerous.0
+            );
+        }
+        assert_eq!(
+            names(end_to_end_headers(
+                headers(&[("Connection", "X-Hop"), ("X-Hop", "1"), ("X-Kept", "1")]),
+                Some(0)
+            )),
+            ["X-Kept"]
+        );
+    }
+
+    #[test]
+    fn disagreeing_content_lengths_of_canister_responses_are_removed() {
+        let content_length = |value: &str, body_length: Option<usize>| {
+            !end_to_end_headers(
+                vec![HeaderField("Content-Length".to_string(), value.to_string())],
+                body_length,
+            )
+            .is_empty()
+        };
+
+        assert!(content_length("5", Some(5)));
+        assert!(content_length(" 5", Some(5)));
+        assert!(!content_length("6", Some(5)));
+        assert!(!content_length("five", Some(5)));
+        // The length of streamed bodies is not known yet.
+        assert!(content_length("1000", None));
+    }
+
+    #[test]
+    fn client_ip_header_is_only_trusted_from_trusted_proxies() {
+        let config = config(&[
+            "--client-ip-header",
+            "CF-Connecting-IP",
+            "--trusted-proxy",
+            "10.0.0.0/8",
+        ]);
+        let client_ip = |peer: &str, value: Option<&str>| {
+            let mut headers = hyper::HeaderMap::new();
+            if let Some(value) = value {
+                headers.insert("cf-connecting-ip", value.parse().unwrap());
+            }
+            config
+                .client_ip(peer.parse().unwrap(), &headers)
+                .to_string()
+        };
+
+        assert_eq!(client_ip("10.0.0.1", Some("198.51.100.7")), "198.51.100.7");
+        assert_eq!(client_ip("10.0.0.1", Some(" 2001:db8::1 ")), "2001:db8::1");
+        assert_eq!(
+            client_ip("::ffff:10.0.0.1", Some("198.51.100.7")),
+            "198.51.100.7"
+        );
+        // Untrusted peers, and invalid or missing addresses, fall back to the peer.
+        assert_eq!(client_ip("192.0.2.1", Some("198.51.100.7")), "192.0.2.1");
+        assert_eq!(client_ip("10.0.0.1", Some("not an address")), "10.0.0.1");
+        assert_eq!(
+            client_ip("10.0.0.1", Some("198.51.100.7, 10.0.0.2")),
+            "10.0.0.1"
+        );
+        assert_eq!(client_ip("10.0.0.1", None), "10.0.0.1");
+
+        let untrusting = crate::tests::config(&["--client-ip-header", "CF-Connecting-IP"]);
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("cf-connecting-ip", "198.51.100.7".parse().unwrap());
+        assert_eq!(
+            untrusting
+                .client_ip("10.0.0.1".parse().unwrap(), &headers)
+                .to_string(),
+            "10.0.0.1"
+        );
+    }
+
+    #[test]
+    fn x_forwarded_for_ipv4() {
+        assert_eq!(
+            proxied_headers("192.0.2.1", &[], &[]),
+            ("192.0.2.1".to_string(), String::new())
+        );
+        assert_eq!(
+            proxied_headers("192.0.2.1", &["198.51.100.7"], &[]).0,
+            "198.51.100.7, 192.0.2.1"
+        );
+    }
+
+    #[test]
+    fn x_forwarded_for_ipv6_is_bare() {
+        assert_eq!(proxied_headers("2001:db8::1", &[], &[]).0, "2001:db8::1");
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_ipv4() {
+        assert_eq!(
+            proxied_headers("::ffff:192.0.2.1", &[], &["--forwarded-header"]),
+            (
+                "192.0.2.1".to_string(),
+                "for=192.0.2.1;proto=http".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn forwarded_quotes_ipv6() {
+        assert_eq!(
+            proxied_headers("2001:db8::1", &[], &["--forwarded-header"]).1,
+            r#"for="[2001:db8::1]";proto=http"#
+        );
+        assert_eq!(
+            proxied_headers("::1", &["192.0.2.1"], &["--forwarded-header"]).1,
+            r#"for=192.0.2.1, for="[::1]";proto=http"#
+        );
+    }
+
+    #[test]
+    fn forwarded_tells_the_requested_host() {
+        let client_ip = "192.0.2.1".parse::<IpAddr>().unwrap();
+        assert_eq!(
+            forwarded_element(&client_ip, "http", Some("example.com")),
+            "for=192.0.2.1;proto=http;host=example.com"
+        );
+        assert_eq!(
+            forwarded_element(&client_ip, "https", Some("localhost:8000")),
+            r#"for=192.0.2.1;proto=https;host="localhost:8000""#
+        );
+        assert_eq!(
+            forwarded_element(&client_ip, "http", Some("[::1]:8000")),
+            r#"for=192.0.2.1;proto=http;host="[::1]:8000""#
+        );
+        assert_eq!(
+            forwarded_element(&client_ip, "http", Some(r#"a"b\c"#)),
+            r#"for=192.0.2.1;proto=http;host="a\"b\\c""#
+        );
+
+        let request = create_proxied_request(
+            &client_ip,
+            "http://localhost:8000/",
+            Request::builder()
+                .uri("/api/v2/status")
+                .header("host", "ic0.app")
+                .body(())
+                .unwrap(),
+            &config(&["--forwarded-header"]),
+        )
+        .unwrap();
+        assert_eq!(
+            request.headers()["forwarded"],
+            "for=192.0.2.1;proto=http;host=ic0.app"
+        );
+    }
+
+    #[test]
+    fn x_forwarded_proto_and_host() {
+        let forwarded = |headers: &[(&str, &str)], is_trusted_peer| {
+            x_forwarded_proto_host(request_with("/", headers).headers(), is_trusted_peer)
+                .into_iter()
+                .map(|HeaderField(name, value)| (name, value))
+                .collect::<Vec<_>>()
+        };
+        let expected = |proto: &str, host: &str| {
+            vec![
+                ("x-forwarded-proto".to_string(), proto.to_string()),
+                ("x-forwarded-host".to_string(), host.to_string()),
+            ]
+        };
+        let spoofed = [
+            ("Host", "example.com"),
+            ("X-Forwarded-Proto", "HTTPS"),
+            ("X-Forwarded-Host", "evil.example.com"),
+        ];
+
+        assert_eq!(
+            forwarded(&[("Host", "example.com:8000")], false),
+            expected("http", "example.com:8000")
+        );
+        assert_eq!(forwarded(&spoofed, false), expected("http", "example.com"));
+        assert_eq!(
+            forwarded(&spoofed, true),
+            expected("https", "evil.example.com")
+        );
+        assert_eq!(
+            forwarded(
+                &[("Host", "example.com"), ("X-Forwarded-Proto", "ftp")],
+                true
+            ),
+            expected("http", "example.com")
+        );
+        assert_eq!(
+            forwarded(&[], false),
+            vec![("x-forwarded-proto".to_string(), "http".to_string())]
+        );
+    }
+
+    #[test]
+    fn forwarded_instead_of_x_forwarded_for() {
+        assert_eq!(
+            proxied_headers(
+                "192.0.2.1",
+                &[],
+                &["--forwarded-header", "--no-x-forwarded-for-header"]
+            ),
+            (String::new(), "for=192.0.2.1;proto=http".to_string())
+        );
+    }
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn stream_limits() -> StreamLimits {
+        StreamLimits {
+            max_callbacks: 1000,
+            max_bytes: None,
+            callback_timeout: Duration::from_secs(30),
+            callback_retries: RetryPolicy {
+                attempts: 3,
+                base_delay: Duration::from_millis(1),
+            },
+            max_duration: None,
+            keepalive_interval: None,
+            rate_limit: None,
+        }
+    }
+
+    /// Stream chunks answered by `answer`, called with the number of calls so far, and
+    /// return the abort reason and the number of calls made.
+    async fn stream_with<A>(content_length: Option<u64>, answer: A) -> (Option<&'static str>, usize)
+    where
+        A: Fn(usize) -> Result<(Vec<u8>, Option<()>), AgentError>,
+    {
+        let (mut sender, body) = Body::channel();
+        let reading = tokio::spawn(hyper::body::to_bytes(body));
+        let mut calls = 0;
+        let abort_reason = stream_chunks(
+            &mut sender,
+            0,
+            |_| {
+                calls += 1;
+                // Every chunk comes with a new token.
+                let answer = answer(calls).map(|(body, more)| (body, more.map(|()| calls)));
+                async move { answer }
+            },
+            stream_limits(),
+            &[],
+            content_length,
+            &logger(),
+        )
+        .await
+        .abort_reason;
+        drop(sender);
+        let _ = reading.await;
+        (abort_reason, calls)
+    }
+
+    #[tokio::test]
+    async fn transient_callback_errors_are_retried() {
+        let (abort_reason, calls) = stream_with(None, |call| match call {
+            1 => Err(AgentError::TransportError("connection reset".into())),
+            2 => Err(http_error(500)),
+            _ => Ok((b"chunk".to_vec(), None)),
+        })
+        .await;
+
+        assert_eq!(abort_reason, None);
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test]
+    async fn callback_retries_are_limited() {
+        let (abort_reason, calls) = stream_with(None, |_| {
+            Err(AgentError::TransportError("connection reset".into()))
+        })
+        .await;
+
+        assert_eq!(abort_reason, Some("callback_error"));
+        assert_eq!(calls, 4);
+    }
+
+    #[tokio::test]
+    async fn rejects_are_not_retried() {
+        let (abort_reason, calls) = stream_with(None, |_| {
+            Err(AgentError::ReplicaError {
+                reject_code: 5,
+                reject_message: "canister trapped".to_string(),
+            })
+        })
+        .await;
+
+        assert_eq!(abort_reason, Some("callback_error"));
+        assert_eq!(calls, 1);
+
+        let (abort_reason, calls) = stream_with(None, |_| Err(http_error(404))).await;
+
+        assert_eq!(abort_reason, Some("callback_error"));
+        assert_eq!(calls, 1);
+    }
+
+    fn chunks(count: usize) -> impl Fn(usize) -> Result<(Vec<u8>, Option<()>), AgentError> {
+        move |call| Ok((vec![0u8; 16], if call < count { Some(()) } else { None }))
+    }
+
+    #[tokio::test]
+    async fn streams_are_summarized() {
+        let (mut sender, body) = Body::channel();
+        let reading = tokio::spawn(hyper::body::to_bytes(body));
+        let summary = stream_chunks(
+            &mut sender,
+            0u32,
+            |token| async move {
+                Ok((
+                    vec![0u8; 16],
+                    if token < 2 { Some(token + 1) } else { None },
+                ))
+            },
+            stream_limits(),
+            &[0u8; 10],
+            None,
+            &logger(),
+        )
+        .await;
+        drop(sender);
+        let _ = reading.await;
+
+        assert_eq!(summary.abort_reason, None);
+        assert_eq!(summary.chunks, 3);
+        assert_eq!(summary.bytes_sent, 58);
+    }
+
+    #[tokio::test]
+    async fn streams_content_length() {
+        let (abort_reason, _) = stream_with(Some(48), chunks(3)).await;
+
+        assert_eq!(abort_reason, None);
+    }
+
+    #[tokio::test]
+    async fn streams_shorter_than_content_length_are_aborted() {
+        let (abort_reason, _) = stream_with(Some(64), chunks(3)).await;
+
+        assert_eq!(abort_reason, Some("length_mismatch"));
+    }
+
+    #[tokio::test]
+    async fn streams_longer_than_content_length_are_aborted() {
+        let (abort_reason, calls) = stream_with(Some(24), chunks(3)).await;
+
+        assert_eq!(abort_reason, Some("length_mismatch"));
+        assert_eq!(calls, 2);
+    }
+
+    /// Call `answer`, called with the number of calls so far, with retries, and return
+    /// the result and the number of calls made.
+    async fn call_with<A>(answer: A) -> (Result<(), AgentError>, usize)
+    where
+        A: Fn(usize) -> Result<(), AgentError>,
+    {
+        let policy = RetryPolicy {
+            attempts: 2,
+            base_delay: Duration::from_millis(1),
+        };
+        let mut calls = 0;
+        let result = call_with_retries(
+            policy,
+            || {
+                calls += 1;
+                let answer = answer(calls);
+                async move { answer }
+            },
+            |_, _, _| {},
+        )
+        .await;
+        (result, calls)
+    }
+
+    fn http_error(status: u16) -> AgentError {
+        AgentError::HttpError(HttpErrorPayload {
+            status,
+            content_type: None,
+            content: vec![],
+        })
+    }
+
+    #[tokio::test]
+    async fn transient_replica_errors_are_retried() {
+        let (result, calls) = call_with(|call| match call {
+            1 => Err(http_error(503)),
+            2 => Err(AgentError::ReplicaError {
+                reject_code: 2,
+                reject_message: "queue full".to_string(),
+            }),
+            _ => Ok(()),
+        })
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(calls, 3);
+
+        // Streaming callbacks are not retried on rejects, transient or not.
+        let (abort_reason, calls) = stream_with(None, |_| {
+            Err(AgentError::ReplicaError {
+                reject_code: 2,
+                reject_message: "queue full".to_string(),
+            })
+        })
+        .await;
+        assert_eq!(abort_reason, Some("callback_error"));
+        assert_eq!(calls, 1);
+        let (abort_reason, calls) = stream_with(None, |_| Err(http_error(429))).await;
+        assert_eq!(abort_reason, Some("callback_error"));
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn replica_retries_are_limited() {
+        let (result, calls) = call_with(|_| Err(http_error(429))).await;
+
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+    }
+
+    #[tokio::test]
+    async fn permanent_replica_errors_are_not_retried() {
+        let (result, calls) = call_with(|_| Err(http_error(400))).await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        let (result, calls) = call_with(|_| {
+            Err(AgentError::ReplicaError {
+                reject_code: 5,
+                reject_message: "canister trapped".to_string(),
+            })
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn retry_delays_grow_exponentially_with_jitter() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(100),
+        };
+
+        for (attempt, max) in [(1, 100), (2, 200), (3, 400)] {
+            let delay = policy.delay(attempt);
+            assert!(delay >= Duration::from_millis(max / 2));
+            assert!(delay <= Duration::from_millis(max));
+        }
+    }
+
+    #[tokio::test]
+    async fn streaming_stops_when_tokens_repeat() {
+        let (mut sender, body) = Body::channel();
+        let reading = tokio::spawn(hyper::body::to_bytes(body));
+        let mut calls = 0;
+        let tokens = ["a", "b", "c", "b"];
+        let abort_reason = stream_chunks(
+            &mut sender,
+            "start".to_string(),
+            |_| {
+                calls += 1;
+                let token = tokens[calls - 1].to_string();
+                async move { Ok((vec![0u8; 16], Some(token))) }
+            },
+            stream_limits(),
+            &[],
+            None,
+            &logger(),
+        )
+        .await
+        .abort_reason;
+        drop(sender);
+        let _ = reading.await;
+
+        assert_eq!(abort_reason, Some("token_loop"));
+        assert_eq!(calls, 4);
+    }
+
+    #[tokio::test]
+    async fn rejects_streams_over_the_limit() {
+        let config = config(&[
+            "--max-concurrent-streams",
+            "1",
+            "--stream-overflow-policy",
+            "reject",
+        ]);
+
+        let permit = acquire_stream_permit(&config).await;
+        assert!(permit.is_some());
+        assert_eq!(config.metrics.streams_in_flight.load(Ordering::Relaxed), 1);
+        assert!(acquire_stream_permit(&config).await.is_none());
+
+        drop(permit);
+        assert_eq!(config.metrics.streams_in_flight.load(Ordering::Relaxed), 0);
+        assert!(acquire_stream_permit(&config).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn streams_over_the_limit_wait() {
+        let config = Arc::new(config(&[
+            "--max-concurrent-streams",
+            "1",
+            "--stream-wait-timeout",
+            "50",
+        ]));
+
+        let permit = acquire_stream_permit(&config).await;
+        assert!(permit.is_some());
+        assert!(acquire_stream_permit(&config).await.is_none());
+
+        let waiting = tokio::spawn({
+            let config = config.clone();
+            async move { acquire_stream_permit(&config).await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(permit);
+        assert!(waiting.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn streaming_is_paced_by_a_slow_client() {
+        let (mut sender, mut body) = Body::channel();
+        let calls = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let calls = calls.clone();
+            async move {
+                stream_chunks(
+                    &mut sender,
+                    0u32,
+                    |token| {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        async move { Ok((vec![0u8; 1024], Some(token + 1))) }
+                    },
+                    stream_limits(),
+                    &[],
+                    None,
+                    &logger(),
+                )
+                .await
+                .abort_reason
+            }
+        });
+
+        for read in 1..=20 {
+            body.data().await.unwrap().unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            // Besides the chunks read, at most one chunk is buffered and one in flight, so
+            // the memory held by the stream stays bounded however slow the client.
+            assert!(calls.load(Ordering::SeqCst) <= read + 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn keepalives_are_sent_while_waiting_for_chunks() {
+        let (mut sender, body) = Body::channel();
+        let reading = tokio::spawn(hyper::body::to_bytes(body));
+        let abort_reason = stream_chunks(
+            &mut sender,
+            0u32,
+            |_| async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                Ok((b"data: done\n\n".to_vec(), None))
+            },
+            StreamLimits {
+                keepalive_interval: Some(Duration::from_millis(20)),
+                ..stream_limits()
+            },
+            &[],
+            None,
+            &logger(),
+        )
+        .await
+        .abort_reason;
+        drop(sender);
+        let body = reading.await.unwrap().unwrap();
+
+        assert_eq!(abort_reason, None);
+        assert!(body.starts_with(b": keepalive\n\n"));
+        assert!(body.ends_with(b"data: done\n\n"));
+    }
+
+    #[tokio::test]
+    async fn keepalives_do_not_split_events() {
+        let stream = |sent: &'static [u8]| async move {
+            let (mut sender, body) = Body::channel();
+            let reading = tokio::spawn(hyper::body::to_bytes(body));
+            stream_chunks(
+                &mut sender,
+                0u32,
+                |token| async move {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Ok(match token {
+                        0 => (b"data: par".to_vec(), Some(1)),
+                        _ => (b"tial\n\n".to_vec(), None),
+                    })
+                },
+                StreamLimits {
+                    keepalive_interval: Some(Duration::from_millis(10)),
+                    ..stream_limits()
+                },
+                sent,
+                None,
+                &logger(),
+            )
+            .await;
+            drop(sender);
+            reading.await.unwrap().unwrap()
+        };
+
+        // Keepalives are sent after the event sent before streaming, but not in the middle
+        // of the next one.
+        let body = stream(b"data: first\n\n").await;
+        assert!(body.starts_with(b": keepalive\n\n"));
+        assert!(body.ends_with(b": keepalive\n\ndata: partial\n\n"));
+        let body = stream(b"data: fir").await;
+        assert_eq!(body, "data: partial\n\n");
+    }
+
+    #[test]
+    fn unknown_streaming_strategies_are_told_apart() {
+        #[derive(CandidType)]
+        enum FutureStreamingStrategy {
+            Push { topic: String },
+        }
+
+        #[derive(CandidType)]
+        struct FutureHttpResponse {
+            status_code: u16,
+            headers: Vec<HeaderField>,
+            body: Vec<u8>,
+            streaming_strategy: Option<FutureStreamingStrategy>,
+        }
+
+        let is_unknown = |answer: &[u8]| {
+            let http_response = Decode!(answer, HttpResponse).unwrap();
+            is_streaming_strategy_unknown(&http_response, answer)
+        };
+
+        let answer = Encode!(&FutureHttpResponse {
+            status_code: 200,
+            headers: vec![],
+            body: b"hello".to_vec(),
+            streaming_strategy: Some(FutureStreamingStrategy::Push {
+                topic: "news".to_string(),
+            }),
+        })
+        .unwrap();
+        assert!(is_unknown(&answer));
+
+        let answer = Encode!(&FutureHttpResponse {
+            status_code: 200,
+            headers: vec![],
+            body: b"hello".to_vec(),
+            streaming_strategy: None,
+        })
+        .unwrap();
+        assert!(!is_unknown(&answer));
+    }
+
+    #[tokio::test]
+    async fn streaming_is_rate_limited() {
+        let (mut sender, body) = Body::channel();
+        let reading = tokio::spawn(hyper::body::to_bytes(body));
+        let start = std::time::Instant::now();
+        let abort_reason = stream_chunks(
+            &mut sender,
+            0u32,
+            |token| async move {
+                Ok((
+                    vec![0u8; 100],
+                    if token < 2 { Some(token + 1) } else { None },
+                ))
+            },
+            StreamLimits {
+                rate_limit: Some(1000),
+                ..stream_limits()
+            },
+            &[],
+            None,
+            &logger(),
+        )
+        .await
+        .abort_reason;
+        drop(sender);
+        let body = reading.await.unwrap().unwrap();
+
+        assert_eq!(abort_reason, None);
+        assert_eq!(body.len(), 300);
+        // 300 bytes at 1000 bytes per second.
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn etags_are_weak_for_encoded_bodies() {
+        let body_sha = [0xab; 32];
+        let hex = "ab".repeat(32);
+
+        assert_eq!(etag(&body_sha, None), format!(r#""{}""#, hex));
+        assert_eq!(etag(&body_sha, Some("identity")), format!(r#""{}""#, hex));
+        assert_eq!(etag(&body_sha, Some("gzip")), format!(r#"W/"{}""#, hex));
+    }
+
+    #[test]
+    fn if_none_match_comparison() {
+        // Strong and weak tags are compared weakly.
+        assert!(if_none_match_matches(r#""abc""#, r#""abc""#));
+        assert!(if_none_match_matches(r#"W/"abc""#, r#""abc""#));
+        assert!(if_none_match_matches(r#""abc""#, r#"W/"abc""#));
+        assert!(!if_none_match_matches(r#""abd""#, r#""abc""#));
+        assert!(!if_none_match_matches(r#""abc""#, r#""ab""#));
+
+        // Any of several tags may match.
+        assert!(if_none_match_matches(r#""x", W/"abc" ,"y""#, r#""abc""#));
+        assert!(!if_none_match_matches(r#""x", "y""#, r#""abc""#));
+        assert!(if_none_match_matches(r#""a,b", "abc""#, r#""abc""#));
+        assert!(if_none_match_matches(r#""a,b""#, r#""a,b""#));
+        assert!(if_none_match_matches("*", r#""abc""#));
+
+        // Malformed tags are skipped.
+        assert!(if_none_match_matches(r#"abc, "abc""#, r#""abc""#));
+        assert!(!if_none_match_matches(r#""abc"#, r#""abc""#));
+    }
+
+    #[test]
+    fn matching_conditional_requests_are_not_modified() {
+        let response = || {
+            Response::builder()
+                .header("etag", r#""abc""#)
+                .header("cache-control", "max-age=60")
+                .header("content-type", "text/html")
+                .body(Body::from("hello"))
+                .unwrap()
+        };
+
+        let not_modified_response = not_modified(&response(), Some(r#"W/"abc""#)).unwrap();
+        assert_eq!(not_modified_response.status(), 304);
+        assert_eq!(not_modified_response.headers()["etag"], r#""abc""#);
+        assert_eq!(
+            not_modified_response.headers()["cache-control"],
+            "max-age=60"
+        );
+        assert!(!not_modified_response.headers().contains_key("content-type"));
+
+        assert!(not_modified(&response(), Some(r#""abd""#)).is_none());
+        assert!(not_modified(&response(), None).is_none());
+        let mut not_found = response();
+        *not_found.status_mut() = hyper::StatusCode::NOT_FOUND;
+        assert!(not_modified(&not_found, Some(r#""abc""#)).is_none());
+    }
+
+    #[tokio::test]
+    async fn unreachable_upstreams_are_bad_gateways() {
+        // Nothing listens on the port once the listener is dropped.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let replica_url = format!("http://127.0.0.1:{}", port);
+        let request = Request::builder()
+            .uri("/api/v2/status")
+            .body(Body::empty())
+            .unwrap();
+
+        let error = forward_api(
+            &"127.0.0.1".parse().unwrap(),
+            request,
+            &replica_url,
+            &config(&["--no-proxy", "127.0.0.1"]),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, ProxyError::ReplicaUnreachable(_)));
+        let response = error.response(true);
+        assert_eq!(response.status(), 502);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains(&replica_url));
+    }
+
+    #[tokio::test]
+    async fn replica_errors_are_passed_through() {
+        let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = incoming.local_addr().port();
+        let service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                Response::builder()
+                    .status(400)
+                    .header("content-type", "text/plain")
+                    .header("x-request-id", "42")
+                    .body(Body::from("Invalid request expiry"))
+            }))
+        });
+        tokio::spawn(hyper::Server::builder(incoming).serve(service));
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/api/v2/canister/rrkah-fqaaa-aaaaa-aaaaq-cai/call")
+            .body(Body::from("call"))
+            .unwrap();
+
+        let response = forward_api(
+            &"127.0.0.1".parse().unwrap(),
+            request,
+            &format!("http://127.0.0.1:{}", port),
+            &config(&["--no-proxy", "127.0.0.1"]),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.status(), 400);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(response.headers()["x-request-id"], "42");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Invalid request expiry");
+    }
+
+    #[tokio::test]
+    async fn request_bodies_are_not_copied() {
+        let upload = Bytes::from(vec![7u8; 2 * 1024 * 1024]);
+
+        let budget = BufferBudget::new(None, Arc::default());
+        let mut buffered = budget.reserve(0).unwrap();
+        let body = read_body(Body::from(upload.clone()), &mut buffered, 0)
+            .await
+            .unwrap();
+
+        assert_eq!(body.len(), upload.len());
+        assert_eq!(body.as_ptr(), upload.as_ptr());
+    }
+
+    #[tokio::test]
+    async fn request_bodies_are_read_within_the_budget() {
+        let budget = BufferBudget::new(Some(1000), Arc::default());
+        let chunked = || {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                for _ in 0..4 {
+                    if sender.send_data(Bytes::from(vec![7u8; 400])).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            body
+        };
+
+        let mut buffered = budget.reserve(0).unwrap();
+        assert!(matches!(
+            read_body(chunked(), &mut buffered, 0).await,
+            Err(ProxyError::Overloaded(_))
+        ));
+        drop(buffered);
+
+        // Bytes announced are not counted twice.
+        let mut buffered = budget.reserve(800).unwrap();
+        let body = read_body(Body::from(vec![7u8; 900]), &mut buffered, 800)
+            .await
+            .unwrap();
+        assert_eq!(body.len(), 900);
+        assert!(budget.reserve(101).is_none());
+        assert!(budget.reserve(100).is_some());
+    }
+
+    #[test]
+    fn bodies_are_decoded_for_clients_not_accepting_their_encoding() {
+        use std::io::Write;
+
+        let body = b"hello world";
+        let gzip = {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        };
+        let deflate = {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        };
+        let br = {
+            let mut encoded = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+                encoder.write_all(body).unwrap();
+            }
+            encoded
+        };
+        let decode = |encoded: &[u8], encoding: &str, accept_encoding: &str| {
+            let mut headers = hyper::HeaderMap::new();
+            headers.insert("accept-encoding", accept_encoding.parse().unwrap());
+            decode_for_client(
+                encoded,
+                Some(encoding),
+                accepted_encodings(&headers),
+                &BufferPool::new(1, 1024),
+                &logger(),
+            )
+            .map(|decoded| decoded.to_vec())
+        };
+
+        for (encoded, encoding) in &[(&gzip, "gzip"), (&deflate, "deflate"), (&br, "br")] {
+            assert_eq!(decode(encoded, encoding, encoding), None);
+            assert_eq!(
+                decode(encoded, encoding, "gzip, deflate, br"),
+                None,
+                "{}",
+                encoding
+            );
+            assert_eq!(
+                decode(encoded, encoding, "identity"),
+                Some(body.to_vec()),
+                "{}",
+                encoding
+            );
+            assert_eq!(
+                decode(encoded, encoding, &format!("{};q=0, *", encoding)),
+                Some(body.to_vec()),
+                "{}",
+                encoding
+            );
+        }
+        // Bodies are served as they are if they cannot be decoded.
+        assert_eq!(decode(b"not gzip", "gzip", "identity"), None);
+        assert!(decode_for_client(
+            body,
+            None,
+            accepted_encodings(&hyper::HeaderMap::new()),
+            &BufferPool::new(1, 1024),
+            &logger()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn request_bodies_are_decoded() {
+        use std::io::Write;
+
+        let body = b"hello world";
+        let gzip = {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        };
+        let deflate = {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        };
+        let br = {
+            let mut encoded = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+                encoder.write_all(body).unwrap();
+            }
+            encoded
+        };
+        let decode = |encoded: &[u8], encoding: &str| {
+            let mut headers = vec![
+                HeaderField("content-type".to_string(), "text/plain".to_string()),
+                HeaderField("content-encoding".to_string(), encoding.to_string()),
+                HeaderField("content-length".to_string(), encoded.len().to_string()),
+            ];
+            let buffers = BufferPool::new(1, 1024);
+            decode_request_body(
+                Bytes::copy_from_slice(encoded),
+                &mut headers,
+                &buffers,
+                &logger(),
+            )
+            .map(|decoded| {
+                let headers = headers
+                    .into_iter()
+                    .map(|HeaderField(name, value)| (name, value))
+                    .collect::<Vec<_>>();
+                (decoded, headers)
+            })
+        };
+        let decoded_headers = vec![
+            ("content-type".to_string(), "text/plain".to_string()),
+            ("content-length".to_string(), body.len().to_string()),
+        ];
+
+        for (encoded, encoding) in &[(&gzip, "gzip"), (&deflate, "deflate"), (&br, "BR")] {
+            let (decoded, headers) = decode(encoded, encoding).unwrap();
+            assert_eq!(decoded, &body[..], "{}", encoding);
+            assert_eq!(headers, decoded_headers, "{}", encoding);
+        }
+
+        // Bodies which are not encoded are left as they are.
+        let (decoded, headers) = decode(body, "identity").unwrap();
+        assert_eq!(decoded, &body[..]);
+        assert_eq!(headers, decoded_headers);
+
+        let error = decode(b"not gzip", "gzip").unwrap_err();
+        assert!(matches!(error, ProxyError::BadRequest(_)), "{:?}", error);
+        let error = decode(body, "compress").unwrap_err();
+        assert!(matches!(error, ProxyError::BadRequest(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn invalid_canister_status_codes_are_bad_gateways() {
+        let canister_id = Principal::from_text(CANISTER_A).unwrap();
+        let status = |status_code| canister_status(status_code, &canister_id, &logger());
+
+        assert_eq!(status(404).unwrap(), hyper::StatusCode::NOT_FOUND);
+        assert_eq!(status(200).unwrap(), hyper::StatusCode::OK);
+        assert_eq!(status(999).unwrap().as_u16(), 999);
+        for status_code in &[0, 99, 100, 101, 103, 199, 1000, u16::MAX] {
+            let error = status(*status_code).unwrap_err();
+            assert_eq!(error.status(), hyper::StatusCode::BAD_GATEWAY);
+            assert!(error.to_string().contains(&status_code.to_string()));
+            assert!(error.to_string().contains(CANISTER_A));
+        }
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(byte_range("bytes=0-499", 1000), Some(0..500));
+        assert_eq!(byte_range("bytes=500-", 1000), Some(500..1000));
+        assert_eq!(byte_range("bytes=-200", 1000), Some(800..1000));
+        assert_eq!(byte_range("bytes=900-2000", 1000), Some(900..1000));
+        assert_eq!(byte_range("bytes=-2000", 1000), Some(0..1000));
+        // Unsatisfiable ranges.
+        assert_eq!(byte_range("bytes=1000-", 1000), Some(1000..1000));
+        assert_eq!(byte_range("bytes=-0", 1000), Some(1000..1000));
+        assert_eq!(byte_range("bytes=0-", 0), Some(0..0));
+        // Ignored headers.
+        assert_eq!(byte_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(byte_range("bytes=500-100", 1000), None);
+        assert_eq!(byte_range("bytes=-", 1000), None);
+        assert_eq!(byte_range("items=0-1", 1000), None);
+    }
+
+    #[tokio::test]
+    async fn ranges_are_served_from_verified_bodies() {
+        let body = Bytes::from_static(b"hello world");
+        let response = || {
+            Response::builder()
+                .header("etag", r#""abc""#)
+                .header("content-type", "text/plain")
+                .header("content-length", "11")
+                .body(Body::from(body.clone()))
+                .unwrap()
+        };
+
+        let partial = partial_content(&response(), &body, Some("bytes=6-"), None, false).unwrap();
+        assert_eq!(partial.status(), 206);
+        assert_eq!(partial.headers()["content-range"], "bytes 6-10/11");
+        assert_eq!(partial.headers()["content-type"], "text/plain");
+        assert!(!partial.headers().contains_key("content-length"));
+        assert_eq!(
+            hyper::body::to_bytes(partial.into_body()).await.unwrap(),
+            "world"
+        );
+
+        let head = partial_content(&response(), &body, Some("bytes=0-4"), None, true).unwrap();
+        assert_eq!(head.headers()["content-length"], "5");
+        assert_eq!(hyper::body::to_bytes(head.into_body()).await.unwrap(), "");
+
+        let unsatisfiable =
+            partial_content(&response(), &body, Some("bytes=20-"), None, false).unwrap();
+        assert_eq!(unsatisfiable.status(), 416);
+        assert_eq!(unsatisfiable.headers()["content-range"], "bytes */11");
+
+        let if_range = |if_range| {
+            partial_content(&response(), &body, Some("bytes=0-4"), Some(if_range), false)
+                .map(|response| response.status())
+        };
+        assert_eq!(
+            if_range(r#""abc""#),
+            Some(hyper::StatusCode::PARTIAL_CONTENT)
+        );
+        assert_eq!(if_range(r#""abd""#), None);
+        assert_eq!(if_range("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+
+        assert!(partial_content(&response(), &body, Some("bytes=0-1,3-4"), None, false).is_none());
+        assert!(partial_content(&response(), &body, None, None, false).is_none());
+        let mut partial = response();
+        *partial.status_mut() = hyper::StatusCode::PARTIAL_CONTENT;
+        assert!(partial_content(&partial, &body, Some("bytes=0-4"), None, false).is_none());
+    }
+
+    #[test]
+    fn identical_queries_have_the_same_key() {
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let key = |method: Method, uri: &str, headers: &[(&str, &str)]| {
+            let headers = headers
+                .iter()
+                .map(|(name, value)| HeaderField(name.to_string(), value.to_string()))
+                .collect::<Vec<_>>();
+            QueryKey::new(canister_id, &method, &uri.parse().unwrap(), &headers)
+        };
+
+        let index = key(
+            Method::GET,
+            "/index.html",
+            &[("accept-encoding", "gzip"), ("user-agent", "curl")],
+        );
+        assert!(index.is_some());
+        assert_eq!(
+            index,
+            key(
+                Method::GET,
+                "/index.html",
+                &[("accept-encoding", "gzip"), ("user-agent", "curl")]
+            )
+        );
+        // Any header the canister is sent may change its answer.
+        assert_ne!(
+            index,
+            key(
+                Method::GET,
+                "/index.html",
+                &[("accept-encoding", "gzip"), ("user-agent", "wget")]
+            )
+        );
+        assert_ne!(
+            index,
+            key(
+                Method::GET,
+                "/index.html",
+                &[
+                    ("accept-encoding", "gzip"),
+                    ("user-agent", "curl"),
+                    ("x-api-key", "secret")
+                ]
+            )
+        );
+        assert_ne!(index, key(Method::GET, "/index.html", &[]));
+        assert_ne!(
+            index,
+            key(
+                Method::GET,
+                "/index.html?v=2",
+                &[("accept-encoding", "gzip")]
+            )
+        );
+
+        assert_eq!(key(Method::POST, "/index.html", &[]), None);
+        assert_eq!(
+            key(Method::GET, "/index.html", &[("cookie", "session=1")]),
+            None
+        );
+        assert_eq!(
+            key(Method::GET, "/index.html", &[("authorization", "Bearer x")]),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_streams_to_end() {
+        let streams = Arc::new(StreamRegistry::new());
+        let registration = streams.register();
+        assert_eq!(streams.active(), 1);
+
+        let ending = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(registration);
+        });
+
+        assert_eq!(streams.shutdown(Duration::from_secs(5)).await, 0);
+        assert_eq!(streams.active(), 0);
+        ending.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_cuts_remaining_streams_short() {
+        let streams = Arc::new(StreamRegistry::new());
+        let stream = tokio::spawn({
+            let streams = streams.clone();
+            async move {
+                let _registration = streams.register();
+                tokio::select! {
+                    _ = futures::future::pending::<()>() => false,
+                    _ = streams.cut_short() => true,
+                }
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(streams.shutdown(Duration::from_millis(20)).await, 1);
+        assert!(stream.await.unwrap());
+        assert_eq!(streams.active(), 0);
+    }
+
+    #[tokio::test]
+    async fn streaming_stops_when_client_disconnects() {
+        let (mut sender, mut body) = Body::channel();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let stream = tokio::spawn({
+            let calls = calls.clone();
+            async move {
+                stream_chunks(
+                    &mut sender,
+                    0u32,
+                    |token| {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        async move { Ok((vec![0u8; 16], Some(token + 1))) }
+                    },
+                    stream_limits(),
+                    &[],
+                    None,
+                    &logger(),
+                )
+                .await
+                .abort_reason
+            }
+        });
+
+        // Read a few chunks, then go away.
+        for _ in 0..3 {
+            body.data().await.unwrap().unwrap();
+        }
+        drop(body);
+
+        let abort_reason = tokio::time::timeout(Duration::from_secs(5), stream)
+            .await
+            .expect("the stream should stop once the client is gone")
+            .unwrap();
+        assert_eq!(abort_reason, Some("client_disconnected"));
+        // Besides the chunks read, at most one chunk was buffered and one in flight.
+        assert!(calls.load(Ordering::SeqCst) <= 5);
+    }
+
+    /// Serve a response of `size` bytes streamed in small chunks, configured by `args`.
+    /// Returns the port served on.
+    async fn serve_streamed(args: &[&str], size: usize) -> u16 {
+        let opts = Opts::parse_from(std::iter::once("icx-proxy").chain(args.iter().copied()));
+        let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = incoming.local_addr().port();
+        let service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_| async move {
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    for chunk in vec![0u8; size].chunks(16 * 1024) {
+                        if sender
+                            .send_data(Bytes::copy_from_slice(chunk))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                });
+                Ok::<_, Infallible>(Response::new(body))
+            }))
+        });
+        tokio::spawn(server_builder(incoming, &opts).serve(service));
+        port
+    }
+
+    #[tokio::test]
+    async fn head_responses_have_the_length_of_get_responses() {
+        let replica = MockReplica::new(|method, _| match method {
+            "http_request" => Ok(Encode!(&canned("hello world")).unwrap()),
+            _ => Err(format!("Unexpected method {}", method)),
+        });
+
+        let get = forward_method_to(&replica, Method::GET, "/", &[])
+            .await
+            .unwrap();
+        let head = forward_method_to(&replica, Method::HEAD, "/", &[])
+            .await
+            .unwrap();
+
+        let get_body = hyper::body::to_bytes(get.into_body()).await.unwrap();
+        assert_eq!(get_body, "hello world");
+        assert_eq!(
+            head.headers()["content-length"],
+            get_body.len().to_string().as_str()
+        );
+        let head_body = hyper::body::to_bytes(head.into_body()).await.unwrap();
+        assert!(head_body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn head_requests_answered_from_the_cache_have_no_body() {
+        let (certificate_sha, certificate_header) =
+            certified_asset(&Principal::from_text(CANISTER_A).unwrap(), "/", b"hello");
+        let replica = MockReplica::new(move |_, _| {
+            let mut response = canned("hello");
+            response.headers.push(HeaderField(
+                "IC-Certificate".to_string(),
+                certificate_header.clone(),
+            ));
+            Ok(Encode!(&response).unwrap())
+        });
+        let config = config(&["--cache-size-bytes", "1024"]);
+        // The mock replica cannot sign certificates.
+        config
+            .verification_cache
+            .as_ref()
+            .unwrap()
+            .insert_certificate(certificate_sha);
+        let host = format!("{}.localhost", CANISTER_A);
+        let forward = |method: Method| {
+            let mut request = request_with("/", &[("Host", &host)]);
+            *request.method_mut() = method;
+            forward_request(request, Arc::new(replica.agent()), &config, logger())
+        };
+
+        let get = forward(Method::GET).await.unwrap();
+        assert_eq!(get.headers()["x-cache"], "MISS");
+        let head = forward(Method::HEAD).await.unwrap();
+
+        assert_eq!(head.headers()["x-cache"], "HIT");
+        assert_eq!(head.headers()["content-length"], "5");
+        let body = hyper::body::to_bytes(head.into_body()).await.unwrap();
+        assert!(body.is_empty());
+        assert_eq!(replica.calls(), ["http_request"]);
+    }
+
+    #[tokio::test]
+    async fn streams_over_http2() {
+        // Larger than the initial HTTP/2 flow control window.
+        let size = 1024 * 1024;
+        let port = serve_streamed(&[], size).await;
+        let client = hyper::Client::builder()
+            .http2_only(true)
+            .build_http::<Body>();
+
+        let response = client
+            .get(format!("http://127.0.0.1:{}/", port).parse().unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body.len(), size);
+    }
+
+    #[tokio::test]
+    async fn http2_can_be_refused() {
+        let port = serve_streamed(&["--http1-only"], 1024).await;
+        let client = hyper::Client::builder()
+            .http2_only(true)
+            .build_http::<Body>();
+
+        assert!(client
+            .get(format!("http://127.0.0.1:{}/", port).parse().unwrap())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn http2_connections_are_dropped_past_their_limit_of_requests() {
+        let opts = Opts::parse_from(&["icx-proxy"]);
+        let mut incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = incoming.local_addr().port();
+        let incoming = accept::from_stream(futures::stream::poll_fn(move |cx| {
+            Pin::new(&mut incoming)
+                .poll_accept(cx)
+                .map_ok(|stream| Metered::new(stream, None))
+        }));
+        let limits = ConnectionLimits {
+            max_requests: Some(2),
+            max_bytes: None,
+        };
+        let service = make_service_fn(move |socket: &Metered<AddrStream>| {
+            let usage = Arc::new(ConnectionUsage::new(
+                limits,
+                socket.bytes(),
+                socket.dropped(),
+            ));
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    let usage = usage.clone();
+                    async move {
+                        let response = async { Ok(Response::new(Body::from("hello"))) };
+                        within_connection_limits([127, 0, 0, 1].into(), &usage, response, &logger())
+                            .await
+                    }
+                }))
+            }
+        });
+        tokio::spawn(server_builder(incoming, &opts).serve(service));
+
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+        let (mut sender, connection) = hyper::client::conn::Builder::new()
+            .http2_only(true)
+            .handshake::<_, Body>(stream)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let mut get = || sender.send_request(Request::get("/").body(Body::empty()).unwrap());
+
+        assert_eq!(get().await.unwrap().status(), 200);
+        assert_eq!(get().await.unwrap().status(), 200);
+        let refused = get().await.unwrap();
+        assert_eq!(refused.status(), 503);
+        assert_eq!(refused.headers()["retry-after"], "1");
+        assert!(get().await.is_err());
+    }
+
+    /// Serve HTTPS with a certificate for localhost issued by a freshly generated CA.
+    /// Returns the port served on and the PEM of the CA.
+    async fn serve_with_custom_ca() -> (u16, String) {
+        let mut ca_params = rcgen::CertificateParams::new(vec![]);
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        // Certificates get the same name by default, which would make that of the server
+        // look self-signed.
+        ca_params.distinguished_name = rcgen::DistinguishedName::new();
+        ca_params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "icx-proxy test CA");
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let server_cert = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+            "localhost".to_string(),
+        ]))
+        .unwrap();
+
+        let tls_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(
+                    server_cert.serialize_der_with_signer(&ca).unwrap(),
+                )],
+                rustls::PrivateKey(server_cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let service = service_fn(|_| async {
+                            Ok::<_, Infallible>(Response::new(Body::from("ok")))
+                        });
+                        let _ = hyper::server::conn::Http::new()
+                            .serve_connection(stream, service)
+                            .await;
+                    }
+                });
+            }
+        });
+
+        (port, ca.serialize_pem().unwrap())
+    }
+
+    #[tokio::test]
+    async fn cors_preflights_are_answered_by_the_proxy() {
+        let preflight = |args: &[&str]| {
+            let host = format!("{}.localhost", CANISTER_A);
+            let mut request = request_with(
+                "/upload",
+                &[
+                    ("Host", host.as_str()),
+                    ("Origin", "https://app.example.com"),
+                    ("Access-Control-Request-Method", "PUT"),
+                    ("Access-Control-Request-Headers", "x-custom"),
+                ],
+            );
+            *request.method_mut() = Method::OPTIONS;
+            crate::handle_request(
+                "127.0.0.1".parse().unwrap(),
+                request,
+                "http://127.0.0.1:1/".to_string(),
+                Arc::new(config(args)),
+                logger(),
+            )
+        };
+
+        // The replica cannot be reached, so only the proxy can answer.
+        let response = preflight(&["--cors", "permissive"]).await.unwrap();
+        assert_eq!(response.status(), 204);
+        assert_eq!(response.headers()["access-control-allow-origin"], "*");
+        assert_eq!(
+            response.headers()["access-control-allow-headers"],
+            "x-custom"
+        );
+
+        let response = preflight(&[
+            "--cors",
+            "allowlist",
+            "--cors-allowed-origin",
+            "https://app.example.com",
+            "--allowed-methods",
+            "GET",
+            "--allowed-methods",
+            "PUT",
+        ])
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 204);
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+        assert_eq!(
+            response.headers()["access-control-allow-credentials"],
+            "true"
+        );
+        assert_eq!(
+            response.headers()["access-control-allow-methods"],
+            "GET, PUT"
+        );
+
+        let response = preflight(&[
+            "--cors",
+            "allowlist",
+            "--cors-allowed-origin",
+            "https://other.example.com",
+        ])
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 403);
+
+        let response = preflight(&[]).await.unwrap();
+        assert_eq!(response.status(), 502);
+    }
+
+    #[tokio::test]
+    async fn maintenance_page_is_served_when_the_replica_is_unreachable() {
+        let path =
+            std::env::temp_dir().join(format!("icx-proxy-maintenance-{}.html", std::process::id()));
+        std::fs::write(&path, "<h1>Back soon</h1>").unwrap();
+        let config = ProxyConfig::new(&Opts::parse_from(&[
+            "icx-proxy",
+            "--maintenance-page",
+            path.to_str().unwrap(),
+        ]));
+        std::fs::remove_file(&path).unwrap();
+        let config = Arc::new(config.unwrap());
+        let host = format!("{}.localhost", CANISTER_A);
+
+        let response = crate::handle_request(
+            "127.0.0.1".parse().unwrap(),
+            request_with("/", &[("Host", &host)]),
+            "http://127.0.0.1:1/".to_string(),
+            config.clone(),
+            logger(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.status(), 503);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.headers()["retry-after"], "30");
+        assert!(response.headers()["server"]
+            .to_str()
+            .unwrap()
+            .starts_with("icx-proxy/"));
+        assert!(response.headers()["date"]
+            .to_str()
+            .unwrap()
+            .ends_with(" GMT"));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "<h1>Back soon</h1>");
+
+        // Agents calling the replica API are told the error rather than shown the page.
+        let response = crate::handle_request(
+            "127.0.0.1".parse().unwrap(),
+            request_with("/api/v2/status", &[("Host", &host)]),
+            "http://127.0.0.1:1/".to_string(),
+            config,
+            logger(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), 502);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_ne!(body, "<h1>Back soon</h1>");
+
+        assert!(ProxyConfig::new(&Opts::parse_from(&[
+            "icx-proxy",
+            "--maintenance-page",
+            "/nonexistent/maintenance.html",
+        ]))
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn replica_ca_cert_is_trusted() {
+        let (port, ca_pem) = serve_with_custom_ca().await;
+        let url = format!("https://localhost:{}/", port);
+        let path =
+            std::env::temp_dir().join(format!("icx-proxy-replica-ca-{}.pem", std::process::id()));
+        std::fs::write(&path, ca_pem).unwrap();
+
+        let default_config = config(&["--no-proxy", "localhost"]);
+        let custom_ca_config = ProxyConfig::new(&Opts::parse_from(&[
+            "icx-proxy",
+            "--no-proxy",
+            "localhost",
+            "--replica-ca-cert",
+            path.to_str().unwrap(),
+        ]));
+        std::fs::remove_file(&path).unwrap();
+        let custom_ca_config = custom_ca_config.unwrap();
+
+        assert!(default_config.http_client.get(&url).send().await.is_err());
+        let response = custom_ca_config.http_client.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn invalid_replica_certs_can_be_accepted() {
+        let (port, _) = serve_with_custom_ca().await;
+        let url = format!("https://localhost:{}/", port);
+
+        let config = config(&[
+            "--no-proxy",
+            "localhost",
+            "--danger-accept-invalid-replica-certs",
+        ]);
+
+        let response = config.http_client.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+
+    #[test]
+    fn replica_ca_cert_without_certificate_is_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("icx-proxy-empty-ca-{}.pem", std::process::id()));
+        std::fs::write(&path, "not a certificate").unwrap();
+
+        let result = ProxyConfig::new(&Opts::parse_from(&[
+            "icx-proxy",
+            "--replica-ca-cert",
+            path.to_str().unwrap(),
+        ]));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::Opts;
-use slog::{Drain, Level, LevelFilter, Logger};
+use slog::{Drain, Level, LevelFilter, Logger, Never};
+use slog_async::{AsyncGuard, OverflowStrategy};
 use std::{fs::File, path::PathBuf};
 
 /// The logging mode to use.
@@ -16,32 +17,36 @@ enum LoggingMode {
     File(PathBuf),
 }
 
-fn create_drain(mode: LoggingMode) -> Logger {
+/// The drains formatting records. They are only used from the thread of the asynchronous
+/// drain, so they need not be shared between threads.
+type FormatDrain = Box<dyn Drain<Ok = (), Err = Never> + Send>;
+
+fn create_drain(mode: LoggingMode) -> FormatDrain {
     match mode {
         LoggingMode::File(out) => {
             let file = File::create(out).expect("Couldn't open log file");
             let decorator = slog_term::PlainDecorator::new(file);
-            let drain = slog_term::FullFormat::new(decorator).build().fuse();
-            Logger::root(slog_async::Async::new(drain).build().fuse(), slog::o!())
+            Box::new(slog_term::FullFormat::new(decorator).build().fuse())
         }
         // A Tee mode is basically 2 drains duplicated.
-        LoggingMode::Tee(out) => Logger::root(
+        LoggingMode::Tee(out) => Box::new(
             slog::Duplicate::new(
                 create_drain(LoggingMode::Stderr),
                 create_drain(LoggingMode::File(out)),
             )
             .fuse(),
-            slog::o!(),
         ),
         LoggingMode::Stderr => {
             let decorator = slog_term::PlainDecorator::new(std::io::stderr());
-            let drain = slog_term::CompactFormat::new(decorator).build().fuse();
-            Logger::root(slog_async::Async::new(drain).build().fuse(), slog::o!())
+            Box::new(slog_term::CompactFormat::new(decorator).build().fuse())
         }
     }
 }
 
-pub(crate) fn setup_logging(opts: &Opts) -> Logger {
+/// Create the root logger. Records are formatted and written on a thread of their own, so
+/// that workers do not wait for each other to log. The returned guard must be kept alive
+/// for as long as the proxy runs: dropping it flushes the records still queued.
+pub(crate) fn setup_logging(opts: &Opts) -> (Logger, Option<AsyncGuard>) {
     // Create a logger with our argument matches.
     let verbose_level = opts.verbose as i64 - opts.quiet as i64;
     let logfile = opts.logfile.clone().unwrap_or_else(|| "log.txt".into());
@@ -65,15 +70,25 @@ pub(crate) fn setup_logging(opts: &Opts) -> Logger {
                 Level::Trace
             } else {
                 // Silent.
-                return Logger::root(slog::Discard, slog::o!());
+                return (Logger::root(slog::Discard, slog::o!()), None);
             }
         }
     };
 
+    let overflow_strategy = match opts.log_overflow_strategy.as_str() {
+        // Dropped records are counted, and reported once the queue has room again.
+        "drop" => OverflowStrategy::DropAndReport,
+        "block" => OverflowStrategy::Block,
+        _ => unreachable!("unhandled log overflow strategy"),
+    };
+
     let drain = LevelFilter::new(create_drain(mode), log_level).fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
+    let (drain, guard) = slog_async::Async::new(drain)
+        .chan_size(opts.log_channel_size)
+        .overflow_strategy(overflow_strategy)
+        .build_with_guard();
 
-    let root = Logger::root(drain, slog::o!("version" => clap::crate_version!()));
+    let root = Logger::root(drain.fuse(), slog::o!("version" => clap::crate_version!()));
     slog::info!(root, "Log Level: {}", log_level);
-    root
+    (root, Some(guard))
 }
@@ -0,0 +1,678 @@
+use crate::validate::VerificationFailure;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use ic_agent::export::Principal;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    fmt::Write,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// The buckets of the number of chunks of streamed responses.
+const STREAM_CHUNKS_BUCKETS: &[f64] =
+    &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0];
+
+/// The buckets of the bytes of streamed responses, from 64KiB to 1GiB.
+const STREAM_BYTES_BUCKETS: &[f64] = &[
+    65536.0,
+    262144.0,
+    1048576.0,
+    4194304.0,
+    16777216.0,
+    67108864.0,
+    268435456.0,
+    1073741824.0,
+];
+
+/// The buckets of the duration of streamed responses, in seconds.
+const STREAM_DURATION_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0];
+
+/// A family of counters, one per distinct key.
+pub(crate) struct Counters<K> {
+    counters: RwLock<HashMap<K, AtomicU64>>,
+}
+
+impl<K> Default for Counters<K> {
+    fn default() -> Self {
+        Counters {
+            counters: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> Counters<K> {
+    pub fn inc(&self, key: K) {
+        self.add(key, 1)
+    }
+
+    pub fn add(&self, key: K, value: u64) {
+        if let Some(counter) = self.counters.read().unwrap().get(&key) {
+            counter.fetch_add(value, Ordering::Relaxed);
+            return;
+        }
+        self.counters
+            .write()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Return the current value of every counter.
+    pub fn values(&self) -> Vec<(K, u64)> {
+        self.counters
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, counter)| (key.clone(), counter.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// A family of histograms sharing the same buckets, one per distinct key.
+pub(crate) struct Histograms<K> {
+    buckets: &'static [f64],
+    histograms: Mutex<HashMap<K, Histogram>>,
+}
+
+#[derive(Clone)]
+struct Histogram {
+    /// The number of observations in each bucket, and past the last bucket.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl<K: Clone + Eq + Hash> Histograms<K> {
+    /// `buckets` are the inclusive upper bounds of the buckets, in increasing order.
+    pub fn new(buckets: &'static [f64]) -> Self {
+        Histograms {
+            buckets,
+            histograms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn observe(&self, key: K, value: f64) {
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.buckets.len());
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(key).or_insert_with(|| Histogram {
+            counts: vec![0; self.buckets.len() + 1],
+            sum: 0.0,
+        });
+        histogram.counts[bucket] += 1;
+        histogram.sum += value;
+    }
+
+    fn values(&self) -> Vec<(K, Histogram)> {
+        self.histograms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, histogram)| (key.clone(), histogram.clone()))
+            .collect()
+    }
+}
+
+/// The outcome of validating a canister response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum CertificationOutcome {
+    /// The body was verified against the certificate.
+    Verified,
+    /// Verification was bypassed by the certification policy.
+    Skipped,
+    /// The response did not carry any certification.
+    NoCertificate,
+    /// The body could not be decoded to be verified.
+    DecodeError,
+    /// The canister answered an uncertified 404 for a path missing from its certified
+    /// tree.
+    UncertifiedError,
+    /// The canister marked the response as not certified.
+    OptedOut,
+    /// The response did not pass verification.
+    Failed(VerificationFailure),
+}
+
+impl CertificationOutcome {
+    fn label(&self) -> &'static str {
+        match self {
+            CertificationOutcome::Verified => "verified",
+            CertificationOutcome::Skipped => "skipped",
+            CertificationOutcome::NoCertificate => "no_certificate",
+            CertificationOutcome::DecodeError => "decode_error",
+            CertificationOutcome::UncertifiedError => "uncertified_error",
+            CertificationOutcome::OptedOut => "opted_out",
+            CertificationOutcome::Failed(_) => "verification_failed",
+        }
+    }
+}
+
+/// The metrics of the proxy, exposed on the metrics endpoint.
+pub(crate) struct Metrics {
+    pub certifications: Counters<(Principal, CertificationOutcome)>,
+    /// Lookups in the verification cache, by cache and whether they hit.
+    pub cache_lookups: Counters<(&'static str, bool)>,
+    /// Events of the response cache: "hit", "miss" and "evict".
+    pub response_cache: Counters<&'static str>,
+    /// Streamed responses which were cut short, by canister and reason.
+    pub aborted_streams: Counters<(Principal, &'static str)>,
+    /// Responses refused for their headers, by canister and reason.
+    pub rejected_response_headers: Counters<(Principal, &'static str)>,
+    /// Calls to replicas which were retried, by canister and reason.
+    pub replica_retries: Counters<(Principal, &'static str)>,
+    /// The number of responses being streamed.
+    pub streams_in_flight: AtomicU64,
+    /// The bytes of request and response bodies being buffered.
+    pub buffered_bytes: Arc<AtomicU64>,
+    /// How late, in microseconds, the last event loop lag probe woke up.
+    pub event_loop_lag: AtomicU64,
+    /// How late, in microseconds, all the event loop lag probes woke up.
+    pub event_loop_lag_total: AtomicU64,
+    /// Streamed responses, by canister and why they ended: "completed", or the reason they
+    /// were cut short.
+    pub streams: Counters<(Principal, &'static str)>,
+    pub stream_chunks: Histograms<Principal>,
+    pub stream_bytes: Histograms<Principal>,
+    pub stream_duration: Histograms<Principal>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            certifications: Counters::default(),
+            cache_lookups: Counters::default(),
+            response_cache: Counters::default(),
+            aborted_streams: Counters::default(),
+            rejected_response_headers: Counters::default(),
+            replica_retries: Counters::default(),
+            streams_in_flight: AtomicU64::default(),
+            buffered_bytes: Arc::default(),
+            event_loop_lag: AtomicU64::default(),
+            event_loop_lag_total: AtomicU64::default(),
+            streams: Counters::default(),
+            stream_chunks: Histograms::new(STREAM_CHUNKS_BUCKETS),
+            stream_bytes: Histograms::new(STREAM_BYTES_BUCKETS),
+            stream_duration: Histograms::new(STREAM_DURATION_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    /// Sleep for `interval` over and over, recording how late the sleeps end. The runtime is
+    /// saturated when they end late, as tasks wait that long to be polled.
+    pub async fn probe_event_loop_lag(&self, interval: Duration) {
+        loop {
+            let start = Instant::now();
+            tokio::time::sleep(interval).await;
+            let lag = start.elapsed().saturating_sub(interval).as_micros() as u64;
+            self.event_loop_lag.store(lag, Ordering::Relaxed);
+            self.event_loop_lag_total.fetch_add(lag, Ordering::Relaxed);
+        }
+    }
+
+    /// Record a streamed response of a canister which ended, cut short for `abort_reason` if
+    /// any.
+    pub fn record_stream(
+        &self,
+        canister_id: Principal,
+        abort_reason: Option<&'static str>,
+        chunks: usize,
+        bytes: u64,
+        duration: Duration,
+    ) {
+        self.streams
+            .inc((canister_id, abort_reason.unwrap_or("completed")));
+        self.stream_chunks.observe(canister_id, chunks as f64);
+        self.stream_bytes.observe(canister_id, bytes as f64);
+        self.stream_duration
+            .observe(canister_id, duration.as_secs_f64());
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut outcomes = BTreeMap::new();
+        let mut failures = BTreeMap::new();
+        for ((canister_id, outcome), value) in self.certifications.values() {
+            *outcomes
+                .entry((canister_id.to_text(), outcome.label()))
+                .or_insert(0) += value;
+            if let CertificationOutcome::Failed(failure) = outcome {
+                *failures
+                    .entry((canister_id.to_text(), failure.label()))
+                    .or_insert(0) += value;
+            }
+        }
+
+        let mut output = String::new();
+        render_header(
+            &mut output,
+            "icx_proxy_certifications_total",
+            "Outcomes of the verification of canister responses.",
+        );
+        for ((canister_id, outcome), value) in outcomes {
+            let _ = writeln!(
+                output,
+                r#"icx_proxy_certifications_total{{canister_id="{}",outcome="{}"}} {}"#,
+                canister_id, outcome, value
+            );
+        }
+        render_header(
+            &mut output,
+            "icx_proxy_certification_failures_total",
+            "Canister responses which failed verification, by class of failure.",
+        );
+        for ((canister_id, failure), value) in failures {
+            let _ = writeln!(
+                output,
+                r#"icx_proxy_certification_failures_total{{canister_id="{}",failure="{}"}} {}"#,
+                canister_id, failure, value
+            );
+        }
+
+        let mut cache_lookups = self.cache_lookups.values();
+        cache_lookups.sort_unstable();
+        render_header(
+            &mut output,
+            "icx_proxy_certification_cache_lookups_total",
+            "Lookups in the cache of verified certificates and responses.",
+        );
+        for ((cache, hit), value) in cache_lookups {
+            let _ = writeln!(
+                output,
+                r#"icx_proxy_certification_cache_lookups_total{{cache="{}",result="{}"}} {}"#,
+                cache,
+                if hit { "hit" } else { "miss" },
+                value
+            );
+        }
+
+        let mut response_cache = self.response_cache.values();
+        response_cache.sort_unstable();
+        render_header(
+            &mut output,
+            "icx_proxy_response_cache_total",
+            "Hits, misses and evictions of the cache of verified responses.",
+        );
+        for (event, value) in response_cache {
+            let _ = writeln!(
+                output,
+                r#"icx_proxy_response_cache_total{{event="{}"}} {}"#,
+                event, value
+            );
+        }
+
+        let _ = writeln!(
+            output,
+            "# HELP icx_proxy_streams_in_flight Responses being streamed."
+        );
+        let _ = writeln!(output, "# TYPE icx_proxy_streams_in_flight gauge");
+        let _ = writeln!(
+            output,
+            "icx_proxy_streams_in_flight {}",
+            self.streams_in_flight.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            output,
+            "# HELP icx_proxy_buffered_bytes Bytes of request and response bodies being buffered."
+        );
+        let _ = writeln!(output, "# TYPE icx_proxy_buffered_bytes gauge");
+        let _ = writeln!(
+            output,
+            "icx_proxy_buffered_bytes {}",
+            self.buffered_bytes.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            output,
+            "# HELP icx_proxy_event_loop_lag_seconds How late the last periodic sleep on the runtime ended."
+        );
+        let _ = writeln!(output, "# TYPE icx_proxy_event_loop_lag_seconds gauge");
+        let _ = writeln!(
+            output,
+            "icx_proxy_event_loop_lag_seconds {}",
+            self.event_loop_lag.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(
+            output,
+            "# HELP icx_proxy_event_loop_lag_seconds_total How late all the periodic sleeps on the runtime ended."
+        );
+        let _ = writeln!(
+            output,
+            "# TYPE icx_proxy_event_loop_lag_seconds_total counter"
+        );
+        let _ = writeln!(
+            output,
+            "icx_proxy_event_loop_lag_seconds_total {}",
+            self.event_loop_lag_total.load(Ordering::Relaxed) as f64 / 1e6
+        );
+
+        render_reasons(
+            &mut output,
+            "icx_proxy_streams_total",
+            "Streamed responses, by why they ended.",
+            &self.streams,
+        );
+        render_histograms(
+            &mut output,
+            "icx_proxy_stream_chunks",
+            "Chunks fetched from the streaming callback of streamed responses.",
+            &self.stream_chunks,
+        );
+        render_histograms(
+            &mut output,
+            "icx_proxy_stream_bytes",
+            "Bytes of streamed responses.",
+            &self.stream_bytes,
+        );
+        render_histograms(
+            &mut output,
+            "icx_proxy_stream_duration_seconds",
+            "Duration of streamed responses.",
+            &self.stream_duration,
+        );
+
+        render_reasons(
+            &mut output,
+            "icx_proxy_rejected_response_headers_total",
+            "Responses refused for their headers, by reason.",
+            &self.rejected_response_headers,
+        );
+        render_reasons(
+            &mut output,
+            "icx_proxy_replica_retries_total",
+            "Calls to replicas which were retried, by reason.",
+            &self.replica_retries,
+        );
+        render_reasons(
+            &mut output,
+            "icx_proxy_aborted_streams_total",
+            "Streamed responses which were cut short, by reason.",
+            &self.aborted_streams,
+        );
+        output
+    }
+
+    /// Log a summary of the certification outcomes across all canisters.
+    pub fn log_summary(&self, logger: &slog::Logger) {
+        let mut totals = BTreeMap::new();
+        for ((_, outcome), value) in self.certifications.values() {
+            let label = match outcome {
+                CertificationOutcome::Failed(failure) => failure.label(),
+                outcome => outcome.label(),
+            };
+            *totals.entry(label).or_insert(0u64) += value;
+        }
+        let summary = totals
+            .iter()
+            .map(|(label, value)| format!("{}={}", label, value))
+            .collect::<Vec<_>>()
+            .join(" ");
+        slog::info!(logger, "Certification summary: {}", summary);
+    }
+}
+
+fn render_header(output: &mut String, name: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} counter", name);
+}
+
+/// Render counters by canister and reason, sorted by both.
+fn render_reasons(
+    output: &mut String,
+    name: &str,
+    help: &str,
+    counters: &Counters<(Principal, &'static str)>,
+) {
+    let mut values = counters
+        .values()
+        .into_iter()
+        .map(|((canister_id, reason), value)| ((canister_id.to_text(), reason), value))
+        .collect::<Vec<_>>();
+    values.sort();
+    render_header(output, name, help);
+    for ((canister_id, reason), value) in values {
+        let _ = writeln!(
+            output,
+            r#"{}{{canister_id="{}",reason="{}"}} {}"#,
+            name, canister_id, reason, value
+        );
+    }
+}
+
+/// Render histograms by canister, sorted by canister.
+fn render_histograms(
+    output: &mut String,
+    name: &str,
+    help: &str,
+    histograms: &Histograms<Principal>,
+) {
+    let mut values = histograms
+        .values()
+        .into_iter()
+        .map(|(canister_id, histogram)| (canister_id.to_text(), histogram))
+        .collect::<Vec<_>>();
+    values.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} histogram", name);
+    for (canister_id, histogram) in values {
+        let mut cumulative = 0;
+        let bounds = histograms
+            .buckets
+            .iter()
+            .map(|bound| bound.to_string())
+            .chain(std::iter::once("+Inf".to_string()));
+        for (bound, count) in bounds.zip(&histogram.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                output,
+                r#"{}_bucket{{canister_id="{}",le="{}"}} {}"#,
+                name, canister_id, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            output,
+            r#"{}_sum{{canister_id="{}"}} {}"#,
+            name, canister_id, histogram.sum
+        );
+        let _ = writeln!(
+            output,
+            r#"{}_count{{canister_id="{}"}} {}"#,
+            name, canister_id, cumulative
+        );
+    }
+}
+
+/// Serve the metrics on GET /metrics.
+pub(crate) async fn handle_metrics_request(
+    request: Request<Body>,
+    metrics: &Metrics,
+) -> Result<Response<Body>, Infallible> {
+    Ok(
+        if request.method() == Method::GET && request.uri().path() == "/metrics" {
+            Response::builder()
+                .header("content-type", "text/plain; version=0.0.4")
+                .body(metrics.render().into())
+                .unwrap()
+        } else {
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body("Not found".into())
+                .unwrap()
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        metrics::{CertificationOutcome, Metrics},
+        validate::VerificationFailure,
+    };
+    use ic_agent::export::Principal;
+    use std::{
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    #[test]
+    fn renders_cache_lookups() {
+        let metrics = Metrics::default();
+        metrics.cache_lookups.inc(("response", false));
+        metrics.cache_lookups.inc(("response", true));
+        metrics.cache_lookups.inc(("response", true));
+        metrics.cache_lookups.inc(("certificate", true));
+
+        assert!(metrics.render().contains(
+            r#"icx_proxy_certification_cache_lookups_total{cache="certificate",result="hit"} 1
+icx_proxy_certification_cache_lookups_total{cache="response",result="miss"} 1
+icx_proxy_certification_cache_lookups_total{cache="response",result="hit"} 2
+"#
+        ));
+    }
+
+    #[test]
+    fn renders_aborted_streams() {
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let metrics = Metrics::default();
+        metrics
+            .aborted_streams
+            .inc((canister_id, "callback_timeout"));
+        metrics.aborted_streams.inc((canister_id, "deadline"));
+        metrics.aborted_streams.inc((canister_id, "deadline"));
+
+        assert!(metrics.render().ends_with(
+            r#"# TYPE icx_proxy_aborted_streams_total counter
+icx_proxy_aborted_streams_total{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",reason="callback_timeout"} 1
+icx_proxy_aborted_streams_total{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",reason="deadline"} 2
+"#
+        ));
+    }
+
+    #[test]
+    fn renders_replica_retries() {
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let metrics = Metrics::default();
+        metrics
+            .replica_retries
+            .inc((canister_id, "transient_reject"));
+
+        assert!(metrics.render().contains(
+            r#"icx_proxy_replica_retries_total{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",reason="transient_reject"} 1
+"#
+        ));
+    }
+
+    #[test]
+    fn renders_response_cache_events() {
+        let metrics = Metrics::default();
+        metrics.response_cache.inc("miss");
+        metrics.response_cache.inc("hit");
+        metrics.response_cache.inc("hit");
+
+        assert!(metrics.render().contains(
+            r#"icx_proxy_response_cache_total{event="hit"} 2
+icx_proxy_response_cache_total{event="miss"} 1
+"#
+        ));
+    }
+
+    #[test]
+    fn renders_stream_metrics() {
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let metrics = Metrics::default();
+        metrics.record_stream(canister_id, None, 3, 1000, Duration::from_millis(200));
+        metrics.record_stream(
+            canister_id,
+            Some("client_disconnected"),
+            30,
+            100_000,
+            Duration::from_secs(2),
+        );
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            r#"icx_proxy_streams_total{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",reason="client_disconnected"} 1
+icx_proxy_streams_total{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",reason="completed"} 1
+"#
+        ));
+        assert!(rendered.contains(
+            r#"icx_proxy_stream_chunks_bucket{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",le="2"} 0
+icx_proxy_stream_chunks_bucket{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",le="5"} 1
+icx_proxy_stream_chunks_bucket{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",le="10"} 1
+icx_proxy_stream_chunks_bucket{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",le="20"} 1
+icx_proxy_stream_chunks_bucket{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",le="50"} 2
+"#
+        ));
+        assert!(rendered.contains(
+            r#"icx_proxy_stream_chunks_bucket{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",le="+Inf"} 2
+icx_proxy_stream_chunks_sum{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai"} 33
+icx_proxy_stream_chunks_count{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai"} 2
+"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn event_loop_lag_is_measured() {
+        let metrics = Arc::new(Metrics::default());
+        let probe = {
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                metrics
+                    .probe_event_loop_lag(Duration::from_millis(10))
+                    .await
+            })
+        };
+        tokio::task::yield_now().await;
+
+        // Block the only thread of the runtime, as a saturated runtime would.
+        std::thread::sleep(Duration::from_millis(100));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        probe.abort();
+
+        let lag_total = metrics.event_loop_lag_total.load(Ordering::Relaxed);
+        assert!(lag_total >= 50_000, "{}", lag_total);
+    }
+
+    #[test]
+    fn renders_certification_counters() {
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let metrics = Metrics::default();
+        metrics
+            .certifications
+            .inc((canister_id, CertificationOutcome::Verified));
+        metrics
+            .certifications
+            .inc((canister_id, CertificationOutcome::Verified));
+        metrics.certifications.inc((
+            canister_id,
+            CertificationOutcome::Failed(VerificationFailure::InvalidSignature),
+        ));
+        metrics.certifications.inc((
+            canister_id,
+            CertificationOutcome::Failed(VerificationFailure::BodyMismatch),
+        ));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            r#"# HELP icx_proxy_certifications_total Outcomes of the verification of canister responses.
+# TYPE icx_proxy_certifications_total counter
+icx_proxy_certifications_total{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",outcome="verification_failed"} 2
+icx_proxy_certifications_total{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",outcome="verified"} 2
+"#
+        ));
+        assert!(rendered.contains(
+            r#"# HELP icx_proxy_certification_failures_total Canister responses which failed verification, by class of failure.
+# TYPE icx_proxy_certification_failures_total counter
+icx_proxy_certification_failures_total{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",failure="body_mismatch"} 1
+icx_proxy_certification_failures_total{canister_id="rrkah-fqaaa-aaaaa-aaaaq-cai",failure="invalid_signature"} 1
+"#
+        ));
+    }
+}
@@ -0,0 +1,250 @@
+use crate::validate::unsigned::certificate_of;
+use candid::{CandidType, Func, Nat};
+use ic_agent::{
+    agent::ReplicaV2Transport,
+    export::Principal,
+    ic_types::hash_tree::{fork, label, leaf},
+    Agent, AgentError, RequestId,
+};
+use ic_utils::interfaces::http_request::HeaderField;
+use serde_cbor::Value;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+type Handler = dyn Fn(&str, &[u8]) -> Result<Vec<u8>, String> + Send + Sync;
+
+/// A replica answering the queries of agents with canned answers, for tests to exercise
+/// the proxy without a network.
+///
+/// Update calls are answered by the same handler, and their outcome is read back with
+/// `read_state`. The certificate it is answered with holds the outcome at the path of the
+/// request id, but its signature is empty: signing it would take the BLS key of a subnet,
+/// so agents refuse it once they verify it.
+pub(crate) struct MockReplica {
+    handler: Box<Handler>,
+    /// How long queries take to be answered.
+    latency: Duration,
+    calls: Mutex<Vec<String>>,
+    /// The outcome of the last update call.
+    update: Mutex<Option<Result<Vec<u8>, String>>>,
+}
+
+impl MockReplica {
+    /// A replica answering a query to `method` with argument `arg` with `handler(method,
+    /// arg)`: the Candid encoded reply, or the message the canister rejected the query with.
+    pub fn new(
+        handler: impl Fn(&str, &[u8]) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+    ) -> Arc<MockReplica> {
+        MockReplica::with_latency(Duration::ZERO, handler)
+    }
+
+    /// A replica answering queries like [MockReplica::new], after `latency`.
+    pub fn with_latency(
+        latency: Duration,
+        handler: impl Fn(&str, &[u8]) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+    ) -> Arc<MockReplica> {
+        Arc::new(MockReplica {
+            handler: Box::new(handler),
+            latency,
+            calls: Mutex::new(Vec::new()),
+            update: Mutex::new(None),
+        })
+    }
+
+    /// An agent talking to this replica.
+    pub fn agent(self: &Arc<MockReplica>) -> Agent {
+        Agent::builder()
+            .with_transport(MockTransport(self.clone()))
+            .build()
+            .unwrap()
+    }
+
+    /// The methods queried or called so far, in order.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn record(&self, envelope: &[u8]) -> Result<(String, Vec<u8>), AgentError> {
+        let content = match serde_cbor::from_slice(envelope) {
+            Ok(Value::Map(mut envelope)) => envelope.remove(&text("content")),
+            _ => None,
+        };
+        let (method, arg) = match content {
+            Some(Value::Map(mut content)) => (
+                content.remove(&text("method_name")),
+                content.remove(&text("arg")),
+            ),
+            _ => (None, None),
+        };
+        match (method, arg) {
+            (Some(Value::Text(method)), Some(Value::Bytes(arg))) => {
+                self.calls.lock().unwrap().push(method.clone());
+                Ok((method, arg))
+            }
+            _ => Err(transport_error("Not a query or a call")),
+        }
+    }
+}
+
+/// An answer to http_request, or to http_request_update, as canisters encode it.
+#[derive(CandidType)]
+pub(crate) struct CannedResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<CannedStreamingStrategy>,
+    pub upgrade: Option<bool>,
+}
+
+#[derive(CandidType)]
+pub(crate) enum CannedStreamingStrategy {
+    Callback { callback: Func, token: CannedToken },
+}
+
+/// A token of the shape of those of asset canisters, the only one the proxy decodes.
+#[derive(CandidType)]
+pub(crate) struct CannedToken {
+    pub key: String,
+    pub content_encoding: String,
+    pub index: Nat,
+    pub sha256: Option<Vec<u8>>,
+}
+
+#[derive(CandidType)]
+pub(crate) struct CannedCallbackResponse {
+    pub body: Vec<u8>,
+    pub token: Option<CannedToken>,
+}
+
+/// A plain text answer with `body`.
+pub(crate) fn canned(body: &str) -> CannedResponse {
+    CannedResponse {
+        status_code: 200,
+        headers: vec![HeaderField(
+            "Content-Type".to_string(),
+            "text/plain".to_string(),
+        )],
+        body: body.as_bytes().to_vec(),
+        streaming_strategy: None,
+        upgrade: None,
+    }
+}
+
+struct MockTransport(Arc<MockReplica>);
+
+impl ReplicaV2Transport for MockTransport {
+    fn call<'a>(
+        &'a self,
+        _effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        _request_id: RequestId,
+    ) -> Pin<Box<dyn Future<Output = Result<(), AgentError>> + Send + 'a>> {
+        Box::pin(async move {
+            let (method, arg) = self.0.record(&envelope)?;
+            *self.0.update.lock().unwrap() = Some((self.0.handler)(&method, &arg));
+            Ok(())
+        })
+    }
+
+    fn read_state<'a>(
+        &'a self,
+        _effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, AgentError>> + Send + 'a>> {
+        Box::pin(async move {
+            let request_id = request_status_id(&envelope)
+                .ok_or_else(|| transport_error("Not a read of the status of a request"))?;
+            let outcome = match self.0.update.lock().unwrap().clone() {
+                Some(Ok(reply)) => fork(
+                    label("reply", leaf(reply)),
+                    label("status", leaf(b"replied".to_vec())),
+                ),
+                Some(Err(message)) => fork(
+                    // CANISTER_REJECT, LEB128 encoded.
+                    label("reject_code", leaf(vec![4])),
+                    fork(
+                        label("reject_message", leaf(message.into_bytes())),
+                        label("status", leaf(b"rejected".to_vec())),
+                    ),
+                ),
+                None => return Err(transport_error("No update was called")),
+            };
+            let tree = label("request_status", label(request_id, outcome));
+            let mut response = BTreeMap::new();
+            response.insert(text("certificate"), Value::Bytes(certificate_of(tree)));
+            serde_cbor::to_vec(&Value::Map(response)).map_err(|e| transport_error(&e.to_string()))
+        })
+    }
+
+    fn query<'a>(
+        &'a self,
+        _effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, AgentError>> + Send + 'a>> {
+        Box::pin(async move {
+            let (method, arg) = self.0.record(&envelope)?;
+            if !self.0.latency.is_zero() {
+                tokio::time::sleep(self.0.latency).await;
+            }
+            let mut response = BTreeMap::new();
+            match (self.0.handler)(&method, &arg) {
+                Ok(reply) => {
+                    let mut reply_map = BTreeMap::new();
+                    reply_map.insert(text("arg"), Value::Bytes(reply));
+                    response.insert(text("status"), text("replied"));
+                    response.insert(text("reply"), Value::Map(reply_map));
+                }
+                Err(message) => {
+                    response.insert(text("status"), text("rejected"));
+                    // CANISTER_REJECT
+                    response.insert(text("reject_code"), Value::Integer(4));
+                    response.insert(text("reject_message"), Value::Text(message));
+                }
+            }
+            serde_cbor::to_vec(&Value::Map(response)).map_err(|e| transport_error(&e.to_string()))
+        })
+    }
+
+    fn status<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, AgentError>> + Send + 'a>> {
+        Box::pin(async { Err(transport_error("The mock replica has no status")) })
+    }
+}
+
+/// The id of the request whose status the `read_state` `envelope` reads.
+fn request_status_id(envelope: &[u8]) -> Option<Vec<u8>> {
+    let mut content = match serde_cbor::from_slice(envelope).ok()? {
+        Value::Map(mut envelope) => match envelope.remove(&text("content"))? {
+            Value::Map(content) => content,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let paths = match content.remove(&text("paths"))? {
+        Value::Array(paths) => paths,
+        _ => return None,
+    };
+    paths.into_iter().find_map(|path| match path {
+        Value::Array(path) => match path.as_slice() {
+            [Value::Bytes(name), Value::Bytes(request_id)] if name == b"request_status" => {
+                Some(request_id.clone())
+            }
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+fn text(text: &str) -> Value {
+    Value::Text(text.to_string())
+}
+
+fn transport_error(message: &str) -> AgentError {
+    AgentError::TransportError(message.into())
+}
@@ -0,0 +1,145 @@
+use ic_agent::{
+    agent::{agent_error::HttpErrorPayload, ReplicaV2Transport},
+    export::Principal,
+    AgentError, RequestId,
+};
+use reqwest::{header::CONTENT_TYPE, Method, Url};
+use std::{future::Future, pin::Pin};
+
+const IC0_DOMAIN: &str = "ic0.app";
+const IC0_SUB_DOMAIN: &str = ".ic0.app";
+
+/// A transport to a replica through a given client, so that calls to canisters go through
+/// the outbound proxy and trust the CA certificates the client was built with. The
+/// transport of ic-agent builds a client of its own.
+pub(crate) struct ClientTransport {
+    url: Url,
+    client: reqwest::Client,
+}
+
+impl ClientTransport {
+    pub fn create(url: &str, client: reqwest::Client) -> Result<ClientTransport, AgentError> {
+        let url = Url::parse(url)
+            .and_then(|mut url| {
+                // Like ic-agent, subdomains of ic0.app are served by ic0.app.
+                if let Some(domain) = url.domain() {
+                    if domain.ends_with(IC0_SUB_DOMAIN) {
+                        url.set_host(Some(IC0_DOMAIN))?;
+                    }
+                }
+                url.join("api/v2/")
+            })
+            .map_err(|_| AgentError::InvalidReplicaUrl(url.to_string()))?;
+        Ok(ClientTransport { url, client })
+    }
+
+    async fn execute(
+        &self,
+        method: Method,
+        endpoint: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>, AgentError> {
+        let mut request = self
+            .client
+            .request(method, self.url.join(endpoint)?)
+            .header(CONTENT_TYPE, "application/cbor");
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| AgentError::TransportError(Box::new(e)))?;
+
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| AgentError::TransportError(Box::new(e)))?
+            .to_vec();
+
+        if status.is_client_error() || status.is_server_error() {
+            Err(AgentError::HttpError(HttpErrorPayload {
+                status: status.into(),
+                content_type,
+                content: body,
+            }))
+        } else {
+            Ok(body)
+        }
+    }
+
+    async fn post(
+        &self,
+        canister_id: Principal,
+        kind: &str,
+        envelope: Vec<u8>,
+    ) -> Result<Vec<u8>, AgentError> {
+        let endpoint = format!("canister/{}/{}", canister_id.to_text(), kind);
+        self.execute(Method::POST, &endpoint, Some(envelope)).await
+    }
+}
+
+impl ReplicaV2Transport for ClientTransport {
+    fn call<'a>(
+        &'a self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+        _request_id: RequestId,
+    ) -> Pin<Box<dyn Future<Output = Result<(), AgentError>> + Send + 'a>> {
+        Box::pin(async move {
+            self.post(effective_canister_id, "call", envelope).await?;
+            Ok(())
+        })
+    }
+
+    fn read_state<'a>(
+        &'a self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, AgentError>> + Send + 'a>> {
+        Box::pin(self.post(effective_canister_id, "read_state", envelope))
+    }
+
+    fn query<'a>(
+        &'a self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, AgentError>> + Send + 'a>> {
+        Box::pin(self.post(effective_canister_id, "query", envelope))
+    }
+
+    fn status<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<Vec<u8>, AgentError>> + Send + 'a>> {
+        Box::pin(self.execute(Method::GET, "status", None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientTransport;
+
+    #[test]
+    fn subdomains_of_ic0_app_are_served_by_ic0_app() {
+        let url = |replica_url| {
+            ClientTransport::create(replica_url, reqwest::Client::new())
+                .unwrap()
+                .url
+                .to_string()
+        };
+
+        assert_eq!(url("https://ic0.app"), "https://ic0.app/api/v2/");
+        assert_eq!(url("https://foo.ic0.app"), "https://ic0.app/api/v2/");
+        assert_eq!(
+            url("http://localhost:8000"),
+            "http://localhost:8000/api/v2/"
+        );
+        assert!(ClientTransport::create("localhost", reqwest::Client::new()).is_err());
+    }
+}
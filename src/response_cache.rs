@@ -0,0 +1,621 @@
+use hyper::{
+    body::Bytes,
+    header::{ACCEPT_ENCODING, ALLOW, CACHE_CONTROL, SET_COOKIE, VARY},
+    Body, HeaderMap, Method, Request, Response, StatusCode, Uri,
+};
+use ic_agent::export::Principal;
+use lru::LruCache;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The content codings clients accept, as far as canisters may tell them apart.
+const CODINGS: &[&str] = &["gzip", "deflate", "br", "zstd"];
+
+/// What a cached response may be served for: the same canister and path, for clients
+/// accepting the same content codings.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    canister_id: Principal,
+    /// The path and the query of the request.
+    path: String,
+    encodings: AcceptedEncodings,
+}
+
+/// The [CODINGS] a client accepts, one bit each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct AcceptedEncodings(u8);
+
+impl AcceptedEncodings {
+    /// Whether a body encoded with `encoding`, a comma-separated list of codings, is
+    /// accepted. Codings other than the [CODINGS] never are, but identity always is.
+    pub fn accepts(self, encoding: &str) -> bool {
+        encoding
+            .split(',')
+            .map(str::trim)
+            .filter(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case("identity"))
+            .all(|coding| {
+                CODINGS
+                    .iter()
+                    .position(|known| known.eq_ignore_ascii_case(coding))
+                    .map_or(false, |index| self.0 & (1 << index) != 0)
+            })
+    }
+}
+
+impl CacheKey {
+    pub fn new(canister_id: Principal, uri: &Uri, headers: &HeaderMap) -> CacheKey {
+        CacheKey {
+            canister_id,
+            path: uri
+                .path_and_query()
+                .map_or_else(|| uri.path().to_string(), |path| path.to_string()),
+            encodings: accepted_encodings(headers),
+        }
+    }
+}
+
+/// The [CODINGS] accepted according to the Accept-Encoding headers.
+pub(crate) fn accepted_encodings(headers: &HeaderMap) -> AcceptedEncodings {
+    let mut encodings = 0;
+    let mut refused_encodings = 0;
+    for value in headers.get_all(ACCEPT_ENCODING) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        for coding in value.split(',') {
+            let mut parameters = coding.split(';').map(str::trim);
+            let coding = parameters.next().unwrap_or_default().to_ascii_lowercase();
+            let refused = parameters.any(|parameter| {
+                parameter
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            let mask = if coding == "*" {
+                (1 << CODINGS.len()) - 1
+            } else if let Some(index) = CODINGS.iter().position(|known| *known == coding) {
+                1 << index
+            } else {
+                continue;
+            };
+            if !refused {
+                encodings |= mask;
+            } else if coding != "*" {
+                refused_encodings |= mask;
+            }
+        }
+    }
+    // A coding refused by name is not accepted through *.
+    AcceptedEncodings(encodings & !refused_encodings)
+}
+
+/// How long a response may be served from the cache according to its Cache-Control
+/// headers, at most `max_ttl`, or [None] if it may not be stored. s-maxage takes precedence
+/// over max-age. Responses which must be revalidated (no-cache) are not stored, as the
+/// cache has no way to revalidate them.
+pub(crate) fn freshness(headers: &HeaderMap, max_ttl: Duration) -> Option<Duration> {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for directive in headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+    {
+        let (name, value) = match directive.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (directive.trim(), None),
+        };
+        if ["no-store", "private", "no-cache"]
+            .iter()
+            .any(|forbidding| name.eq_ignore_ascii_case(forbidding))
+        {
+            return None;
+        }
+        let seconds = value.and_then(|value| value.parse::<u64>().ok());
+        if name.eq_ignore_ascii_case("max-age") {
+            max_age = seconds;
+        } else if name.eq_ignore_ascii_case("s-maxage") {
+            s_maxage = seconds;
+        }
+    }
+    let ttl = s_maxage
+        .or(max_age)
+        .map_or(max_ttl, |seconds| Duration::from_secs(seconds).min(max_ttl));
+    Some(ttl).filter(|ttl| !ttl.is_zero())
+}
+
+/// Whether a response may be served to any client asking for it: responses setting
+/// cookies are meant for a single client, and the cache only tells clients apart by the
+/// codings they accept, so responses varying on other request headers are not shared.
+fn is_shareable(headers: &HeaderMap) -> bool {
+    !headers.contains_key(SET_COOKIE)
+        && headers
+            .get_all(VARY)
+            .iter()
+            .flat_map(|value| {
+                value
+                    .to_str()
+                    .map_or(vec!["*"], |value| value.split(',').collect())
+            })
+            .map(str::trim)
+            .all(|name| name.is_empty() || name.eq_ignore_ascii_case(ACCEPT_ENCODING.as_str()))
+}
+
+/// A verified response, as it was served.
+#[derive(Clone, Debug)]
+pub(crate) struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl CachedResponse {
+    /// The bytes the response takes up in the cache.
+    fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>()
+    }
+}
+
+/// A cache of verified responses, bounded by the bytes of the responses, evicting the least
+/// recently used ones first. Entries expire once they, or the certificate of their
+/// response, are older than the time to live of the response: the time to live of the
+/// cache, or less if the Cache-Control of the response says so. They also expire once
+/// their certificate is older than the maximum age of certificates, if any.
+pub(crate) struct ResponseCache {
+    entries: Mutex<Entries>,
+    capacity: usize,
+    ttl: Duration,
+    max_certificate_age: Option<Duration>,
+}
+
+struct Entries {
+    responses: LruCache<CacheKey, (Instant, CachedResponse)>,
+    size: usize,
+}
+
+impl ResponseCache {
+    pub fn new(
+        capacity: usize,
+        ttl: Duration,
+        max_certificate_age: Option<Duration>,
+    ) -> ResponseCache {
+        ResponseCache {
+            entries: Mutex::new(Entries {
+                responses: LruCache::unbounded(),
+                size: 0,
+            }),
+            capacity,
+            ttl,
+            max_certificate_age,
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.responses.get(key) {
+            Some((expires, response)) if Instant::now() < *expires => Some(response.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Store a response whose certificate is `certificate_age` old. Returns the number of
+    /// responses evicted to make room for it. Responses larger than the cache, which their
+    /// Cache-Control does not let the cache store, or which may not be shared between
+    /// clients, are not stored.
+    pub fn insert(
+        &self,
+        key: CacheKey,
+        response: CachedResponse,
+        certificate_age: Duration,
+    ) -> usize {
+        if !is_shareable(&response.headers) {
+            return 0;
+        }
+        let size = response.size();
+        let ttl = match freshness(&response.headers, self.ttl) {
+            Some(ttl) => ttl.saturating_sub(certificate_age),
+            None => return 0,
+        };
+        let ttl = match self.max_certificate_age {
+            Some(max_age) => ttl.min(max_age.saturating_sub(certificate_age)),
+            None => ttl,
+        };
+        if size > self.capacity || ttl.is_zero() {
+            return 0;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+        entries.responses.put(key, (Instant::now() + ttl, response));
+        entries.size += size;
+
+        let mut evicted = 0;
+        while entries.size > self.capacity {
+            match entries.responses.pop_lru() {
+                Some((_, (_, response))) => {
+                    entries.size -= response.size();
+                    evicted += 1;
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    /// Remove the responses of a canister whose path starts with `path_prefix`. Returns the
+    /// number of responses removed.
+    pub fn purge(&self, canister_id: &Principal, path_prefix: &str) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let keys = entries
+            .responses
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.canister_id == *canister_id && key.path.starts_with(path_prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in &keys {
+            entries.remove(key);
+        }
+        keys.len()
+    }
+}
+
+/// Purge the responses of the canister_id query parameter, optionally only those whose
+/// path starts with the path_prefix query parameter, on POST /cache/purge.
+pub(crate) fn handle_purge_request(
+    request: &Request<Body>,
+    cache: Option<&ResponseCache>,
+) -> Response<Body> {
+    if request.method() != Method::POST {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(ALLOW, "POST")
+            .body("Method not allowed".into())
+            .unwrap();
+    }
+
+    let parameters = url::form_urlencoded::parse(request.uri().query().unwrap_or("").as_bytes())
+        .collect::<HashMap<_, _>>();
+    let canister_id = match parameters
+        .get("canister_id")
+        .map(|canister_id| Principal::from_text(canister_id.as_ref()))
+    {
+        Some(Ok(canister_id)) => canister_id,
+        Some(Err(e)) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(format!("Invalid canister_id: {}", e).into())
+                .unwrap()
+        }
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("The canister_id query parameter is required".into())
+                .unwrap()
+        }
+    };
+    let path_prefix = parameters
+        .get("path_prefix")
+        .map_or("", |prefix| prefix.as_ref());
+
+    let purged = cache.map_or(0, |cache| cache.purge(&canister_id, path_prefix));
+    Response::builder()
+        .body(format!("Purged {} responses\n", purged).into())
+        .unwrap()
+}
+
+impl Entries {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some((_, response)) = self.responses.pop(key) {
+            self.size -= response.size();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::response_cache::{
+        freshness, handle_purge_request, CacheKey, CachedResponse, ResponseCache,
+    };
+    use hyper::{body::Bytes, Body, HeaderMap, Request, StatusCode, Uri};
+    use ic_agent::export::Principal;
+    use std::time::Duration;
+
+    fn key(path: &str, accept_encoding: &str) -> CacheKey {
+        let mut headers = HeaderMap::new();
+        headers.insert("accept-encoding", accept_encoding.parse().unwrap());
+        CacheKey::new(
+            Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap(),
+            &path.parse::<Uri>().unwrap(),
+            &headers,
+        )
+    }
+
+    fn response(body: &'static str) -> CachedResponse {
+        CachedResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(body.as_bytes()),
+        }
+    }
+
+    fn cache_control(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("cache-control", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn caches_responses() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), None);
+        assert!(cache.get(&key("/index.html", "gzip")).is_none());
+
+        cache.insert(
+            key("/index.html", "gzip"),
+            response("hello"),
+            Duration::ZERO,
+        );
+
+        assert_eq!(
+            cache.get(&key("/index.html", "gzip")).unwrap().body,
+            "hello"
+        );
+        assert!(cache.get(&key("/app.js", "gzip")).is_none());
+        assert!(cache.get(&key("/index.html?v=2", "gzip")).is_none());
+    }
+
+    #[test]
+    fn responses_are_cached_by_accepted_encodings() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), None);
+        cache.insert(
+            key("/index.html", "gzip, br"),
+            response("hello"),
+            Duration::ZERO,
+        );
+
+        assert!(cache.get(&key("/index.html", "br;q=0.8, GZIP")).is_some());
+        assert!(cache.get(&key("/index.html", "gzip")).is_none());
+        assert!(cache
+            .get(&key("/index.html", "gzip, br, zstd;q=0"))
+            .is_some());
+        assert!(cache.get(&key("/index.html", "identity")).is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = ResponseCache::new(10, Duration::from_secs(60), None);
+        cache.insert(key("/a", ""), response("aaaa"), Duration::ZERO);
+        cache.insert(key("/b", ""), response("bbbb"), Duration::ZERO);
+        assert!(cache.get(&key("/a", "")).is_some());
+
+        let evicted = cache.insert(key("/c", ""), response("cccc"), Duration::ZERO);
+
+        assert_eq!(evicted, 1);
+        assert!(cache.get(&key("/a", "")).is_some());
+        assert!(cache.get(&key("/b", "")).is_none());
+        assert!(cache.get(&key("/c", "")).is_some());
+    }
+
+    #[test]
+    fn responses_larger_than_the_cache_are_not_stored() {
+        let cache = ResponseCache::new(4, Duration::from_secs(60), None);
+
+        assert_eq!(
+            cache.insert(key("/a", ""), response("aaaaa"), Duration::ZERO),
+            0
+        );
+        assert!(cache.get(&key("/a", "")).is_none());
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = ResponseCache::new(100, Duration::from_millis(10), None);
+        cache.insert(key("/a", ""), response("a"), Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert!(cache.get(&key("/a", "")).is_none());
+    }
+
+    #[test]
+    fn responses_with_old_certificates_are_not_stored() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), None);
+        cache.insert(key("/a", ""), response("a"), Duration::from_secs(60));
+
+        assert!(cache.get(&key("/a", "")).is_none());
+    }
+
+    #[test]
+    fn entries_expire_with_their_certificate() {
+        let cache = ResponseCache::new(
+            100,
+            Duration::from_secs(60),
+            Some(Duration::from_millis(30)),
+        );
+        // Within the time to live of the cache, but past the maximum age of certificates.
+        cache.insert(key("/a", ""), response("a"), Duration::from_millis(40));
+        cache.insert(key("/b", ""), response("b"), Duration::from_millis(10));
+
+        assert!(cache.get(&key("/a", "")).is_none());
+        assert!(cache.get(&key("/b", "")).is_some());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(cache.get(&key("/b", "")).is_none());
+    }
+
+    #[test]
+    fn responses_setting_cookies_are_not_stored() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), None);
+        let mut response = response("a");
+        response
+            .headers
+            .insert("set-cookie", "session=1".parse().unwrap());
+
+        assert_eq!(cache.insert(key("/a", ""), response, Duration::ZERO), 0);
+        assert!(cache.get(&key("/a", "")).is_none());
+    }
+
+    #[test]
+    fn responses_varying_on_other_headers_than_accept_encoding_are_not_stored() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), None);
+        let vary = |value: &str| {
+            let mut response = response("a");
+            response.headers.insert("vary", value.parse().unwrap());
+            response
+        };
+        cache.insert(key("/a", ""), vary("Accept-Encoding"), Duration::ZERO);
+        cache.insert(
+            key("/b", ""),
+            vary("Accept-Encoding, Cookie"),
+            Duration::ZERO,
+        );
+        cache.insert(key("/c", ""), vary("*"), Duration::ZERO);
+
+        assert!(cache.get(&key("/a", "")).is_some());
+        assert!(cache.get(&key("/b", "")).is_none());
+        assert!(cache.get(&key("/c", "")).is_none());
+    }
+
+    #[test]
+    fn cache_control_can_prevent_storing() {
+        let max_ttl = Duration::from_secs(60);
+        assert_eq!(freshness(&HeaderMap::new(), max_ttl), Some(max_ttl));
+        assert_eq!(freshness(&cache_control("public"), max_ttl), Some(max_ttl));
+        assert_eq!(freshness(&cache_control("No-Store"), max_ttl), None);
+        assert_eq!(
+            freshness(
+                &cache_control(r#"max-age=60, private="set-cookie""#),
+                max_ttl
+            ),
+            None
+        );
+        assert_eq!(freshness(&cache_control("no-cache"), max_ttl), None);
+        assert_eq!(freshness(&cache_control("max-age=0"), max_ttl), None);
+    }
+
+    #[test]
+    fn cache_control_shortens_the_time_to_live() {
+        let max_ttl = Duration::from_secs(60);
+        assert_eq!(
+            freshness(&cache_control("max-age=10"), max_ttl),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            freshness(&cache_control("max-age=10, s-maxage=20"), max_ttl),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            freshness(&cache_control("s-maxage=0, max-age=10"), max_ttl),
+            None
+        );
+        // The time to live of the cache is an upper bound.
+        assert_eq!(
+            freshness(&cache_control("max-age=3600"), max_ttl),
+            Some(max_ttl)
+        );
+        assert_eq!(
+            freshness(&cache_control("max-age=invalid"), max_ttl),
+            Some(max_ttl)
+        );
+    }
+
+    #[test]
+    fn responses_expire_as_their_cache_control_says() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), None);
+        let mut short_lived = response("a");
+        short_lived.headers = cache_control("max-age=1");
+        cache.insert(key("/a", ""), short_lived, Duration::ZERO);
+        assert!(cache.get(&key("/a", "")).is_some());
+
+        let mut uncacheable = response("b");
+        uncacheable.headers = cache_control("no-store");
+        cache.insert(key("/b", ""), uncacheable, Duration::ZERO);
+        assert!(cache.get(&key("/b", "")).is_none());
+
+        // The certificate counts against the age of the response.
+        let mut short_lived = response("c");
+        short_lived.headers = cache_control("max-age=1");
+        cache.insert(key("/c", ""), short_lived, Duration::from_secs(1));
+        assert!(cache.get(&key("/c", "")).is_none());
+    }
+
+    #[test]
+    fn purges_responses_by_path_prefix() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), None);
+        cache.insert(key("/assets/a.js", "gzip"), response("a"), Duration::ZERO);
+        cache.insert(key("/assets/a.js", ""), response("a"), Duration::ZERO);
+        cache.insert(key("/index.html", ""), response("i"), Duration::ZERO);
+        let other = CacheKey::new(
+            Principal::from_text("r7inp-6aaaa-aaaaa-aaabq-cai").unwrap(),
+            &"/assets/a.js".parse::<Uri>().unwrap(),
+            &HeaderMap::new(),
+        );
+        cache.insert(other.clone(), response("a"), Duration::ZERO);
+
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        assert_eq!(cache.purge(&canister_id, "/assets/"), 2);
+
+        assert!(cache.get(&key("/assets/a.js", "gzip")).is_none());
+        assert!(cache.get(&key("/index.html", "")).is_some());
+        assert!(cache.get(&other).is_some());
+
+        assert_eq!(cache.purge(&canister_id, ""), 1);
+        assert!(cache.get(&key("/index.html", "")).is_none());
+    }
+
+    #[test]
+    fn purge_requests() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), None);
+        cache.insert(key("/assets/a.js", ""), response("a"), Duration::ZERO);
+        cache.insert(key("/index.html", ""), response("i"), Duration::ZERO);
+        let request = |method: &str, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = handle_purge_request(
+            &request(
+                "POST",
+                "/cache/purge?canister_id=rrkah-fqaaa-aaaaa-aaaaq-cai&path_prefix=%2Fassets%2F",
+            ),
+            Some(&cache),
+        );
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(cache.get(&key("/assets/a.js", "")).is_none());
+        assert!(cache.get(&key("/index.html", "")).is_some());
+
+        let response = handle_purge_request(
+            &request(
+                "GET",
+                "/cache/purge?canister_id=rrkah-fqaaa-aaaaa-aaaaq-cai",
+            ),
+            Some(&cache),
+        );
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let response = handle_purge_request(&request("POST", "/cache/purge"), Some(&cache));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = handle_purge_request(
+            &request("POST", "/cache/purge?canister_id=not-a-principal"),
+            Some(&cache),
+        );
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(cache.get(&key("/index.html", "")).is_some());
+    }
+}
@@ -0,0 +1,184 @@
+use std::{collections::HashMap, future::Future, hash::Hash, sync::Mutex};
+use tokio::sync::watch;
+
+/// Calls in flight, so that identical calls made in the meantime wait for their result
+/// rather than being made again.
+pub(crate) struct SingleFlight<K, V> {
+    in_flight: Mutex<HashMap<K, Flight<V>>>,
+    max_followers: usize,
+}
+
+struct Flight<V> {
+    result: watch::Receiver<Option<V>>,
+    followers: usize,
+}
+
+enum Role<V> {
+    Leader(watch::Sender<Option<V>>),
+    Follower(watch::Receiver<Option<V>>),
+    /// Too many calls are waiting for the call in flight already.
+    Alone,
+}
+
+/// Removes a call from the calls in flight when it completes, or is cancelled.
+struct Landing<'a, K: Eq + Hash, V> {
+    in_flight: &'a Mutex<HashMap<K, Flight<V>>>,
+    key: &'a K,
+}
+
+impl<K: Eq + Hash, V> Drop for Landing<'_, K, V> {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(self.key);
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> SingleFlight<K, V> {
+    /// Let up to `max_followers` calls wait for an identical call in flight.
+    pub fn new(max_followers: usize) -> SingleFlight<K, V> {
+        SingleFlight {
+            in_flight: Mutex::new(HashMap::new()),
+            max_followers,
+        }
+    }
+
+    /// Make `call`, unless a call with the same key is in flight, in which case wait for
+    /// its result instead. Errors are not shared: if the call in flight fails, `call` is
+    /// made anyway, as it is when too many calls are waiting already.
+    pub async fn run<E, Fut>(&self, key: K, call: impl FnOnce() -> Fut) -> Result<V, E>
+    where
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let role = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get_mut(&key) {
+                Some(flight) if flight.followers < self.max_followers => {
+                    flight.followers += 1;
+                    Role::Follower(flight.result.clone())
+                }
+                Some(_) => Role::Alone,
+                None => {
+                    let (sender, receiver) = watch::channel(None);
+                    in_flight.insert(
+                        key.clone(),
+                        Flight {
+                            result: receiver,
+                            followers: 0,
+                        },
+                    );
+                    Role::Leader(sender)
+                }
+            }
+        };
+
+        match role {
+            Role::Leader(sender) => {
+                let landing = Landing {
+                    in_flight: &self.in_flight,
+                    key: &key,
+                };
+                let result = call().await;
+                // Followers only join while the call is in flight, so they all see the
+                // result.
+                drop(landing);
+                if let Ok(value) = &result {
+                    let _ = sender.send(Some(value.clone()));
+                }
+                result
+            }
+            Role::Follower(mut result) => {
+                // The sender is dropped without a value if the leader failed.
+                if result.changed().await.is_ok() {
+                    let value = result.borrow().clone();
+                    if let Some(value) = value {
+                        return Ok(value);
+                    }
+                }
+                call().await
+            }
+            Role::Alone => call().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::single_flight::SingleFlight;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// Make `count` calls at once, which take a while and fail if `fail` says so, and
+    /// return their results and the number of calls actually made.
+    async fn calls(
+        single_flight: SingleFlight<&'static str, usize>,
+        count: usize,
+        fail: impl Fn(usize) -> bool + Send + Sync + 'static,
+    ) -> (Vec<Result<usize, ()>>, usize) {
+        let single_flight = Arc::new(single_flight);
+        let made = Arc::new(AtomicUsize::new(0));
+        let fail = Arc::new(fail);
+        let handles = (0..count)
+            .map(|_| {
+                let single_flight = single_flight.clone();
+                let made = made.clone();
+                let fail = fail.clone();
+                tokio::spawn(async move {
+                    single_flight
+                        .run("/index.html", || async move {
+                            let call = made.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            if fail(call) {
+                                Err(())
+                            } else {
+                                Ok(call)
+                            }
+                        })
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut results = Vec::new();
+        for handle in handles {
+            results.push(handle.await.unwrap());
+        }
+        (results, made.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn identical_calls_share_the_result() {
+        let (results, made) = calls(SingleFlight::new(10), 5, |_| false).await;
+
+        assert_eq!(made, 1);
+        assert!(results.iter().all(|result| *result == Ok(0)));
+    }
+
+    #[tokio::test]
+    async fn followers_call_again_if_the_leader_fails() {
+        let (results, made) = calls(SingleFlight::new(10), 5, |call| call == 0).await;
+
+        assert_eq!(made, 5);
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn followers_are_limited() {
+        let (results, made) = calls(SingleFlight::new(2), 5, |_| false).await;
+
+        assert_eq!(made, 3);
+        assert!(results.iter().all(|result| result.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn calls_are_made_again_once_completed() {
+        let single_flight = SingleFlight::new(10);
+        let first: Result<_, ()> = single_flight.run("/index.html", || async { Ok(1) }).await;
+        let second: Result<_, ()> = single_flight.run("/index.html", || async { Ok(2) }).await;
+
+        assert_eq!(first, Ok(1));
+        assert_eq!(second, Ok(2));
+    }
+}
@@ -0,0 +1,429 @@
+use crate::buffer_budget::{BufferBudget, BufferReservation};
+use futures::future::poll_fn;
+use hyper::{
+    body::{self, Bytes, HttpBody},
+    Body,
+};
+use std::{
+    collections::VecDeque,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+/// How many bytes are read back from a spill file at once.
+const SPILL_READ_SIZE: usize = 64 * 1024;
+
+/// Tells apart the spill files of the streams of this process.
+static SPILL_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Forward the chunks of `chunks` to `client` as fast as they come, whether or not the
+/// client keeps up. Up to `threshold` bytes waiting to be sent are kept in memory, and the
+/// rest in a temporary file in `dir`, removed once the stream ends one way or another.
+/// The bytes spilled are counted in `budget` until the file is removed.
+///
+/// The stream is aborted if `chunks` is, or would spill more than `budget` allows, and
+/// `chunks` is dropped once the client goes away, so that its sender notices.
+pub(crate) async fn forward(
+    mut chunks: Body,
+    mut client: body::Sender,
+    threshold: usize,
+    dir: PathBuf,
+    budget: BufferBudget,
+    logger: slog::Logger,
+) {
+    let mut buffer = SpillBuffer::new(threshold, dir, budget);
+    let mut finished = false;
+    loop {
+        let has_pending = buffer.has_pending();
+        if finished && !has_pending {
+            return;
+        }
+        tokio::select! {
+            chunk = chunks.data(), if !finished => match chunk {
+                Some(Ok(chunk)) => {
+                    if let Err(e) = buffer.push(chunk).await {
+                        slog::warn!(
+                            logger,
+                            "Aborting a stream which could not be spilled to disk: {}",
+                            e
+                        );
+                        client.abort();
+                        return;
+                    }
+                }
+                Some(Err(_)) => {
+                    client.abort();
+                    return;
+                }
+                None => finished = true,
+            },
+            ready = poll_fn(|cx| client.poll_ready(cx)), if has_pending => {
+                if ready.is_err() {
+                    return;
+                }
+                let chunk = match buffer.pop().await {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        slog::warn!(
+                            logger,
+                            "Aborting a stream which could not be read back from disk: {}",
+                            e
+                        );
+                        client.abort();
+                        return;
+                    }
+                };
+                if client.send_data(chunk).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The chunks waiting to be sent to a client, in memory up to a threshold and then in a
+/// temporary file. Once anything is spilled, further chunks are spilled as well until the
+/// file is read back, so that chunks are sent in order.
+struct SpillBuffer {
+    memory: VecDeque<Bytes>,
+    memory_bytes: usize,
+    threshold: usize,
+    dir: PathBuf,
+    budget: BufferBudget,
+    file: Option<SpillFile>,
+}
+
+impl SpillBuffer {
+    fn new(threshold: usize, dir: PathBuf, budget: BufferBudget) -> SpillBuffer {
+        SpillBuffer {
+            memory: VecDeque::new(),
+            memory_bytes: 0,
+            threshold,
+            dir,
+            budget,
+            file: None,
+        }
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.memory.is_empty() || self.file.as_ref().map_or(false, SpillFile::has_unread)
+    }
+
+    async fn push(&mut self, chunk: Bytes) -> io::Result<()> {
+        let is_spilling = self.file.as_ref().map_or(false, SpillFile::has_unread);
+        if !is_spilling && self.memory_bytes + chunk.len() <= self.threshold {
+            self.memory_bytes += chunk.len();
+            self.memory.push_back(chunk);
+            return Ok(());
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self
+                .file
+                .insert(SpillFile::create(&self.dir, &self.budget).await?),
+        };
+        file.write(&chunk).await
+    }
+
+    /// The next chunk to send. Only called when [SpillBuffer::has_pending].
+    async fn pop(&mut self) -> io::Result<Bytes> {
+        if let Some(chunk) = self.memory.pop_front() {
+            self.memory_bytes -= chunk.len();
+            return Ok(chunk);
+        }
+        match &mut self.file {
+            Some(file) => file.read().await,
+            None => Ok(Bytes::new()),
+        }
+    }
+}
+
+/// A temporary file chunks are appended to and read back from, removed when dropped. The
+/// file is emptied whenever everything written was read back.
+struct SpillFile {
+    path: PathBuf,
+    writer: File,
+    reader: File,
+    /// The unread bytes of the file, counted in the budget of spilled bytes.
+    spilled: BufferReservation,
+    written: u64,
+    flushed: u64,
+    read: u64,
+}
+
+impl SpillFile {
+    async fn create(dir: &Path, budget: &BufferBudget) -> io::Result<SpillFile> {
+        let spilled = budget.reserve(0).ok_or_else(over_spill_budget)?;
+        let path = dir.join(format!(
+            "icx-proxy-stream-{}-{}",
+            std::process::id(),
+            SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let writer = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        let reader = match File::open(&path).await {
+            Ok(reader) => reader,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&path).await;
+                return Err(e);
+            }
+        };
+        Ok(SpillFile {
+            path,
+            writer,
+            reader,
+            spilled,
+            written: 0,
+            flushed: 0,
+            read: 0,
+        })
+    }
+
+    fn has_unread(&self) -> bool {
+        self.read < self.written
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        if !self.spilled.grow(chunk.len() as u64) {
+            return Err(over_spill_budget());
+        }
+        self.writer.write_all(chunk).await?;
+        self.written += chunk.len() as u64;
+        Ok(())
+    }
+
+    async fn read(&mut self) -> io::Result<Bytes> {
+        // Written bytes are only visible to the reader once flushed.
+        if self.flushed < self.written {
+            self.writer.flush().await?;
+            self.flushed = self.written;
+        }
+        let unread = (self.written - self.read).min(SPILL_READ_SIZE as u64) as usize;
+        let mut chunk = vec![0; unread];
+        self.reader.read_exact(&mut chunk).await?;
+        self.read += unread as u64;
+        if !self.has_unread() {
+            self.rewind().await?;
+        }
+        Ok(chunk.into())
+    }
+
+    /// Empty the file once it was read back entirely, so that a stream spilling time and
+    /// again does not grow it and hold its budget for as long as it lasts.
+    async fn rewind(&mut self) -> io::Result<()> {
+        self.writer.set_len(0).await?;
+        self.writer.seek(SeekFrom::Start(0)).await?;
+        self.reader.seek(SeekFrom::Start(0)).await?;
+        self.spilled.shrink_to(0);
+        self.written = 0;
+        self.flushed = 0;
+        self.read = 0;
+        Ok(())
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.path);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(move || std::fs::remove_file(path));
+            }
+            Err(_) => {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+fn over_spill_budget() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "too many bytes are spilled to disk at the moment",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        buffer_budget::BufferBudget,
+        stream_spill::{forward, SpillBuffer},
+    };
+    use hyper::{
+        body::{Bytes, HttpBody},
+        Body,
+    };
+    use std::{
+        path::{Path, PathBuf},
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    /// An empty directory of its own for a test to spill into.
+    fn spill_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("icx-proxy-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn spill_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir).unwrap().count()
+    }
+
+    /// Wait for the spill files of `dir` to be removed, which happens in the background.
+    async fn spill_files_removed(dir: &Path) -> bool {
+        for _ in 0..100 {
+            if spill_files(dir) == 0 {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn chunks_are_spilled_for_slow_clients_in_order() {
+        let dir = spill_dir("spilled-in-order");
+        let (mut sender, chunks) = Body::channel();
+        let (client, mut received) = Body::channel();
+        let forwarding = tokio::spawn(forward(
+            chunks,
+            client,
+            10,
+            dir.clone(),
+            BufferBudget::new(None, Arc::default()),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        ));
+
+        // The sender is not held back by a client not reading anything.
+        let mut expected = Vec::new();
+        for i in 0..100u8 {
+            let chunk = vec![i; 7];
+            expected.extend_from_slice(&chunk);
+            tokio::time::timeout(Duration::from_secs(5), sender.send_data(chunk.into()))
+                .await
+                .expect("the sender was held back")
+                .unwrap();
+        }
+        assert_eq!(spill_files(&dir), 1);
+        drop(sender);
+
+        let mut body = Vec::new();
+        while let Some(chunk) = received.data().await {
+            body.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(body, expected);
+        forwarding.await.unwrap();
+        assert!(spill_files_removed(&dir).await);
+    }
+
+    #[tokio::test]
+    async fn aborted_streams_are_aborted_and_cleaned_up() {
+        let dir = spill_dir("aborted");
+        let (mut sender, chunks) = Body::channel();
+        let (client, mut received) = Body::channel();
+        let forwarding = tokio::spawn(forward(
+            chunks,
+            client,
+            0,
+            dir.clone(),
+            BufferBudget::new(None, Arc::default()),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        ));
+
+        sender
+            .send_data(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        sender.abort();
+        forwarding.await.unwrap();
+
+        assert!(spill_files_removed(&dir).await);
+        let mut aborted = false;
+        while let Some(chunk) = received.data().await {
+            aborted |= chunk.is_err();
+        }
+        assert!(aborted);
+    }
+
+    #[tokio::test]
+    async fn streams_spilling_too_much_are_aborted() {
+        let dir = spill_dir("over-budget");
+        let spilled = Arc::default();
+        let (mut sender, chunks) = Body::channel();
+        let (client, mut received) = Body::channel();
+        let forwarding = tokio::spawn(forward(
+            chunks,
+            client,
+            0,
+            dir.clone(),
+            BufferBudget::new(Some(20), Arc::clone(&spilled)),
+            slog::Logger::root(slog::Discard, slog::o!()),
+        ));
+
+        // The client takes a chunk before its channel fills up, the spill file being
+        // emptied then, so that it takes more chunks to go over budget.
+        for _ in 0..10 {
+            if sender
+                .send_data(Bytes::from_static(b"0123456789"))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        forwarding.await.unwrap();
+
+        let mut aborted = false;
+        while let Some(chunk) = received.data().await {
+            aborted |= chunk.is_err();
+        }
+        assert!(aborted);
+        assert_eq!(spilled.load(Ordering::Relaxed), 0);
+        assert!(spill_files_removed(&dir).await);
+    }
+
+    #[tokio::test]
+    async fn drained_spill_files_are_emptied() {
+        let dir = spill_dir("drained");
+        let spilled = Arc::default();
+        let mut buffer = SpillBuffer::new(
+            0,
+            dir.clone(),
+            BufferBudget::new(None, Arc::clone(&spilled)),
+        );
+        let spill_file_len = || {
+            let file = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+            file.metadata().unwrap().len()
+        };
+
+        for chunk in &[&b"hello "[..], b"world"] {
+            buffer.push(Bytes::from_static(chunk)).await.unwrap();
+        }
+        assert_eq!(spilled.load(Ordering::Relaxed), 11);
+        assert_eq!(
+            buffer.pop().await.unwrap(),
+            Bytes::from_static(b"hello world")
+        );
+        assert!(!buffer.has_pending());
+        assert_eq!(spilled.load(Ordering::Relaxed), 0);
+        assert_eq!(spill_file_len(), 0);
+
+        buffer.push(Bytes::from_static(b"again")).await.unwrap();
+        assert_eq!(spilled.load(Ordering::Relaxed), 5);
+        assert_eq!(buffer.pop().await.unwrap(), Bytes::from_static(b"again"));
+        assert_eq!(spilled.load(Ordering::Relaxed), 0);
+
+        drop(buffer);
+        assert!(spill_files_removed(&dir).await);
+    }
+}
@@ -0,0 +1,228 @@
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap, Method,
+};
+use ic_agent::export::Principal;
+use opentelemetry::{
+    global::{self, BoxedTracer},
+    propagation::{Extractor, Injector},
+    sdk::propagation::TraceContextPropagator,
+    trace::{FutureExt, Span, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use std::{
+    future::Future,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Export spans to the OTLP collector at `endpoint`, honoring and propagating the W3C trace
+/// context of requests. Without an endpoint, spans are not recorded and the trace context
+/// of requests is passed through untouched. Must be called within the Tokio runtime.
+pub(crate) fn init(endpoint: Option<&str>) -> anyhow::Result<()> {
+    if let Some(endpoint) = endpoint {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint),
+            )
+            .install_batch(opentelemetry::runtime::Tokio)?;
+    }
+    Ok(())
+}
+
+fn tracer() -> BoxedTracer {
+    global::tracer("icx-proxy")
+}
+
+/// The time spent in each phase of a request, in the order the phases started. A phase is
+/// timed by running it in a span, whether or not spans are exported.
+#[derive(Default)]
+pub(crate) struct PhaseTimings(Mutex<Vec<(&'static str, Duration)>>);
+
+impl PhaseTimings {
+    fn add(&self, phase: &'static str, duration: Duration) {
+        let mut timings = self.0.lock().unwrap();
+        match timings.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += duration,
+            None => timings.push((phase, duration)),
+        }
+    }
+
+    /// The time spent in each phase so far. Phases run more than once, such as retried
+    /// queries, are summed up.
+    pub fn timings(&self) -> Vec<(&'static str, Duration)> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Start the span of a request, as a child of the trace context in its `headers` if any.
+/// Returns the context to run the handling of the request in, which times its phases.
+pub(crate) fn start_request(headers: &HeaderMap, method: &Method, path: &str) -> Context {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    let mut span = tracer().start_with_context("handle_request", parent.clone());
+    span.set_attribute(KeyValue::new("http.method", method.to_string()));
+    span.set_attribute(KeyValue::new("http.target", path.to_string()));
+    parent
+        .with_span(span)
+        .with_value(Arc::new(PhaseTimings::default()))
+}
+
+/// The time spent so far in each phase of the request handled in `cx`.
+pub(crate) fn phase_timings(cx: &Context) -> Vec<(&'static str, Duration)> {
+    cx.get::<Arc<PhaseTimings>>()
+        .map(|timings| timings.timings())
+        .unwrap_or_default()
+}
+
+/// End the span of a request started with [start_request].
+pub(crate) fn end_request(cx: &Context, status: u16, canister_id: Option<&Principal>) {
+    let span = cx.span();
+    span.set_attribute(KeyValue::new("http.status_code", status as i64));
+    if let Some(canister_id) = canister_id {
+        span.set_attribute(KeyValue::new("canister_id", canister_id.to_text()));
+    }
+    span.end();
+}
+
+/// Run `f` in a span named `name`, a child of the current span.
+pub(crate) fn in_span<T>(
+    name: &'static str,
+    canister_id: Option<&Principal>,
+    f: impl FnOnce() -> T,
+) -> T {
+    let cx = child_context(name, canister_id);
+    let started = Instant::now();
+    let output = f();
+    record_phase(&cx, name, started);
+    cx.span().end();
+    output
+}
+
+/// Await `future` in a span named `name`, a child of the span current when this is called,
+/// even if `future` is spawned.
+pub(crate) fn traced<F: Future>(
+    name: &'static str,
+    canister_id: Option<&Principal>,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    let cx = child_context(name, canister_id);
+    async move {
+        let started = Instant::now();
+        let output = future.with_context(cx.clone()).await;
+        record_phase(&cx, name, started);
+        cx.span().end();
+        output
+    }
+}
+
+fn record_phase(cx: &Context, name: &'static str, started: Instant) {
+    if let Some(timings) = cx.get::<Arc<PhaseTimings>>() {
+        timings.add(name, started.elapsed());
+    }
+}
+
+fn child_context(name: &'static str, canister_id: Option<&Principal>) -> Context {
+    let mut span = tracer().start(name);
+    if let Some(canister_id) = canister_id {
+        span.set_attribute(KeyValue::new("canister_id", canister_id.to_text()));
+    }
+    Context::current_with_span(span)
+}
+
+/// Set the trace context of the current span in `headers`, for upstreams to continue the
+/// trace.
+pub(crate) fn inject_context(headers: &mut HeaderMap) {
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Context::current(), &mut HeaderInjector(headers))
+    });
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_str(key), HeaderValue::from_str(&value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{in_span, phase_timings, start_request, traced, HeaderExtractor, HeaderInjector};
+    use hyper::{HeaderMap, Method};
+    use opentelemetry::{
+        propagation::TextMapPropagator,
+        sdk::propagation::TraceContextPropagator,
+        trace::{FutureExt, TraceContextExt},
+    };
+    use std::time::Duration;
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn trace_context_round_trips_through_headers() {
+        let propagator = TraceContextPropagator::new();
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", TRACEPARENT.parse().unwrap());
+
+        let cx = propagator.extract(&HeaderExtractor(&headers));
+        assert!(cx.span().span_context().is_remote());
+
+        let mut forwarded = HeaderMap::new();
+        propagator.inject_context(&cx, &mut HeaderInjector(&mut forwarded));
+        assert_eq!(forwarded.get("traceparent").unwrap(), TRACEPARENT);
+    }
+
+    #[tokio::test]
+    async fn phases_of_requests_are_timed() {
+        let cx = start_request(&HeaderMap::new(), &Method::GET, "/");
+        async {
+            in_span("resolve_canister_id", None, || ());
+            for _ in 0..2 {
+                traced("query", None, tokio::time::sleep(Duration::from_millis(20))).await;
+            }
+            in_span("verify", None, || ());
+        }
+        .with_context(cx.clone())
+        .await;
+
+        let timings = phase_timings(&cx);
+        let phases = timings.iter().map(|(phase, _)| *phase).collect::<Vec<_>>();
+        assert_eq!(phases, ["resolve_canister_id", "query", "verify"]);
+        assert!(timings[1].1 >= Duration::from_millis(40));
+
+        // Spans outside of a request are not timed.
+        in_span("verify", None, || ());
+        assert_eq!(phase_timings(&cx).len(), 3);
+    }
+
+    #[test]
+    fn missing_trace_context_is_not_injected() {
+        let propagator = TraceContextPropagator::new();
+        let cx = propagator.extract(&HeaderExtractor(&HeaderMap::new()));
+
+        let mut forwarded = HeaderMap::new();
+        propagator.inject_context(&cx, &mut HeaderInjector(&mut forwarded));
+        assert!(forwarded.is_empty());
+    }
+}
@@ -0,0 +1,92 @@
+use ic_agent::ic_types::HashTree;
+use serde_cbor::Value;
+use std::fmt::Write;
+
+/// Format a hash tree as an indented outline, one labeled subtree, leaf or pruned
+/// subtree per line. Forks are flattened into the list of their children.
+pub(crate) fn format_hash_tree(tree: &HashTree) -> String {
+    let mut output = String::new();
+    // The nodes of hash trees are private to ic-types, so the tree is walked in the form
+    // it is serialized in: [0] is empty, [1, left, right] a fork, [2, label, subtree] a
+    // labeled subtree, [3, value] a leaf and [4, digest] a pruned subtree.
+    match serde_cbor::value::to_value(tree) {
+        Ok(node) => format_node(&mut output, &node, 0),
+        Err(e) => {
+            let _ = writeln!(output, "(unable to serialize the tree: {})", e);
+        }
+    }
+    output
+}
+
+fn format_node(output: &mut String, node: &Value, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let node = match node {
+        Value::Array(node) => node.as_slice(),
+        _ => &[],
+    };
+    match node {
+        [Value::Integer(0)] => {
+            let _ = writeln!(output, "{}(empty)", indent);
+        }
+        [Value::Integer(1), left, right] => {
+            format_node(output, left, depth);
+            format_node(output, right, depth);
+        }
+        [Value::Integer(2), Value::Bytes(label), child] => {
+            let _ = writeln!(output, "{}{}", indent, format_label(label));
+            format_node(output, child, depth + 1);
+        }
+        [Value::Integer(3), Value::Bytes(value)] => {
+            let _ = writeln!(output, "{}leaf {}", indent, hex::encode(value));
+        }
+        [Value::Integer(4), Value::Bytes(digest)] => {
+            let _ = writeln!(output, "{}pruned {}", indent, hex::encode(digest));
+        }
+        _ => {
+            let _ = writeln!(output, "{}(unknown node)", indent);
+        }
+    }
+}
+
+/// Labels are quoted if they are printable text, and hex encoded otherwise.
+fn format_label(label: &[u8]) -> String {
+    match std::str::from_utf8(label) {
+        Ok(label) if !label.chars().any(char::is_control) => format!("{:?}", label),
+        _ => format!("0x{}", hex::encode(label)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_hash_tree;
+    use ic_agent::ic_types::hash_tree::{empty, fork, label, leaf, pruned};
+
+    #[test]
+    fn formats_http_assets_tree() {
+        let tree = label(
+            "http_assets",
+            fork(
+                label("/index.html", leaf([0xab; 4])),
+                fork(pruned([0x01; 32]), label("/app.js", leaf([0xcd; 4]))),
+            ),
+        );
+
+        assert_eq!(
+            format_hash_tree(&tree),
+            r#""http_assets"
+  "/index.html"
+    leaf abababab
+  pruned 0101010101010101010101010101010101010101010101010101010101010101
+  "/app.js"
+    leaf cdcdcdcd
+"#
+        );
+    }
+
+    #[test]
+    fn formats_binary_labels_as_hex() {
+        let tree = fork(label(vec![0u8, 0xff], empty()), empty());
+
+        assert_eq!(format_hash_tree(&tree), "0x00ff\n  (empty)\n(empty)\n");
+    }
+}
@@ -0,0 +1,1657 @@
+use crate::{
+    buffer_pool::{BufferPool, PooledBuffer},
+    config::certification_policy::CertificationPolicy,
+    metrics::{CertificationOutcome, Metrics},
+    tree_dump::format_hash_tree,
+    verification_cache::{ResponseKey, VerificationCache},
+};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use hyper::Uri;
+use ic_agent::{
+    export::Principal,
+    ic_types::{hash_tree::LookupResult, HashTree},
+    lookup_value, Agent, Certificate,
+};
+use ic_utils::interfaces::http_request::HeaderField;
+use lazy_regex::regex_captures;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{ErrorKind, Read},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// The limit of a buffer we should decompress ~10mb.
+const MAX_BYTES_SIZE_TO_DECOMPRESS: u64 = 10_000_000;
+
+// The size of the internal buffer used by the brotli decompressor.
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+// The size of the buffer decoded bytes are fed to the hasher through.
+const HASH_BUFFER_SIZE: usize = 8192;
+
+/// The certification related data extracted from the headers of a canister response.
+pub(crate) struct HeadersData {
+    pub certificate: Option<Result<Vec<u8>, ()>>,
+    pub tree: Option<Result<Vec<u8>, ()>>,
+    pub encoding: Option<String>,
+    /// The canister marked the response as not certified.
+    pub opted_out: bool,
+}
+
+/// Extract the certification related data of the headers of a canister response. The
+/// response is marked as not certified by the canister if it has a `uncertified_header`
+/// set to true.
+pub(crate) fn extract_headers_data(
+    headers: &[HeaderField],
+    uncertified_header: Option<&str>,
+    logger: &slog::Logger,
+) -> HeadersData {
+    let mut headers_data = HeadersData {
+        certificate: None,
+        tree: None,
+        encoding: None,
+        opted_out: false,
+    };
+
+    for HeaderField(name, value) in headers {
+        if uncertified_header.map_or(false, |header| name.eq_ignore_ascii_case(header)) {
+            headers_data.opted_out |= value.trim().eq_ignore_ascii_case("true");
+        }
+        if name.eq_ignore_ascii_case("IC-CERTIFICATE") {
+            for field in value.split(',') {
+                if let Some((_, name, b64_value)) = regex_captures!("^(.*)=:(.*):$", field.trim()) {
+                    slog::trace!(logger, ">> certificate {}: {}", name, b64_value);
+                    let bytes = base64::decode(b64_value).map_err(|e| {
+                        slog::warn!(
+                            logger,
+                            "Unable to decode {} in ic-certificate from base64: {}",
+                            name,
+                            e
+                        );
+                    });
+                    if name == "certificate" {
+                        headers_data.certificate = Some(match (headers_data.certificate, bytes) {
+                            (None, bytes) => bytes,
+                            (Some(Ok(certificate)), Ok(bytes)) => {
+                                slog::warn!(logger, "duplicate certificate field: {:?}", bytes);
+                                Ok(certificate)
+                            }
+                            (Some(Ok(certificate)), Err(_)) => {
+                                slog::warn!(
+                                    logger,
+                                    "duplicate certificate field (failed to decode)"
+                                );
+                                Ok(certificate)
+                            }
+                            (Some(Err(_)), bytes) => {
+                                slog::warn!(
+                                    logger,
+                                    "duplicate certificate field (failed to decode)"
+                                );
+                                bytes
+                            }
+                        });
+                    } else if name == "tree" {
+                        headers_data.tree = Some(match (headers_data.tree, bytes) {
+                            (None, bytes) => bytes,
+                            (Some(Ok(tree)), Ok(bytes)) => {
+                                slog::warn!(logger, "duplicate tree field: {:?}", bytes);
+                                Ok(tree)
+                            }
+                            (Some(Ok(tree)), Err(_)) => {
+                                slog::warn!(logger, "duplicate tree field (failed to decode)");
+                                Ok(tree)
+                            }
+                            (Some(Err(_)), bytes) => {
+                                slog::warn!(logger, "duplicate tree field (failed to decode)");
+                                bytes
+                            }
+                        });
+                    }
+                }
+            }
+        }
+    }
+    headers_data.encoding = content_encoding(headers);
+
+    headers_data
+}
+
+/// Extract the data of the headers of a canister response which is not verified, leaving
+/// its certificate and tree undecoded.
+pub(crate) fn extract_uncertified_headers_data(headers: &[HeaderField]) -> HeadersData {
+    HeadersData {
+        certificate: None,
+        tree: None,
+        encoding: content_encoding(headers),
+        opted_out: false,
+    }
+}
+
+fn content_encoding(headers: &[HeaderField]) -> Option<String> {
+    // Repeated Content-Encoding fields are equivalent to a single comma-separated one.
+    let mut content_encoding: Option<String> = None;
+    for HeaderField(name, value) in headers {
+        if name.eq_ignore_ascii_case("CONTENT-ENCODING") {
+            let value = value.trim().to_ascii_lowercase();
+            content_encoding = Some(match content_encoding {
+                Some(encoding) => format!("{}, {}", encoding, value),
+                None => value,
+            });
+        }
+    }
+    content_encoding
+}
+
+/// How a response that passed validation was certified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Certification {
+    /// The body was verified against the certificate, which certifies this hash of the
+    /// decoded body.
+    Verified { body_sha: [u8; 32] },
+    /// The response is the reply of an update call, which the agent verified with the
+    /// certificate of the state of the subnet it read the reply from.
+    Consensus,
+    /// The response is served without having been verified.
+    Unverified,
+    /// Verification did not run.
+    Skipped,
+}
+
+impl Certification {
+    /// The value of the X-IC-Certified header for this outcome.
+    pub(crate) fn header_value(self) -> &'static str {
+        match self {
+            Certification::Verified { .. } | Certification::Consensus => "true",
+            Certification::Unverified => "false",
+            Certification::Skipped => "skipped",
+        }
+    }
+}
+
+/// Why a certified response did not pass verification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum VerificationFailure {
+    /// Only one of the certificate and the tree was provided, or one could not be parsed
+    /// from the header.
+    IncompleteCertification,
+    /// The certificate or the tree is not valid CBOR.
+    MalformedCertification,
+    /// The signature of the certificate is invalid.
+    InvalidSignature,
+    /// The certificate holds no certified data for the canister.
+    MissingCertifiedData,
+    /// The certified data does not match the digest of the tree.
+    TreeMismatch,
+    /// The tree does not contain the requested path.
+    PathNotFound,
+    /// The body does not match the hash found in the tree.
+    BodyMismatch,
+    /// The certificate is older than the maximum age of certificates.
+    StaleCertificate,
+}
+
+impl VerificationFailure {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            VerificationFailure::IncompleteCertification => "incomplete_certification",
+            VerificationFailure::MalformedCertification => "malformed_certification",
+            VerificationFailure::InvalidSignature => "invalid_signature",
+            VerificationFailure::MissingCertifiedData => "missing_certified_data",
+            VerificationFailure::TreeMismatch => "tree_mismatch",
+            VerificationFailure::PathNotFound => "path_not_found",
+            VerificationFailure::BodyMismatch => "body_mismatch",
+            VerificationFailure::StaleCertificate => "stale_certificate",
+        }
+    }
+}
+
+/// The settings of validation which apply to every canister.
+#[derive(Clone, Debug)]
+pub(crate) struct ValidationConfig {
+    /// The document single page apps serve for paths missing from their tree.
+    pub spa_fallback: String,
+    /// Log the tree of responses whose certification does not match, at debug level.
+    pub dump_trees_on_failure: bool,
+    /// How old certificates may be, even if they were verified already. Any age is
+    /// accepted if [None].
+    pub max_certificate_age: Option<Duration>,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            spa_fallback: "/index.html".to_string(),
+            dump_trees_on_failure: false,
+            max_certificate_age: None,
+        }
+    }
+}
+
+/// Validate the body of a canister response against the certificate and tree found in
+/// its headers, according to the certification policy of the canister. The outcome is
+/// counted in `metrics`. Responses found in `cache` are
+/// not verified again.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn validate(
+    headers_data: &HeadersData,
+    canister_id: &Principal,
+    agent: &Agent,
+    uri: &Uri,
+    status_code: u16,
+    response_body: &[u8],
+    policy: CertificationPolicy,
+    metrics: &Metrics,
+    cache: Option<&VerificationCache>,
+    validation_config: &ValidationConfig,
+    logger: slog::Logger,
+) -> Result<Certification, String> {
+    if policy == CertificationPolicy::Skip {
+        slog::debug!(
+            logger,
+            "Skipping verification of {} for canister {}",
+            uri,
+            canister_id
+        );
+        metrics
+            .certifications
+            .inc((*canister_id, CertificationOutcome::Skipped));
+        return Ok(Certification::Skipped);
+    }
+    // Canisters may only opt out of verification if they do not have to be certified.
+    if headers_data.opted_out && policy == CertificationPolicy::Optional {
+        slog::warn!(
+            logger,
+            "Serving {} of canister {} without verification, as the canister marked it as not certified",
+            uri,
+            canister_id
+        );
+        metrics
+            .certifications
+            .inc((*canister_id, CertificationOutcome::OptedOut));
+        return Ok(Certification::Unverified);
+    }
+
+    let mut hashed_body_sha = None;
+    let outcome = match (&headers_data.certificate, &headers_data.tree) {
+        (Some(Ok(certificate)), Some(Ok(tree))) => {
+            hashed_body_sha =
+                hash_decoded_body(response_body, headers_data.encoding.as_deref(), &logger);
+            match hashed_body_sha {
+                Some(body_sha) => match validate_body(
+                    certificate,
+                    tree,
+                    canister_id,
+                    agent,
+                    uri,
+                    status_code,
+                    &body_sha,
+                    metrics,
+                    cache,
+                    validation_config,
+                    logger.clone(),
+                ) {
+                    Ok(outcome) => outcome,
+                    Err(failure) => CertificationOutcome::Failed(failure),
+                },
+                None => CertificationOutcome::DecodeError,
+            }
+        }
+        (Some(_), _) | (_, Some(_)) => {
+            CertificationOutcome::Failed(VerificationFailure::IncompleteCertification)
+        }
+        (None, None) => CertificationOutcome::NoCertificate,
+    };
+    metrics.certifications.inc((*canister_id, outcome));
+
+    let body_valid = match outcome {
+        CertificationOutcome::Verified => Ok(Certification::Verified {
+            // Only hashed bodies can be verified.
+            body_sha: hashed_body_sha.unwrap_or_default(),
+        }),
+        CertificationOutcome::Failed(VerificationFailure::MalformedCertification) => {
+            Err("Certificate validation failed: invalid CBOR data".to_string())
+        }
+        CertificationOutcome::Failed(VerificationFailure::StaleCertificate) => {
+            Err("Certificate is too old".to_string())
+        }
+        CertificationOutcome::Failed(_) => Err("Body does not pass verification".to_string()),
+        CertificationOutcome::DecodeError => Err("Body could not be decoded".to_string()),
+        CertificationOutcome::NoCertificate | CertificationOutcome::UncertifiedError
+            if policy == CertificationPolicy::Required =>
+        {
+            Err("Response is not certified".to_string())
+        }
+        // Canisters don't have to provide certified variables
+        CertificationOutcome::NoCertificate
+        | CertificationOutcome::UncertifiedError
+        | CertificationOutcome::OptedOut
+        | CertificationOutcome::Skipped => Ok(Certification::Unverified),
+    };
+
+    if body_valid.is_err() && cfg!(feature = "skip_body_verification") {
+        return Ok(Certification::Unverified);
+    }
+    body_valid
+}
+
+// The content codings bodies can be decoded from, to be verified.
+const DECODABLE_CODINGS: &[&str] = &["identity", "gzip", "deflate", "br", "zstd"];
+
+/// Restrict an Accept-Encoding header to the codings bodies can be decoded from, so that
+/// canisters do not answer with a body which cannot be verified. The weights of the
+/// codings are kept, and a wildcard stands for the decodable codings not listed.
+pub(crate) fn decodable_accept_encoding(accept_encoding: &str) -> String {
+    let codings = accept_encoding
+        .split(',')
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .map(|coding| match coding.split_once(';') {
+            Some((name, parameters)) => (name.trim().to_ascii_lowercase(), Some(parameters)),
+            None => (coding.to_ascii_lowercase(), None),
+        })
+        .collect::<Vec<_>>();
+    let with_parameters = |name: &str, parameters: Option<&str>| match parameters {
+        Some(parameters) => format!("{};{}", name, parameters),
+        None => name.to_string(),
+    };
+
+    let mut decodable = Vec::new();
+    for (name, parameters) in &codings {
+        if name == "*" {
+            decodable.extend(
+                DECODABLE_CODINGS
+                    .iter()
+                    .filter(|coding| !codings.iter().any(|(name, _)| name == *coding))
+                    .map(|coding| with_parameters(coding, *parameters)),
+            );
+        } else if DECODABLE_CODINGS.contains(&name.as_str()) {
+            decodable.push(with_parameters(name, *parameters));
+        }
+    }
+    // Dropping the header would accept any coding, so the identity coding is asked for.
+    if decodable.is_empty() {
+        return "identity".to_string();
+    }
+    decodable.join(", ")
+}
+
+/// Wrap `body` in the decoders for its content encoding, a comma-separated list of the
+/// codings in the order they were applied. Decoding happens lazily as the returned reader
+/// is read, so the decoded body is never materialized. Returns [None] if a coding is not
+/// supported.
+pub(crate) fn decoding_reader<'a, R: Read + 'a>(
+    body: R,
+    encoding: Option<&str>,
+    logger: &slog::Logger,
+) -> Option<Box<dyn Read + 'a>> {
+    let codings = encoding
+        .map(|encoding| {
+            encoding
+                .split(',')
+                .map(str::trim)
+                .filter(|coding| !coding.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    // The last coding applied is the outermost layer, so decode right to left.
+    let mut reader: Box<dyn Read + 'a> = Box::new(body);
+    for coding in codings.into_iter().rev() {
+        reader = match coding {
+            "identity" => reader,
+            "gzip" => Box::new(GzDecoder::new(reader)),
+            "deflate" => Box::new(ZlibDecoder::new(reader)),
+            "br" => Box::new(brotli::Decompressor::new(reader, BROTLI_BUFFER_SIZE)),
+            "zstd" => match zstd::stream::read::Decoder::new(reader) {
+                Ok(decoder) => Box::new(decoder),
+                Err(e) => {
+                    slog::warn!(logger, "Unable to create a zstd decoder: {}", e);
+                    return None;
+                }
+            },
+            coding => {
+                slog::warn!(
+                    logger,
+                    "Unable to verify a body with unsupported content encoding \"{}\"",
+                    coding
+                );
+                return None;
+            }
+        };
+    }
+    Some(reader)
+}
+
+/// Decode `body` according to its content encoding, for clients which do not accept it.
+/// Returns [None] if the body could not be decoded, or is larger than
+/// [MAX_BYTES_SIZE_TO_DECOMPRESS] bytes once decoded.
+///
+/// The body is decoded into a buffer of `buffers`, which is handed to hyper as it is and
+/// goes back to the pool once the response was written.
+pub(crate) fn decode_body(
+    body: &[u8],
+    encoding: Option<&str>,
+    buffers: &Arc<BufferPool>,
+    logger: &slog::Logger,
+) -> Option<PooledBuffer> {
+    let mut decoded = buffers.get();
+    let read = decoding_reader(body, encoding, logger)?
+        .take(MAX_BYTES_SIZE_TO_DECOMPRESS + 1)
+        .read_to_end(&mut decoded);
+    match read {
+        Ok(_) if decoded.len() as u64 <= MAX_BYTES_SIZE_TO_DECOMPRESS => Some(decoded),
+        Ok(_) => {
+            slog::warn!(
+                logger,
+                "Unable to decode a body larger than {} bytes",
+                MAX_BYTES_SIZE_TO_DECOMPRESS
+            );
+            None
+        }
+        Err(e) => {
+            slog::warn!(logger, "Unable to decode body: {}", e);
+            None
+        }
+    }
+}
+
+/// Compute the SHA-256 of everything `reader` produces, feeding the hasher through a
+/// fixed-size buffer. Fails once more than [MAX_BYTES_SIZE_TO_DECOMPRESS] bytes have been
+/// produced.
+pub(crate) fn hash_body<R: Read>(mut reader: R) -> std::io::Result<[u8; 32]> {
+    let mut sha256 = Sha256::new();
+    let mut buffer = [0u8; HASH_BUFFER_SIZE];
+    let mut total: u64 = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        total += read as u64;
+        if total > MAX_BYTES_SIZE_TO_DECOMPRESS {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "decoded body is larger than {} bytes",
+                    MAX_BYTES_SIZE_TO_DECOMPRESS
+                ),
+            ));
+        }
+        sha256.update(&buffer[..read]);
+    }
+
+    let mut body_sha = [0u8; 32];
+    body_sha.copy_from_slice(&sha256.finalize());
+    Ok(body_sha)
+}
+
+/// Decode `body` according to its content encoding and hash the result. Returns [None] if
+/// the body could not be decoded.
+///
+/// The certified hash of an asset is the hash of its decoded body, whatever encoding it is
+/// served with: asset canisters certify the SHA-256 of the identity encoding for all the
+/// encodings of an asset, as the HTTP gateway specification requires.
+fn hash_decoded_body(
+    body: &[u8],
+    encoding: Option<&str>,
+    logger: &slog::Logger,
+) -> Option<[u8; 32]> {
+    match hash_body(decoding_reader(body, encoding, logger)?) {
+        Ok(body_sha) => Some(body_sha),
+        Err(e) => {
+            slog::warn!(logger, "Unable to decode body: {}", e);
+            None
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn validate_body(
+    certificate: &[u8],
+    tree: &[u8],
+    canister_id: &Principal,
+    agent: &Agent,
+    uri: &Uri,
+    status_code: u16,
+    body_sha: &[u8; 32],
+    metrics: &Metrics,
+    cache: Option<&VerificationCache>,
+    validation_config: &ValidationConfig,
+    logger: slog::Logger,
+) -> Result<CertificationOutcome, VerificationFailure> {
+    // Checked first, so that no cached outcome outlives its certificate.
+    if let Some(max_age) = validation_config.max_certificate_age {
+        check_certificate_age(certificate, max_age, &logger)?;
+    }
+
+    let key = cache.map(|_| ResponseKey {
+        canister_id: *canister_id,
+        certificate_sha: sha256(certificate),
+        tree_sha: sha256(tree),
+        body_sha: *body_sha,
+        path: uri.path().to_string(),
+        status_code,
+    });
+    if let (Some(cache), Some(key)) = (cache, &key) {
+        let outcome = cache.response(key);
+        metrics.cache_lookups.inc(("response", outcome.is_some()));
+        if let Some(outcome) = outcome {
+            return Ok(outcome);
+        }
+    }
+
+    let cert: Certificate = serde_cbor::from_slice(certificate).map_err(|e| {
+        slog::trace!(logger, ">> certificate is not valid CBOR: {}", e);
+        VerificationFailure::MalformedCertification
+    })?;
+    let tree: HashTree = serde_cbor::from_slice(tree).map_err(|e| {
+        slog::trace!(logger, ">> tree is not valid CBOR: {}", e);
+        VerificationFailure::MalformedCertification
+    })?;
+
+    // Verifying the signature is by far the most expensive part.
+    let certificate_verified = match (cache, &key) {
+        (Some(cache), Some(key)) => {
+            let verified = cache.is_certificate_verified(&key.certificate_sha);
+            metrics.cache_lookups.inc(("certificate", verified));
+            verified
+        }
+        _ => false,
+    };
+    if !certificate_verified {
+        if let Err(e) = agent.verify(&cert) {
+            slog::trace!(logger, ">> certificate failed verification: {}", e);
+            return Err(VerificationFailure::InvalidSignature);
+        }
+        if let (Some(cache), Some(key)) = (cache, &key) {
+            cache.insert_certificate(key.certificate_sha);
+        }
+    }
+
+    let certified_data_path = vec![
+        "canister".into(),
+        canister_id.into(),
+        "certified_data".into(),
+    ];
+    let witness = match lookup_value(&cert, certified_data_path) {
+        Ok(witness) => witness,
+        Err(e) => {
+            slog::trace!(
+                logger,
+                ">> Could not find certified data for this canister in the certificate: {}",
+                e
+            );
+            return Err(VerificationFailure::MissingCertifiedData);
+        }
+    };
+    let digest = match (cache, &key) {
+        (Some(cache), Some(key)) => {
+            let digest = cache.tree_digest(&key.tree_sha);
+            metrics.cache_lookups.inc(("tree", digest.is_some()));
+            digest.unwrap_or_else(|| {
+                let digest = tree.digest();
+                cache.insert_tree_digest(key.tree_sha, digest);
+                digest
+            })
+        }
+        _ => tree.digest(),
+    };
+
+    if witness != digest {
+        slog::trace!(
+            logger,
+            ">> witness ({}) did not match digest ({})",
+            hex::encode(witness),
+            hex::encode(digest)
+        );
+        if validation_config.dump_trees_on_failure {
+            dump_tree(&tree, witness, canister_id, uri, &logger);
+        }
+
+        return Err(VerificationFailure::TreeMismatch);
+    }
+
+    let result = validate_body_in_tree(
+        &tree,
+        uri.path(),
+        status_code,
+        body_sha,
+        &validation_config.spa_fallback,
+        &logger,
+    );
+    if result == Err(VerificationFailure::PathNotFound) && validation_config.dump_trees_on_failure {
+        dump_tree(&tree, witness, canister_id, uri, &logger);
+    }
+    if let (Some(cache), Some(key), Ok(outcome)) = (cache, key, &result) {
+        cache.insert_response(key, *outcome);
+    }
+    result
+}
+
+/// The time a certificate was issued at, or [None] if it cannot be read.
+pub(crate) fn certificate_time(certificate: &[u8]) -> Option<SystemTime> {
+    let cert: Certificate = serde_cbor::from_slice(certificate).ok()?;
+    let time = lookup_value(&cert, vec!["time".into()]).ok()?;
+    // The time is a LEB128-encoded number of nanoseconds since the epoch.
+    let mut nanos: u64 = 0;
+    for (index, byte) in time.iter().enumerate() {
+        nanos |= u64::from(byte & 0x7f).checked_shl(7 * index as u32)?;
+        if byte & 0x80 == 0 {
+            return Some(UNIX_EPOCH + Duration::from_nanos(nanos));
+        }
+    }
+    None
+}
+
+/// Fail if `certificate` was issued more than `max_age` ago, or its time cannot be read.
+/// Certificates from the future, as far as the clock of the proxy tells, are accepted.
+fn check_certificate_age(
+    certificate: &[u8],
+    max_age: Duration,
+    logger: &slog::Logger,
+) -> Result<(), VerificationFailure> {
+    let time = certificate_time(certificate).ok_or_else(|| {
+        slog::trace!(logger, ">> certificate has no readable time");
+        VerificationFailure::MalformedCertification
+    })?;
+    let age = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or(Duration::ZERO);
+    if age > max_age {
+        slog::trace!(logger, ">> certificate is {}s old", age.as_secs());
+        return Err(VerificationFailure::StaleCertificate);
+    }
+    Ok(())
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut sha = [0u8; 32];
+    sha.copy_from_slice(&Sha256::digest(data));
+    sha
+}
+
+/// Look the hash of the body served for `path` up in a tree whose digest was certified.
+/// Successful responses for paths missing from the tree fall back to `spa_fallback`, for
+/// single page apps.
+///
+/// Canisters answering 404 usually have no asset for the path at all: their response
+/// passes through as [CertificationOutcome::UncertifiedError], which only canisters whose
+/// certification is optional may serve, unless its body is certified as `/404.html` or
+/// `spa_fallback`, in which case it must match. Other errors must be certified at their
+/// path.
+fn validate_body_in_tree(
+    tree: &HashTree,
+    path: &str,
+    status_code: u16,
+    body_sha: &[u8; 32],
+    spa_fallback: &str,
+    logger: &slog::Logger,
+) -> Result<CertificationOutcome, VerificationFailure> {
+    let lookup = |path: &str| match tree.lookup_path(&["http_assets".into(), path.into()]) {
+        LookupResult::Found(v) => Some(v),
+        _ => None,
+    };
+
+    let tree_sha = match lookup(path) {
+        Some(v) => v,
+        None if status_code == 404 => {
+            let certified_error = ["/404.html", spa_fallback]
+                .iter()
+                .any(|fallback| lookup(*fallback) == Some(&body_sha[..]));
+            return Ok(if certified_error {
+                CertificationOutcome::Verified
+            } else {
+                slog::debug!(
+                    logger,
+                    "Passing through the uncertified 404 of path {} missing from the tree",
+                    path
+                );
+                CertificationOutcome::UncertifiedError
+            });
+        }
+        // Only a successful response can be the fallback document a single page app
+        // intentionally served for one of its routes.
+        None if (200..300).contains(&status_code) => match lookup(spa_fallback) {
+            Some(v) => v,
+            None => {
+                slog::trace!(
+                    logger,
+                    ">> Invalid Tree in the header. Does not contain path {:?}",
+                    path
+                );
+                return Err(VerificationFailure::PathNotFound);
+            }
+        },
+        None => {
+            slog::trace!(
+                logger,
+                ">> Invalid Tree in the header. Does not contain path {:?} answered with status {}",
+                path,
+                status_code
+            );
+            return Err(VerificationFailure::PathNotFound);
+        }
+    };
+
+    if &body_sha[..] != tree_sha {
+        return Err(VerificationFailure::BodyMismatch);
+    }
+    Ok(CertificationOutcome::Verified)
+}
+
+/// Log everything needed to diagnose a certification mismatch without reconstructing the
+/// tree by hand.
+fn dump_tree(
+    tree: &HashTree,
+    certified_data: &[u8],
+    canister_id: &Principal,
+    uri: &Uri,
+    logger: &slog::Logger,
+) {
+    slog::debug!(
+        logger,
+        "Certification of {} by canister {} does not match.\nLooked up path: http_assets/{}\nCertified data: {}\nTree digest: {}\nTree:\n{}",
+        uri,
+        canister_id,
+        uri.path(),
+        hex::encode(certified_data),
+        hex::encode(tree.digest()),
+        format_hash_tree(tree)
+    );
+}
+
+/// Certificates with an empty signature, for tests and benchmarks to go without the key
+/// of a subnet. They only pass verification once a [VerificationCache] holds them as
+/// verified.
+#[cfg(any(test, feature = "bench"))]
+pub(crate) mod unsigned {
+    use super::sha256;
+    use ic_agent::{
+        export::Principal,
+        ic_types::{
+            hash_tree::{fork, label, leaf},
+            HashTree,
+        },
+    };
+
+    /// The tree of the hashes of the bodies of `assets`, by path.
+    pub fn asset_tree(assets: &[(&str, &[u8])]) -> HashTree<'static> {
+        // Lookups expect the labels of a tree in order.
+        let mut assets = assets.to_vec();
+        assets.sort_by_key(|(path, _)| *path);
+        let assets = assets
+            .into_iter()
+            .map(|(path, body)| label(path.to_string(), leaf(sha256(body).to_vec())))
+            .reduce(fork)
+            .unwrap();
+        label("http_assets", assets)
+    }
+
+    /// The leaf of the time a certificate is issued at.
+    pub fn time_leaf(time: std::time::SystemTime) -> HashTree<'static> {
+        let mut nanos = time
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let mut leb128 = Vec::new();
+        loop {
+            let byte = (nanos & 0x7f) as u8;
+            nanos >>= 7;
+            if nanos == 0 {
+                leb128.push(byte);
+                break;
+            }
+            leb128.push(byte | 0x80);
+        }
+        leaf(leb128)
+    }
+
+    /// A certificate of `tree`, with an empty signature.
+    pub fn certificate_of(tree: HashTree) -> Vec<u8> {
+        let certificate = [
+            (
+                serde_cbor::Value::Text("tree".to_string()),
+                serde_cbor::value::to_value(tree).unwrap(),
+            ),
+            (
+                serde_cbor::Value::Text("signature".to_string()),
+                serde_cbor::Value::Bytes(Vec::new()),
+            ),
+        ];
+        serde_cbor::to_vec(&serde_cbor::Value::Map(
+            certificate.iter().cloned().collect(),
+        ))
+        .unwrap()
+    }
+
+    /// The SHA-256 of the certificate of an asset of `canister_id` with `body` at `path`,
+    /// and the IC-Certificate header carrying it.
+    pub fn certified_asset(canister_id: &Principal, path: &str, body: &[u8]) -> ([u8; 32], String) {
+        let tree = asset_tree(&[(path, body)]);
+        let certified_data = label(
+            "canister",
+            label(
+                canister_id.as_slice(),
+                label("certified_data", leaf(tree.digest().to_vec())),
+            ),
+        );
+        let certificate = certificate_of(fork(
+            certified_data,
+            label("time", time_leaf(std::time::SystemTime::now())),
+        ));
+        let header = format!(
+            "certificate=:{}:, tree=:{}:",
+            base64::encode(&certificate),
+            base64::encode(serde_cbor::to_vec(&tree).unwrap())
+        );
+        (sha256(&certificate), header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_certificate_age, decodable_accept_encoding, extract_headers_data,
+        extract_uncertified_headers_data, hash_body, hash_decoded_body,
+        unsigned::{asset_tree, certificate_of, time_leaf},
+        validate, validate_body_in_tree, BufferPool, Certification, CertificationOutcome,
+        CertificationPolicy, HeadersData, Metrics, ResponseKey, ValidationConfig,
+        VerificationCache, VerificationFailure, MAX_BYTES_SIZE_TO_DECOMPRESS,
+    };
+    use flate2::{
+        write::{GzEncoder, ZlibEncoder},
+        Compression,
+    };
+    use hyper::Uri;
+    use ic_agent::{
+        agent::http_transport::ReqwestHttpReplicaV2Transport,
+        export::Principal,
+        ic_types::hash_tree::{label, leaf},
+        Agent,
+    };
+    use ic_utils::interfaces::http_request::HeaderField;
+    use sha2::{Digest, Sha256};
+    use std::{io::Write, time::Duration};
+
+    fn logger() -> slog::Logger {
+        slog::Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn agent() -> Agent {
+        Agent::builder()
+            .with_transport(ReqwestHttpReplicaV2Transport::create("http://localhost:8000").unwrap())
+            .build()
+            .unwrap()
+    }
+
+    fn decode_body(body: &[u8], encoding: Option<&str>) -> Option<Vec<u8>> {
+        super::decode_body(body, encoding, &BufferPool::new(1, 1024), &logger())
+            .map(|decoded| decoded.to_vec())
+    }
+
+    fn sha256(body: &[u8]) -> [u8; 32] {
+        let mut sha256 = [0u8; 32];
+        sha256.copy_from_slice(&Sha256::digest(body));
+        sha256
+    }
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn deflate(body: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decode_identity() {
+        let body = b"hello world";
+        assert_eq!(decode_body(body, None), Some(body.to_vec()));
+        assert_eq!(decode_body(body, Some("identity")), Some(body.to_vec()));
+    }
+
+    #[test]
+    fn decode_gzip() {
+        let body = b"hello world";
+        assert_eq!(decode_body(&gzip(body), Some("gzip")), Some(body.to_vec()));
+    }
+
+    #[test]
+    fn accept_encoding_is_restricted_to_decodable_codings() {
+        assert_eq!(
+            decodable_accept_encoding("gzip, deflate, br, zstd"),
+            "gzip, deflate, br, zstd"
+        );
+        assert_eq!(
+            decodable_accept_encoding("gzip;q=0.8, compress, BR;q=1.0, exi"),
+            "gzip;q=0.8, br;q=1.0"
+        );
+        assert_eq!(
+            decodable_accept_encoding("br, *;q=0.1"),
+            "br, identity;q=0.1, gzip;q=0.1, deflate;q=0.1, zstd;q=0.1"
+        );
+        assert_eq!(decodable_accept_encoding("compress"), "identity");
+        assert_eq!(decodable_accept_encoding(""), "identity");
+    }
+
+    #[test]
+    fn decode_br() {
+        let body = b"hello world";
+        let mut encoded = Vec::new();
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+            encoder.write_all(body).unwrap();
+        }
+        assert_eq!(decode_body(&encoded, Some("br")), Some(body.to_vec()));
+    }
+
+    #[test]
+    fn decode_zstd() {
+        let body = b"hello world";
+        let encoded = zstd::stream::encode_all(&body[..], 0).unwrap();
+        assert_eq!(decode_body(&encoded, Some("zstd")), Some(body.to_vec()));
+    }
+
+    #[test]
+    fn decode_invalid_zstd() {
+        assert_eq!(decode_body(b"not zstd", Some("zstd")), None);
+    }
+
+    #[test]
+    fn decode_unknown_encoding() {
+        assert_eq!(decode_body(b"hello world", Some("compress")), None);
+    }
+
+    #[test]
+    fn decode_gzip_with_identity() {
+        let body = b"hello world";
+        assert_eq!(
+            decode_body(&gzip(body), Some("gzip, identity")),
+            Some(body.to_vec())
+        );
+        assert_eq!(
+            decode_body(&gzip(body), Some("identity,gzip")),
+            Some(body.to_vec())
+        );
+    }
+
+    #[test]
+    fn decode_stacked_encodings_right_to_left() {
+        let body = b"hello world";
+        // deflate was applied first, then gzip.
+        let encoded = gzip(&deflate(body));
+
+        assert_eq!(
+            decode_body(&encoded, Some("deflate, gzip")),
+            Some(body.to_vec())
+        );
+        assert_eq!(decode_body(&encoded, Some("gzip, deflate")), None);
+    }
+
+    #[test]
+    fn decode_stacked_with_unknown_encoding() {
+        assert_eq!(
+            decode_body(&gzip(b"hello world"), Some("compress, gzip")),
+            None
+        );
+    }
+
+    #[test]
+    fn repeated_content_encoding_headers_are_concatenated() {
+        let headers = vec![
+            HeaderField("Content-Encoding".to_string(), "deflate".to_string()),
+            HeaderField("content-encoding".to_string(), "GZIP".to_string()),
+        ];
+        let headers_data = extract_headers_data(&headers, None, &logger());
+        assert_eq!(headers_data.encoding.as_deref(), Some("deflate, gzip"));
+
+        let body = b"hello world";
+        assert_eq!(
+            decode_body(&gzip(&deflate(body)), headers_data.encoding.as_deref()),
+            Some(body.to_vec())
+        );
+    }
+
+    #[test]
+    fn uncertified_headers_data_leave_the_certificate_undecoded() {
+        let headers = vec![
+            HeaderField(
+                "IC-Certificate".to_string(),
+                "certificate=:aGVsbG8=:, tree=:d29ybGQ=:".to_string(),
+            ),
+            HeaderField("Content-Encoding".to_string(), "gzip".to_string()),
+        ];
+
+        let headers_data = extract_headers_data(&headers, None, &logger());
+        assert_eq!(headers_data.certificate, Some(Ok(b"hello".to_vec())));
+        assert_eq!(headers_data.encoding.as_deref(), Some("gzip"));
+
+        let headers_data = extract_uncertified_headers_data(&headers);
+        assert_eq!(headers_data.certificate, None);
+        assert_eq!(headers_data.tree, None);
+        assert_eq!(headers_data.encoding.as_deref(), Some("gzip"));
+    }
+
+    #[test]
+    fn single_content_encoding_header() {
+        let headers = vec![HeaderField(
+            "Content-Encoding".to_string(),
+            "gzip".to_string(),
+        )];
+        let headers_data = extract_headers_data(&headers, None, &logger());
+        assert_eq!(headers_data.encoding.as_deref(), Some("gzip"));
+    }
+
+    #[test]
+    fn hash_matches_decoded_body() {
+        // Larger than the hashing buffer, so it is hashed in several chunks.
+        let body = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+
+        assert_eq!(hash_body(&body[..]).unwrap(), sha256(&body));
+        assert_eq!(
+            hash_decoded_body(&gzip(&body), Some("gzip"), &logger()),
+            Some(sha256(&body))
+        );
+        assert_eq!(
+            hash_decoded_body(&body, Some("identity"), &logger()),
+            Some(sha256(&body))
+        );
+    }
+
+    #[test]
+    fn hash_limit_applies_to_decoded_bytes() {
+        let body = vec![0u8; MAX_BYTES_SIZE_TO_DECOMPRESS as usize + 1];
+        let encoded = zstd::stream::encode_all(&body[..], 0).unwrap();
+        // The compressed body itself is tiny.
+        assert!((encoded.len() as u64) < MAX_BYTES_SIZE_TO_DECOMPRESS);
+
+        assert_eq!(hash_decoded_body(&encoded, Some("zstd"), &logger()), None);
+        assert!(hash_body(&body[..]).is_err());
+        assert!(hash_body(&body[1..]).is_ok());
+    }
+
+    #[test]
+    fn unknown_encoding_fails_certified_response() {
+        let headers_data = HeadersData {
+            certificate: Some(Ok(vec![])),
+            tree: Some(Ok(vec![])),
+            encoding: Some("compress".to_string()),
+            opted_out: false,
+        };
+
+        let result = validate(
+            &headers_data,
+            &Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap(),
+            &agent(),
+            &Uri::from_static("/index.html"),
+            200,
+            b"hello world",
+            CertificationPolicy::Optional,
+            &Metrics::default(),
+            None,
+            &ValidationConfig::default(),
+            logger(),
+        );
+
+        if cfg!(feature = "skip_body_verification") {
+            assert_eq!(result, Ok(Certification::Unverified));
+        } else {
+            assert_eq!(result, Err("Body could not be decoded".to_string()));
+        }
+    }
+
+    #[test]
+    fn unknown_encoding_passes_uncertified_response() {
+        let headers_data = HeadersData {
+            certificate: None,
+            tree: None,
+            encoding: Some("compress".to_string()),
+            opted_out: false,
+        };
+
+        let result = validate(
+            &headers_data,
+            &Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap(),
+            &agent(),
+            &Uri::from_static("/index.html"),
+            200,
+            b"hello world",
+            CertificationPolicy::Optional,
+            &Metrics::default(),
+            None,
+            &ValidationConfig::default(),
+            logger(),
+        );
+
+        assert_eq!(result, Ok(Certification::Unverified));
+    }
+
+    #[test]
+    fn required_policy_fails_uncertified_response() {
+        let headers_data = HeadersData {
+            certificate: None,
+            tree: None,
+            encoding: None,
+            opted_out: false,
+        };
+
+        let result = validate(
+            &headers_data,
+            &Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap(),
+            &agent(),
+            &Uri::from_static("/index.html"),
+            200,
+            b"hello world",
+            CertificationPolicy::Required,
+            &Metrics::default(),
+            None,
+            &ValidationConfig::default(),
+            logger(),
+        );
+
+        if cfg!(feature = "skip_body_verification") {
+            assert_eq!(result, Ok(Certification::Unverified));
+        } else {
+            assert_eq!(result, Err("Response is not certified".to_string()));
+        }
+    }
+
+    #[test]
+    fn required_policy_fails_uncertified_error() {
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let headers_data = HeadersData {
+            certificate: Some(Ok(b"certificate".to_vec())),
+            tree: Some(Ok(b"tree".to_vec())),
+            encoding: None,
+            opted_out: false,
+        };
+        // The 404 of a path missing from the tree, as verified before.
+        let cache = VerificationCache::new(10, Duration::from_secs(60));
+        cache.insert_response(
+            ResponseKey {
+                canister_id,
+                certificate_sha: sha256(b"certificate"),
+                tree_sha: sha256(b"tree"),
+                body_sha: sha256(b"Not found"),
+                path: "/missing.js".to_string(),
+                status_code: 404,
+            },
+            CertificationOutcome::UncertifiedError,
+        );
+
+        let validate_with = |policy| {
+            validate(
+                &headers_data,
+                &canister_id,
+                &agent(),
+                &Uri::from_static("/missing.js"),
+                404,
+                b"Not found",
+                policy,
+                &Metrics::default(),
+                Some(&cache),
+                &ValidationConfig::default(),
+                logger(),
+            )
+        };
+
+        assert_eq!(
+            validate_with(CertificationPolicy::Optional),
+            Ok(Certification::Unverified)
+        );
+        if !cfg!(feature = "skip_body_verification") {
+            assert_eq!(
+                validate_with(CertificationPolicy::Required),
+                Err("Response is not certified".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn skip_policy_passes_invalid_certification() {
+        let headers_data = HeadersData {
+            certificate: Some(Err(())),
+            tree: None,
+            encoding: None,
+            opted_out: false,
+        };
+
+        let result = validate(
+            &headers_data,
+            &Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap(),
+            &agent(),
+            &Uri::from_static("/index.html"),
+            200,
+            b"hello world",
+            CertificationPolicy::Skip,
+            &Metrics::default(),
+            None,
+            &ValidationConfig::default(),
+            logger(),
+        );
+
+        assert_eq!(result, Ok(Certification::Skipped));
+    }
+
+    #[test]
+    fn canisters_opt_out_of_optional_verification() {
+        let headers = vec![
+            HeaderField("IC-Uncertified".to_string(), " TRUE".to_string()),
+            HeaderField(
+                "IC-Certificate".to_string(),
+                "certificate=:aGVsbG8=:".to_string(),
+            ),
+        ];
+        assert!(!extract_headers_data(&headers, None, &logger()).opted_out);
+        assert!(!extract_headers_data(&headers, Some("X-Other"), &logger()).opted_out);
+        let headers_data = extract_headers_data(&headers, Some("ic-uncertified"), &logger());
+        assert!(headers_data.opted_out);
+
+        let metrics = Metrics::default();
+        let validate_with = |policy| {
+            validate(
+                &headers_data,
+                &Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap(),
+                &agent(),
+                &Uri::from_static("/index.html"),
+                200,
+                b"hello world",
+                policy,
+                &metrics,
+                None,
+                &ValidationConfig::default(),
+                logger(),
+            )
+        };
+
+        // The partial certificate is not even looked at.
+        assert_eq!(
+            validate_with(CertificationPolicy::Optional),
+            Ok(Certification::Unverified)
+        );
+        if !cfg!(feature = "skip_body_verification") {
+            assert_eq!(
+                validate_with(CertificationPolicy::Required),
+                Err("Body does not pass verification".to_string())
+            );
+        }
+        assert_eq!(metrics.certifications.values().len(), 2);
+    }
+
+    // An asset served gzipped by an asset canister, and the hash certified for it.
+    const GZIPPED_ASSET: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xb3, 0x51, 0x74, 0xf1, 0x77,
+        0x0e, 0x89, 0x0c, 0x70, 0x55, 0xc8, 0x28, 0xc9, 0xcd, 0xb1, 0xb3, 0x81, 0x90, 0x49, 0xf9,
+        0x29, 0x95, 0x76, 0x1e, 0xa9, 0x39, 0x39, 0xf9, 0x3a, 0x0a, 0xc9, 0xa9, 0x45, 0x25, 0x99,
+        0x69, 0x99, 0xa9, 0x29, 0x0a, 0xe5, 0xf9, 0x45, 0x39, 0x29, 0x8a, 0x36, 0xfa, 0x60, 0x59,
+        0x1b, 0x7d, 0xb0, 0x52, 0x2e, 0x00, 0x13, 0xa2, 0x1a, 0x8c, 0x41, 0x00, 0x00, 0x00,
+    ];
+    const GZIPPED_ASSET_CERTIFIED_SHA: &str =
+        "181aa701350d0297afc9a3d8e32b4201c76f60f2b9dfcf91c97d8d9ec2b56e1b";
+
+    #[test]
+    fn body_is_verified_at_its_path() {
+        let tree = asset_tree(&[("/index.html", b"index"), ("/app.js", b"app")]);
+
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/app.js",
+                200,
+                &sha256(b"app"),
+                "/index.html",
+                &logger()
+            ),
+            Ok(CertificationOutcome::Verified)
+        );
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/app.js",
+                200,
+                &sha256(b"tampered"),
+                "/index.html",
+                &logger()
+            ),
+            Err(VerificationFailure::BodyMismatch)
+        );
+    }
+
+    /// A certificate issued at `time`, with an empty signature.
+    fn certificate_at(time: std::time::SystemTime) -> Vec<u8> {
+        certificate_of(label("time", time_leaf(time)))
+    }
+
+    #[test]
+    fn stale_certificates_are_rejected() {
+        let now = std::time::SystemTime::now();
+        let max_age = Duration::from_secs(300);
+
+        assert_eq!(
+            check_certificate_age(&certificate_at(now), max_age, &logger()),
+            Ok(())
+        );
+        assert_eq!(
+            check_certificate_age(
+                &certificate_at(now + Duration::from_secs(30)),
+                max_age,
+                &logger()
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check_certificate_age(
+                &certificate_at(now - Duration::from_secs(600)),
+                max_age,
+                &logger()
+            ),
+            Err(VerificationFailure::StaleCertificate)
+        );
+        assert_eq!(
+            check_certificate_age(b"not a certificate", max_age, &logger()),
+            Err(VerificationFailure::MalformedCertification)
+        );
+    }
+
+    #[test]
+    fn gzipped_asset_is_verified_against_its_decoded_body() {
+        let certified_sha = hex::decode(GZIPPED_ASSET_CERTIFIED_SHA).unwrap();
+        let tree = label(
+            "http_assets",
+            label("/index.html", leaf(certified_sha.clone())),
+        );
+
+        let body_sha = hash_decoded_body(GZIPPED_ASSET, Some("gzip"), &logger()).unwrap();
+        assert_eq!(body_sha.to_vec(), certified_sha);
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/index.html",
+                200,
+                &body_sha,
+                "/index.html",
+                &logger()
+            ),
+            Ok(CertificationOutcome::Verified)
+        );
+
+        // The bytes as served are not what is certified.
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/index.html",
+                200,
+                &sha256(GZIPPED_ASSET),
+                "/index.html",
+                &logger()
+            ),
+            Err(VerificationFailure::BodyMismatch)
+        );
+    }
+
+    #[test]
+    fn single_page_app_falls_back_to_index() {
+        let tree = asset_tree(&[("/index.html", b"index")]);
+
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/some/route",
+                200,
+                &sha256(b"index"),
+                "/index.html",
+                &logger()
+            ),
+            Ok(CertificationOutcome::Verified)
+        );
+        // A single page app serving its index with a 404 is still verified.
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/some/route",
+                404,
+                &sha256(b"index"),
+                "/index.html",
+                &logger()
+            ),
+            Ok(CertificationOutcome::Verified)
+        );
+    }
+
+    #[test]
+    fn single_page_app_falls_back_to_custom_document() {
+        let tree = asset_tree(&[("/200.html", b"app"), ("/index.html", b"index")]);
+
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/some/route",
+                200,
+                &sha256(b"app"),
+                "/200.html",
+                &logger()
+            ),
+            Ok(CertificationOutcome::Verified)
+        );
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/some/route",
+                200,
+                &sha256(b"index"),
+                "/200.html",
+                &logger()
+            ),
+            Err(VerificationFailure::BodyMismatch)
+        );
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/some/route",
+                200,
+                &sha256(b"app"),
+                "/app.html",
+                &logger()
+            ),
+            Err(VerificationFailure::PathNotFound)
+        );
+    }
+
+    #[test]
+    fn not_found_passes_through_when_path_is_missing() {
+        let tree = asset_tree(&[("/index.html", b"index")]);
+
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/missing.js",
+                404,
+                &sha256(b"Not found"),
+                "/index.html",
+                &logger()
+            ),
+            Ok(CertificationOutcome::UncertifiedError)
+        );
+        // Only a 404 passes through.
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/missing.js",
+                200,
+                &sha256(b"Not found"),
+                "/index.html",
+                &logger()
+            ),
+            Err(VerificationFailure::BodyMismatch)
+        );
+    }
+
+    #[test]
+    fn real_not_found_is_told_apart_from_single_page_app_route() {
+        let tree = asset_tree(&[("/index.html", b"index")]);
+
+        // A single page app routing /missing.js to its index.
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/missing.js",
+                200,
+                &sha256(b"index"),
+                "/index.html",
+                &logger()
+            ),
+            Ok(CertificationOutcome::Verified)
+        );
+        // A canister which has no /missing.js.
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/missing.js",
+                404,
+                &sha256(b"Not found"),
+                "/index.html",
+                &logger()
+            ),
+            Ok(CertificationOutcome::UncertifiedError)
+        );
+    }
+
+    #[test]
+    fn fallback_only_applies_to_successful_responses() {
+        let tree = asset_tree(&[("/index.html", b"index")]);
+
+        for status_code in [301, 500] {
+            assert_eq!(
+                validate_body_in_tree(
+                    &tree,
+                    "/missing.js",
+                    status_code,
+                    &sha256(b"index"),
+                    "/index.html",
+                    &logger()
+                ),
+                Err(VerificationFailure::PathNotFound)
+            );
+        }
+    }
+
+    #[test]
+    fn certified_not_found_page_is_verified() {
+        let tree = asset_tree(&[("/index.html", b"index"), ("/404.html", b"Not found")]);
+
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/missing.js",
+                404,
+                &sha256(b"Not found"),
+                "/index.html",
+                &logger()
+            ),
+            Ok(CertificationOutcome::Verified)
+        );
+    }
+
+    #[test]
+    fn certified_not_found_at_its_path_is_verified() {
+        let tree = asset_tree(&[("/index.html", b"index"), ("/gone.html", b"Gone")]);
+
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/gone.html",
+                404,
+                &sha256(b"Gone"),
+                "/index.html",
+                &logger()
+            ),
+            Ok(CertificationOutcome::Verified)
+        );
+    }
+
+    #[test]
+    fn not_found_of_existing_path_must_match() {
+        let tree = asset_tree(&[("/index.html", b"index"), ("/app.js", b"app")]);
+
+        assert_eq!(
+            validate_body_in_tree(
+                &tree,
+                "/app.js",
+                404,
+                &sha256(b"Not found"),
+                "/index.html",
+                &logger()
+            ),
+            Err(VerificationFailure::BodyMismatch)
+        );
+    }
+
+    #[test]
+    fn outcomes_are_counted_per_canister() {
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let metrics = Metrics::default();
+        let validate_with = |certificate, tree, policy| {
+            let headers_data = HeadersData {
+                certificate,
+                tree,
+                encoding: None,
+                opted_out: false,
+            };
+            let _ = validate(
+                &headers_data,
+                &canister_id,
+                &agent(),
+                &Uri::from_static("/index.html"),
+                200,
+                b"hello world",
+                policy,
+                &metrics,
+                None,
+                &ValidationConfig::default(),
+                logger(),
+            );
+        };
+
+        validate_with(None, None, CertificationPolicy::Optional);
+        validate_with(None, None, CertificationPolicy::Required);
+        validate_with(None, None, CertificationPolicy::Skip);
+        validate_with(Some(Ok(vec![])), None, CertificationPolicy::Optional);
+        validate_with(
+            Some(Ok(b"not cbor".to_vec())),
+            Some(Ok(b"not cbor".to_vec())),
+            CertificationPolicy::Optional,
+        );
+
+        let mut values = metrics.certifications.values();
+        values.sort_by_key(|((_, outcome), _)| format!("{:?}", outcome));
+        assert_eq!(
+            values,
+            vec![
+                (
+                    (
+                        canister_id,
+                        CertificationOutcome::Failed(VerificationFailure::IncompleteCertification)
+                    ),
+                    1
+                ),
+                (
+                    (
+                        canister_id,
+                        CertificationOutcome::Failed(VerificationFailure::MalformedCertification)
+                    ),
+                    1
+                ),
+                ((canister_id, CertificationOutcome::NoCertificate), 2),
+                ((canister_id, CertificationOutcome::Skipped), 1),
+            ]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use crate::metrics::CertificationOutcome;
+use ic_agent::export::Principal;
+use lru::LruCache;
+use std::{
+    hash::Hash,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// What the outcome of verifying a response depends on.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct ResponseKey {
+    pub canister_id: Principal,
+    pub certificate_sha: [u8; 32],
+    pub tree_sha: [u8; 32],
+    pub body_sha: [u8; 32],
+    pub path: String,
+    pub status_code: u16,
+}
+
+/// A bounded cache of the certificates and responses which passed verification, and of
+/// the digests of trees, so that identical responses are not verified again. Entries
+/// expire after a fixed time to live, after which their certificate has to be verified
+/// again.
+pub(crate) struct VerificationCache {
+    certificates: TimedLru<[u8; 32], ()>,
+    responses: TimedLru<ResponseKey, CertificationOutcome>,
+    /// The digests of trees, by the hash of their CBOR.
+    digests: TimedLru<[u8; 32], [u8; 32]>,
+}
+
+impl VerificationCache {
+    pub fn new(capacity: usize, ttl: Duration) -> VerificationCache {
+        VerificationCache {
+            certificates: TimedLru::new(capacity, ttl),
+            responses: TimedLru::new(capacity, ttl),
+            digests: TimedLru::new(capacity, ttl),
+        }
+    }
+
+    /// Whether the certificate with this hash was verified recently.
+    pub fn is_certificate_verified(&self, certificate_sha: &[u8; 32]) -> bool {
+        self.certificates.get(certificate_sha).is_some()
+    }
+
+    pub fn insert_certificate(&self, certificate_sha: [u8; 32]) {
+        self.certificates.insert(certificate_sha, ());
+    }
+
+    /// The outcome of verifying an identical response recently, if any.
+    pub fn response(&self, key: &ResponseKey) -> Option<CertificationOutcome> {
+        self.responses.get(key)
+    }
+
+    pub fn insert_response(&self, key: ResponseKey, outcome: CertificationOutcome) {
+        self.responses.insert(key, outcome);
+    }
+
+    /// The digest of the tree whose CBOR has this hash, if it was computed recently.
+    pub fn tree_digest(&self, tree_sha: &[u8; 32]) -> Option<[u8; 32]> {
+        self.digests.get(tree_sha)
+    }
+
+    pub fn insert_tree_digest(&self, tree_sha: [u8; 32], digest: [u8; 32]) {
+        self.digests.insert(tree_sha, digest);
+    }
+}
+
+struct TimedLru<K: Hash + Eq, V> {
+    entries: Mutex<LruCache<K, (Instant, V)>>,
+    ttl: Duration,
+}
+
+impl<K: Hash + Eq, V: Copy> TimedLru<K, V> {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        TimedLru {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((inserted, value)) if inserted.elapsed() < self.ttl => Some(*value),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: K, value: V) {
+        self.entries
+            .lock()
+            .unwrap()
+            .put(key, (Instant::now(), value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        metrics::CertificationOutcome,
+        verification_cache::{ResponseKey, VerificationCache},
+    };
+    use ic_agent::export::Principal;
+    use std::time::Duration;
+
+    fn key(path: &str) -> ResponseKey {
+        ResponseKey {
+            canister_id: Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap(),
+            certificate_sha: [1; 32],
+            tree_sha: [2; 32],
+            body_sha: [3; 32],
+            path: path.to_string(),
+            status_code: 200,
+        }
+    }
+
+    #[test]
+    fn caches_responses() {
+        let cache = VerificationCache::new(10, Duration::from_secs(60));
+        assert_eq!(cache.response(&key("/index.html")), None);
+
+        cache.insert_response(key("/index.html"), CertificationOutcome::Verified);
+
+        assert_eq!(
+            cache.response(&key("/index.html")),
+            Some(CertificationOutcome::Verified)
+        );
+        assert_eq!(cache.response(&key("/app.js")), None);
+    }
+
+    #[test]
+    fn caches_tree_digests() {
+        let cache = VerificationCache::new(10, Duration::from_secs(60));
+        assert_eq!(cache.tree_digest(&[2; 32]), None);
+
+        cache.insert_tree_digest([2; 32], [4; 32]);
+
+        assert_eq!(cache.tree_digest(&[2; 32]), Some([4; 32]));
+        assert_eq!(cache.tree_digest(&[3; 32]), None);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = VerificationCache::new(2, Duration::from_secs(60));
+        cache.insert_certificate([1; 32]);
+        cache.insert_certificate([2; 32]);
+        assert!(cache.is_certificate_verified(&[1; 32]));
+
+        cache.insert_certificate([3; 32]);
+
+        assert!(cache.is_certificate_verified(&[1; 32]));
+        assert!(!cache.is_certificate_verified(&[2; 32]));
+        assert!(cache.is_certificate_verified(&[3; 32]));
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = VerificationCache::new(10, Duration::from_secs(0));
+        cache.insert_certificate([1; 32]);
+        cache.insert_response(key("/index.html"), CertificationOutcome::Verified);
+
+        assert!(!cache.is_certificate_verified(&[1; 32]));
+        assert_eq!(cache.response(&key("/index.html")), None);
+    }
+}